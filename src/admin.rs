@@ -0,0 +1,519 @@
+use crate::chaos::{Fault, Latency};
+use crate::dispatch_audited;
+use crate::errors::AwsService;
+use crate::misc::{lock, read, write};
+use crate::persistence::{migrate_snapshot, run_blocking, save_snapshot};
+use crate::spill::add_spilled_messages;
+use crate::state::{QueuePath, ReceiveHandle, State};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::stream::StreamExt;
+use warp::http::StatusCode;
+use warp::Reply;
+
+/// Summarise the current state.
+pub async fn get_stats(state: Arc<State>) -> Result<impl Reply, Infallible> {
+    Ok(warp::reply::json(&state.get_stats()))
+}
+
+/// Ready unless shutting down. Includes the same summary as `get_stats()`.
+pub async fn get_readiness(state: Arc<State>) -> Result<impl Reply, Infallible> {
+    let stats = state.get_stats();
+    let status = match stats.shutting_down {
+        true => StatusCode::SERVICE_UNAVAILABLE,
+        false => StatusCode::OK,
+    };
+    Ok(warp::reply::with_status(warp::reply::json(&stats), status))
+}
+
+/// List all queues, with their attributes and message counts.
+pub async fn get_queues(state: Arc<State>) -> Result<impl Reply, Infallible> {
+    Ok(warp::reply::json(&state.get_queue_summaries()))
+}
+
+/// Queues are looked up in the default region and account unless the `region` or
+/// `account_id` query parameters are given.
+fn get_queue_path(s: &State, queue_name: &str, query: &HashMap<String, String>) -> QueuePath {
+    let ctx = s.get_request_context(
+        None,
+        query.get("account_id").map(|x| x.as_str()),
+        query.get("region").map(|x| x.as_str()),
+    );
+    QueuePath::new(&ctx.region, &ctx.account_id, queue_name)
+}
+
+/// List the messages waiting in a queue, without receiving them. Messages spilled to disk
+/// aren't listed, but are counted in the state view.
+pub async fn peek_messages(
+    queue_name: String,
+    query: HashMap<String, String>,
+    state: Arc<State>,
+) -> Result<impl Reply, Infallible> {
+    let path = get_queue_path(&state, &queue_name, &query);
+    match state.get_queue(&path) {
+        Some(q) => Ok(warp::reply::with_status(
+            warp::reply::json(&read(&q).messages),
+            StatusCode::OK,
+        )),
+        None => Ok(warp::reply::with_status(
+            warp::reply::json(&json!({ "error": format!("Queue not found: {}", path.as_str()) })),
+            StatusCode::NOT_FOUND,
+        )),
+    }
+}
+
+async fn set_queue_paused(
+    queue_name: String,
+    query: HashMap<String, String>,
+    paused: bool,
+    state: Arc<State>,
+) -> StatusCode {
+    let path = get_queue_path(&state, &queue_name, &query);
+    match state.get_queue(&path) {
+        Some(q) => {
+            write(&q).set_paused(paused);
+            StatusCode::NO_CONTENT
+        }
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+/// Stop handing out messages from a queue. Messages are still accepted while paused.
+pub async fn pause_queue(
+    queue_name: String,
+    query: HashMap<String, String>,
+    state: Arc<State>,
+) -> Result<impl Reply, Infallible> {
+    Ok(set_queue_paused(queue_name, query, true, state).await)
+}
+
+/// Start handing out messages from a paused queue again.
+pub async fn resume_queue(
+    queue_name: String,
+    query: HashMap<String, String>,
+    state: Arc<State>,
+) -> Result<impl Reply, Infallible> {
+    Ok(set_queue_paused(queue_name, query, false, state).await)
+}
+
+/// Move all messages from a queue, such as a dead-letter queue, to the queue named by the `to`
+/// query parameter. Messages spilled to disk stay behind until they have been read back.
+pub async fn redrive_queue(
+    queue_name: String,
+    query: HashMap<String, String>,
+    state: Arc<State>,
+) -> Result<impl Reply, Infallible> {
+    let target = match query.get("to") {
+        Some(x) => x,
+        None => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({ "error": "The to parameter is required" })),
+                StatusCode::BAD_REQUEST,
+            ))
+        }
+    };
+
+    let from = get_queue_path(&state, &queue_name, &query);
+    let to = get_queue_path(&state, target, &query);
+    match state.move_messages(&from, &to) {
+        Some(moved) => Ok(warp::reply::with_status(
+            warp::reply::json(&json!({ "moved": moved })),
+            StatusCode::OK,
+        )),
+        None => Ok(warp::reply::with_status(
+            warp::reply::json(&json!({ "error": "Queue not found" })),
+            StatusCode::NOT_FOUND,
+        )),
+    }
+}
+
+/// List received messages that have not been deleted yet.
+pub async fn get_in_flight_messages(state: Arc<State>) -> Result<impl Reply, Infallible> {
+    Ok(warp::reply::json(&state.get_in_flight_messages()))
+}
+
+/// Expire the visibility timeout of received messages from a queue immediately, so they are
+/// redelivered. Only the message with the `receipt_handle` query parameter is expired, if given.
+pub async fn expire_in_flight_messages(
+    queue_name: String,
+    query: HashMap<String, String>,
+    state: Arc<State>,
+) -> Result<impl Reply, Infallible> {
+    let path = get_queue_path(&state, &queue_name, &query);
+    let handles: Vec<ReceiveHandle> = match state.get_queue(&path) {
+        Some(q) => read(&q)
+            .in_flight
+            .keys()
+            .filter(|handle| query.get("receipt_handle").map_or(true, |h| h == &handle.0))
+            .cloned()
+            .collect(),
+        None => Vec::new(),
+    };
+    let expired = state.requeue_received_messages(&path, &handles);
+    Ok(warp::reply::json(&json!({ "expired": expired })))
+}
+
+/// Get the lifecycle trace of a message, from being sent or published until it is deleted.
+pub async fn get_message_trace(
+    message_id: String,
+    state: Arc<State>,
+) -> Result<impl Reply, Infallible> {
+    match state.get_message_trace(&message_id) {
+        Some(events) => Ok(warp::reply::with_status(
+            warp::reply::json(&events),
+            StatusCode::OK,
+        )),
+        None => Ok(warp::reply::with_status(
+            warp::reply::json(&json!({ "error": format!("Message not found: {}", message_id) })),
+            StatusCode::NOT_FOUND,
+        )),
+    }
+}
+
+/// Search queued and in-flight messages in all queues. The `id`, `body` (a substring of the
+/// body), `attribute` (an attribute name) and `value` (the attribute's value) query parameters
+/// must all match, if given. Messages spilled to disk aren't searched.
+pub async fn search_messages(
+    query: HashMap<String, String>,
+    state: Arc<State>,
+) -> Result<impl Reply, Infallible> {
+    let found = state.find_messages(|m| {
+        let attribute = query.get("attribute").map(|name| m.get_attribute(name));
+        query.get("id").map_or(true, |id| &m.id == id)
+            && query
+                .get("body")
+                .map_or(true, |x| m.content.contains(x.as_str()))
+            && attribute.map_or(true, |a| a.is_some())
+            && query.get("value").map_or(true, |v| {
+                attribute.flatten().map(|a| a.get_value()).as_ref() == Some(v)
+            })
+    });
+    Ok(warp::reply::json(&found))
+}
+
+/// Delete a queued or in-flight message by id. Messages spilled to disk can't be deleted until
+/// they have been read back.
+pub async fn remove_message(
+    message_id: String,
+    state: Arc<State>,
+) -> Result<impl Reply, Infallible> {
+    match state.delete_message_by_id(&message_id) {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Ok(StatusCode::NOT_FOUND),
+    }
+}
+
+/// List all topics, with their attributes and subscriptions.
+pub async fn get_topics(state: Arc<State>) -> Result<impl Reply, Infallible> {
+    Ok(warp::reply::json(&state.get_topic_summaries()))
+}
+
+/// Stream every message sent, published, received or deleted, as server-sent events.
+pub async fn stream_events(state: Arc<State>) -> Result<impl Reply, Infallible> {
+    let events = state.subscribe_events();
+    // Events missed by a slow subscriber are skipped.
+    let stream = events.filter_map(|e| e.ok().map(|e| Ok::<_, Infallible>(warp::sse::json(e))));
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(stream)))
+}
+
+/// Stream each message sent to a queue, as server-sent events, without receiving them.
+pub async fn tail_queue(
+    queue_name: String,
+    query: HashMap<String, String>,
+    state: Arc<State>,
+) -> Result<impl Reply, Infallible> {
+    let path = get_queue_path(&state, &queue_name, &query);
+    let events = state.subscribe_events();
+    let stream = events.filter_map(move |e| match e {
+        Ok(e) if e.resource == path.as_str() && e.is_arrival() => {
+            Some(Ok::<_, Infallible>(warp::sse::json(e)))
+        }
+        _ => None,
+    });
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(stream)))
+}
+
+/// List recent actions that created, changed or deleted resources.
+pub async fn get_audit_log(state: Arc<State>) -> Result<impl Reply, Infallible> {
+    Ok(warp::reply::json(&*lock(&state.audit_log)))
+}
+
+/// Export the queues and topics, including messages, in-flight messages and subscriptions, as
+/// JSON. Spilled messages are read back once the queues are unlocked.
+pub async fn export_state(state: Arc<State>) -> Result<impl Reply, Infallible> {
+    let (mut export, spilled) = state.export();
+    let result = run_blocking(move || {
+        add_spilled_messages(&mut export, spilled);
+        Ok(export)
+    });
+    match result.await {
+        Ok(export) => Ok(warp::reply::with_status(
+            warp::reply::json(&export),
+            StatusCode::OK,
+        )),
+        Err(e) => Ok(warp::reply::with_status(
+            warp::reply::json(&json!({ "error": e.to_string() })),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+/// Replace the queues, topics and in-flight messages with those from `export_state()`.
+/// Exports from older versions are upgraded first.
+pub async fn import_state(
+    value: serde_json::Value,
+    state: Arc<State>,
+) -> Result<impl Reply, Infallible> {
+    let snapshot = match migrate_snapshot(value) {
+        Ok(x) => x,
+        Err(e) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({ "error": e.to_string() })),
+                StatusCode::BAD_REQUEST,
+            ))
+        }
+    };
+    state.import(snapshot);
+    Ok(warp::reply::with_status(
+        warp::reply::json(&json!({})),
+        StatusCode::OK,
+    ))
+}
+
+/// Move the virtual clock forward by the `seconds` query parameter.
+/// Timers that come due as a result run immediately, so messages whose visibility timeout
+/// expires are requeued and delayed messages become visible.
+pub async fn advance_clock(
+    query: HashMap<String, String>,
+    state: Arc<State>,
+) -> Result<impl Reply, Infallible> {
+    let seconds: i64 = match query.get("seconds").and_then(|x| x.parse().ok()) {
+        Some(x) if x >= 0 => x,
+        _ => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({ "error": "seconds must be a non-negative integer" })),
+                StatusCode::BAD_REQUEST,
+            ))
+        }
+    };
+
+    match state.advance_clock(chrono::Duration::seconds(seconds)) {
+        Some(now) => Ok(warp::reply::with_status(
+            warp::reply::json(&json!({ "now": now })),
+            StatusCode::OK,
+        )),
+        None => Ok(warp::reply::with_status(
+            warp::reply::json(&json!({ "error": "The virtual clock is not enabled" })),
+            StatusCode::CONFLICT,
+        )),
+    }
+}
+
+/// A topic and queue to create and subscribe together, for `wire_topic_to_queue()`.
+#[derive(Deserialize)]
+pub struct Wiring {
+    pub topic: String,
+    pub queue: String,
+    #[serde(default)]
+    pub raw_message_delivery: bool,
+    pub filter_policy: Option<serde_json::Value>,
+    pub region: Option<String>,
+    pub account_id: Option<String>,
+}
+
+/// Create a topic and a queue, if they don't exist, and subscribe the queue to the topic.
+/// Returns the queue URL and ARN, topic ARN and subscription ARN.
+pub async fn wire_topic_to_queue(
+    wiring: Wiring,
+    state: Arc<State>,
+) -> Result<impl Reply, Infallible> {
+    let ctx =
+        state.get_request_context(None, wiring.account_id.as_deref(), wiring.region.as_deref());
+    let queue_url = state.get_queue_url(&ctx, &wiring.queue);
+    let topic_arn = state.get_topic_arn(&ctx, &wiring.topic);
+    let queue_arn = QueuePath::new(&ctx.region, &ctx.account_id, &wiring.queue).get_arn();
+
+    let mut subscription_attributes = Vec::new();
+    if wiring.raw_message_delivery {
+        subscription_attributes.push(("RawMessageDelivery".to_string(), "true".to_string()));
+    }
+    if let Some(policy) = wiring.filter_policy {
+        subscription_attributes.push(("FilterPolicy".to_string(), policy.to_string()));
+    }
+    let mut subscribe = vec![
+        ("Action".to_string(), "Subscribe".to_string()),
+        ("TopicArn".to_string(), topic_arn.0.clone()),
+        ("Protocol".to_string(), "sqs".to_string()),
+        ("Endpoint".to_string(), queue_arn.clone()),
+        ("ReturnSubscriptionArn".to_string(), "true".to_string()),
+    ];
+    for (i, (k, v)) in subscription_attributes.into_iter().enumerate() {
+        subscribe.push((format!("Attributes.entry.{}.key", i + 1), k));
+        subscribe.push((format!("Attributes.entry.{}.value", i + 1), v));
+    }
+
+    // Go through the API, so that names are validated and the changes are audited.
+    let requests = vec![
+        vec![
+            ("Action".to_string(), "CreateQueue".to_string()),
+            ("QueueName".to_string(), wiring.queue.clone()),
+        ],
+        vec![
+            ("Action".to_string(), "CreateTopic".to_string()),
+            ("Name".to_string(), wiring.topic.clone()),
+        ],
+        subscribe,
+    ];
+    for params in requests {
+        let params: HashMap<String, String> = params.into_iter().collect();
+        let action = params["Action"].clone();
+        if let Err(e) = dispatch_audited(&action, params, ctx.clone(), state.clone()).await {
+            let service = AwsService::from_action(&action).unwrap_or(AwsService::Sqs);
+            let status =
+                StatusCode::from_u16(e.get_status_code(service)).unwrap_or(StatusCode::BAD_REQUEST);
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({ "error": e.to_string() })),
+                status,
+            ));
+        }
+    }
+
+    let subscription_arn = state
+        .get_topic(&topic_arn)
+        .and_then(|t| {
+            read(&t)
+                .subscriptions
+                .iter()
+                .find(|x| x.protocol == "sqs" && x.endpoint == queue_arn)
+                .map(|x| x.arn.clone())
+        })
+        .unwrap_or_default();
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&json!({
+            "queue_url": queue_url,
+            "queue_arn": queue_arn,
+            "topic_arn": topic_arn.0,
+            "subscription_arn": subscription_arn,
+        })),
+        StatusCode::OK,
+    ))
+}
+
+/// Save a snapshot to the data directory or store now, rather than waiting for shutdown.
+pub async fn save_state(state: Arc<State>) -> Result<impl Reply, Infallible> {
+    let store = match state.store.clone() {
+        Some(x) => x,
+        None => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({ "error": "No data directory or store is configured" })),
+                StatusCode::CONFLICT,
+            ))
+        }
+    };
+    match save_snapshot(store, &state).await {
+        Ok(()) => Ok(warp::reply::with_status(
+            warp::reply::json(&json!({})),
+            StatusCode::OK,
+        )),
+        Err(e) => Ok(warp::reply::with_status(
+            warp::reply::json(&json!({ "error": e.to_string() })),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+/// List the faults being injected, by action.
+pub async fn get_faults(state: Arc<State>) -> Result<impl Reply, Infallible> {
+    Ok(warp::reply::json(&lock(&state.chaos).faults))
+}
+
+/// Add or replace the fault injected for an action.
+pub async fn set_fault(fault: Fault, state: Arc<State>) -> Result<impl Reply, Infallible> {
+    let mut chaos = lock(&state.chaos);
+    match chaos.set_fault(fault) {
+        Ok(()) => Ok(warp::reply::with_status(
+            warp::reply::json(&chaos.faults),
+            StatusCode::OK,
+        )),
+        Err(e) => Ok(warp::reply::with_status(
+            warp::reply::json(&json!({ "error": e })),
+            StatusCode::BAD_REQUEST,
+        )),
+    }
+}
+
+/// Stop injecting faults, for all actions or only the `action` query parameter if given.
+pub async fn clear_faults(
+    query: HashMap<String, String>,
+    state: Arc<State>,
+) -> Result<impl Reply, Infallible> {
+    let mut chaos = lock(&state.chaos);
+    match query.get("action") {
+        Some(action) => {
+            chaos.faults.remove(action);
+        }
+        None => chaos.faults.clear(),
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// List the latencies being added to requests.
+pub async fn get_latencies(state: Arc<State>) -> Result<impl Reply, Infallible> {
+    Ok(warp::reply::json(&lock(&state.chaos).latencies))
+}
+
+/// Add or replace the latency added to requests for an action, and optionally a queue.
+pub async fn set_latency(latency: Latency, state: Arc<State>) -> Result<impl Reply, Infallible> {
+    let mut chaos = lock(&state.chaos);
+    match chaos.set_latency(latency) {
+        Ok(()) => Ok(warp::reply::with_status(
+            warp::reply::json(&chaos.latencies),
+            StatusCode::OK,
+        )),
+        Err(e) => Ok(warp::reply::with_status(
+            warp::reply::json(&json!({ "error": e })),
+            StatusCode::BAD_REQUEST,
+        )),
+    }
+}
+
+/// Stop adding latency, for all actions or only the `action` query parameter if given.
+pub async fn clear_latencies(
+    query: HashMap<String, String>,
+    state: Arc<State>,
+) -> Result<impl Reply, Infallible> {
+    let mut chaos = lock(&state.chaos);
+    match query.get("action") {
+        Some(action) => chaos.latencies.retain(|x| &x.action != action),
+        None => chaos.latencies.clear(),
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// List the notifications published to mobile push endpoints.
+pub async fn get_push_messages(state: Arc<State>) -> Result<impl Reply, Infallible> {
+    Ok(warp::reply::json(&*lock(&state.push_messages)))
+}
+
+/// Opt a phone number out of SMS, as if the recipient had replied STOP.
+pub async fn opt_out_phone_number(
+    phone_number: String,
+    state: Arc<State>,
+) -> Result<impl Reply, Infallible> {
+    lock(&state.opted_out_phone_numbers).insert(phone_number);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// List the records delivered to firehose subscriptions.
+pub async fn get_firehose_records(state: Arc<State>) -> Result<impl Reply, Infallible> {
+    Ok(warp::reply::json(&*lock(&state.firehose_records)))
+}
+
+/// List recent delivery attempts, by subscription ARN.
+pub async fn get_delivery_attempts(state: Arc<State>) -> Result<impl Reply, Infallible> {
+    Ok(warp::reply::json(&*lock(&state.delivery_attempts)))
+}
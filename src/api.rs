@@ -0,0 +1,792 @@
+//! Typed requests and responses for the most common SQS and SNS actions, for building and
+//! inspecting requests in code rather than as form parameters and XML.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), String> {
+//! use smoqs::api::{self, CreateQueueRequest, SendMessageRequest};
+//!
+//! let server = smoqs::Server::spawn().await?;
+//! let state = server.get_state();
+//! let queue = api::execute(&state, &CreateQueueRequest::new("orders")).await?;
+//! let sent = api::execute(&state, &SendMessageRequest::new(&queue.queue_url, "hello")).await?;
+//! println!("Sent {}", sent.message_id);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::dispatch;
+use crate::errors::{MyError, MyResult};
+#[cfg(feature = "sqs")]
+use crate::misc::{
+    get_attribute_names, get_attributes, get_message_attribute_names, get_trace_header_attribute,
+};
+use crate::misc::{get_message_attributes, validate_message_attributes};
+#[cfg(feature = "sns")]
+use crate::misc::{get_sns_attributes, get_sns_message_attributes};
+use crate::state::State;
+use crate::xml::{get_elements, get_raw_elements, ToXml, XmlWriter};
+use std::collections::{BTreeMap, HashMap};
+#[cfg(feature = "sqs")]
+use std::str::FromStr;
+use std::sync::Arc;
+
+pub use crate::state::MessageAttributeValue;
+
+/// A request for one action, which can be converted to the form parameters a client sends.
+pub trait ApiRequest {
+    type Response: ApiResponse;
+
+    fn get_action(&self) -> &'static str;
+
+    fn to_params(&self) -> HashMap<String, String>;
+}
+
+/// A response that can be read from the XML body returned for a successful request.
+pub trait ApiResponse: Sized {
+    fn from_xml(xml: &str) -> Result<Self, String>;
+}
+
+/// A request that can be parsed from the form parameters a client sent. Missing and
+/// malformed parameters are reported the same way for every action.
+pub(crate) trait FromParams: Sized {
+    fn from_params(form: &HashMap<String, String>) -> MyResult<Self>;
+}
+
+/// Actions with nothing in their response besides the request id.
+impl ApiResponse for () {
+    fn from_xml(_xml: &str) -> Result<Self, String> {
+        Ok(())
+    }
+}
+
+/// Make a request against a server's state directly, as if it had been sent over HTTP, in
+/// the default region and account.
+pub async fn execute<R: ApiRequest>(
+    state: &Arc<State>,
+    request: &R,
+) -> Result<R::Response, String> {
+    let action = request.get_action();
+    let mut params = request.to_params();
+    params.insert("Action".to_string(), action.to_string());
+    let ctx = state.get_request_context(None, None, None);
+    let response = dispatch(action, params, ctx, state.clone())
+        .await
+        .map_err(|e| format!("{} failed: {}", action, e))?;
+    R::Response::from_xml(&response.into_string())
+}
+
+fn require(xml: &str, tag: &str) -> Result<String, String> {
+    get_elements(xml, tag)
+        .pop()
+        .ok_or_else(|| format!("The response has no {}: {}", tag, xml))
+}
+
+/// Get a parameter that the request must include.
+fn get_required(form: &HashMap<String, String>, name: &str) -> MyResult<String> {
+    form.get(name)
+        .cloned()
+        .ok_or_else(|| MyError::MissingParameter(name.to_string()))
+}
+
+// Why a number of seconds couldn't be parsed.
+#[cfg(feature = "sqs")]
+const SECONDS_REASON: &str = "Must be a non-negative number of seconds.";
+
+/// Get an optional parameter, which must parse as `T` if it is included. Otherwise the error
+/// gives the reason, which says what the value should be.
+#[cfg(feature = "sqs")]
+fn get_parsed<T: FromStr>(
+    form: &HashMap<String, String>,
+    name: &str,
+    reason: &str,
+) -> MyResult<Option<T>> {
+    match form.get(name) {
+        Some(value) => value.parse().map(Some).map_err(|_| {
+            MyError::InvalidParameterValue(name.to_string(), value.clone(), reason.to_string())
+        }),
+        None => Ok(None),
+    }
+}
+
+fn get_params(params: &[(&str, &str)]) -> HashMap<String, String> {
+    params
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+/// Add numbered parameters for a list, e.g. `AttributeName.1`.
+fn add_list_params(params: &mut HashMap<String, String>, prefix: &str, values: &[String]) {
+    for (i, value) in values.iter().enumerate() {
+        params.insert(format!("{}.{}", prefix, i + 1), value.clone());
+    }
+}
+
+/// Add numbered parameters for attributes, in order of name. SQS uses `Attribute.1.Name` and
+/// `Attribute.1.Value`, and SNS uses `Attributes.entry.1.key` and `Attributes.entry.1.value`.
+fn add_attribute_params(
+    params: &mut HashMap<String, String>,
+    prefix: &str,
+    key_name: &str,
+    value_name: &str,
+    attributes: &HashMap<String, String>,
+) {
+    let sorted: BTreeMap<_, _> = attributes.iter().collect();
+    for (i, (k, v)) in sorted.into_iter().enumerate() {
+        params.insert(format!("{}.{}.{}", prefix, i + 1, key_name), k.clone());
+        params.insert(format!("{}.{}.{}", prefix, i + 1, value_name), v.clone());
+    }
+}
+
+/// Add numbered parameters for message attributes, in order of name, e.g.
+/// `MessageAttribute.1.Name` and `MessageAttribute.1.Value.DataType`.
+fn add_message_attribute_params(
+    params: &mut HashMap<String, String>,
+    prefix: &str,
+    attributes: &HashMap<String, MessageAttributeValue>,
+) {
+    let sorted: BTreeMap<_, _> = attributes.iter().collect();
+    for (i, (name, value)) in sorted.into_iter().enumerate() {
+        let entry = format!("{}.{}", prefix, i + 1);
+        params.insert(format!("{}.Name", entry), name.clone());
+        params.insert(format!("{}.Value.DataType", entry), value.data_type.clone());
+        if let Some(x) = &value.string_value {
+            params.insert(format!("{}.Value.StringValue", entry), x.clone());
+        }
+        if let Some(x) = &value.binary_value {
+            params.insert(format!("{}.Value.BinaryValue", entry), x.clone());
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CreateQueueRequest {
+    pub queue_name: String,
+    pub attributes: HashMap<String, String>,
+}
+
+impl CreateQueueRequest {
+    pub fn new(queue_name: &str) -> Self {
+        Self {
+            queue_name: queue_name.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+impl ApiRequest for CreateQueueRequest {
+    type Response = CreateQueueResponse;
+
+    fn get_action(&self) -> &'static str {
+        "CreateQueue"
+    }
+
+    fn to_params(&self) -> HashMap<String, String> {
+        let mut params = get_params(&[("QueueName", &self.queue_name)]);
+        add_attribute_params(&mut params, "Attribute", "Name", "Value", &self.attributes);
+        params
+    }
+}
+
+#[cfg(feature = "sqs")]
+impl FromParams for CreateQueueRequest {
+    fn from_params(form: &HashMap<String, String>) -> MyResult<Self> {
+        Ok(Self {
+            queue_name: get_required(form, "QueueName")?,
+            attributes: get_attributes(form),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CreateQueueResponse {
+    pub queue_url: String,
+}
+
+impl ApiResponse for CreateQueueResponse {
+    fn from_xml(xml: &str) -> Result<Self, String> {
+        Ok(Self {
+            queue_url: require(xml, "QueueUrl")?,
+        })
+    }
+}
+
+impl ToXml for CreateQueueResponse {
+    fn write_xml(&self, w: &mut XmlWriter) {
+        w.text("QueueUrl", &self.queue_url);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DeleteQueueRequest {
+    pub queue_url: String,
+}
+
+impl ApiRequest for DeleteQueueRequest {
+    type Response = ();
+
+    fn get_action(&self) -> &'static str {
+        "DeleteQueue"
+    }
+
+    fn to_params(&self) -> HashMap<String, String> {
+        get_params(&[("QueueUrl", &self.queue_url)])
+    }
+}
+
+impl FromParams for DeleteQueueRequest {
+    fn from_params(form: &HashMap<String, String>) -> MyResult<Self> {
+        Ok(Self {
+            queue_url: get_required(form, "QueueUrl")?,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PurgeQueueRequest {
+    pub queue_url: String,
+}
+
+impl ApiRequest for PurgeQueueRequest {
+    type Response = ();
+
+    fn get_action(&self) -> &'static str {
+        "PurgeQueue"
+    }
+
+    fn to_params(&self) -> HashMap<String, String> {
+        get_params(&[("QueueUrl", &self.queue_url)])
+    }
+}
+
+impl FromParams for PurgeQueueRequest {
+    fn from_params(form: &HashMap<String, String>) -> MyResult<Self> {
+        Ok(Self {
+            queue_url: get_required(form, "QueueUrl")?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SendMessageRequest {
+    pub queue_url: String,
+    pub message_body: String,
+    pub delay_seconds: Option<u32>,
+    pub message_attributes: HashMap<String, MessageAttributeValue>,
+    /// The `AWSTraceHeader` system attribute.
+    pub trace_header: Option<String>,
+}
+
+impl SendMessageRequest {
+    pub fn new(queue_url: &str, message_body: &str) -> Self {
+        Self {
+            queue_url: queue_url.to_string(),
+            message_body: message_body.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+impl ApiRequest for SendMessageRequest {
+    type Response = SendMessageResponse;
+
+    fn get_action(&self) -> &'static str {
+        "SendMessage"
+    }
+
+    fn to_params(&self) -> HashMap<String, String> {
+        let mut params = get_params(&[
+            ("QueueUrl", &self.queue_url),
+            ("MessageBody", &self.message_body),
+        ]);
+        if let Some(x) = self.delay_seconds {
+            params.insert("DelaySeconds".to_string(), x.to_string());
+        }
+        add_message_attribute_params(&mut params, "MessageAttribute", &self.message_attributes);
+        if let Some(x) = &self.trace_header {
+            let mut system_attributes = HashMap::new();
+            system_attributes.insert(
+                "AWSTraceHeader".to_string(),
+                MessageAttributeValue::string(x),
+            );
+            add_message_attribute_params(&mut params, "MessageSystemAttribute", &system_attributes);
+        }
+        params
+    }
+}
+
+#[cfg(feature = "sqs")]
+impl FromParams for SendMessageRequest {
+    fn from_params(form: &HashMap<String, String>) -> MyResult<Self> {
+        let message_attributes = get_message_attributes(form);
+        validate_message_attributes(&message_attributes)?;
+        Ok(Self {
+            queue_url: get_required(form, "QueueUrl")?,
+            message_body: get_required(form, "MessageBody")?,
+            delay_seconds: get_parsed(form, "DelaySeconds", SECONDS_REASON)?,
+            message_attributes,
+            trace_header: get_trace_header_attribute(form),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SendMessageResponse {
+    pub message_id: String,
+    pub md5_of_message_body: String,
+    pub md5_of_message_attributes: Option<String>,
+}
+
+impl ApiResponse for SendMessageResponse {
+    fn from_xml(xml: &str) -> Result<Self, String> {
+        Ok(Self {
+            message_id: require(xml, "MessageId")?,
+            md5_of_message_body: require(xml, "MD5OfMessageBody")?,
+            md5_of_message_attributes: get_elements(xml, "MD5OfMessageAttributes").pop(),
+        })
+    }
+}
+
+impl ToXml for SendMessageResponse {
+    fn write_xml(&self, w: &mut XmlWriter) {
+        w.text("MD5OfMessageBody", &self.md5_of_message_body);
+        w.optional_text(
+            "MD5OfMessageAttributes",
+            self.md5_of_message_attributes.as_deref(),
+        );
+        w.text("MessageId", &self.message_id);
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ReceiveMessageRequest {
+    pub queue_url: String,
+    pub max_number_of_messages: Option<u8>,
+    pub wait_time_seconds: Option<u64>,
+    pub visibility_timeout: Option<u32>,
+    /// System attributes to return, such as `AWSTraceHeader`, or `All`.
+    pub attribute_names: Vec<String>,
+    /// Message attributes to return, or `All`.
+    pub message_attribute_names: Vec<String>,
+}
+
+impl ReceiveMessageRequest {
+    pub fn new(queue_url: &str) -> Self {
+        Self {
+            queue_url: queue_url.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+impl ApiRequest for ReceiveMessageRequest {
+    type Response = ReceiveMessageResponse;
+
+    fn get_action(&self) -> &'static str {
+        "ReceiveMessage"
+    }
+
+    fn to_params(&self) -> HashMap<String, String> {
+        let mut params = get_params(&[("QueueUrl", &self.queue_url)]);
+        if let Some(x) = self.max_number_of_messages {
+            params.insert("MaxNumberOfMessages".to_string(), x.to_string());
+        }
+        if let Some(x) = self.wait_time_seconds {
+            params.insert("WaitTimeSeconds".to_string(), x.to_string());
+        }
+        if let Some(x) = self.visibility_timeout {
+            params.insert("VisibilityTimeout".to_string(), x.to_string());
+        }
+        add_list_params(&mut params, "AttributeName", &self.attribute_names);
+        add_list_params(
+            &mut params,
+            "MessageAttributeName",
+            &self.message_attribute_names,
+        );
+        params
+    }
+}
+
+#[cfg(feature = "sqs")]
+impl FromParams for ReceiveMessageRequest {
+    fn from_params(form: &HashMap<String, String>) -> MyResult<Self> {
+        Ok(Self {
+            queue_url: get_required(form, "QueueUrl")?,
+            max_number_of_messages: get_parsed(form, "MaxNumberOfMessages", "Must be an integer.")?,
+            wait_time_seconds: get_parsed(form, "WaitTimeSeconds", SECONDS_REASON)?,
+            visibility_timeout: get_parsed(form, "VisibilityTimeout", SECONDS_REASON)?,
+            attribute_names: get_attribute_names(form),
+            message_attribute_names: get_message_attribute_names(form),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReceivedMessage {
+    pub message_id: String,
+    pub receipt_handle: String,
+    pub md5_of_body: String,
+    pub body: String,
+    pub attributes: HashMap<String, String>,
+    pub md5_of_message_attributes: Option<String>,
+    pub message_attributes: HashMap<String, MessageAttributeValue>,
+}
+
+impl ApiResponse for ReceivedMessage {
+    fn from_xml(xml: &str) -> Result<Self, String> {
+        let mut attributes = HashMap::new();
+        for attribute in get_raw_elements(xml, "Attribute") {
+            attributes.insert(require(attribute, "Name")?, require(attribute, "Value")?);
+        }
+        let mut message_attributes = HashMap::new();
+        for attribute in get_raw_elements(xml, "MessageAttribute") {
+            let value = MessageAttributeValue {
+                data_type: require(attribute, "DataType")?,
+                string_value: get_elements(attribute, "StringValue").pop(),
+                binary_value: get_elements(attribute, "BinaryValue").pop(),
+            };
+            message_attributes.insert(require(attribute, "Name")?, value);
+        }
+        Ok(Self {
+            message_id: require(xml, "MessageId")?,
+            receipt_handle: require(xml, "ReceiptHandle")?,
+            md5_of_body: require(xml, "MD5OfBody")?,
+            body: require(xml, "Body")?,
+            attributes,
+            md5_of_message_attributes: get_elements(xml, "MD5OfMessageAttributes").pop(),
+            message_attributes,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReceiveMessageResponse {
+    pub messages: Vec<ReceivedMessage>,
+}
+
+impl ApiResponse for ReceiveMessageResponse {
+    fn from_xml(xml: &str) -> Result<Self, String> {
+        let messages = get_raw_elements(xml, "Message")
+            .into_iter()
+            .map(ReceivedMessage::from_xml)
+            .collect::<Result<_, _>>()?;
+        Ok(Self { messages })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DeleteMessageRequest {
+    pub queue_url: String,
+    pub receipt_handle: String,
+}
+
+impl ApiRequest for DeleteMessageRequest {
+    type Response = ();
+
+    fn get_action(&self) -> &'static str {
+        "DeleteMessage"
+    }
+
+    fn to_params(&self) -> HashMap<String, String> {
+        get_params(&[
+            ("QueueUrl", &self.queue_url),
+            ("ReceiptHandle", &self.receipt_handle),
+        ])
+    }
+}
+
+impl FromParams for DeleteMessageRequest {
+    fn from_params(form: &HashMap<String, String>) -> MyResult<Self> {
+        Ok(Self {
+            queue_url: get_required(form, "QueueUrl")?,
+            receipt_handle: get_required(form, "ReceiptHandle")?,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChangeMessageVisibilityRequest {
+    pub queue_url: String,
+    pub receipt_handle: String,
+    pub visibility_timeout: u32,
+}
+
+impl ApiRequest for ChangeMessageVisibilityRequest {
+    type Response = ();
+
+    fn get_action(&self) -> &'static str {
+        "ChangeMessageVisibility"
+    }
+
+    fn to_params(&self) -> HashMap<String, String> {
+        get_params(&[
+            ("QueueUrl", &self.queue_url),
+            ("ReceiptHandle", &self.receipt_handle),
+            ("VisibilityTimeout", &self.visibility_timeout.to_string()),
+        ])
+    }
+}
+
+#[cfg(feature = "sqs")]
+impl FromParams for ChangeMessageVisibilityRequest {
+    fn from_params(form: &HashMap<String, String>) -> MyResult<Self> {
+        let visibility_timeout = get_parsed(form, "VisibilityTimeout", SECONDS_REASON)?
+            .ok_or_else(|| MyError::MissingParameter("VisibilityTimeout".to_string()))?;
+        Ok(Self {
+            queue_url: get_required(form, "QueueUrl")?,
+            receipt_handle: get_required(form, "ReceiptHandle")?,
+            visibility_timeout,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CreateTopicRequest {
+    pub name: String,
+    pub attributes: HashMap<String, String>,
+}
+
+impl CreateTopicRequest {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+impl ApiRequest for CreateTopicRequest {
+    type Response = CreateTopicResponse;
+
+    fn get_action(&self) -> &'static str {
+        "CreateTopic"
+    }
+
+    fn to_params(&self) -> HashMap<String, String> {
+        let mut params = get_params(&[("Name", &self.name)]);
+        add_attribute_params(
+            &mut params,
+            "Attributes.entry",
+            "key",
+            "value",
+            &self.attributes,
+        );
+        params
+    }
+}
+
+#[cfg(feature = "sns")]
+impl FromParams for CreateTopicRequest {
+    fn from_params(form: &HashMap<String, String>) -> MyResult<Self> {
+        Ok(Self {
+            name: get_required(form, "Name")?,
+            attributes: get_sns_attributes(form),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CreateTopicResponse {
+    pub topic_arn: String,
+}
+
+impl ApiResponse for CreateTopicResponse {
+    fn from_xml(xml: &str) -> Result<Self, String> {
+        Ok(Self {
+            topic_arn: require(xml, "TopicArn")?,
+        })
+    }
+}
+
+impl ToXml for CreateTopicResponse {
+    fn write_xml(&self, w: &mut XmlWriter) {
+        w.text("TopicArn", &self.topic_arn);
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SubscribeRequest {
+    pub topic_arn: String,
+    pub protocol: String,
+    /// For SQS, a queue URL or ARN.
+    pub endpoint: String,
+    pub attributes: HashMap<String, String>,
+    /// Return the subscription ARN even if the subscription is pending confirmation.
+    pub return_subscription_arn: bool,
+}
+
+impl SubscribeRequest {
+    pub fn new(topic_arn: &str, protocol: &str, endpoint: &str) -> Self {
+        Self {
+            topic_arn: topic_arn.to_string(),
+            protocol: protocol.to_string(),
+            endpoint: endpoint.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+impl ApiRequest for SubscribeRequest {
+    type Response = SubscribeResponse;
+
+    fn get_action(&self) -> &'static str {
+        "Subscribe"
+    }
+
+    fn to_params(&self) -> HashMap<String, String> {
+        let mut params = get_params(&[
+            ("TopicArn", &self.topic_arn),
+            ("Protocol", &self.protocol),
+            ("Endpoint", &self.endpoint),
+        ]);
+        add_attribute_params(
+            &mut params,
+            "Attributes.entry",
+            "key",
+            "value",
+            &self.attributes,
+        );
+        if self.return_subscription_arn {
+            params.insert("ReturnSubscriptionArn".to_string(), "true".to_string());
+        }
+        params
+    }
+}
+
+#[cfg(feature = "sns")]
+impl FromParams for SubscribeRequest {
+    fn from_params(form: &HashMap<String, String>) -> MyResult<Self> {
+        Ok(Self {
+            topic_arn: get_required(form, "TopicArn")?,
+            protocol: get_required(form, "Protocol")?,
+            endpoint: get_required(form, "Endpoint")?,
+            attributes: get_sns_attributes(form),
+            return_subscription_arn: form
+                .get("ReturnSubscriptionArn")
+                .map(|v| v.eq_ignore_ascii_case("true"))
+                .unwrap_or(false),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SubscribeResponse {
+    pub subscription_arn: String,
+}
+
+impl ApiResponse for SubscribeResponse {
+    fn from_xml(xml: &str) -> Result<Self, String> {
+        Ok(Self {
+            subscription_arn: require(xml, "SubscriptionArn")?,
+        })
+    }
+}
+
+impl ToXml for SubscribeResponse {
+    fn write_xml(&self, w: &mut XmlWriter) {
+        w.text("SubscriptionArn", &self.subscription_arn);
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PublishRequest {
+    pub topic_arn: String,
+    /// A platform endpoint ARN to publish to instead of a topic.
+    pub target_arn: Option<String>,
+    pub message: String,
+    pub subject: Option<String>,
+    /// Set to `json` to send a different message to each protocol.
+    pub message_structure: Option<String>,
+    pub message_attributes: HashMap<String, MessageAttributeValue>,
+    /// Required for FIFO topics.
+    pub message_group_id: Option<String>,
+    pub message_deduplication_id: Option<String>,
+}
+
+impl PublishRequest {
+    pub fn new(topic_arn: &str, message: &str) -> Self {
+        Self {
+            topic_arn: topic_arn.to_string(),
+            message: message.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+impl ApiRequest for PublishRequest {
+    type Response = PublishResponse;
+
+    fn get_action(&self) -> &'static str {
+        "Publish"
+    }
+
+    fn to_params(&self) -> HashMap<String, String> {
+        let mut params = get_params(&[("TopicArn", &self.topic_arn), ("Message", &self.message)]);
+        let optional = [
+            ("TargetArn", &self.target_arn),
+            ("Subject", &self.subject),
+            ("MessageStructure", &self.message_structure),
+            ("MessageGroupId", &self.message_group_id),
+            ("MessageDeduplicationId", &self.message_deduplication_id),
+        ];
+        for (k, v) in optional.iter() {
+            if let Some(x) = v {
+                params.insert(k.to_string(), x.clone());
+            }
+        }
+        add_message_attribute_params(
+            &mut params,
+            "MessageAttributes.entry",
+            &self.message_attributes,
+        );
+        params
+    }
+}
+
+#[cfg(feature = "sns")]
+impl FromParams for PublishRequest {
+    fn from_params(form: &HashMap<String, String>) -> MyResult<Self> {
+        let target_arn = form.get("TargetArn").cloned();
+        // TopicArn is only required when there is no TargetArn.
+        let topic_arn = match target_arn {
+            Some(_) => form.get("TopicArn").cloned().unwrap_or_default(),
+            None => get_required(form, "TopicArn")?,
+        };
+        let mut message_attributes = get_message_attributes(form);
+        message_attributes.extend(get_sns_message_attributes(form));
+        validate_message_attributes(&message_attributes)?;
+        Ok(Self {
+            topic_arn,
+            target_arn,
+            message: get_required(form, "Message")?,
+            subject: form.get("Subject").cloned(),
+            message_structure: form.get("MessageStructure").cloned(),
+            message_attributes,
+            message_group_id: form.get("MessageGroupId").cloned(),
+            message_deduplication_id: form.get("MessageDeduplicationId").cloned(),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PublishResponse {
+    pub message_id: String,
+    /// Only set for FIFO topics.
+    pub sequence_number: Option<String>,
+}
+
+impl ApiResponse for PublishResponse {
+    fn from_xml(xml: &str) -> Result<Self, String> {
+        Ok(Self {
+            message_id: require(xml, "MessageId")?,
+            sequence_number: get_elements(xml, "SequenceNumber").pop(),
+        })
+    }
+}
+
+impl ToXml for PublishResponse {
+    fn write_xml(&self, w: &mut XmlWriter) {
+        w.text("MessageId", &self.message_id);
+        w.optional_text("SequenceNumber", self.sequence_number.as_deref());
+    }
+}
@@ -0,0 +1,301 @@
+use crate::xml::get_elements;
+use rand::Rng;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// The requests the load generator can make.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum BenchAction {
+    Send,
+    Receive,
+    Publish,
+}
+
+impl BenchAction {
+    pub fn get_name(self) -> &'static str {
+        match self {
+            BenchAction::Send => "SendMessage",
+            BenchAction::Receive => "ReceiveMessage",
+            BenchAction::Publish => "Publish",
+        }
+    }
+}
+
+/// The relative weight of each action, in the form `send=5,receive=4,publish=1`.
+#[derive(Debug, Clone)]
+pub struct Mix(Vec<(BenchAction, u32)>);
+
+impl FromStr for Mix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut weights = Vec::new();
+        for entry in s.split(',') {
+            let i = entry
+                .find('=')
+                .ok_or_else(|| format!("Invalid mix entry: {}", entry))?;
+            let action = match entry[..i].trim() {
+                "send" => BenchAction::Send,
+                "receive" => BenchAction::Receive,
+                "publish" => BenchAction::Publish,
+                x => return Err(format!("Unknown action in mix: {}", x)),
+            };
+            let weight: u32 = entry[i + 1..]
+                .trim()
+                .parse()
+                .map_err(|_| format!("Invalid weight in mix: {}", entry))?;
+            weights.push((action, weight));
+        }
+        if weights.iter().all(|(_, w)| *w == 0) {
+            return Err("The mix needs at least one action with a weight above 0".to_string());
+        }
+        Ok(Mix(weights))
+    }
+}
+
+impl Mix {
+    fn pick(&self, rng: &mut impl Rng) -> BenchAction {
+        let total: u32 = self.0.iter().map(|(_, w)| w).sum();
+        let mut n = rng.gen_range(0, total);
+        for (action, weight) in &self.0 {
+            if n < *weight {
+                return *action;
+            }
+            n -= weight;
+        }
+        unreachable!("pick is within the total weight")
+    }
+
+    fn contains(&self, action: BenchAction) -> bool {
+        self.0.iter().any(|(a, w)| *a == action && *w > 0)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    pub duration: Duration,
+    pub concurrency: usize,
+    pub mix: Mix,
+    pub queue_name: String,
+    pub topic_name: String,
+    pub message_size: usize,
+}
+
+/// The latency of every request made for one action, and the number that failed.
+#[derive(Debug, Default)]
+struct ActionStats {
+    latencies: Vec<Duration>,
+    errors: u64,
+}
+
+impl ActionStats {
+    fn merge(&mut self, other: ActionStats) {
+        self.latencies.extend(other.latencies);
+        self.errors += other.errors;
+    }
+}
+
+pub type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// Send one request, given its form parameters, and return the response body.
+/// Error responses are returned as errors.
+pub trait BenchClient: Clone + Send + Sync + 'static {
+    type Future: Future<Output = Result<String, String>> + Send;
+
+    fn call(&self, params: HashMap<String, String>) -> Self::Future;
+}
+
+impl<F, Fut> BenchClient for F
+where
+    F: Fn(HashMap<String, String>) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Result<String, String>> + Send,
+{
+    type Future = Fut;
+
+    fn call(&self, params: HashMap<String, String>) -> Self::Future {
+        self(params)
+    }
+}
+
+fn make_params(action: &str, params: &[(&str, &str)]) -> HashMap<String, String> {
+    let mut map: HashMap<String, String> = params
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    map.insert("Action".to_string(), action.to_string());
+    map
+}
+
+/// Create the queue and topic to use, and subscribe the queue to the topic so that published
+/// messages can be received. Returns the queue URL and topic ARN.
+async fn set_up<C: BenchClient>(
+    client: &C,
+    options: &BenchOptions,
+) -> Result<(String, String), String> {
+    let response = client
+        .call(make_params(
+            "CreateQueue",
+            &[("QueueName", &options.queue_name)],
+        ))
+        .await?;
+    let queue_url = get_elements(&response, "QueueUrl")
+        .pop()
+        .ok_or("CreateQueue didn't return a QueueUrl")?;
+
+    let response = client
+        .call(make_params("CreateTopic", &[("Name", &options.topic_name)]))
+        .await?;
+    let topic_arn = get_elements(&response, "TopicArn")
+        .pop()
+        .ok_or("CreateTopic didn't return a TopicArn")?;
+
+    if options.mix.contains(BenchAction::Publish) {
+        client
+            .call(make_params(
+                "Subscribe",
+                &[
+                    ("TopicArn", &topic_arn),
+                    ("Protocol", "sqs"),
+                    ("Endpoint", &queue_url),
+                ],
+            ))
+            .await?;
+    }
+    Ok((queue_url, topic_arn))
+}
+
+async fn run_worker<C: BenchClient>(
+    client: C,
+    options: BenchOptions,
+    queue_url: String,
+    topic_arn: String,
+    deadline: Instant,
+) -> HashMap<&'static str, ActionStats> {
+    let mut stats: HashMap<&'static str, ActionStats> = HashMap::new();
+    let body = "x".repeat(options.message_size);
+    while Instant::now() < deadline {
+        let action = options.mix.pick(&mut rand::thread_rng());
+        let params = match action {
+            BenchAction::Send => make_params(
+                "SendMessage",
+                &[("QueueUrl", &queue_url), ("MessageBody", &body)],
+            ),
+            BenchAction::Receive => make_params(
+                "ReceiveMessage",
+                &[("QueueUrl", &queue_url), ("MaxNumberOfMessages", "10")],
+            ),
+            BenchAction::Publish => {
+                make_params("Publish", &[("TopicArn", &topic_arn), ("Message", &body)])
+            }
+        };
+
+        let started = Instant::now();
+        let result = client.call(params).await;
+        let entry = stats.entry(action.get_name()).or_default();
+        entry.latencies.push(started.elapsed());
+        let response = match result {
+            Ok(x) => x,
+            Err(_) => {
+                entry.errors += 1;
+                continue;
+            }
+        };
+
+        // Delete received messages, as a consumer would.
+        if action == BenchAction::Receive {
+            for handle in get_elements(&response, "ReceiptHandle") {
+                let params = make_params(
+                    "DeleteMessage",
+                    &[("QueueUrl", &queue_url), ("ReceiptHandle", &handle)],
+                );
+                let started = Instant::now();
+                let result = client.call(params).await;
+                let entry = stats.entry("DeleteMessage").or_default();
+                entry.latencies.push(started.elapsed());
+                if result.is_err() {
+                    entry.errors += 1;
+                }
+            }
+        }
+    }
+    stats
+}
+
+fn get_percentile(sorted: &[Duration], percentile: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::default();
+    }
+    let index = ((sorted.len() as f64 * percentile / 100.0).ceil() as usize).max(1) - 1;
+    sorted[index.min(sorted.len() - 1)]
+}
+
+fn format_ms(d: Duration) -> String {
+    format!("{:.2}ms", d.as_secs_f64() * 1000.0)
+}
+
+/// Make requests with the given mix of actions from a number of concurrent workers for the
+/// given duration, then print the throughput and latency percentiles for each action.
+pub async fn run_bench<C: BenchClient>(client: C, options: BenchOptions) -> Result<(), String> {
+    let (queue_url, topic_arn) = set_up(&client, &options).await?;
+    println!(
+        "Running {} workers for {}s against {}",
+        options.concurrency,
+        options.duration.as_secs_f64(),
+        queue_url
+    );
+
+    let started = Instant::now();
+    let deadline = started + options.duration;
+    let mut handles = Vec::new();
+    for _ in 0..options.concurrency.max(1) {
+        handles.push(tokio::spawn(run_worker(
+            client.clone(),
+            options.clone(),
+            queue_url.clone(),
+            topic_arn.clone(),
+            deadline,
+        )));
+    }
+
+    let mut stats: HashMap<&'static str, ActionStats> = HashMap::new();
+    for handle in handles {
+        let worker_stats = handle.await.map_err(|e| e.to_string())?;
+        for (action, s) in worker_stats {
+            stats.entry(action).or_default().merge(s);
+        }
+    }
+    let elapsed = started.elapsed().as_secs_f64();
+
+    println!(
+        "{:<16} {:>9} {:>7} {:>10} {:>10} {:>10} {:>10} {:>10}",
+        "Action", "Requests", "Errors", "Req/s", "p50", "p90", "p99", "Max"
+    );
+    let mut actions: Vec<_> = stats.into_iter().collect();
+    actions.sort_by_key(|(action, _)| *action);
+    let mut total = 0;
+    for (action, mut s) in actions {
+        s.latencies.sort();
+        total += s.latencies.len();
+        println!(
+            "{:<16} {:>9} {:>7} {:>10.1} {:>10} {:>10} {:>10} {:>10}",
+            action,
+            s.latencies.len(),
+            s.errors,
+            s.latencies.len() as f64 / elapsed,
+            format_ms(get_percentile(&s.latencies, 50.0)),
+            format_ms(get_percentile(&s.latencies, 90.0)),
+            format_ms(get_percentile(&s.latencies, 99.0)),
+            format_ms(s.latencies.last().copied().unwrap_or_default()),
+        );
+    }
+    println!(
+        "Total: {} requests in {:.1}s ({:.1} req/s)",
+        total,
+        elapsed,
+        total as f64 / elapsed
+    );
+    Ok(())
+}
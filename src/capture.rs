@@ -0,0 +1,77 @@
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use tokio::time::delay_for;
+
+/// A request recorded to the capture file, one JSON object per line.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CapturedRequest {
+    pub timestamp: DateTime<Utc>,
+    pub action: String,
+    pub params: HashMap<String, String>,
+}
+
+pub fn append_request(path: &Path, request: &CapturedRequest) -> std::io::Result<()> {
+    let line = serde_json::to_string(request)?;
+    let mut f = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(f, "{}", line)
+}
+
+fn read_requests(path: &Path) -> std::io::Result<Vec<CapturedRequest>> {
+    let mut requests = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        requests.push(serde_json::from_str(&line)?);
+    }
+    Ok(requests)
+}
+
+/// Re-issue captured requests against a running instance, with the original timing.
+/// Each request is sent at its original offset from the first, without waiting for earlier
+/// requests (such as long polls) to complete.
+pub async fn replay(path: &Path, endpoint: &str) -> std::io::Result<()> {
+    let requests = read_requests(path)?;
+    info!("Replaying {} requests to {}", requests.len(), endpoint);
+
+    let client = reqwest::Client::new();
+    let started = Utc::now();
+    let first_timestamp = match requests.first() {
+        Some(x) => x.timestamp,
+        None => return Ok(()),
+    };
+
+    let mut handles = Vec::new();
+    for request in requests {
+        let offset = request.timestamp - first_timestamp;
+        let elapsed = Utc::now() - started;
+        if let Ok(delay) = (offset - elapsed).to_std() {
+            delay_for(delay).await;
+        }
+
+        let client = client.clone();
+        let endpoint = endpoint.to_string();
+        handles.push(tokio::spawn(async move {
+            info!("Replaying {}", request.action);
+            let result = client.post(&endpoint).form(&request.params).send().await;
+            match result {
+                Ok(r) if !r.status().is_success() => {
+                    warn!("{} returned HTTP status {}", request.action, r.status())
+                }
+                Ok(_) => {}
+                Err(e) => warn!("{} failed: {}", request.action, e),
+            }
+        }));
+    }
+
+    for handle in handles {
+        let _ = handle.await;
+    }
+    Ok(())
+}
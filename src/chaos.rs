@@ -0,0 +1,266 @@
+use crate::errors::MyError;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// The errors that can be injected.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum FaultError {
+    InternalError,
+    ServiceUnavailable,
+    Throttling,
+}
+
+impl FaultError {
+    fn get_error(self) -> MyError {
+        match self {
+            FaultError::InternalError => MyError::InternalError,
+            FaultError::ServiceUnavailable => MyError::ServiceUnavailable,
+            FaultError::Throttling => MyError::Throttling,
+        }
+    }
+}
+
+/// Fail a proportion of the requests for an action, between 0 and 1, with an error.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Fault {
+    pub action: String,
+    pub error: FaultError,
+    pub rate: f64,
+}
+
+impl Fault {
+    /// Parse a fault given on the command line, as ACTION=ERROR:RATE,
+    /// e.g. `SendMessage=InternalError:0.1`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid fault '{}'. Expected ACTION=ERROR:RATE", s);
+        let mut parts = s.splitn(2, '=');
+        let action = parts.next().ok_or_else(invalid)?;
+        let mut parts = parts.next().ok_or_else(invalid)?.splitn(2, ':');
+        let error = match parts.next() {
+            Some("InternalError") => FaultError::InternalError,
+            Some("ServiceUnavailable") => FaultError::ServiceUnavailable,
+            Some("Throttling") => FaultError::Throttling,
+            _ => return Err(invalid()),
+        };
+        let rate = parts
+            .next()
+            .and_then(|x| x.parse().ok())
+            .ok_or_else(invalid)?;
+        Ok(Fault {
+            action: action.to_string(),
+            error,
+            rate,
+        })
+    }
+}
+
+/// Delay requests for an action by `min_ms`, plus a random amount up to `max_ms` if given.
+/// If `queue` is given, only requests for that queue (by name) are delayed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Latency {
+    pub action: String,
+    pub queue: Option<String>,
+    pub min_ms: u64,
+    pub max_ms: Option<u64>,
+}
+
+impl Latency {
+    /// Parse a latency given on the command line, as ACTION[@QUEUE]=MS or ACTION[@QUEUE]=MIN-MAX,
+    /// e.g. `ReceiveMessage@orders=100-500`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid latency '{}'. Expected ACTION[@QUEUE]=MS[-MS]", s);
+        let mut parts = s.splitn(2, '=');
+        let mut target = parts.next().ok_or_else(invalid)?.splitn(2, '@');
+        let action = target.next().ok_or_else(invalid)?;
+        let queue = target.next();
+        let mut range = parts.next().ok_or_else(invalid)?.splitn(2, '-');
+        let min_ms = range
+            .next()
+            .and_then(|x| x.parse().ok())
+            .ok_or_else(invalid)?;
+        let max_ms = match range.next() {
+            Some(x) => Some(x.parse().map_err(|_| invalid())?),
+            None => None,
+        };
+        Ok(Latency {
+            action: action.to_string(),
+            queue: queue.map(String::from),
+            min_ms,
+            max_ms,
+        })
+    }
+}
+
+/// Failures to inject into requests, and the random source that decides when to inject them.
+/// Seeding the random source makes the sequence of failures repeatable.
+pub struct Chaos {
+    pub faults: HashMap<String, Fault>,
+    pub latencies: Vec<Latency>,
+    // The probability that a received message is also left in the queue to be delivered again.
+    duplicate_rate: f64,
+    // The probability that a notification or received message is lost.
+    drop_rate: f64,
+    // The probability that a response body is truncated or made invalid.
+    malformed_rate: f64,
+    // Deliver messages from standard queues in random order.
+    pub shuffle_delivery: bool,
+    rng: StdRng,
+}
+
+impl Default for Chaos {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Chaos {
+    pub fn new() -> Self {
+        Self {
+            faults: HashMap::new(),
+            latencies: Vec::new(),
+            duplicate_rate: 0.0,
+            drop_rate: 0.0,
+            malformed_rate: 0.0,
+            shuffle_delivery: false,
+            rng: StdRng::from_entropy(),
+        }
+    }
+
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    /// Add or replace the fault for an action.
+    pub fn set_fault(&mut self, fault: Fault) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&fault.rate) {
+            return Err(format!(
+                "Fault rate for {} must be between 0 and 1",
+                fault.action
+            ));
+        }
+        self.faults.insert(fault.action.clone(), fault);
+        Ok(())
+    }
+
+    pub fn set_duplicate_rate(&mut self, rate: f64) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&rate) {
+            return Err("Duplicate rate must be between 0 and 1".to_string());
+        }
+        self.duplicate_rate = rate;
+        Ok(())
+    }
+
+    /// Whether a message being received should also be delivered again, as SQS occasionally
+    /// does.
+    pub fn should_duplicate(&mut self) -> bool {
+        self.duplicate_rate > 0.0 && self.rng.gen_bool(self.duplicate_rate)
+    }
+
+    pub fn set_drop_rate(&mut self, rate: f64) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&rate) {
+            return Err("Drop rate must be between 0 and 1".to_string());
+        }
+        self.drop_rate = rate;
+        Ok(())
+    }
+
+    /// Whether a notification or received message should be lost instead of delivered.
+    pub fn should_drop(&mut self) -> bool {
+        self.drop_rate > 0.0 && self.rng.gen_bool(self.drop_rate)
+    }
+
+    pub fn set_malformed_rate(&mut self, rate: f64) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&rate) {
+            return Err("Malformed response rate must be between 0 and 1".to_string());
+        }
+        self.malformed_rate = rate;
+        Ok(())
+    }
+
+    pub fn has_malformed_responses(&self) -> bool {
+        self.malformed_rate > 0.0
+    }
+
+    /// Get a broken version of a response body, if one should be sent instead. The body is
+    /// either cut off part way through, has a stray character inserted, or loses its final
+    /// closing tag (or brace, for JSON), as a misbehaving proxy might do.
+    pub fn get_malformed_response(&mut self, body: &str) -> Option<String> {
+        if self.malformed_rate <= 0.0 || !self.rng.gen_bool(self.malformed_rate) || body.is_empty()
+        {
+            return None;
+        }
+
+        let boundaries: Vec<usize> = body.char_indices().map(|(i, _)| i).skip(1).collect();
+        let pick = |rng: &mut StdRng| match boundaries.is_empty() {
+            true => 0,
+            false => boundaries[rng.gen_range(0, boundaries.len())],
+        };
+        let malformed = match self.rng.gen_range(0, 3) {
+            0 => body[..pick(&mut self.rng)].to_string(),
+            1 => {
+                let i = pick(&mut self.rng);
+                format!("{}<{}", &body[..i], &body[i..])
+            }
+            _ => {
+                let trimmed = body.trim_end();
+                let end = match trimmed.starts_with('{') || trimmed.starts_with('[') {
+                    true => trimmed.len() - 1,
+                    false => trimmed.rfind("</").unwrap_or(0),
+                };
+                trimmed[..end].to_string()
+            }
+        };
+        Some(malformed)
+    }
+
+    /// Choose a random index below `len`, which must not be zero.
+    pub fn pick_index(&mut self, len: usize) -> usize {
+        self.rng.gen_range(0, len)
+    }
+
+    /// Add or replace the latency for an action and queue.
+    pub fn set_latency(&mut self, latency: Latency) -> Result<(), String> {
+        if latency.max_ms.unwrap_or(latency.min_ms) < latency.min_ms {
+            return Err(format!(
+                "Maximum latency for {} must not be less than the minimum",
+                latency.action
+            ));
+        }
+        self.latencies
+            .retain(|x| x.action != latency.action || x.queue != latency.queue);
+        self.latencies.push(latency);
+        Ok(())
+    }
+
+    /// Get the delay to add to a request, if any. A latency for the request's queue takes
+    /// precedence over one for the action as a whole.
+    pub fn get_latency(&mut self, action: &str, queue: Option<&str>) -> Option<Duration> {
+        let latency = self
+            .latencies
+            .iter()
+            .filter(|x| x.action == action)
+            .find(|x| x.queue.is_some() && x.queue.as_deref() == queue)
+            .or_else(|| {
+                self.latencies
+                    .iter()
+                    .find(|x| x.action == action && x.queue.is_none())
+            })?;
+        let ms = match latency.max_ms {
+            Some(max_ms) => self.rng.gen_range(latency.min_ms, max_ms + 1),
+            None => latency.min_ms,
+        };
+        Some(Duration::from_millis(ms))
+    }
+
+    /// Get the error to return instead of handling a request, if one should be injected.
+    pub fn get_fault_error(&mut self, action: &str) -> Option<MyError> {
+        let fault = self.faults.get(action)?;
+        match self.rng.gen_bool(fault.rate) {
+            true => Some(fault.error.get_error()),
+            false => None,
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use crate::errors::{MyError, MyResult};
+use crate::misc::read;
+use crate::state::{QueuePath, RequestContext, State};
+use crate::xml::{get_response_xml, ToXml, XmlWriter, CLOUDWATCH_NAMESPACE};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// The statistics that can be requested. They are used as element names in the response.
+const STATISTICS: &[&str] = &["SampleCount", "Average", "Sum", "Minimum", "Maximum"];
+
+struct Datapoint {
+    timestamp: DateTime<Utc>,
+    statistics: Vec<(&'static str, f64)>,
+    unit: &'static str,
+}
+
+impl ToXml for Datapoint {
+    fn write_xml(&self, w: &mut XmlWriter) {
+        w.text("Timestamp", &self.timestamp.to_rfc3339());
+        for (statistic, value) in &self.statistics {
+            w.text(statistic, &value.to_string());
+        }
+        w.text("Unit", self.unit);
+    }
+}
+
+struct GetMetricStatisticsResult<'a> {
+    label: &'a str,
+    datapoints: Vec<Datapoint>,
+}
+
+impl<'a> ToXml for GetMetricStatisticsResult<'a> {
+    fn write_xml(&self, w: &mut XmlWriter) {
+        w.text("Label", self.label);
+        w.element("Datapoints", |w| w.list("member", &self.datapoints));
+    }
+}
+
+/// Get the current value of an SQS queue metric, if the metric is supported.
+fn get_queue_metric(state: &State, path: &QueuePath, metric_name: &str) -> Option<f64> {
+    let now = state.now();
+    let q = state.get_queue(path)?;
+    let q = read(&q);
+    match metric_name {
+        "NumberOfMessagesSent" => Some(q.messages_sent as f64),
+        "ApproximateNumberOfMessagesVisible" => Some(q.get_message_count() as f64),
+        "ApproximateNumberOfMessagesNotVisible" => Some(q.in_flight.len() as f64),
+        "ApproximateAgeOfOldestMessage" => Some(
+            q.messages
+                .iter()
+                .map(|m| (now - m.sent).num_seconds())
+                .max()
+                .unwrap_or(0) as f64,
+        ),
+        _ => None,
+    }
+}
+
+/// Serve SQS metrics, derived from the current state.
+/// There is no history, so a single datapoint with the current value is returned.
+pub async fn get_metric_statistics(
+    form: HashMap<String, String>,
+    ctx: RequestContext,
+    state: Arc<State>,
+) -> MyResult<String> {
+    let namespace = form
+        .get("Namespace")
+        .ok_or_else(|| MyError::MissingParameter("Namespace".to_string()))?;
+    let metric_name = form
+        .get("MetricName")
+        .ok_or_else(|| MyError::MissingParameter("MetricName".to_string()))?;
+
+    let mut queue_name = None;
+    let mut statistics = Vec::new();
+    for count in 1..100 {
+        if form
+            .get(&format!("Dimensions.member.{}.Name", count))
+            .map(|x| x.as_str())
+            == Some("QueueName")
+        {
+            queue_name = form.get(&format!("Dimensions.member.{}.Value", count));
+        }
+        if let Some(x) = form.get(&format!("Statistics.member.{}", count)) {
+            let statistic = STATISTICS.iter().find(|s| **s == x).ok_or_else(|| {
+                MyError::InvalidParameterValue(
+                    "Statistics".to_string(),
+                    x.clone(),
+                    format!("Must be one of {}.", STATISTICS.join(", ")),
+                )
+            })?;
+            statistics.push(*statistic);
+        }
+    }
+
+    let value = match (namespace.as_str(), queue_name) {
+        ("AWS/SQS", Some(name)) => {
+            let path = QueuePath::new(&ctx.region, &ctx.account_id, name);
+            get_queue_metric(&state, &path, metric_name)
+        }
+        _ => None,
+    };
+
+    let now = state.get_reported_time(state.now());
+    let mut datapoints = Vec::new();
+    if let Some(value) = value {
+        let statistics = statistics
+            .into_iter()
+            .map(|statistic| match statistic {
+                "SampleCount" => (statistic, 1.0),
+                _ => (statistic, value),
+            })
+            .collect();
+        let unit = match metric_name.as_str() {
+            "ApproximateAgeOfOldestMessage" => "Seconds",
+            _ => "Count",
+        };
+        datapoints.push(Datapoint {
+            timestamp: now,
+            statistics,
+            unit,
+        });
+    }
+
+    let result = GetMetricStatisticsResult {
+        label: metric_name,
+        datapoints,
+    };
+    Ok(get_response_xml(
+        "GetMetricStatistics",
+        CLOUDWATCH_NAMESPACE,
+        Some(&result),
+    ))
+}
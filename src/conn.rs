@@ -0,0 +1,216 @@
+use log::{debug, warn};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time::{delay_for, Delay};
+
+// Connections that have been accepted, waiting for the server to take them.
+const MAX_PENDING_CONNECTIONS: usize = 64;
+
+#[derive(Default)]
+struct RequestCounts {
+    started: AtomicUsize,
+    in_flight: AtomicUsize,
+    // Woken when the last request finishes, so the connection starts waiting for the next one.
+    idle_waker: Mutex<Option<Waker>>,
+}
+
+/// The requests being handled on a connection, so it can tell when it's idle.
+#[derive(Clone, Default)]
+pub struct Requests(Arc<RequestCounts>);
+
+impl Requests {
+    /// Mark a request as being handled, until the returned guard is dropped.
+    pub fn start(&self) -> RequestGuard {
+        self.0.started.fetch_add(1, Ordering::SeqCst);
+        self.0.in_flight.fetch_add(1, Ordering::SeqCst);
+        RequestGuard(self.0.clone())
+    }
+}
+
+pub struct RequestGuard(Arc<RequestCounts>);
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        if self.0.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            let mut waker = self.0.idle_waker.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(x) = waker.take() {
+                x.wake();
+            }
+        }
+    }
+}
+
+/// A client connection. If the client takes longer than the header read timeout to send a
+/// request, counting from when the connection opened or its last request finished, the
+/// connection is closed. Requests being handled, such as long polls, aren't limited.
+pub struct Connection<S> {
+    stream: S,
+    remote_addr: SocketAddr,
+    requests: Requests,
+    header_read_timeout: Option<Duration>,
+    // When the next request must arrive by, and the number of requests started when set.
+    deadline: Option<(usize, Delay)>,
+}
+
+impl<S> Connection<S> {
+    pub fn new(stream: S, remote_addr: SocketAddr, header_read_timeout: Option<Duration>) -> Self {
+        Self {
+            stream,
+            remote_addr,
+            requests: Requests::default(),
+            header_read_timeout,
+            deadline: None,
+        }
+    }
+
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    pub fn requests(&self) -> Requests {
+        self.requests.clone()
+    }
+
+    /// Check whether the client has run out of time to send its next request.
+    fn poll_timed_out(&mut self, cx: &mut Context<'_>) -> bool {
+        let timeout = match self.header_read_timeout {
+            Some(x) => x,
+            None => return false,
+        };
+        let counts = &self.requests.0;
+        *counts.idle_waker.lock().unwrap_or_else(|e| e.into_inner()) = Some(cx.waker().clone());
+        if counts.in_flight.load(Ordering::SeqCst) > 0 {
+            self.deadline = None;
+            return false;
+        }
+        let started = counts.started.load(Ordering::SeqCst);
+        match &self.deadline {
+            Some((x, _)) if *x == started => {}
+            _ => self.deadline = Some((started, delay_for(timeout))),
+        }
+        match &mut self.deadline {
+            Some((_, delay)) => Pin::new(delay).poll(cx).is_ready(),
+            None => false,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Connection<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.stream).poll_read(cx, buf) {
+            Poll::Pending if this.poll_timed_out(cx) => {
+                debug!("Timed out waiting for a request from {}", this.remote_addr);
+                let e = io::Error::new(io::ErrorKind::TimedOut, "Timed out waiting for a request");
+                Poll::Ready(Err(e))
+            }
+            x => x,
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Connection<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+/// Accept connections, setting TCP keep-alive and the header read timeout on each. Stops
+/// accepting on shutdown.
+pub fn accept_tcp(
+    mut listener: TcpListener,
+    tcp_keepalive: Option<Duration>,
+    header_read_timeout: Option<Duration>,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> mpsc::Receiver<Connection<TcpStream>> {
+    let (mut tx, rx) = mpsc::channel(MAX_PENDING_CONNECTIONS);
+    tokio::spawn(async move {
+        tokio::pin!(shutdown);
+        loop {
+            let accepted = tokio::select! {
+                _ = &mut shutdown => break,
+                x = listener.accept() => x,
+            };
+            let (stream, remote_addr) = match accepted {
+                Ok(x) => x,
+                Err(e) => {
+                    // Usually out of file descriptors, so give some connections time to close.
+                    warn!("Failed to accept connection: {}", e);
+                    delay_for(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+            if let Err(e) = stream.set_keepalive(tcp_keepalive) {
+                debug!("Failed to set TCP keep-alive for {}: {}", remote_addr, e);
+            }
+            let connection = Connection::new(stream, remote_addr, header_read_timeout);
+            if tx.send(connection).await.is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Read<'a>(&'a mut Connection<TcpStream>);
+
+    impl Future for Read<'_> {
+        type Output = io::Result<usize>;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            let mut buf = [0; 16];
+            Pin::new(&mut *self.0).poll_read(cx, &mut buf)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_header_read_timeout() {
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let timeout = Duration::from_millis(100);
+        let shutdown = delay_for(Duration::from_secs(10));
+        let mut connections = accept_tcp(listener, None, Some(timeout), shutdown);
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let mut conn = connections.recv().await.unwrap();
+
+        // Requests being handled, such as long polls, can take as long as they need.
+        let request = conn.requests().start();
+        let result = tokio::time::timeout(timeout * 3, Read(&mut conn)).await;
+        assert!(result.is_err());
+
+        drop(request);
+        let result = tokio::time::timeout(timeout * 3, Read(&mut conn)).await;
+        assert_eq!(result.unwrap().unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -1,35 +1,390 @@
 use crate::misc::get_new_id;
+use crate::xml::{XmlWriter, CLOUDWATCH_NAMESPACE, SNS_NAMESPACE, SQS_NAMESPACE};
+use serde_json::json;
 use thiserror::Error;
 
+/// The service a request is for. SQS and SNS report some of the same errors with different
+/// codes and statuses, and their error responses are shaped differently.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AwsService {
+    Sqs,
+    Sns,
+    CloudWatch,
+}
+
+/// The protocol a request was made with. Errors are written in the same protocol.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    /// Form parameters, with XML responses.
+    Query,
+    /// A JSON body naming the action in the `X-Amz-Target` header, with JSON responses.
+    Json,
+}
+
+impl AwsService {
+    /// The service an action belongs to, including actions we don't implement.
+    pub fn from_action(action: &str) -> Option<Self> {
+        match action {
+            "ListQueues"
+            | "CreateQueue"
+            | "DeleteQueue"
+            | "PurgeQueue"
+            | "GetQueueUrl"
+            | "GetQueueAttributes"
+            | "SetQueueAttributes"
+            | "SendMessage"
+            | "SendMessageBatch"
+            | "ReceiveMessage"
+            | "DeleteMessage"
+            | "DeleteMessageBatch"
+            | "ChangeMessageVisibility"
+            | "ChangeMessageVisibilityBatch"
+            | "ListDeadLetterSourceQueues"
+            | "ListQueueTags"
+            | "TagQueue"
+            | "UntagQueue"
+            | "AddPermission"
+            | "RemovePermission" => Some(AwsService::Sqs),
+            "ListTopics"
+            | "CreateTopic"
+            | "DeleteTopic"
+            | "GetTopicAttributes"
+            | "SetTopicAttributes"
+            | "Publish"
+            | "PublishBatch"
+            | "Subscribe"
+            | "ConfirmSubscription"
+            | "Unsubscribe"
+            | "ListSubscriptions"
+            | "ListSubscriptionsByTopic"
+            | "SetSubscriptionAttributes"
+            | "GetSubscriptionAttributes"
+            | "CreatePlatformApplication"
+            | "CreatePlatformEndpoint"
+            | "ListEndpointsByPlatformApplication"
+            | "OptInPhoneNumber"
+            | "CheckIfPhoneNumberIsOptedOut"
+            | "ListPhoneNumbersOptedOut"
+            | "PutDataProtectionPolicy"
+            | "GetDataProtectionPolicy" => Some(AwsService::Sns),
+            "GetMetricStatistics" => Some(AwsService::CloudWatch),
+            _ => None,
+        }
+    }
+
+    /// The service a request was signed for, from the scope of its credential.
+    pub fn from_signing_name(name: &str) -> Option<Self> {
+        match name {
+            "sqs" => Some(AwsService::Sqs),
+            "sns" => Some(AwsService::Sns),
+            "monitoring" => Some(AwsService::CloudWatch),
+            _ => None,
+        }
+    }
+
+    /// The prefix of error types in the JSON protocol, which SQS uses.
+    fn get_json_namespace(self) -> Option<&'static str> {
+        match self {
+            AwsService::Sqs => Some("com.amazonaws.sqs"),
+            AwsService::Sns | AwsService::CloudWatch => None,
+        }
+    }
+
+    fn get_namespace(self) -> &'static str {
+        match self {
+            AwsService::Sqs => SQS_NAMESPACE,
+            AwsService::Sns => SNS_NAMESPACE,
+            AwsService::CloudWatch => CLOUDWATCH_NAMESPACE,
+        }
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum MyError {
     #[error("Missing action")]
     MissingAction,
+    #[error("The query string or form body contains a syntax error: {0}")]
+    MalformedQueryString(String),
+    #[error("The JSON protocol is not supported. Please use the query protocol instead.")]
+    UnsupportedProtocol,
     #[error("Unknown action: {0}")]
     UnknownAction(String),
-    #[error("Missing parameter: {0}")]
+    #[error("The request must contain the parameter {0}")]
     MissingParameter(String),
+    /// The parameter, its value and the reason the value is invalid.
+    #[error("Value {1} for parameter {0} is invalid. Reason: {2}")]
+    InvalidParameterValue(String, String, String),
+    /// The parameter and the reason it is invalid, when there's no value to blame, e.g. one
+    /// that is required only in combination with others.
+    #[error("Invalid parameter: {0} Reason: {1}")]
+    InvalidParameter(String, String),
+    #[error("{0}")]
+    InvalidMessageAttribute(String),
+    #[error("Unknown Attribute {0}.")]
+    InvalidAttributeName(String),
+    /// The attribute and the reason its value is invalid.
+    #[error("Invalid value for the parameter {0}. Reason: {1}")]
+    InvalidAttributeValue(String, String),
     #[error("Queue not found: {0}")]
     QueueNotFound(String),
     #[error("Topic not found: {0}")]
     TopicNotFound(String),
+    #[error("Platform application not found: {0}")]
+    PlatformApplicationNotFound(String),
+    #[error("Endpoint not found: {0}")]
+    EndpointNotFound(String),
+    #[error("Subscription not found: {0}")]
+    SubscriptionNotFound(String),
+    #[error("Request is missing Authentication Token")]
+    MissingAuthenticationToken,
+    #[error("{0}")]
+    IncompleteSignature(String),
+    #[error("The security token included in the request is invalid.")]
+    InvalidClientTokenId,
+    #[error(
+        "The request signature we calculated does not match the signature you provided. \
+         Check your AWS Secret Access Key and signing method."
+    )]
+    SignatureDoesNotMatch,
+    #[error("The provided 'x-amz-content-sha256' header does not match what was computed.")]
+    ContentSha256Mismatch,
+    #[error("Timed out reading the request body.")]
+    RequestTimeout,
+    #[error("The request body could not be read: {0}")]
+    IncompleteBody(String),
+    #[error("The store is unavailable: {0}")]
+    StoreUnavailable(String),
+    #[error("We encountered an internal error. Please try again.")]
+    InternalError,
+    #[error("The request has failed due to a temporary failure of the server.")]
+    ServiceUnavailable,
+    #[error("Rate exceeded")]
+    Throttling,
+    #[error("The server is holding too many messages. Please try again once consumers catch up.")]
+    MemoryBudgetExceeded,
 }
 
 pub type MyResult<T> = Result<T, MyError>;
 
 impl MyError {
-    pub fn get_error_response(&self) -> String {
+    /// The code clients see, which SDKs use to pick the exception to raise.
+    pub fn get_error_code(&self, service: AwsService) -> &'static str {
+        match (self, service) {
+            (MyError::MissingAction, _) => "MissingAction",
+            (MyError::MalformedQueryString(_), _) => "MalformedQueryString",
+            (MyError::UnknownAction(_), _) => "InvalidAction",
+            (MyError::UnsupportedProtocol, AwsService::Sqs) => {
+                "AWS.SimpleQueueService.UnsupportedOperation"
+            }
+            (MyError::UnsupportedProtocol, _) => "UnsupportedOperation",
+            (MyError::MissingParameter(_), _) => "MissingParameter",
+            (MyError::InvalidParameterValue(..), AwsService::Sns) => "InvalidParameter",
+            (MyError::InvalidParameterValue(..), _) => "InvalidParameterValue",
+            (MyError::InvalidParameter(..), AwsService::Sns) => "InvalidParameter",
+            (MyError::InvalidParameter(..), _) => "InvalidParameterValue",
+            (MyError::InvalidMessageAttribute(_), AwsService::Sns) => "ParameterValueInvalid",
+            (MyError::InvalidMessageAttribute(_), _) => "InvalidParameterValue",
+            // SNS reports bad attributes like any other bad parameter.
+            (MyError::InvalidAttributeName(_), AwsService::Sns)
+            | (MyError::InvalidAttributeValue(..), AwsService::Sns) => "InvalidParameter",
+            (MyError::InvalidAttributeName(_), _) => "InvalidAttributeName",
+            (MyError::InvalidAttributeValue(..), _) => "InvalidAttributeValue",
+            (MyError::QueueNotFound(_), AwsService::Sqs) => {
+                "AWS.SimpleQueueService.NonExistentQueue"
+            }
+            (MyError::QueueNotFound(_), _)
+            | (MyError::TopicNotFound(_), _)
+            | (MyError::PlatformApplicationNotFound(_), _)
+            | (MyError::EndpointNotFound(_), _)
+            | (MyError::SubscriptionNotFound(_), _) => "NotFound",
+            (MyError::MissingAuthenticationToken, _) => "MissingAuthenticationToken",
+            (MyError::IncompleteSignature(_), _) => "IncompleteSignature",
+            (MyError::InvalidClientTokenId, _) => "InvalidClientTokenId",
+            (MyError::SignatureDoesNotMatch, _) => "SignatureDoesNotMatch",
+            (MyError::ContentSha256Mismatch, _) => "XAmzContentSHA256Mismatch",
+            (MyError::RequestTimeout, _) => "RequestTimeout",
+            (MyError::IncompleteBody(_), _) => "IncompleteBody",
+            (MyError::InternalError, AwsService::Sns) => "InternalError",
+            (MyError::InternalError, _) => "InternalFailure",
+            (MyError::StoreUnavailable(_), _) | (MyError::ServiceUnavailable, _) => {
+                "ServiceUnavailable"
+            }
+            (MyError::Throttling, AwsService::Sns)
+            | (MyError::MemoryBudgetExceeded, AwsService::Sns) => "Throttled",
+            (MyError::Throttling, _) | (MyError::MemoryBudgetExceeded, _) => "Throttling",
+        }
+    }
+
+    pub fn get_status_code(&self, service: AwsService) -> u16 {
+        match (self, service) {
+            // SQS reports a missing queue as a bad request, not a missing resource.
+            (MyError::QueueNotFound(_), AwsService::Sqs) => 400,
+            (MyError::QueueNotFound(_), _)
+            | (MyError::TopicNotFound(_), _)
+            | (MyError::PlatformApplicationNotFound(_), _)
+            | (MyError::EndpointNotFound(_), _)
+            | (MyError::SubscriptionNotFound(_), _) => 404,
+            (MyError::MissingAuthenticationToken, _)
+            | (MyError::InvalidClientTokenId, _)
+            | (MyError::SignatureDoesNotMatch, _) => 403,
+            (MyError::RequestTimeout, _) => 408,
+            (MyError::Throttling, AwsService::Sns)
+            | (MyError::MemoryBudgetExceeded, AwsService::Sns) => 429,
+            (MyError::InternalError, _) => 500,
+            (MyError::StoreUnavailable(_), _) | (MyError::ServiceUnavailable, _) => 503,
+            _ => 400,
+        }
+    }
+
+    /// The message clients see. SNS words invalid parameters differently than SQS.
+    pub fn get_message(&self, service: AwsService) -> String {
+        match (self, service) {
+            (MyError::InvalidParameterValue(name, _, reason), AwsService::Sns)
+            | (MyError::InvalidAttributeValue(name, reason), AwsService::Sns) => {
+                format!("Invalid parameter: {} Reason: {}", name, reason)
+            }
+            (MyError::InvalidAttributeName(name), AwsService::Sns) => {
+                format!(
+                    "Invalid parameter: AttributeName Reason: Unknown attribute {}",
+                    name
+                )
+            }
+            _ => self.to_string(),
+        }
+    }
+
+    /// Whether the error is the client's fault, or ours.
+    pub fn get_error_type(&self, service: AwsService) -> &'static str {
+        match self.get_status_code(service) {
+            500..=599 => "Receiver",
+            _ => "Sender",
+        }
+    }
+
+    /// The error type in the JSON protocol. SQS names some errors differently than in the
+    /// query protocol, and qualifies them with its namespace.
+    pub fn get_json_error_type(&self, service: AwsService) -> String {
+        let code = match self.get_error_code(service) {
+            "AWS.SimpleQueueService.NonExistentQueue" => "QueueDoesNotExist",
+            "AWS.SimpleQueueService.UnsupportedOperation" => "UnsupportedOperation",
+            x => x,
+        };
+        match service.get_json_namespace() {
+            Some(namespace) => format!("{}#{}", namespace, code),
+            None => code.to_string(),
+        }
+    }
+
+    /// The value of the `x-amzn-query-error` header sent with JSON errors, from which SDKs
+    /// recover the query protocol's code for compatibility.
+    pub fn get_query_error_header(&self, service: AwsService) -> String {
         format!(
-            "<ErrorResponse>\
-                <Error>\
-                    <Type>Sender</Type>\
-                    <Code>InvalidParameterValue</Code>\
-                    <Message>{}</Message>\
-                </Error>\
-                <RequestId>{}</RequestId>\
-            </ErrorResponse>",
-            self.to_string(),
-            get_new_id()
+            "{};{}",
+            self.get_error_code(service),
+            self.get_error_type(service)
         )
     }
+
+    pub fn get_error_response(&self, service: AwsService, protocol: Protocol) -> String {
+        match protocol {
+            Protocol::Query => self.get_xml_error_response(service),
+            Protocol::Json => self.get_json_error_response(service),
+        }
+    }
+
+    fn get_json_error_response(&self, service: AwsService) -> String {
+        json!({
+            "__type": self.get_json_error_type(service),
+            "message": self.get_message(service),
+        })
+        .to_string()
+    }
+
+    /// The ErrorResponse document for the service. SQS includes an empty `<Detail>` element,
+    /// which SNS doesn't.
+    fn get_xml_error_response(&self, service: AwsService) -> String {
+        let mut w = XmlWriter::default();
+        w.root_element("ErrorResponse", service.get_namespace(), |w| {
+            w.element("Error", |w| {
+                w.text("Type", self.get_error_type(service));
+                w.text("Code", self.get_error_code(service));
+                w.text("Message", &self.get_message(service));
+                if service == AwsService::Sqs {
+                    w.element("Detail", |_| {});
+                }
+            });
+            w.text("RequestId", &get_new_id());
+        });
+        w.into_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_element<'a>(xml: &'a str, tag: &str) -> &'a str {
+        let start = xml.find(&format!("<{}>", tag)).unwrap() + tag.len() + 2;
+        let end = xml[start..].find(&format!("</{}>", tag)).unwrap() + start;
+        &xml[start..end]
+    }
+
+    #[test]
+    fn test_sqs_error_shape() {
+        let e = MyError::MissingParameter("QueueUrl".to_string());
+        let xml = e.get_error_response(AwsService::Sqs, Protocol::Query);
+        assert_eq!(get_element(&xml, "Code"), "MissingParameter");
+        assert_eq!(get_element(&xml, "Type"), "Sender");
+        assert_eq!(
+            get_element(&xml, "Message"),
+            "The request must contain the parameter QueueUrl"
+        );
+        assert!(xml.contains("<Detail"));
+        assert_eq!(e.get_status_code(AwsService::Sqs), 400);
+
+        let e = MyError::InvalidParameterValue(
+            "DelaySeconds".to_string(),
+            "soon".to_string(),
+            "Must be a non-negative number of seconds.".to_string(),
+        );
+        let xml = e.get_error_response(AwsService::Sqs, Protocol::Query);
+        assert_eq!(get_element(&xml, "Code"), "InvalidParameterValue");
+        assert_eq!(
+            get_element(&xml, "Message"),
+            "Value soon for parameter DelaySeconds is invalid. \
+             Reason: Must be a non-negative number of seconds."
+        );
+    }
+
+    #[test]
+    fn test_sns_error_shape() {
+        let e = MyError::InvalidParameterValue(
+            "Name".to_string(),
+            "a b".to_string(),
+            "Topic names can only include alphanumeric characters.".to_string(),
+        );
+        let xml = e.get_error_response(AwsService::Sns, Protocol::Query);
+        assert_eq!(get_element(&xml, "Code"), "InvalidParameter");
+        assert_eq!(
+            get_element(&xml, "Message"),
+            "Invalid parameter: Name Reason: Topic names can only include alphanumeric \
+             characters."
+        );
+        assert!(!xml.contains("<Detail"));
+
+        let e = MyError::InvalidParameter(
+            "MessageDeduplicationId".to_string(),
+            "The topic should either have ContentBasedDeduplication enabled or \
+             MessageDeduplicationId provided explicitly."
+                .to_string(),
+        );
+        let xml = e.get_error_response(AwsService::Sns, Protocol::Query);
+        assert_eq!(get_element(&xml, "Code"), "InvalidParameter");
+        assert_eq!(
+            get_element(&xml, "Message"),
+            "Invalid parameter: MessageDeduplicationId Reason: The topic should either have \
+             ContentBasedDeduplication enabled or MessageDeduplicationId provided explicitly."
+        );
+        assert_eq!(e.get_status_code(AwsService::Sns), 400);
+    }
 }
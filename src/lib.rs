@@ -0,0 +1,1990 @@
+//! SmoQS is a mock for SNS/SQS providing a fast, in-memory queue.
+//!
+//! Besides running the `smoqs` binary, the server can be started in-process, for example from
+//! integration tests:
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), String> {
+//! let server = smoqs::Server::spawn().await?;
+//! // Point SQS and SNS clients at this URL.
+//! println!("{}", server.get_endpoint_url());
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Both SQS and SNS are enabled by default. Build with `--no-default-features --features sqs`
+//! (or `sns`) to leave out the other service's API actions, which then fail as unknown actions.
+
+#[cfg(feature = "sqs")]
+use crate::sqs::{
+    change_message_visibility, create_queue, delete_message, delete_queue, get_queue_attributes,
+    list_queues, purge_queue, receive_message, send_message, set_queue_attributes,
+};
+use crate::state::{AuditRecord, RequestContext, AUDITED_ACTIONS};
+use crate::timers::run_timers;
+use crate::verify::verify;
+use crate::xml::ResponseBody;
+
+use log::{debug, info, warn};
+
+use crate::admin::{
+    advance_clock, clear_faults, clear_latencies, expire_in_flight_messages, export_state,
+    get_audit_log, get_delivery_attempts, get_faults, get_firehose_records, get_in_flight_messages,
+    get_latencies, get_message_trace, get_push_messages, get_queues, get_readiness, get_stats,
+    get_topics, import_state, opt_out_phone_number, pause_queue, peek_messages, redrive_queue,
+    remove_message, resume_queue, save_state, search_messages, set_fault, set_latency,
+    stream_events, tail_queue, wire_topic_to_queue,
+};
+use crate::bench::{run_bench, BenchOptions, BoxFuture, Mix};
+use crate::capture::replay;
+use crate::chaos::{Chaos, Fault, Latency};
+use crate::cloudwatch::get_metric_statistics;
+use crate::conn::{accept_tcp, Connection};
+use crate::errors::{AwsService, MyError, MyResult, Protocol};
+use crate::misc::{
+    accepts_gzip, decode_aws_chunked, get_new_id, get_region_from_host, gzip_compress,
+    gzip_decompress, lock, traceparent_to_trace_header, with_id_generator, IdGenerator,
+};
+use crate::persistence::{
+    open_store, save_snapshot, Compression, FileStore, JournalEntry, JournalFollower, Store,
+};
+use crate::scenario::{load_scenario, Scenario};
+use crate::seed::{apply_seed, list_init_files, load_seed, read_init_requests, InitFile};
+use crate::sigv4::{verify_signature, Authorization, SignedRequest};
+#[cfg(feature = "sns")]
+use crate::sns::{
+    check_if_phone_number_is_opted_out, create_platform_application, create_platform_endpoint,
+    create_topic, delete_topic, get_data_protection_policy, get_subscription_attributes,
+    get_topic_attributes, list_endpoints_by_platform_application, list_phone_numbers_opted_out,
+    list_subscriptions, list_subscriptions_by_topic, list_topics, opt_in_phone_number, publish,
+    put_data_protection_policy, set_subscription_attributes, set_topic_attributes, subscribe,
+    unsubscribe,
+};
+use crate::tls::{accept_tls, load_tls_config};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use chrono::DateTime;
+use hyper::server::accept;
+use hyper::service::{make_service_fn, service_fn, Service};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+use structopt::StructOpt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::stream::{Stream, StreamExt};
+use tokio::sync::{oneshot, watch};
+use tokio::task::{spawn_blocking, JoinHandle};
+use tokio::time::{delay_for, timeout, Duration};
+use tokio_rustls::server::TlsStream;
+use tracing::{info_span, Instrument};
+use warp::http::{HeaderMap, HeaderValue, Method, Response};
+use warp::path::FullPath;
+use warp::{Filter, Rejection, Reply};
+
+mod admin;
+pub mod api;
+mod bench;
+mod capture;
+mod chaos;
+mod cloudwatch;
+mod conn;
+mod errors;
+mod misc;
+mod persistence;
+#[cfg(feature = "redis")]
+mod redis_store;
+mod scenario;
+mod seed;
+mod sigv4;
+#[cfg(feature = "sns")]
+mod sns;
+mod spill;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(feature = "sqs")]
+mod sqs;
+mod state;
+pub mod testing;
+mod timers;
+mod tls;
+mod verify;
+mod xml;
+
+#[cfg(not(any(feature = "sqs", feature = "sns")))]
+compile_error!("At least one of the sqs and sns features must be enabled");
+
+pub use crate::state::{
+    InFlightView, MessageEvent, MessageView, QueueView, State, StateView, SubscriptionView,
+    TopicView,
+};
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Debug, Default, StructOpt)]
+#[structopt(name = "SmoQS", about = "A quick and dirty SNS/SQS mock")]
+pub struct Opt {
+    /// The port to listen on. Default is 3566.
+    #[structopt(short, long, env = "SMOQS_PORT")]
+    port: Option<u16>,
+
+    /// The addresses to listen on, comma-separated. Use :: to listen on IPv6 (and IPv4, on
+    /// dual-stack hosts). Default is 0.0.0.0.
+    #[structopt(long, env = "SMOQS_BIND", use_delimiter = true)]
+    bind: Vec<IpAddr>,
+
+    /// The default AWS region. Default is ap-southeast-2.
+    #[structopt(long, env = "SMOQS_REGION")]
+    region: Option<String>,
+
+    #[structopt(long, env = "SMOQS_ACCOUNTID")]
+    account: Option<String>,
+
+    /// Create missing queues when subscribing SQS endpoints, instead of returning an error.
+    #[structopt(long)]
+    auto_create_subscribed_queues: bool,
+
+    /// Append records delivered to firehose subscriptions to this file.
+    #[structopt(long, env = "SMOQS_FIREHOSE_FILE", parse(from_os_str))]
+    firehose_file: Option<PathBuf>,
+
+    /// Reject requests that are not signed with SigV4 using the configured credentials.
+    #[structopt(long)]
+    verify_signatures: bool,
+
+    /// The access key id to accept when verifying signatures. Default is "test".
+    #[structopt(long, env = "SMOQS_ACCESS_KEY_ID")]
+    access_key_id: Option<String>,
+
+    /// The secret access key to verify signatures with. Default is "test".
+    #[structopt(long, env = "SMOQS_SECRET_ACCESS_KEY")]
+    secret_access_key: Option<String>,
+
+    /// Scope requests signed with an access key to an account, as ACCESS_KEY=ACCOUNT_ID.
+    /// Requests can also set the X-Smoqs-Account-Id header.
+    #[structopt(long, env = "SMOQS_ACCOUNT_MAP", use_delimiter = true)]
+    account_map: Vec<String>,
+
+    /// Serve HTTPS using this certificate (PEM). Requires --tls-key.
+    #[structopt(long, env = "SMOQS_TLS_CERT", parse(from_os_str))]
+    tls_cert: Option<PathBuf>,
+
+    /// The private key (PEM) for --tls-cert.
+    #[structopt(long, env = "SMOQS_TLS_KEY", parse(from_os_str))]
+    tls_key: Option<PathBuf>,
+
+    /// The maximum request body size, in bytes. Default is 10 MiB, which leaves room for a
+    /// full 10-message batch of 256 KiB messages once form encoded.
+    #[structopt(long, env = "SMOQS_MAX_BODY_SIZE")]
+    max_body_size: Option<u64>,
+
+    /// Build queue URLs from the X-Forwarded-Host and X-Forwarded-Proto headers, for when
+    /// running behind a reverse proxy.
+    #[structopt(long)]
+    use_forwarded_headers: bool,
+
+    /// The domain for virtual-host style endpoints such as sqs.us-east-1.smoqs.local.
+    /// The service and region are taken from the Host header. Default is smoqs.local.
+    #[structopt(long, env = "SMOQS_VIRTUAL_HOST_DOMAIN")]
+    virtual_host_domain: Option<String>,
+
+    /// Record every request to this file, as newline-delimited JSON, for `smoqs replay`.
+    #[structopt(long, env = "SMOQS_CAPTURE_FILE", parse(from_os_str))]
+    capture_file: Option<PathBuf>,
+
+    /// The maximum time a ReceiveMessage long poll waits, in seconds. Default is 20.
+    #[structopt(long, env = "SMOQS_MAX_WAIT_TIME_SECONDS")]
+    max_wait_time_seconds: Option<u64>,
+
+    /// Close connections after each request, instead of keeping them alive.
+    #[structopt(long)]
+    no_keep_alive: bool,
+
+    /// Send TCP keep-alive probes on idle connections after this many seconds.
+    #[structopt(long, env = "SMOQS_TCP_KEEPALIVE_SECONDS")]
+    tcp_keepalive_seconds: Option<u64>,
+
+    /// Close connections that don't send a complete request header within this many seconds
+    /// of connecting or of their last response. Requests being handled aren't affected.
+    #[structopt(long, env = "SMOQS_HEADER_READ_TIMEOUT_SECONDS")]
+    header_read_timeout_seconds: Option<u64>,
+
+    /// Fail requests whose body takes longer than this many seconds to receive.
+    #[structopt(long, env = "SMOQS_BODY_READ_TIMEOUT_SECONDS")]
+    body_read_timeout_seconds: Option<u64>,
+
+    /// Freeze the clock used for visibility timeouts and deduplication windows. It only moves
+    /// when advanced with POST /admin/clock/advance?seconds=N.
+    #[structopt(long)]
+    virtual_clock: bool,
+
+    /// Shift the timestamps in responses and SNS notifications by this many seconds, which
+    /// may be negative, as if the server's clock were wrong.
+    #[structopt(long, env = "SMOQS_CLOCK_SKEW_SECONDS", allow_hyphen_values = true)]
+    clock_skew_seconds: Option<i64>,
+
+    /// Restore state from a snapshot in this directory at startup, and save it on shutdown.
+    #[structopt(long, env = "SMOQS_DATA_DIR", parse(from_os_str))]
+    data_dir: Option<PathBuf>,
+
+    /// Keep snapshots and the journal in a database instead of a data directory: sqlite:PATH
+    /// for a SQLite database, which needs the sqlite feature, or redis://HOST for a Redis
+    /// server, which needs the redis feature. Processes sharing a Redis server share their
+    /// queues and topics, and always journal.
+    #[structopt(long, env = "SMOQS_STORE", conflicts_with = "data-dir")]
+    store: Option<String>,
+
+    /// Also append every change to a journal in the data directory or store, so that state
+    /// survives a crash. The journal is replayed on startup and cleared whenever a snapshot is
+    /// saved.
+    #[structopt(long)]
+    journal: bool,
+
+    /// Also save a snapshot to the data directory or store on this interval. A snapshot can be
+    /// saved at any time by sending SIGUSR1.
+    #[structopt(long, env = "SMOQS_SNAPSHOT_INTERVAL_SECONDS")]
+    snapshot_interval_seconds: Option<u64>,
+
+    /// Compress snapshots and the journal in the data directory: none, gzip or zstd.
+    #[structopt(long, env = "SMOQS_COMPRESSION", default_value = "none")]
+    compression: Compression,
+
+    /// Create the queues, topics and subscriptions declared in this YAML file at startup.
+    #[structopt(long, env = "SMOQS_SEED", parse(from_os_str))]
+    seed: Option<PathBuf>,
+
+    /// Apply the seed files (.yaml, .yml or .json) and request files (.requests, with one
+    /// URL-encoded request body per line) in this directory at startup, in order of name.
+    #[structopt(long, env = "SMOQS_INIT_DIR", parse(from_os_str))]
+    init_dir: Option<PathBuf>,
+
+    /// Play back the timed requests in this YAML file, starting once the server is up.
+    #[structopt(long, env = "SMOQS_SCENARIO", parse(from_os_str))]
+    scenario: Option<PathBuf>,
+
+    /// Keep at most this many messages per queue in memory, and write the rest to a spill
+    /// file, or to the store if it can hold them, until they are needed.
+    #[structopt(long, env = "SMOQS_SPILL_THRESHOLD")]
+    spill_threshold: Option<usize>,
+
+    /// The directory for spill files. Default is smoqs-spill in the system temp directory.
+    #[structopt(long, env = "SMOQS_SPILL_DIR", parse(from_os_str))]
+    spill_dir: Option<PathBuf>,
+
+    /// Reject new messages with a Throttling error once the messages held in memory take
+    /// roughly this many megabytes, rather than running out of memory when consumers fall
+    /// behind. Messages in spill files don't count towards this.
+    #[structopt(long, env = "SMOQS_MAX_MEMORY_MB")]
+    max_memory_mb: Option<usize>,
+
+    /// Fail a proportion of requests for an action, as ACTION=ERROR:RATE, where ERROR is
+    /// InternalError, ServiceUnavailable or Throttling and RATE is between 0 and 1.
+    /// e.g. SendMessage=InternalError:0.1
+    #[structopt(long, env = "SMOQS_FAULTS", use_delimiter = true)]
+    fault: Vec<String>,
+
+    /// Delay requests for an action, as ACTION=MS or ACTION=MIN-MAX for a random delay.
+    /// Use ACTION@QUEUE to only delay requests for one queue. e.g. ReceiveMessage@orders=100-500
+    #[structopt(long, env = "SMOQS_LATENCY", use_delimiter = true)]
+    latency: Vec<String>,
+
+    /// Also leave this proportion of received messages in the queue, between 0 and 1, so they
+    /// are delivered more than once.
+    #[structopt(long, env = "SMOQS_DUPLICATE_RATE")]
+    duplicate_rate: Option<f64>,
+
+    /// Lose this proportion of notifications to subscriptions and messages being received,
+    /// between 0 and 1.
+    #[structopt(long, env = "SMOQS_DROP_RATE")]
+    drop_rate: Option<f64>,
+
+    /// Truncate or otherwise break this proportion of API responses, between 0 and 1, to
+    /// exercise client-side parsing errors and retries.
+    #[structopt(long, env = "SMOQS_MALFORMED_RATE")]
+    malformed_rate: Option<f64>,
+
+    /// Deliver messages from standard (non-FIFO) queues in random order rather than the order
+    /// they were sent, since SQS makes no ordering guarantee for them.
+    #[structopt(long)]
+    shuffle_delivery: bool,
+
+    /// Make ids, receipt handles and timestamps the same on every run, for comparing responses
+    /// against saved copies. Ids are generated from this seed, and the clock is frozen as with
+    /// --virtual-clock, starting at 2020-01-01T00:00:00Z.
+    #[structopt(long, env = "SMOQS_DETERMINISTIC")]
+    deterministic: Option<u64>,
+
+    /// Seed the random choices made when injecting failures, so they repeat between runs.
+    #[structopt(long, env = "SMOQS_CHAOS_SEED")]
+    chaos_seed: Option<u64>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+impl Opt {
+    // Prefer CLI arg, otherwise environment variable, otherwise 3566.
+    fn get_port(&self) -> u16 {
+        self.port.unwrap_or(3566)
+    }
+
+    fn get_region(&self) -> String {
+        self.region
+            .clone()
+            .unwrap_or_else(|| "ap-southeast-2".to_string())
+    }
+
+    fn get_account_id(&self) -> String {
+        self.account
+            .clone()
+            .unwrap_or_else(|| "000000000000".to_string())
+    }
+}
+
+/// The client address, for connections served by hyper directly, where warp can't see it.
+#[derive(Clone, Copy)]
+struct RemoteAddr(SocketAddr);
+
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Re-issue requests from a capture file against a running instance, with the original
+    /// timing.
+    Replay {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+
+        /// The instance to send requests to. Default is http://localhost:<port>.
+        #[structopt(long)]
+        endpoint: Option<String>,
+    },
+
+    /// Make requests with a mix of actions against a running instance, or an embedded one, and
+    /// report the throughput and latency percentiles for each action.
+    Bench {
+        /// The instance to send requests to. Default is http://localhost:<port>.
+        #[structopt(long)]
+        endpoint: Option<String>,
+
+        /// Make requests against an instance in this process, without going through HTTP.
+        #[structopt(long, conflicts_with = "endpoint")]
+        embedded: bool,
+
+        #[structopt(long, default_value = "10")]
+        duration_seconds: u64,
+
+        /// The number of workers making requests at once.
+        #[structopt(long, default_value = "8")]
+        concurrency: usize,
+
+        /// The relative weight of each action, e.g. send=5,receive=4,publish=1.
+        #[structopt(long, default_value = "send=1,receive=1,publish=1")]
+        mix: Mix,
+
+        /// The queue to send to and receive from. It is created if it doesn't exist.
+        #[structopt(long, default_value = "smoqs-bench")]
+        queue: String,
+
+        /// The topic to publish to. It is created if it doesn't exist, and the queue is
+        /// subscribed to it.
+        #[structopt(long, default_value = "smoqs-bench")]
+        topic: String,
+
+        /// The size in bytes of each message body.
+        #[structopt(long, default_value = "256")]
+        message_size: usize,
+    },
+
+    /// Run a suite of SQS and SNS protocol checks against a running instance and report any
+    /// that fail.
+    Verify {
+        /// The instance to check. Default is http://localhost:<port>.
+        #[structopt(long)]
+        endpoint: Option<String>,
+    },
+}
+
+/// Run smoqs as the command line describes: one of the subcommands, or otherwise the server
+/// until it receives SIGINT or SIGTERM.
+pub async fn run(mut opt: Opt) {
+    let port = opt.get_port();
+    match opt.command.take() {
+        Some(Command::Replay { file, endpoint }) => {
+            let endpoint = endpoint.unwrap_or_else(|| format!("http://localhost:{}", port));
+            if let Err(e) = replay(&file, &endpoint).await {
+                println!("Failed to replay {}: {}", file.display(), e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Bench {
+            endpoint,
+            embedded,
+            duration_seconds,
+            concurrency,
+            mix,
+            queue,
+            topic,
+            message_size,
+        }) => {
+            let options = BenchOptions {
+                duration: Duration::from_secs(duration_seconds),
+                concurrency,
+                mix,
+                queue_name: queue,
+                topic_name: topic,
+                message_size,
+            };
+            let result = if embedded {
+                let state = State::new(port, &opt.get_region(), &opt.get_account_id());
+                let state = Arc::new(state);
+                tokio::spawn(process_received_messages(state.clone()));
+                run_bench(embedded_client(state), options).await
+            } else {
+                let endpoint = endpoint.unwrap_or_else(|| format!("http://localhost:{}", port));
+                run_bench(http_client(endpoint), options).await
+            };
+            if let Err(e) = result {
+                println!("Benchmark failed: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Verify { endpoint }) => {
+            let endpoint = endpoint.unwrap_or_else(|| format!("http://localhost:{}", port));
+            if verify(&endpoint).await > 0 {
+                std::process::exit(1);
+            }
+            return;
+        }
+        None => {}
+    }
+
+    let server = match Server::start(opt, wait_for_shutdown_signal()).await {
+        Ok(x) => x,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    server.wait().await;
+}
+
+/// The default for --max-body-size.
+const DEFAULT_MAX_BODY_SIZE: u64 = 10 * 1024 * 1024;
+
+/// All of the routes, as a warp filter, for mounting smoqs inside another warp server.
+/// Requests with bodies larger than `max_body_size` bytes are rejected, as are those whose
+/// body takes longer than `body_read_timeout` to arrive.
+///
+/// Also spawn `process_received_messages()` with the same state, so that received messages
+/// become visible again when their visibility timeout expires, and delayed messages once
+/// their delay has passed.
+pub fn routes(
+    state: Arc<State>,
+    max_body_size: u64,
+    body_read_timeout: Option<Duration>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let state_filter = warp::any().map(move || state.clone());
+
+    let healthz = warp::path!("healthz").map(|| "OK".to_string());
+    let readyz = warp::get()
+        .and(warp::path!("readyz"))
+        .and(state_filter.clone())
+        .and_then(get_readiness);
+    let stats = warp::get()
+        .and(warp::path!("stats"))
+        .and(state_filter.clone())
+        .and_then(get_stats);
+
+    // A page for publishing, sending and inspecting messages in a browser.
+    // Its requests are unsigned, so it doesn't work with --verify-signatures.
+    let ui = warp::get()
+        .and(warp::path!("ui"))
+        .map(|| warp::reply::html(include_str!("ui.html")));
+
+    // Admin API.
+    let admin_queues = warp::get()
+        .and(warp::path!("admin" "queues"))
+        .and(state_filter.clone())
+        .and_then(get_queues);
+    let admin_peek = warp::get()
+        .and(warp::path!("admin" "queues" String "messages"))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(state_filter.clone())
+        .and_then(peek_messages);
+    let admin_tail = warp::get()
+        .and(warp::path!("admin" "queues" String "tail"))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(state_filter.clone())
+        .and_then(tail_queue);
+    let admin_pause = warp::post()
+        .and(warp::path!("admin" "queues" String "pause"))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(state_filter.clone())
+        .and_then(pause_queue);
+    let admin_resume = warp::post()
+        .and(warp::path!("admin" "queues" String "resume"))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(state_filter.clone())
+        .and_then(resume_queue);
+    let admin_redrive = warp::post()
+        .and(warp::path!("admin" "queues" String "redrive"))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(state_filter.clone())
+        .and_then(redrive_queue);
+    let admin_in_flight = warp::get()
+        .and(warp::path!("admin" "in-flight"))
+        .and(state_filter.clone())
+        .and_then(get_in_flight_messages);
+    let admin_expire = warp::post()
+        .and(warp::path!("admin" "queues" String "expire"))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(state_filter.clone())
+        .and_then(expire_in_flight_messages);
+    let admin_message_trace = warp::get()
+        .and(warp::path!("admin" "messages" String "trace"))
+        .and(state_filter.clone())
+        .and_then(get_message_trace);
+    let admin_messages = warp::get()
+        .and(warp::path!("admin" "messages"))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(state_filter.clone())
+        .and_then(search_messages);
+    let admin_delete_message = warp::delete()
+        .and(warp::path!("admin" "messages" String))
+        .and(state_filter.clone())
+        .and_then(remove_message);
+    let admin_topics = warp::get()
+        .and(warp::path!("admin" "topics"))
+        .and(state_filter.clone())
+        .and_then(get_topics);
+    let admin_stream = warp::get()
+        .and(warp::path!("admin" "stream"))
+        .and(state_filter.clone())
+        .and_then(stream_events);
+    let admin_audit = warp::get()
+        .and(warp::path!("admin" "audit"))
+        .and(state_filter.clone())
+        .and_then(get_audit_log);
+    let admin_export = warp::get()
+        .and(warp::path!("admin" "export"))
+        .and(state_filter.clone())
+        .and_then(export_state);
+    let admin_import = warp::post()
+        .and(warp::path!("admin" "import"))
+        .and(warp::body::json())
+        .and(state_filter.clone())
+        .and_then(import_state);
+    let admin_clock = warp::post()
+        .and(warp::path!("admin" "clock" "advance"))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(state_filter.clone())
+        .and_then(advance_clock);
+    let admin_wire = warp::post()
+        .and(warp::path!("admin" "wire"))
+        .and(warp::body::json())
+        .and(state_filter.clone())
+        .and_then(wire_topic_to_queue);
+    let admin_snapshot = warp::post()
+        .and(warp::path!("admin" "snapshot"))
+        .and(state_filter.clone())
+        .and_then(save_state);
+    let admin_faults = warp::get()
+        .and(warp::path!("admin" "faults"))
+        .and(state_filter.clone())
+        .and_then(get_faults);
+    let admin_set_fault = warp::post()
+        .and(warp::path!("admin" "faults"))
+        .and(warp::body::json())
+        .and(state_filter.clone())
+        .and_then(set_fault);
+    let admin_clear_faults = warp::delete()
+        .and(warp::path!("admin" "faults"))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(state_filter.clone())
+        .and_then(clear_faults);
+    let admin_latency = warp::get()
+        .and(warp::path!("admin" "latency"))
+        .and(state_filter.clone())
+        .and_then(get_latencies);
+    let admin_set_latency = warp::post()
+        .and(warp::path!("admin" "latency"))
+        .and(warp::body::json())
+        .and(state_filter.clone())
+        .and_then(set_latency);
+    let admin_clear_latency = warp::delete()
+        .and(warp::path!("admin" "latency"))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(state_filter.clone())
+        .and_then(clear_latencies);
+    let admin_push = warp::get()
+        .and(warp::path!("admin" "push"))
+        .and(state_filter.clone())
+        .and_then(get_push_messages);
+    let admin_opt_out = warp::post()
+        .and(warp::path!("admin" "sms" "opt-out" String))
+        .and(state_filter.clone())
+        .and_then(opt_out_phone_number);
+    let admin_firehose = warp::get()
+        .and(warp::path!("admin" "firehose"))
+        .and(state_filter.clone())
+        .and_then(get_firehose_records);
+    let admin_deliveries = warp::get()
+        .and(warp::path!("admin" "deliveries"))
+        .and(state_filter.clone())
+        .and_then(get_delivery_attempts);
+
+    // SNS/SQS requests come via forms, with parameters in the query string and/or the body.
+    // The raw request is kept for signature verification.
+    let query_string = warp::query::raw().or(warp::any().map(String::new)).unify();
+    let remote_addr = warp::ext::get::<RemoteAddr>()
+        .map(|x: RemoteAddr| Some(x.0))
+        .or(warp::addr::remote())
+        .unify();
+    let root_post_form = warp::post()
+        .and(warp::method())
+        .and(warp::path::full())
+        .and(query_string.clone())
+        .and(warp::header::headers_cloned())
+        .and(remote_addr.clone())
+        .and(warp::body::content_length_limit(max_body_size))
+        .and(read_body(body_read_timeout))
+        .and(state_filter.clone())
+        .and_then(handle_request)
+        .recover(recover_body_error);
+    let root_get_query = warp::get()
+        .and(warp::method())
+        .and(warp::path::full())
+        .and(query_string)
+        .and(warp::header::headers_cloned())
+        .and(remote_addr)
+        .and(warp::any().map(Bytes::new))
+        .and(state_filter.clone())
+        .and_then(handle_request);
+
+    healthz
+        .or(readyz)
+        .or(stats)
+        .or(ui)
+        .or(admin_queues)
+        .or(admin_peek)
+        .or(admin_tail)
+        .or(admin_pause)
+        .or(admin_resume)
+        .or(admin_redrive)
+        .or(admin_in_flight)
+        .or(admin_expire)
+        .or(admin_messages)
+        .or(admin_delete_message)
+        .or(admin_message_trace)
+        .or(admin_topics)
+        .or(admin_stream)
+        .or(admin_audit)
+        .or(admin_export)
+        .or(admin_import)
+        .or(admin_clock)
+        .or(admin_wire)
+        .or(admin_snapshot)
+        .or(admin_faults)
+        .or(admin_set_fault)
+        .or(admin_clear_faults)
+        .or(admin_latency)
+        .or(admin_set_latency)
+        .or(admin_clear_latency)
+        .or(admin_push)
+        .or(admin_opt_out)
+        .or(admin_firehose)
+        .or(admin_deliveries)
+        .or(root_post_form)
+        .or(root_get_query)
+}
+
+/// All of the routes, as a `tower::Service`, for mounting smoqs inside another server, or
+/// for calling it without listening on a socket:
+///
+/// ```no_run
+/// # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+/// use hyper::service::Service;
+/// use std::sync::Arc;
+///
+/// let state = Arc::new(smoqs::State::new(3566, "us-east-1", "000000000000"));
+/// let mut service = smoqs::service(state);
+/// let request = hyper::Request::post("/")
+///     .header("Content-Type", "application/x-www-form-urlencoded")
+///     .body(hyper::Body::from("Action=CreateQueue&QueueName=orders"))?;
+/// let response = service.call(request).await?;
+/// assert!(response.status().is_success());
+/// # Ok(())
+/// # }
+/// ```
+///
+/// As with `routes()`, also spawn `process_received_messages()` with the same state.
+pub fn service(
+    state: Arc<State>,
+) -> impl Service<
+    hyper::Request<hyper::Body>,
+    Response = hyper::Response<hyper::Body>,
+    Error = Infallible,
+> + Clone {
+    warp::service(routes(state, DEFAULT_MAX_BODY_SIZE, None))
+}
+
+/// Options for starting a server in-process. Anything not set has the same default as on the
+/// command line, but environment variables are not read.
+///
+/// ```no_run
+/// # async fn example() -> Result<(), String> {
+/// let server = smoqs::Server::builder()
+///     .port(0)
+///     .region("us-east-1")
+///     .start()
+///     .await?;
+/// println!("Listening on {}", server.get_addr());
+/// server.shutdown().await;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Default)]
+pub struct ServerBuilder {
+    opt: Opt,
+}
+
+impl ServerBuilder {
+    /// The port to listen on. Use 0 for a port chosen by the OS, which avoids conflicts when
+    /// running several servers at once.
+    pub fn port(mut self, port: u16) -> Self {
+        self.opt.port = Some(port);
+        self
+    }
+
+    /// Listen on this address. May be called more than once. Default is 0.0.0.0.
+    pub fn bind(mut self, ip: IpAddr) -> Self {
+        self.opt.bind.push(ip);
+        self
+    }
+
+    pub fn region(mut self, region: &str) -> Self {
+        self.opt.region = Some(region.to_string());
+        self
+    }
+
+    pub fn account_id(mut self, account_id: &str) -> Self {
+        self.opt.account = Some(account_id.to_string());
+        self
+    }
+
+    /// Create the queues, topics and subscriptions in this seed file at startup.
+    pub fn seed(mut self, path: &Path) -> Self {
+        self.opt.seed = Some(path.to_path_buf());
+        self
+    }
+
+    /// Freeze the clock, so that it only moves when advanced through the admin API.
+    pub fn virtual_clock(mut self, virtual_clock: bool) -> Self {
+        self.opt.virtual_clock = virtual_clock;
+        self
+    }
+
+    pub fn auto_create_subscribed_queues(mut self, auto_create: bool) -> Self {
+        self.opt.auto_create_subscribed_queues = auto_create;
+        self
+    }
+
+    /// Start the server. It stops when shut down through its handle, or when both the
+    /// server and its handle are dropped.
+    pub async fn start(self) -> Result<Server, String> {
+        let (tx, rx) = oneshot::channel();
+        let mut server = Server::start(self.opt, async move {
+            let _ = rx.await;
+        })
+        .await?;
+        server.shutdown_handle = Some(ShutdownHandle(tx));
+        Ok(server)
+    }
+}
+
+/// Stops the server it was taken from. Dropping it also stops the server.
+#[derive(Debug)]
+pub struct ShutdownHandle(oneshot::Sender<()>);
+
+impl ShutdownHandle {
+    pub fn shutdown(self) {
+        let _ = self.0.send(());
+    }
+}
+
+/// A running instance of smoqs, serving on one or more addresses.
+pub struct Server {
+    addrs: Vec<SocketAddr>,
+    endpoint_url: String,
+    state: Arc<State>,
+    servers: Vec<JoinHandle<()>>,
+    shutdown_handle: Option<ShutdownHandle>,
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    /// Start a server with the default options on an ephemeral port on localhost, for
+    /// integration tests. It stops when dropped.
+    pub async fn spawn() -> Result<Self, String> {
+        Self::builder()
+            .port(0)
+            .bind(IpAddr::from([127, 0, 0, 1]))
+            .start()
+            .await
+    }
+
+    /// Start serving as the options describe, until `shutdown_signal` completes.
+    /// Returns once the server is listening. Subcommands in the options are ignored.
+    pub async fn start(
+        opt: Opt,
+        shutdown_signal: impl Future<Output = ()> + Send + 'static,
+    ) -> Result<Self, String> {
+        let port = opt.get_port();
+        let region = opt.get_region();
+        let account_id = opt.get_account_id();
+        let max_body_size = opt.max_body_size.unwrap_or(DEFAULT_MAX_BODY_SIZE);
+        let body_read_timeout = opt.body_read_timeout_seconds.map(Duration::from_secs);
+
+        let mut bind = opt.bind;
+        if bind.is_empty() {
+            bind.push(IpAddr::from([0, 0, 0, 0]));
+        }
+        let addrs: Vec<SocketAddr> = bind
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect();
+
+        let tls = match (opt.tls_cert, opt.tls_key) {
+            (Some(cert), Some(key)) => Some(load_tls_config(&cert, &key)?),
+            (None, None) => None,
+            _ => {
+                return Err("Both --tls-cert and --tls-key are required for TLS".to_string());
+            }
+        };
+
+        // Set up state.
+        let mut state = State::new(port, &region, &account_id);
+        let mut chaos = Chaos::new();
+        if tls.is_some() {
+            state.use_https();
+        }
+        state.auto_create_subscribed_queues = opt.auto_create_subscribed_queues;
+        state.firehose_file = opt.firehose_file;
+        state.use_forwarded_headers = opt.use_forwarded_headers;
+        if let Some(domain) = opt.virtual_host_domain {
+            state.virtual_host_domain = domain;
+        }
+        state.capture_file = opt.capture_file;
+        if let Some(x) = opt.max_wait_time_seconds {
+            state.max_wait_time_seconds = x;
+        }
+        if opt.virtual_clock {
+            state.use_virtual_clock();
+        }
+        if let Some(seconds) = opt.clock_skew_seconds {
+            state.clock_skew = chrono::Duration::seconds(seconds);
+        }
+        if let Some(seed) = opt.deterministic {
+            state.ids = Arc::new(IdGenerator::deterministic(seed));
+            state.use_virtual_clock_at(DateTime::from(
+                UNIX_EPOCH + Duration::from_secs(1_577_836_800),
+            ));
+            chaos.set_seed(seed);
+        }
+        state.spill_threshold = opt.spill_threshold;
+        state.memory_budget = opt.max_memory_mb.map(|x| x * 1024 * 1024);
+        if let Some(dir) = opt.spill_dir {
+            state.spill_dir = dir;
+        }
+        let store: Option<Arc<dyn Store>> = match (opt.data_dir, &opt.store) {
+            (Some(dir), _) => Some(Arc::new(FileStore::new(dir, opt.compression))),
+            (None, Some(location)) => Some(open_store(location)?),
+            (None, None) => None,
+        };
+        if let Some(store) = &store {
+            let snapshot = store.load_snapshot().map_err(|e| {
+                format!(
+                    "Unable to load snapshot from {}: {}",
+                    store.get_location(),
+                    e
+                )
+            })?;
+            if let Some(snapshot) = snapshot {
+                state.import(snapshot);
+            }
+        } else if opt.journal {
+            return Err("--journal needs --data-dir or --store".to_string());
+        } else if opt.snapshot_interval_seconds.is_some() {
+            return Err("--snapshot-interval-seconds needs --data-dir or --store".to_string());
+        }
+        state.store = store.clone();
+        if let Some(path) = opt.seed {
+            let seed = load_seed(&path)
+                .map_err(|e| format!("Unable to load seed file {}: {}", path.display(), e))?;
+            apply_seed(&state, seed);
+        }
+        if opt.verify_signatures {
+            let mut credentials = HashMap::new();
+            credentials.insert(
+                opt.access_key_id.unwrap_or_else(|| "test".to_string()),
+                opt.secret_access_key.unwrap_or_else(|| "test".to_string()),
+            );
+            state.signature_credentials = Some(credentials);
+        }
+        if let Some(seed) = opt.chaos_seed {
+            chaos.set_seed(seed);
+        }
+        for entry in opt.fault {
+            Fault::parse(&entry).and_then(|x| chaos.set_fault(x))?;
+        }
+        chaos.shuffle_delivery = opt.shuffle_delivery;
+        if let Some(rate) = opt.drop_rate {
+            chaos.set_drop_rate(rate)?;
+        }
+        if let Some(rate) = opt.duplicate_rate {
+            chaos.set_duplicate_rate(rate)?;
+        }
+        if let Some(rate) = opt.malformed_rate {
+            chaos.set_malformed_rate(rate)?;
+        }
+        for entry in opt.latency {
+            Latency::parse(&entry).and_then(|x| chaos.set_latency(x))?;
+        }
+        state.chaos = Mutex::new(chaos);
+        for entry in opt.account_map {
+            match entry.find('=') {
+                Some(i) => {
+                    let (access_key, account_id) = (&entry[..i], &entry[i + 1..]);
+                    state
+                        .access_key_accounts
+                        .insert(access_key.to_string(), account_id.to_string());
+                }
+                None => return Err(format!("Invalid account map entry: {}", entry)),
+            }
+        }
+        let scenario =
+            match &opt.scenario {
+                Some(path) => Some(load_scenario(path).map_err(|e| {
+                    format!("Unable to load scenario file {}: {}", path.display(), e)
+                })?),
+                None => None,
+            };
+
+        // Listen before sharing the state, so that it knows the port the OS chose.
+        let tcp_keepalive = opt.tcp_keepalive_seconds.map(Duration::from_secs);
+        let header_read_timeout = opt.header_read_timeout_seconds.map(Duration::from_secs);
+        let mut listeners = Vec::new();
+        let mut bound_addrs: Vec<SocketAddr> = Vec::new();
+        for addr in addrs {
+            // When the OS chooses the port, listen on the same one at every address.
+            let addr = match bound_addrs.first() {
+                Some(first) if port == 0 => SocketAddr::new(addr.ip(), first.port()),
+                _ => addr,
+            };
+            let listener = TcpListener::bind(addr)
+                .await
+                .map_err(|e| format!("Unable to listen on {}: {}", addr, e))?;
+            let addr = listener
+                .local_addr()
+                .map_err(|e| format!("Unable to listen on {}: {}", addr, e))?;
+            bound_addrs.push(addr);
+            listeners.push((listener, addr));
+        }
+        if let (0, Some(addr)) = (port, bound_addrs.first()) {
+            state.set_port(addr.port());
+        }
+        let endpoint_url = state.get_endpoint_url().to_string();
+
+        let state = Arc::new(state);
+        if let Some(dir) = &opt.init_dir {
+            run_init_dir(dir, &state)
+                .await
+                .map_err(|e| format!("Unable to apply init directory {}: {}", dir.display(), e))?;
+        }
+        let shared = store.as_ref().map_or(false, |x| x.is_shared());
+        if let (true, Some(store)) = (opt.journal || shared, &store) {
+            let location = store.get_location();
+            let count = replay_journal(store.as_ref(), &state)
+                .await
+                .map_err(|e| format!("Unable to replay journal from {}: {}", location, e))?;
+            info!("Replayed {} journal entries from {}", count, location);
+            state.enable_journal();
+        }
+        if let Some(follower) = store.as_ref().and_then(|x| x.follow_journal()) {
+            let cloned_state = state.clone();
+            tokio::spawn(async move { follow_journal(follower, cloned_state).await });
+        }
+        let cloned_state = state.clone();
+        // Spawn the received messages handler as a separate task.
+        tokio::spawn(async move { process_received_messages(cloned_state).await });
+
+        if let Some(scenario) = scenario {
+            let cloned_state = state.clone();
+            tokio::spawn(async move { play_scenario(scenario, cloned_state).await });
+        }
+
+        if let Some(store) = &store {
+            if let Some(seconds) = opt.snapshot_interval_seconds {
+                let (store, cloned_state) = (store.clone(), state.clone());
+                let interval = Duration::from_secs(seconds.max(1));
+                tokio::spawn(async move {
+                    save_snapshots_periodically(store, cloned_state, interval).await
+                });
+            }
+            #[cfg(unix)]
+            {
+                let (store, cloned_state) = (store.clone(), state.clone());
+                tokio::spawn(async move { save_snapshots_on_signal(store, cloned_state).await });
+            }
+        }
+
+        let routes = routes(state.clone(), max_body_size, body_read_timeout);
+
+        // On shutdown, stop accepting connections and wake any long polls so in-flight requests
+        // can complete.
+        let server_state = state.clone();
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        tokio::spawn(async move {
+            shutdown_signal.await;
+            info!("Shutting down");
+            state.shutdown();
+            let _ = shutdown_tx.broadcast(true);
+        });
+        let shutdown = move || {
+            let mut rx = shutdown_rx.clone();
+            async move { while let Some(false) = rx.recv().await {} }
+        };
+
+        // Serve through hyper directly, since warp doesn't expose the connection options.
+        let mut servers = Vec::new();
+        for (listener, addr) in listeners {
+            let connections = accept_tcp(listener, tcp_keepalive, header_read_timeout, shutdown());
+            let service = warp::service(routes.clone());
+            match &tls {
+                Some(tls_config) => {
+                    let make_service =
+                        make_service_fn(move |conn: &TlsStream<Connection<TcpStream>>| {
+                            let service = with_connection(service.clone(), conn.get_ref().0);
+                            async move { Ok::<_, Infallible>(service_fn(service)) }
+                        });
+                    let incoming =
+                        accept_tls(connections, tls_config.clone()).map(Ok::<_, io::Error>);
+                    info!("Server running at {} (HTTPS)", addr);
+                    let server = hyper::Server::builder(accept::from_stream(incoming))
+                        .http1_keepalive(!opt.no_keep_alive)
+                        .serve(make_service)
+                        .with_graceful_shutdown(shutdown());
+                    servers.push(tokio::spawn(async move {
+                        if let Err(e) = server.await {
+                            warn!("Server error: {}", e);
+                        }
+                    }));
+                }
+                None => {
+                    let make_service = make_service_fn(move |conn: &Connection<TcpStream>| {
+                        let service = with_connection(service.clone(), conn);
+                        async move { Ok::<_, Infallible>(service_fn(service)) }
+                    });
+                    let incoming = connections.map(Ok::<_, io::Error>);
+                    info!("Server running at {}", addr);
+                    let server = hyper::Server::builder(accept::from_stream(incoming))
+                        .http1_keepalive(!opt.no_keep_alive)
+                        .serve(make_service)
+                        .with_graceful_shutdown(shutdown());
+                    servers.push(tokio::spawn(async move {
+                        if let Err(e) = server.await {
+                            warn!("Server error: {}", e);
+                        }
+                    }));
+                }
+            }
+        }
+
+        Ok(Server {
+            addrs: bound_addrs,
+            endpoint_url,
+            state: server_state,
+            servers,
+            shutdown_handle: None,
+        })
+    }
+
+    /// The addresses the server is listening on.
+    pub fn get_addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+
+    /// The first address the server is listening on, with the port it was given.
+    pub fn get_addr(&self) -> SocketAddr {
+        self.addrs[0]
+    }
+
+    /// The URL for SQS and SNS clients to send requests to.
+    pub fn get_endpoint_url(&self) -> &str {
+        &self.endpoint_url
+    }
+
+    /// The state behind the server, for inspecting or changing it directly.
+    pub fn get_state(&self) -> Arc<State> {
+        self.state.clone()
+    }
+
+    /// Take the handle for shutting the server down, so that it can be done from elsewhere.
+    /// Only servers started from a builder have one.
+    pub fn take_shutdown_handle(&mut self) -> Option<ShutdownHandle> {
+        self.shutdown_handle.take()
+    }
+
+    /// Shut the server down and wait for it to stop. Long polls return immediately and
+    /// requests in progress are allowed to complete.
+    pub async fn shutdown(mut self) {
+        if let Some(handle) = self.shutdown_handle.take() {
+            handle.shutdown();
+        }
+        self.wait().await;
+    }
+
+    /// Wait for the server to shut down, then save a snapshot if there is a data directory or
+    /// store.
+    pub async fn wait(self) {
+        for server in self.servers {
+            let _ = server.await;
+        }
+
+        // Save once the servers have stopped, so the snapshot includes every request.
+        if let Some(store) = self.state.store.clone() {
+            save_state_snapshot(store, &self.state).await;
+        }
+        // Finish writing captured requests and firehose records.
+        self.state.file_writer.flush();
+    }
+}
+
+/// Save a snapshot to the data directory or store, logging any failure.
+async fn save_state_snapshot(store: Arc<dyn Store>, state: &State) {
+    let location = store.get_location();
+    if let Err(e) = save_snapshot(store, state).await {
+        warn!("Unable to save snapshot to {}: {}", location, e);
+    }
+}
+
+async fn save_snapshots_periodically(store: Arc<dyn Store>, state: Arc<State>, interval: Duration) {
+    loop {
+        delay_for(interval).await;
+        save_state_snapshot(store.clone(), &state).await;
+    }
+}
+
+#[cfg(unix)]
+async fn save_snapshots_on_signal(store: Arc<dyn Store>, state: Arc<State>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigusr1 = signal(SignalKind::user_defined1()).expect("Failed to listen for SIGUSR1");
+    while sigusr1.recv().await.is_some() {
+        save_state_snapshot(store.clone(), &state).await;
+    }
+}
+
+/// Wrap a warp service so that each request carries the client address, and the connection
+/// knows when it has a request in flight. The guard is kept with the request, so it's dropped
+/// once the response is ready.
+fn with_connection<S, T>(
+    service: S,
+    conn: &Connection<T>,
+) -> impl FnMut(hyper::Request<hyper::Body>) -> S::Future
+where
+    S: Service<hyper::Request<hyper::Body>> + Clone,
+{
+    let remote_addr = RemoteAddr(conn.remote_addr());
+    let requests = conn.requests();
+    move |mut req| {
+        req.extensions_mut().insert(remote_addr);
+        req.extensions_mut().insert(requests.start());
+        service.clone().call(req)
+    }
+}
+
+/// The request body couldn't be read in full.
+#[derive(Debug)]
+struct BodyReadFailed(MyError);
+
+impl warp::reject::Reject for BodyReadFailed {}
+
+/// Read the whole request body, failing if it takes longer than the timeout to arrive.
+fn read_body(
+    timeout: Option<Duration>,
+) -> impl Filter<Extract = (Bytes,), Error = Rejection> + Clone {
+    warp::body::stream().and_then(move |body| async move {
+        let read = collect_body(body);
+        let result = match timeout {
+            Some(x) => tokio::time::timeout(x, read)
+                .await
+                .unwrap_or(Err(MyError::RequestTimeout)),
+            None => read.await,
+        };
+        result.map_err(|e| warp::reject::custom(BodyReadFailed(e)))
+    })
+}
+
+async fn collect_body<S, B>(body: S) -> MyResult<Bytes>
+where
+    S: Stream<Item = Result<B, warp::Error>>,
+    B: Buf,
+{
+    tokio::pin!(body);
+    let mut bytes = BytesMut::new();
+    while let Some(chunk) = body.next().await {
+        bytes.put(chunk.map_err(|e| MyError::IncompleteBody(e.to_string()))?);
+    }
+    Ok(bytes.freeze())
+}
+
+/// Respond to a request whose body couldn't be read with an error, rather than a rejection.
+async fn recover_body_error(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    match rejection.find::<BodyReadFailed>() {
+        // The headers aren't available here, so the error is reported as SQS reports it.
+        Some(BodyReadFailed(e)) => {
+            let headers = HeaderMap::new();
+            Ok(make_error_response(
+                e,
+                AwsService::Sqs,
+                Protocol::Query,
+                &headers,
+            ))
+        }
+        None => Err(rejection),
+    }
+}
+
+/// Wait for SIGINT (Ctrl-C) or SIGTERM.
+#[cfg(unix)]
+async fn wait_for_shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigterm = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = sigterm.recv() => {},
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_shutdown_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Verify the request signature, if signature verification is enabled.
+async fn check_signature(
+    method: &Method,
+    path: &FullPath,
+    query: &str,
+    headers: &HeaderMap,
+    body: &[u8],
+    state: &Arc<State>,
+) -> MyResult<()> {
+    let credentials = match &state.signature_credentials {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+
+    let mut header_values: HashMap<String, String> = HashMap::new();
+    for (name, value) in headers.iter() {
+        let value = String::from_utf8_lossy(value.as_bytes()).to_string();
+        header_values
+            .entry(name.as_str().to_string())
+            .and_modify(|x| *x = format!("{},{}", x, value))
+            .or_insert(value);
+    }
+    let req = SignedRequest {
+        method: method.as_str(),
+        path: path.as_str(),
+        query,
+        headers: &header_values,
+        body,
+    };
+    verify_signature(&req, credentials)?;
+    Ok(())
+}
+
+/// Get the request parameters, from both the query string and the form-encoded body.
+/// Parameters that can't be decoded are an error, rather than being dropped.
+fn get_params(query: &str, body: &[u8]) -> MyResult<HashMap<String, String>> {
+    check_form_encoding(query.as_bytes())?;
+    check_form_encoding(body)?;
+    let malformed = |e: serde_urlencoded::de::Error| MyError::MalformedQueryString(e.to_string());
+    let mut params: HashMap<String, String> =
+        serde_urlencoded::from_str(query).map_err(malformed)?;
+    let body_params: HashMap<String, String> =
+        serde_urlencoded::from_bytes(body).map_err(malformed)?;
+    params.extend(body_params);
+    Ok(params)
+}
+
+/// Check form-encoded data is UTF-8 with valid percent escapes, which the decoder would
+/// otherwise silently replace.
+fn check_form_encoding(data: &[u8]) -> MyResult<()> {
+    let text = std::str::from_utf8(data)
+        .map_err(|_| MyError::MalformedQueryString("invalid UTF-8".to_string()))?;
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        rest = tail;
+        if b != b'%' {
+            bytes.push(b);
+            continue;
+        }
+        let escape = rest
+            .get(..2)
+            .filter(|x| x.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|x| std::str::from_utf8(x).ok())
+            .and_then(|x| u8::from_str_radix(x, 16).ok())
+            .ok_or_else(|| MyError::MalformedQueryString("invalid percent-encoding".to_string()))?;
+        bytes.push(escape);
+        rest = &rest[2..];
+    }
+    match String::from_utf8(bytes) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(MyError::MalformedQueryString(
+            "percent-encoded data is not UTF-8".to_string(),
+        )),
+    }
+}
+
+/// Scope the request to the account in the X-Smoqs-Account-Id header, or the account mapped
+/// to the request's access key, and to the region it was signed for (or the region in the
+/// Host header). Queue URLs use the virtual host the request was made to, if any, or the
+/// forwarded host when behind a proxy.
+async fn get_request_context(headers: &HeaderMap, state: &Arc<State>) -> MyResult<RequestContext> {
+    let header_value = |name: &str| headers.get(name).and_then(|x| x.to_str().ok());
+    // Queue URLs are only resolved back to an account if it looks like an AWS account id.
+    let account_id = header_value("x-smoqs-account-id");
+    if let Some(x) = account_id {
+        if x.len() != 12 || !x.chars().all(|c| c.is_ascii_digit()) {
+            return Err(MyError::InvalidParameterValue(
+                "X-Smoqs-Account-Id".to_string(),
+                x.to_string(),
+                "Account ids must be 12 digits.".to_string(),
+            ));
+        }
+    }
+    let auth = header_value("authorization").and_then(|x| Authorization::parse(x).ok());
+    let host = header_value("host").unwrap_or_default();
+    let region = match &auth {
+        Some(x) => Some(x.region.as_str()),
+        None => state
+            .parse_virtual_host(host)
+            .map(|(_, region)| region)
+            .or_else(|| get_region_from_host(host)),
+    };
+    let mut ctx = state.get_request_context(
+        auth.as_ref().map(|x| x.access_key.as_str()),
+        account_id,
+        region,
+    );
+    ctx.endpoint_url = state.get_virtual_host_url(host);
+    ctx.trace_header = header_value("x-amzn-trace-id")
+        .map(String::from)
+        .or_else(|| header_value("traceparent").and_then(traceparent_to_trace_header));
+    if state.use_forwarded_headers {
+        // These may be lists when there are multiple proxies. The first is the client-facing one.
+        let first_value = |name: &str| header_value(name).and_then(|x| x.split(',').next());
+        if let Some(host) = first_value("x-forwarded-host") {
+            let proto = first_value("x-forwarded-proto").unwrap_or("http");
+            ctx.endpoint_url = Some(format!("{}://{}", proto.trim(), host.trim()));
+        }
+    }
+    Ok(ctx)
+}
+
+/// Handle an SQS or SNS request. Ids are generated by the server's id generator throughout,
+/// including for errors before the request is dispatched.
+pub async fn handle_request(
+    method: Method,
+    path: FullPath,
+    query: String,
+    headers: HeaderMap,
+    remote_addr: Option<SocketAddr>,
+    body: Bytes,
+    state: Arc<State>,
+) -> Result<impl Reply, Infallible> {
+    let ids = state.ids.clone();
+    let request = handle_action(method, path, query, headers, remote_addr, body, state);
+    with_id_generator(ids, request).await
+}
+
+async fn handle_action(
+    method: Method,
+    path: FullPath,
+    query: String,
+    headers: HeaderMap,
+    remote_addr: Option<SocketAddr>,
+    body: Bytes,
+    state: Arc<State>,
+) -> Result<impl Reply, Infallible> {
+    // Until the action is known, errors are reported for the service the request was signed for.
+    let service = get_service(&headers, None);
+    let protocol = get_protocol(&headers);
+    if let Err(e) = check_signature(&method, &path, &query, &headers, &body, &state).await {
+        return Ok(make_error_response(&e, service, protocol, &headers));
+    }
+    if protocol == Protocol::Json {
+        let e = MyError::UnsupportedProtocol;
+        return Ok(make_error_response(&e, service, protocol, &headers));
+    }
+
+    // The signature covers the encoded body, so only decode it after verifying.
+    let body = match decode_body(&headers, body) {
+        Ok(x) => x,
+        Err(e) => return Ok(make_error_response(&e, service, protocol, &headers)),
+    };
+
+    let ctx = match get_request_context(&headers, &state).await {
+        Ok(x) => x,
+        Err(e) => return Ok(make_error_response(&e, service, protocol, &headers)),
+    };
+    let f = match get_params(&query, &body) {
+        Ok(x) => x,
+        Err(e) => return Ok(make_error_response(&e, service, protocol, &headers)),
+    };
+    match f.get("Action") {
+        Some(action) => {
+            info!("ACTION: {}: {:?}", action, f);
+            let service = get_service(&headers, Some(action));
+            state.capture_request(action, &f);
+            let action = action.clone();
+            let span = info_span!(
+                "action",
+                action = action.as_str(),
+                trace_header = ctx.trace_header.as_deref().unwrap_or_default()
+            );
+            let audit_record = match AUDITED_ACTIONS.contains(&action.as_str()) {
+                true => Some(AuditRecord::new(
+                    &action,
+                    remote_addr.map(|x| x.ip()),
+                    &ctx,
+                    &f,
+                )),
+                false => None,
+            };
+            let queue_name = f.get("QueueUrl").and_then(|x| x.rsplit('/').next());
+            let latency = lock(&state.chaos).get_latency(&action, queue_name);
+            if let Some(latency) = latency {
+                delay_for(latency).await;
+            }
+            let fault = lock(&state.chaos).get_fault_error(&action);
+            // Audited actions are journaled, so they generate ids from a seed that is
+            // journaled with them.
+            let result = match (fault, &audit_record) {
+                (Some(e), _) => {
+                    info!("Injecting {} into {}", e.get_error_code(service), action);
+                    Err(e)
+                }
+                (None, Some(record)) => {
+                    dispatch_seeded(&action, f, ctx, record.id_seed, state.clone())
+                        .instrument(span)
+                        .await
+                }
+                (None, None) => {
+                    dispatch(&action, f, ctx, state.clone())
+                        .instrument(span)
+                        .await
+                }
+            };
+            if let Some(record) = audit_record {
+                record_audited_action(record, &result, &state).await;
+            }
+
+            let (status, body) = match result {
+                Ok(x) => (200, x),
+                Err(e) => (
+                    e.get_status_code(service),
+                    e.get_error_response(service, protocol).into(),
+                ),
+            };
+            // Streamed responses are only written in full if they may be malformed.
+            let may_be_malformed = lock(&state.chaos).has_malformed_responses();
+            let body = match body {
+                ResponseBody::Streamed(x) if !may_be_malformed => ResponseBody::Streamed(x),
+                x => {
+                    let mut body = x.into_string();
+                    let malformed = lock(&state.chaos).get_malformed_response(&body);
+                    if let Some(malformed) = malformed {
+                        info!("Sending a malformed response to {}", action);
+                        body = malformed;
+                    }
+                    ResponseBody::Complete(body)
+                }
+            };
+            Ok(make_response(status, body, &headers))
+        }
+        None => Ok(make_error_response(
+            &MyError::MissingAction,
+            service,
+            protocol,
+            &headers,
+        )),
+    }
+}
+
+/// Add a request to the audit log and, if it succeeded, to the journal.
+async fn record_audited_action(
+    mut record: AuditRecord,
+    result: &MyResult<ResponseBody>,
+    state: &Arc<State>,
+) {
+    // Audited actions are all ones we implement, so their service is known.
+    let service = AwsService::from_action(&record.action).unwrap_or(AwsService::Sqs);
+    record.error = result
+        .as_ref()
+        .err()
+        .map(|e| e.get_error_code(service).to_string());
+    if record.error.is_none() {
+        state.journal(JournalEntry::Action {
+            action: record.action.clone(),
+            account_id: record.account_id.clone(),
+            region: record.region.clone(),
+            params: record.params.clone(),
+            id_seed: Some(record.id_seed),
+        });
+    }
+    state.add_audit_record(record);
+}
+
+/// Dispatch a request made on a client's behalf, such as through the admin API, auditing and
+/// journaling it as if the client had made it directly.
+async fn dispatch_audited(
+    action: &str,
+    f: HashMap<String, String>,
+    ctx: RequestContext,
+    state: Arc<State>,
+) -> MyResult<ResponseBody> {
+    let ids = state.ids.clone();
+    let record = with_id_generator(ids, async { AuditRecord::new(action, None, &ctx, &f) }).await;
+    let result = dispatch_seeded(action, f, ctx, record.id_seed, state.clone()).await;
+    record_audited_action(record, &result, &state).await;
+    result
+}
+
+/// Apply the seed and request files in an init directory. Failed requests are logged and
+/// skipped, so that one bad request doesn't stop the rest from being applied.
+async fn run_init_dir(dir: &Path, state: &Arc<State>) -> std::io::Result<()> {
+    for file in list_init_files(dir)? {
+        match file {
+            InitFile::Seed(path) => {
+                info!("Applying seed file {}", path.display());
+                let seed = load_seed(&path)?;
+                apply_seed(state, seed);
+            }
+            InitFile::Requests(path) => {
+                info!("Applying requests from {}", path.display());
+                for params in read_init_requests(&path)? {
+                    let action = match params.get("Action") {
+                        Some(x) => x.clone(),
+                        None => {
+                            warn!("Skipping request without an Action in {}", path.display());
+                            continue;
+                        }
+                    };
+                    let ctx = state.get_request_context(None, None, None);
+                    let ids = state.ids.clone();
+                    let request = dispatch(&action, params, ctx, state.clone());
+                    if let Err(e) = with_id_generator(ids, request).await {
+                        warn!("{} from {} failed: {}", action, path.display(), e);
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Send benchmark requests to a running instance.
+fn http_client(
+    endpoint: String,
+) -> impl Fn(HashMap<String, String>) -> BoxFuture<Result<String, String>> + Clone {
+    let client = reqwest::Client::new();
+    move |params| {
+        let request = client.post(&endpoint).form(&params);
+        Box::pin(async move {
+            let response = request.send().await.map_err(|e| e.to_string())?;
+            let status = response.status();
+            let body = response.text().await.map_err(|e| e.to_string())?;
+            if status.is_success() {
+                Ok(body)
+            } else {
+                Err(body)
+            }
+        })
+    }
+}
+
+/// Send benchmark requests straight to the handlers, sharing state with nothing else.
+fn embedded_client(
+    state: Arc<State>,
+) -> impl Fn(HashMap<String, String>) -> BoxFuture<Result<String, String>> + Clone {
+    move |params| {
+        let state = state.clone();
+        Box::pin(async move {
+            let action = params.get("Action").cloned().unwrap_or_default();
+            let ctx = state.get_request_context(None, None, None);
+            dispatch(&action, params, ctx, state)
+                .await
+                .map(ResponseBody::into_string)
+                .map_err(|e| e.to_string())
+        })
+    }
+}
+
+/// Make each request in the scenario when it falls due. Failed requests are logged and the
+/// scenario carries on.
+async fn play_scenario(scenario: Scenario, state: Arc<State>) {
+    let start = std::time::Instant::now();
+    for (offset, event) in scenario.get_schedule() {
+        let elapsed = start.elapsed();
+        if offset > elapsed {
+            delay_for(offset - elapsed).await;
+        }
+        info!(
+            "Scenario: {} at t+{:.1}s",
+            event.action,
+            offset.as_secs_f64()
+        );
+        let ctx = state.get_request_context(None, None, None);
+        let ids = state.ids.clone();
+        let mut params = event.params.clone();
+        params.insert("Action".to_string(), event.action.clone());
+        let request = dispatch(&event.action, params, ctx, state.clone());
+        if let Err(e) = with_id_generator(ids, request).await {
+            warn!("Scenario: {} failed: {}", event.action, e);
+        }
+    }
+    info!("Scenario finished");
+}
+
+/// Re-apply the changes journaled since the last snapshot.
+async fn replay_journal(store: &dyn Store, state: &Arc<State>) -> std::io::Result<usize> {
+    let entries = store.read_journal()?;
+    let count = entries.len();
+    apply_journal_entries(entries, state).await;
+    Ok(count)
+}
+
+/// Apply changes from the journal. Actions are dispatched again as requests, generating the
+/// same ids as before if they were journaled with a seed.
+async fn apply_journal_entries(entries: Vec<JournalEntry>, state: &Arc<State>) {
+    for entry in entries {
+        match entry {
+            JournalEntry::Action {
+                action,
+                account_id,
+                region,
+                params,
+                id_seed,
+            } => {
+                let ctx = RequestContext {
+                    account_id,
+                    region,
+                    endpoint_url: None,
+                    trace_header: None,
+                };
+                let result = match id_seed {
+                    Some(seed) => dispatch_seeded(&action, params, ctx, seed, state.clone()).await,
+                    None => dispatch(&action, params, ctx, state.clone()).await,
+                };
+                if let Err(e) = result {
+                    warn!("Unable to apply {} from the journal: {}", action, e);
+                }
+            }
+            entry => state.apply_journal_entry(entry),
+        }
+    }
+}
+
+/// Apply the changes that other processes sharing the store journal, as they journal them.
+async fn follow_journal(mut follower: Box<dyn JournalFollower>, state: Arc<State>) {
+    loop {
+        // Reading waits on the store, so it is done off the runtime's threads.
+        let read = tokio::task::spawn_blocking(move || {
+            let entries = follower.next_entries();
+            (follower, entries)
+        });
+        let entries = match read.await {
+            Ok((x, entries)) => {
+                follower = x;
+                entries
+            }
+            // Reading panicked, which has already been reported.
+            Err(_) => return,
+        };
+        match entries {
+            Ok(entries) => apply_journal_entries(entries, &state).await,
+            Err(e) => {
+                warn!("Unable to follow the shared journal: {}", e);
+                delay_for(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+/// Handle an action with an id generator of its own, so that it generates the same ids each
+/// time it is handled with the same seed.
+async fn dispatch_seeded(
+    action: &str,
+    f: HashMap<String, String>,
+    ctx: RequestContext,
+    seed: u64,
+    state: Arc<State>,
+) -> MyResult<ResponseBody> {
+    let ids = Arc::new(IdGenerator::deterministic(seed));
+    with_id_generator(ids, dispatch(action, f, ctx, state)).await
+}
+
+async fn dispatch(
+    action: &str,
+    f: HashMap<String, String>,
+    ctx: RequestContext,
+    state: Arc<State>,
+) -> MyResult<ResponseBody> {
+    let body = match action {
+        // SQS.
+        #[cfg(feature = "sqs")]
+        "ListQueues" => list_queues(f, ctx, state).await,
+        #[cfg(feature = "sqs")]
+        "CreateQueue" => create_queue(f, ctx, state).await,
+        #[cfg(feature = "sqs")]
+        "DeleteQueue" => delete_queue(f, ctx, state).await,
+        #[cfg(feature = "sqs")]
+        "PurgeQueue" => purge_queue(f, ctx, state).await,
+        #[cfg(feature = "sqs")]
+        "GetQueueAttributes" => get_queue_attributes(f, ctx, state).await,
+        #[cfg(feature = "sqs")]
+        "SetQueueAttributes" => set_queue_attributes(f, ctx, state).await,
+        #[cfg(feature = "sqs")]
+        "SendMessage" => send_message(f, ctx, state).await,
+        #[cfg(feature = "sqs")]
+        "ReceiveMessage" => return receive_message(f, ctx, state).await,
+        #[cfg(feature = "sqs")]
+        "DeleteMessage" => delete_message(f, ctx, state).await,
+        #[cfg(feature = "sqs")]
+        "ChangeMessageVisibility" => change_message_visibility(f, ctx, state).await,
+        // SNS.
+        #[cfg(feature = "sns")]
+        "ListTopics" => list_topics(f, ctx, state).await,
+        #[cfg(feature = "sns")]
+        "CreateTopic" => create_topic(f, ctx, state).await,
+        #[cfg(feature = "sns")]
+        "DeleteTopic" => delete_topic(f, state).await,
+        #[cfg(feature = "sns")]
+        "GetTopicAttributes" => get_topic_attributes(f, state).await,
+        #[cfg(feature = "sns")]
+        "SetTopicAttributes" => set_topic_attributes(f, state).await,
+        #[cfg(feature = "sns")]
+        "Publish" => publish(f, ctx, state).await,
+        #[cfg(feature = "sns")]
+        "Subscribe" => subscribe(f, ctx, state).await,
+        #[cfg(feature = "sns")]
+        "Unsubscribe" => unsubscribe(f, state).await,
+        #[cfg(feature = "sns")]
+        "ListSubscriptions" => list_subscriptions(f, ctx, state).await,
+        #[cfg(feature = "sns")]
+        "ListSubscriptionsByTopic" => list_subscriptions_by_topic(f, state).await,
+        #[cfg(feature = "sns")]
+        "SetSubscriptionAttributes" => set_subscription_attributes(f, state).await,
+        #[cfg(feature = "sns")]
+        "GetSubscriptionAttributes" => get_subscription_attributes(f, state).await,
+        #[cfg(feature = "sns")]
+        "CreatePlatformApplication" => create_platform_application(f, ctx, state).await,
+        #[cfg(feature = "sns")]
+        "CreatePlatformEndpoint" => create_platform_endpoint(f, state).await,
+        #[cfg(feature = "sns")]
+        "ListEndpointsByPlatformApplication" => {
+            list_endpoints_by_platform_application(f, state).await
+        }
+        #[cfg(feature = "sns")]
+        "OptInPhoneNumber" => opt_in_phone_number(f, state).await,
+        #[cfg(feature = "sns")]
+        "CheckIfPhoneNumberIsOptedOut" => check_if_phone_number_is_opted_out(f, state).await,
+        #[cfg(feature = "sns")]
+        "ListPhoneNumbersOptedOut" => list_phone_numbers_opted_out(f, state).await,
+        #[cfg(feature = "sns")]
+        "PutDataProtectionPolicy" => put_data_protection_policy(f, state).await,
+        #[cfg(feature = "sns")]
+        "GetDataProtectionPolicy" => get_data_protection_policy(f, state).await,
+        // CloudWatch.
+        "GetMetricStatistics" => get_metric_statistics(f, ctx, state).await,
+        x => Err(MyError::UnknownAction(x.to_string())),
+    };
+    body.map(ResponseBody::from)
+}
+
+/// Undo the request's content encodings. The aws-chunked framing is always the outermost
+/// layer, regardless of where it appears in the header.
+fn decode_body(headers: &HeaderMap, body: Bytes) -> MyResult<Bytes> {
+    let encodings: Vec<String> = headers
+        .get("content-encoding")
+        .and_then(|x| x.to_str().ok())
+        .unwrap_or_default()
+        .split(',')
+        .map(|x| x.trim().to_ascii_lowercase())
+        .filter(|x| !x.is_empty())
+        .collect();
+    let to_error = |e: String| {
+        MyError::InvalidParameterValue("Content-Encoding".to_string(), encodings.join(","), e)
+    };
+
+    let mut body = body;
+    if encodings.iter().any(|x| x == "aws-chunked") {
+        body = Bytes::from(decode_aws_chunked(&body).map_err(to_error)?);
+    }
+    for encoding in encodings.iter().rev() {
+        if encoding == "gzip" {
+            let decoded = gzip_decompress(&body).map_err(|e| to_error(e.to_string()))?;
+            body = Bytes::from(decoded);
+        }
+    }
+    Ok(body)
+}
+
+fn get_request_id(body: &str) -> Option<&str> {
+    let start = body.find("<RequestId>")? + "<RequestId>".len();
+    let end = body[start..].find("</RequestId>")? + start;
+    Some(&body[start..end])
+}
+
+/// The service a request is for, from its action if there is one, or otherwise from the
+/// service it was signed for. Unsigned requests without a known action are taken to be SQS.
+fn get_service(headers: &HeaderMap, action: Option<&str>) -> AwsService {
+    let header_value = |name: &str| headers.get(name).and_then(|x| x.to_str().ok());
+    // JSON requests name the action in the target, e.g. AmazonSQS.SendMessage.
+    let target_action = header_value("x-amz-target").and_then(|x| x.rsplit('.').next());
+    action
+        .or(target_action)
+        .and_then(AwsService::from_action)
+        .or_else(|| {
+            let auth = header_value("authorization")?;
+            AwsService::from_signing_name(&Authorization::parse(auth).ok()?.service)
+        })
+        .unwrap_or(AwsService::Sqs)
+}
+
+fn get_protocol(headers: &HeaderMap) -> Protocol {
+    let is_json = headers
+        .get("content-type")
+        .and_then(|x| x.to_str().ok())
+        .map(|x| x.starts_with("application/x-amz-json"))
+        .unwrap_or(false);
+    match is_json || headers.contains_key("x-amz-target") {
+        true => Protocol::Json,
+        false => Protocol::Query,
+    }
+}
+
+/// Build an error response in the protocol of the request. JSON errors also carry the query
+/// protocol's code in a header, which SDKs use to raise the same exceptions for both.
+fn make_error_response(
+    e: &MyError,
+    service: AwsService,
+    protocol: Protocol,
+    request_headers: &HeaderMap,
+) -> warp::http::Result<Response<hyper::Body>> {
+    let body = ResponseBody::Complete(e.get_error_response(service, protocol));
+    let mut response = make_response(e.get_status_code(service), body, request_headers)?;
+    if protocol == Protocol::Json {
+        let headers = response.headers_mut();
+        let content_type = HeaderValue::from_static("application/x-amz-json-1.0");
+        headers.insert("content-type", content_type);
+        if let Ok(x) = HeaderValue::from_str(&e.get_query_error_header(service)) {
+            headers.insert("x-amzn-query-error", x);
+        }
+    }
+    Ok(response)
+}
+
+/// Build the response, compressing the body if the client accepts gzip.
+/// The x-amzn-RequestId header matches the RequestId in the body.
+/// Streamed bodies are sent a chunk at a time, unless they are compressed, in which case
+/// they are written in full first.
+fn make_response(
+    status: u16,
+    body: ResponseBody,
+    request_headers: &HeaderMap,
+) -> warp::http::Result<Response<hyper::Body>> {
+    let accepts_gzip = request_headers
+        .get("accept-encoding")
+        .and_then(|x| x.to_str().ok())
+        .map(accepts_gzip)
+        .unwrap_or(false);
+
+    let body = match body {
+        ResponseBody::Streamed(x) if !accepts_gzip => {
+            debug!("Streaming response {}", x.request_id);
+            return Response::builder()
+                .status(status)
+                .header("Content-Type", "text/xml")
+                .header("x-amzn-RequestId", x.request_id.clone())
+                .body(hyper::Body::wrap_stream(tokio::stream::iter(
+                    x.map(Ok::<_, Infallible>),
+                )));
+        }
+        x => x.into_string(),
+    };
+    debug!("Response:\n{}", body);
+
+    let request_id = get_request_id(&body)
+        .map(String::from)
+        .unwrap_or_else(get_new_id);
+    let builder = Response::builder()
+        .status(status)
+        .header("Content-Type", "text/xml")
+        .header("x-amzn-RequestId", request_id);
+    if accepts_gzip {
+        match gzip_compress(body.as_bytes()) {
+            Ok(x) => return builder.header("Content-Encoding", "gzip").body(x.into()),
+            Err(e) => warn!("Failed to compress response: {:?}", e),
+        }
+    }
+    builder.body(body.into())
+}
+
+/// Run the timers for delays, visibility timeouts, retention periods and deduplication
+/// windows as they come due, read spilled messages back or spill backlogs to disk, and
+/// periodically recount the memory used by messages.
+pub async fn process_received_messages(state: Arc<State>) {
+    let spill_backlogs = async {
+        let wake = state.spill_wake.clone();
+        loop {
+            // Queues that run low on messages ask for spilled ones to be read back sooner.
+            let _ = timeout(Duration::new(5, 0), wake.notified()).await;
+            let jobs = state.get_spill_jobs();
+            if jobs.is_empty() {
+                continue;
+            }
+            let results = spawn_blocking(move || {
+                jobs.into_iter()
+                    .map(|(path, job)| {
+                        let result = job.run();
+                        (path, job, result)
+                    })
+                    .collect::<Vec<_>>()
+            });
+            match results.await {
+                Ok(x) => state.finish_spill_jobs(x),
+                Err(e) => warn!("Spill files could not be read or written: {}", e),
+            }
+        }
+    };
+    let recount_memory = async {
+        loop {
+            delay_for(Duration::new(5, 0)).await;
+            state.update_memory_used();
+        }
+    };
+    tokio::join!(run_timers(state.clone()), spill_backlogs, recount_memory);
+}
@@ -1,7 +1,108 @@
+use crate::errors::{MyError, MyResult};
+use crate::state::MessageAttributeValue;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::future::Future;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use uuid::{Variant, Version};
 
+/// Generates the ids for messages, receipt handles, requests and so on. Ids are random unless
+/// the generator is seeded, in which case they are derived from the seed and a counter, so
+/// that the same sequence of ids can be generated again. Each server has its own, so that
+/// servers in the same process don't disturb each other's sequence.
+#[derive(Debug, Default)]
+pub struct IdGenerator {
+    seed: Option<u64>,
+    counter: AtomicU64,
+}
+
+impl IdGenerator {
+    /// Generate the same sequence of ids for the same seed.
+    pub fn deterministic(seed: u64) -> Self {
+        Self {
+            seed: Some(seed),
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    /// The source of the next id or seed, if the generator is seeded.
+    fn get_next_rng(&self) -> Option<StdRng> {
+        let seed = self.seed?;
+        let n = self.counter.fetch_add(1, Ordering::SeqCst);
+        Some(StdRng::seed_from_u64(seed.wrapping_add(n)))
+    }
+
+    pub fn get_new_id(&self) -> String {
+        match self.get_next_rng() {
+            Some(mut rng) => uuid::Builder::from_bytes(rng.gen())
+                .set_variant(Variant::RFC4122)
+                .set_version(Version::Random)
+                .build()
+                .to_string(),
+            None => uuid::Uuid::new_v4().to_string(),
+        }
+    }
+
+    /// Get a seed for another generator.
+    pub fn get_new_seed(&self) -> u64 {
+        match self.get_next_rng() {
+            Some(mut rng) => rng.gen(),
+            None => rand::random(),
+        }
+    }
+}
+
+tokio::task_local! {
+    // The id generator of the server handling the current request, or of the action being
+    // handled, if it must generate the same ids when it's applied again, such as from the
+    // journal.
+    static ID_GENERATOR: Arc<IdGenerator>;
+}
+
+/// Handle a request or action with an id generator, which `get_new_id()` then uses.
+pub async fn with_id_generator<F: Future>(ids: Arc<IdGenerator>, f: F) -> F::Output {
+    ID_GENERATOR.scope(ids, f).await
+}
+
+/// Get a new id from the generator of the request or action being handled, or a random one
+/// outside of a request.
 pub fn get_new_id() -> String {
-    uuid::Uuid::new_v4().to_string()
+    ID_GENERATOR
+        .try_with(|x| x.get_new_id())
+        .unwrap_or_else(|_| uuid::Uuid::new_v4().to_string())
+}
+
+/// Get a seed for an id generator, from the generator of the request being handled if there
+/// is one.
+pub fn get_new_seed() -> u64 {
+    ID_GENERATOR
+        .try_with(|x| x.get_new_seed())
+        .unwrap_or_else(|_| rand::random())
+}
+
+/// Lock a mutex, even if a task panicked while holding it. Requests are handled on tasks of
+/// their own, so one that panics shouldn't leave the queue or log it was using locked for
+/// every request after it.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Take a shared lock, as `lock()` does. Requests that only read a queue or topic share it.
+pub fn read<T>(rwlock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    rwlock.read().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Take an exclusive lock, as `lock()` does.
+pub fn write<T>(rwlock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    rwlock.write().unwrap_or_else(|e| e.into_inner())
 }
 
 pub fn get_attributes(form: &HashMap<String, String>) -> HashMap<String, String> {
@@ -19,11 +120,13 @@ pub fn get_attributes(form: &HashMap<String, String>) -> HashMap<String, String>
     attributes
 }
 
-pub fn get_message_attributes(form: &HashMap<String, String>) -> HashMap<String, String> {
+/// SNS encodes attributes as `Attributes.entry.N.key` and `Attributes.entry.N.value`.
+#[cfg(feature = "sns")]
+fn get_entry_attributes(form: &HashMap<String, String>) -> HashMap<String, String> {
     let mut attributes = HashMap::new();
     for count in 1..100 {
-        if let Some(k) = form.get(&format!("MessageAttribute.{}.Name", count)) {
-            if let Some(v) = form.get(&format!("MessageAttribute.{}.Value", count)) {
+        if let Some(k) = form.get(&format!("Attributes.entry.{}.key", count)) {
+            if let Some(v) = form.get(&format!("Attributes.entry.{}.value", count)) {
                 attributes.insert(k.clone(), v.clone());
                 continue;
             }
@@ -34,10 +137,277 @@ pub fn get_message_attributes(form: &HashMap<String, String>) -> HashMap<String,
     attributes
 }
 
+/// Get attributes for SNS requests, which may use either encoding.
+#[cfg(feature = "sns")]
+pub fn get_sns_attributes(form: &HashMap<String, String>) -> HashMap<String, String> {
+    let mut attributes = get_attributes(form);
+    attributes.extend(get_entry_attributes(form));
+    attributes
+}
+
+/// Parse typed message attributes, e.g. `MessageAttribute.N.Name` with
+/// `MessageAttribute.N.Value.DataType` and `MessageAttribute.N.Value.StringValue`.
+/// A plain `MessageAttribute.N.Value` is treated as a String attribute.
+fn parse_message_attributes(
+    form: &HashMap<String, String>,
+    prefix: &str,
+) -> HashMap<String, MessageAttributeValue> {
+    let mut attributes = HashMap::new();
+    for count in 1..100 {
+        if let Some(k) = form.get(&format!("{}.{}.Name", prefix, count)) {
+            let value_prefix = format!("{}.{}.Value", prefix, count);
+            if let Some(v) = form.get(&value_prefix) {
+                attributes.insert(k.clone(), MessageAttributeValue::string(v));
+                continue;
+            }
+            if let Some(data_type) = form.get(&format!("{}.DataType", value_prefix)) {
+                attributes.insert(
+                    k.clone(),
+                    MessageAttributeValue {
+                        data_type: data_type.clone(),
+                        string_value: form.get(&format!("{}.StringValue", value_prefix)).cloned(),
+                        binary_value: form.get(&format!("{}.BinaryValue", value_prefix)).cloned(),
+                    },
+                );
+                continue;
+            }
+        }
+
+        break;
+    }
+    attributes
+}
+
+pub fn get_message_attributes(
+    form: &HashMap<String, String>,
+) -> HashMap<String, MessageAttributeValue> {
+    parse_message_attributes(form, "MessageAttribute")
+}
+
+/// SNS encodes message attributes as `MessageAttributes.entry.N.Name` etc.
+#[cfg(feature = "sns")]
+pub fn get_sns_message_attributes(
+    form: &HashMap<String, String>,
+) -> HashMap<String, MessageAttributeValue> {
+    parse_message_attributes(form, "MessageAttributes.entry")
+}
+
+/// Validate message attribute names the same way AWS does.
+pub fn validate_message_attributes(
+    attributes: &HashMap<String, MessageAttributeValue>,
+) -> MyResult<()> {
+    for name in attributes.keys() {
+        let lower = name.to_lowercase();
+        if lower.starts_with("aws.") || lower.starts_with("amazon.") {
+            return Err(MyError::InvalidMessageAttribute(
+                "Message (user) attribute names starting with 'AWS.' or 'Amazon.' are reserved \
+                 for use by Amazon."
+                    .to_string(),
+            ));
+        }
+        if name.is_empty() || name.chars().count() > 256 {
+            return Err(MyError::InvalidMessageAttribute(format!(
+                "Message (user) attribute name '{}' must be between 1 and 256 characters long.",
+                name
+            )));
+        }
+        let has_valid_chars = name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.');
+        if !has_valid_chars || name.starts_with('.') || name.ends_with('.') || name.contains("..") {
+            return Err(MyError::InvalidMessageAttribute(format!(
+                "Message (user) attribute name '{}' is invalid. Attribute name can contain \
+                 A-Z, a-z, 0-9, underscore(_), hyphen(-), and period (.) characters.",
+                name
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Validate a queue name the same way AWS does. Names may have up to 80 alphanumeric
+/// characters, hyphens and underscores, and FIFO queues, and only FIFO queues, must have
+/// names ending in `.fifo`.
+pub fn validate_queue_name(name: &str, attributes: &HashMap<String, String>) -> MyResult<()> {
+    let is_fifo = attributes
+        .get("FifoQueue")
+        .map(|v| v.eq_ignore_ascii_case("true"))
+        .unwrap_or(false);
+    validate_name(name, "FifoQueue", is_fifo, 80).map_err(|reason| {
+        MyError::InvalidParameterValue(
+            "QueueName".to_string(),
+            name.to_string(),
+            format!("Queue {}", reason),
+        )
+    })
+}
+
+/// The attributes for a queue created on demand, such as for a subscription, where only its
+/// name says whether it is a FIFO queue.
+pub fn get_implicit_queue_attributes(name: &str) -> HashMap<String, String> {
+    let mut attributes = HashMap::new();
+    if name.ends_with(".fifo") {
+        attributes.insert("FifoQueue".to_string(), "true".to_string());
+    }
+    attributes
+}
+
+/// Validate a topic name the same way AWS does, as for queues but with up to 256 characters.
+pub fn validate_topic_name(name: &str, attributes: &HashMap<String, String>) -> MyResult<()> {
+    let is_fifo = attributes
+        .get("FifoTopic")
+        .map(|v| v.eq_ignore_ascii_case("true"))
+        .unwrap_or(false);
+    validate_name(name, "FifoTopic", is_fifo, 256).map_err(|reason| {
+        MyError::InvalidParameterValue(
+            "Name".to_string(),
+            name.to_string(),
+            format!("Topic {}", reason),
+        )
+    })
+}
+
+/// Check the rules queue and topic names share, returning the reason a name is invalid.
+fn validate_name(
+    name: &str,
+    fifo_attribute: &str,
+    is_fifo: bool,
+    max_len: usize,
+) -> Result<(), String> {
+    let base_name = match (is_fifo, name.strip_suffix(".fifo")) {
+        (true, Some(x)) => x,
+        (true, None) => {
+            return Err(format!(
+                "names must end with .fifo when {} is true.",
+                fifo_attribute
+            ))
+        }
+        (false, Some(_)) => {
+            return Err(format!(
+                "names can only end with .fifo when {} is true.",
+                fifo_attribute
+            ))
+        }
+        (false, None) => name,
+    };
+    let has_valid_chars = base_name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if base_name.is_empty() || name.len() > max_len || !has_valid_chars {
+        return Err(format!(
+            "names can only include alphanumeric characters, hyphens, or underscores, and \
+             must be 1 to {} characters long.",
+            max_len
+        ));
+    }
+    Ok(())
+}
+
+/// The values a queue or topic attribute accepts.
+pub enum AttributeRule {
+    /// An integer in the inclusive range.
+    Integer(i64, i64),
+    Boolean,
+    /// A JSON document, such as a policy. Empty values are accepted too, which clear it.
+    Json,
+    OneOf(&'static [&'static str]),
+    Any,
+}
+
+impl AttributeRule {
+    /// Check a value, returning the reason it's invalid.
+    fn validate(&self, value: &str) -> Result<(), String> {
+        match self {
+            AttributeRule::Integer(min, max) => match value.parse::<i64>() {
+                Ok(x) if x >= *min && x <= *max => Ok(()),
+                _ => Err(format!("Must be an integer from {} to {}.", min, max)),
+            },
+            AttributeRule::Boolean => match value.to_ascii_lowercase().as_str() {
+                "true" | "false" => Ok(()),
+                _ => Err("Must be true or false.".to_string()),
+            },
+            AttributeRule::Json if value.is_empty() => Ok(()),
+            AttributeRule::Json => serde_json::from_str::<serde_json::Value>(value)
+                .map(|_| ())
+                .map_err(|e| format!("Must be valid JSON ({}).", e)),
+            AttributeRule::OneOf(values) if values.contains(&value) => Ok(()),
+            AttributeRule::OneOf(values) => Err(format!("Must be one of {}.", values.join(", "))),
+            AttributeRule::Any => Ok(()),
+        }
+    }
+}
+
+/// Validate queue or topic attributes against the attributes that can be set and the values
+/// each accepts. Attributes are checked in name order, so the same error is always reported.
+pub fn validate_attributes(
+    attributes: &HashMap<String, String>,
+    rules: &[(&str, AttributeRule)],
+) -> MyResult<()> {
+    let mut names: Vec<&String> = attributes.keys().collect();
+    names.sort();
+    for name in names {
+        let rule = rules
+            .iter()
+            .find(|(x, _)| x == name)
+            .map(|(_, rule)| rule)
+            .ok_or_else(|| MyError::InvalidAttributeName(name.clone()))?;
+        rule.validate(&attributes[name])
+            .map_err(|reason| MyError::InvalidAttributeValue(name.clone(), reason))?;
+    }
+    Ok(())
+}
+
+/// Get the system attribute names requested with `AttributeName.N`.
+#[cfg(feature = "sqs")]
+pub fn get_attribute_names(form: &HashMap<String, String>) -> Vec<String> {
+    let mut attribute_names = Vec::new();
+    for count in 1..100 {
+        match form.get(&format!("AttributeName.{}", count)) {
+            Some(k) => attribute_names.push(k.clone()),
+            None => break,
+        }
+    }
+    attribute_names
+}
+
+/// Get the `AWSTraceHeader` system attribute from `MessageSystemAttribute.N`, if set.
+#[cfg(feature = "sqs")]
+pub fn get_trace_header_attribute(form: &HashMap<String, String>) -> Option<String> {
+    for count in 1..100 {
+        let name = form.get(&format!("MessageSystemAttribute.{}.Name", count))?;
+        if name == "AWSTraceHeader" {
+            let value_key = format!("MessageSystemAttribute.{}.Value.StringValue", count);
+            return form.get(&value_key).cloned();
+        }
+    }
+    None
+}
+
+/// Convert a W3C `traceparent` header (`00-<trace id>-<parent id>-<flags>`) to the X-Ray
+/// format used by `X-Amzn-Trace-Id` and the `AWSTraceHeader` attribute.
+pub fn traceparent_to_trace_header(traceparent: &str) -> Option<String> {
+    let parts: Vec<&str> = traceparent.trim().split('-').collect();
+    if parts.len() != 4 || parts[1].len() != 32 || parts[2].len() != 16 {
+        return None;
+    }
+    let sampled = u8::from_str_radix(parts[3], 16).ok()? & 1;
+    Some(format!(
+        "Root=1-{}-{};Parent={};Sampled={}",
+        &parts[1][..8],
+        &parts[1][8..],
+        parts[2],
+        sampled
+    ))
+}
+
+#[cfg(feature = "sqs")]
 pub fn get_message_attribute_names(form: &HashMap<String, String>) -> Vec<String> {
     let mut attribute_names = Vec::new();
     for count in 1..100 {
-        if let Some(k) = form.get(&format!("MessageAttribute.{}.Name", count)) {
+        if let Some(k) = form
+            .get(&format!("MessageAttributeName.{}", count))
+            .or_else(|| form.get(&format!("MessageAttribute.{}.Name", count)))
+        {
             attribute_names.push(k.clone());
             continue;
         }
@@ -47,20 +417,208 @@ pub fn get_message_attribute_names(form: &HashMap<String, String>) -> Vec<String
     attribute_names
 }
 
+/// Check whether an Accept-Encoding header accepts gzip, either by name or with `*`. An
+/// encoding with a quality of zero is refused.
+pub fn accepts_gzip(accept_encoding: &str) -> bool {
+    let mut gzip_quality = None;
+    let mut any_quality = None;
+    for entry in accept_encoding.split(',') {
+        let mut parts = entry.split(';');
+        let coding = parts.next().unwrap_or_default().trim();
+        let quality = parts
+            .filter_map(|x| {
+                let mut param = x.splitn(2, '=');
+                match (param.next(), param.next()) {
+                    (Some(name), Some(value)) if name.trim().eq_ignore_ascii_case("q") => {
+                        value.trim().parse::<f32>().ok()
+                    }
+                    _ => None,
+                }
+            })
+            .next()
+            .unwrap_or(1.0);
+        if coding.eq_ignore_ascii_case("gzip") || coding.eq_ignore_ascii_case("x-gzip") {
+            gzip_quality = Some(quality);
+        } else if coding == "*" {
+            any_quality = Some(quality);
+        }
+    }
+    gzip_quality
+        .or(any_quality)
+        .map(|x| x > 0.0)
+        .unwrap_or(false)
+}
+
+pub fn gzip_compress(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+pub fn gzip_decompress(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    GzDecoder::new(data).read_to_end(&mut decoded)?;
+    Ok(decoded)
+}
+
+/// Strip the framing from an `aws-chunked` body, which is a series of
+/// `<hex size>;chunk-signature=<signature>\r\n<data>\r\n` chunks ending with an empty chunk.
+/// Chunk signatures are not verified.
+pub fn decode_aws_chunked(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut decoded = Vec::new();
+    let mut pos = 0;
+    loop {
+        let line_end = data[pos..]
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .ok_or_else(|| "Missing aws-chunked chunk header".to_string())?
+            + pos;
+        let header = String::from_utf8_lossy(&data[pos..line_end]);
+        let size_str = header.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size_str, 16)
+            .map_err(|_| format!("Invalid aws-chunked chunk size: {}", size_str))?;
+        pos = line_end + 2;
+        if size == 0 {
+            return Ok(decoded);
+        }
+
+        let chunk = data
+            .get(pos..pos + size)
+            .ok_or_else(|| "Truncated aws-chunked chunk".to_string())?;
+        decoded.extend_from_slice(chunk);
+        // Skip the trailing CRLF.
+        pos += size + 2;
+    }
+}
+
+/// Find the region in a host name such as `sqs.us-east-1.amazonaws.com`.
+pub fn get_region_from_host(host: &str) -> Option<&str> {
+    let host = host.split(':').next().unwrap_or(host);
+    host.split('.').find(|label| {
+        let parts: Vec<&str> = label.split('-').collect();
+        parts.len() >= 3
+            && parts[0].len() == 2
+            && parts[0].chars().all(|c| c.is_ascii_lowercase())
+            && parts[parts.len() - 1].chars().all(|c| c.is_ascii_digit())
+    })
+}
+
+/// Escapes ', ", &, <, and > with the appropriate XML entities. Text with nothing to escape
+/// is returned as it is, without copying it.
+pub fn escape_xml(input: &str) -> Cow<'_, str> {
+    if !input.bytes().any(|b| get_xml_entity(b).is_some()) {
+        return Cow::Borrowed(input);
+    }
+    let mut result = String::with_capacity(input.len() + input.len() / 8);
+    escape_xml_into(&mut result, input);
+    Cow::Owned(result)
+}
+
+/// Append text to `output`, escaped as by `escape_xml()`. The text between characters that
+/// need escaping is copied in one go, rather than a character at a time.
+pub fn escape_xml_into(output: &mut String, input: &str) {
+    let mut start = 0;
+    // These are all ASCII, so their byte offsets are always on character boundaries.
+    for (i, b) in input.bytes().enumerate() {
+        if let Some(entity) = get_xml_entity(b) {
+            output.push_str(&input[start..i]);
+            output.push_str(entity);
+            start = i + 1;
+        }
+    }
+    output.push_str(&input[start..]);
+}
+
 #[inline]
-/// Escapes ', ", &, <, and > with the appropriate XML entities.
-pub fn escape_xml(input: &str) -> String {
-    let mut result = String::with_capacity(input.len());
+fn get_xml_entity(b: u8) -> Option<&'static str> {
+    match b {
+        b'&' => Some("&amp;"),
+        b'<' => Some("&lt;"),
+        b'>' => Some("&gt;"),
+        b'\'' => Some("&apos;"),
+        b'"' => Some("&quot;"),
+        _ => None,
+    }
+}
+
+type WriteJob = Box<dyn FnOnce() + Send>;
+
+/// Writes files on a thread of its own, in the order the writes are queued, so that requests
+/// don't wait on the disk while holding the state lock. The thread is started by the first write
+/// and stops when the writer is dropped.
+#[derive(Default)]
+pub struct FileWriter {
+    sender: Mutex<Option<Sender<WriteJob>>>,
+}
+
+impl FileWriter {
+    /// Queue a write, to run after every write queued before it.
+    pub fn write(&self, job: impl FnOnce() + Send + 'static) {
+        let mut sender = self.sender.lock().unwrap_or_else(|e| e.into_inner());
+        let sender = sender.get_or_insert_with(|| {
+            let (sender, receiver) = channel::<WriteJob>();
+            std::thread::Builder::new()
+                .name("file-writer".to_string())
+                .spawn(move || receiver.into_iter().for_each(|job| job()))
+                .expect("Failed to start file writer thread");
+            sender
+        });
+        // Sending only fails if a write panicked, which has already been reported.
+        let _ = sender.send(Box::new(job));
+    }
 
-    for c in input.chars() {
-        match c {
-            '&' => result.push_str("&amp;"),
-            '<' => result.push_str("&lt;"),
-            '>' => result.push_str("&gt;"),
-            '\'' => result.push_str("&apos;"),
-            '"' => result.push_str("&quot;"),
-            o => result.push(o),
+    /// Wait for the writes queued so far to finish.
+    pub fn flush(&self) {
+        let (done, finished) = sync_channel(1);
+        self.write(move || {
+            let _ = done.send(());
+        });
+        let _ = finished.recv();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_file_writer_keeps_order() {
+        let writer = FileWriter::default();
+        let written = Arc::new(Mutex::new(Vec::new()));
+        for i in 0..100 {
+            let written = written.clone();
+            writer.write(move || written.lock().unwrap().push(i));
+        }
+        writer.flush();
+        assert_eq!(*written.lock().unwrap(), (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_accepts_gzip() {
+        let accepted = [
+            "gzip",
+            "deflate, gzip",
+            "GZIP;q=0.5",
+            "gzip; q=1.0, identity",
+            "*",
+            "br, *;q=0.1",
+            "x-gzip",
+        ];
+        for x in accepted.iter() {
+            assert!(accepts_gzip(x), "{}", x);
+        }
+        let refused = [
+            "",
+            "identity",
+            "gzip;q=0",
+            "gzip; q=0.000, deflate",
+            "*;q=0",
+            "gzip;q=0, *",
+            "gzipx",
+        ];
+        for x in refused.iter() {
+            assert!(!accepts_gzip(x), "{}", x);
         }
     }
-    result
 }
@@ -0,0 +1,401 @@
+use crate::spill::{add_spilled_messages, SpillStorage};
+use crate::state::{Message, QueuePath, ReceiveHandle, ReceivedMessage, Snapshot, State};
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::oneshot;
+
+const SNAPSHOT_FILE: &str = "snapshot.json";
+const SNAPSHOT_TEMP_FILE: &str = "snapshot.json.tmp";
+const JOURNAL_FILE: &str = "journal.jsonl";
+
+/// The snapshot format version written by `State::export()`. When the format changes, bump this
+/// and add a step to `migrate_snapshot()` to upgrade older snapshots.
+pub const SNAPSHOT_VERSION: u64 = 2;
+
+/// How snapshots and journals are compressed. Files are named with the matching extension,
+/// so they can be read back whatever the current setting is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::None
+    }
+}
+
+impl Compression {
+    const ALL: [Compression; 3] = [Compression::None, Compression::Gzip, Compression::Zstd];
+
+    fn get_path(self, dir: &Path, file_name: &str) -> PathBuf {
+        match self {
+            Compression::None => dir.join(file_name),
+            Compression::Gzip => dir.join(format!("{}.gz", file_name)),
+            Compression::Zstd => dir.join(format!("{}.zst", file_name)),
+        }
+    }
+
+    /// Compress everything written by `write` to `writer`, returning the writer when done.
+    fn encode<W: Write>(
+        self,
+        writer: W,
+        write: impl FnOnce(&mut dyn Write) -> io::Result<()>,
+    ) -> io::Result<W> {
+        match self {
+            Compression::None => {
+                let mut writer = writer;
+                write(&mut writer)?;
+                Ok(writer)
+            }
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(writer, flate2::Compression::default());
+                write(&mut encoder)?;
+                encoder.finish()
+            }
+            Compression::Zstd => {
+                let mut encoder = zstd::stream::write::Encoder::new(writer, 0)?;
+                write(&mut encoder)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    /// Decompress a file. Files made of several compressed frames, such as journals, are read
+    /// to the end.
+    fn decode(self, file: File) -> io::Result<Box<dyn BufRead>> {
+        Ok(match self {
+            Compression::None => Box::new(BufReader::new(file)),
+            Compression::Gzip => Box::new(BufReader::new(MultiGzDecoder::new(file))),
+            Compression::Zstd => Box::new(BufReader::new(zstd::stream::read::Decoder::new(file)?)),
+        })
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!(
+                "Unknown compression '{}'. Expected none, gzip or zstd",
+                s
+            )),
+        }
+    }
+}
+
+/// Open a file, if it exists.
+fn open_existing(path: &Path) -> io::Result<Option<File>> {
+    match File::open(path) {
+        Ok(x) => Ok(Some(x)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn remove_existing(path: &Path) -> io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Where snapshots and the journal are kept, so that state survives restarts.
+pub trait Store: Send + Sync {
+    /// Where the store is, for messages.
+    fn get_location(&self) -> String;
+
+    /// Read the last snapshot saved, if there is one.
+    fn load_snapshot(&self) -> io::Result<Option<Snapshot>>;
+
+    /// Save a snapshot in place of the last one, and clear the journal up to the position the
+    /// snapshot was taken at, since the snapshot includes everything before it.
+    fn save_snapshot(
+        &self,
+        snapshot: &serde_json::Value,
+        journal_position: Option<&str>,
+    ) -> io::Result<()>;
+
+    fn append_journal_entry(&self, entry: &JournalEntry) -> io::Result<()>;
+
+    /// Read the entries journaled since the last snapshot.
+    fn read_journal(&self) -> io::Result<Vec<JournalEntry>>;
+
+    /// Whether other processes may share the store, in which case every change is journaled
+    /// and the journal is always replayed on startup.
+    fn is_shared(&self) -> bool {
+        false
+    }
+
+    /// Follow the entries other processes journal after those already read, if the store
+    /// is shared.
+    fn follow_journal(&self) -> Option<Box<dyn JournalFollower>> {
+        None
+    }
+
+    /// How far the journal has been applied, for a snapshot taken now. Only shared stores
+    /// track this, since their journal is also cleared for other processes.
+    fn get_journal_position(&self) -> Option<String> {
+        None
+    }
+
+    /// Claim a message being received, for the visibility timeout, so that no other process
+    /// sharing the store receives it in the meantime. Fails if another process has it in
+    /// flight or has deleted it. Claiming it again with the same receipt handle changes the
+    /// timeout.
+    fn claim_message(
+        &self,
+        _message_id: &str,
+        _receipt_handle: &ReceiveHandle,
+        _timeout_seconds: u32,
+    ) -> io::Result<bool> {
+        Ok(true)
+    }
+
+    /// Mark a message as deleted, so that no other process receives it again. Fails if
+    /// another process has received it since the receipt handle was given out.
+    fn delete_message(
+        &self,
+        _message_id: &str,
+        _receipt_handle: &ReceiveHandle,
+    ) -> io::Result<bool> {
+        Ok(true)
+    }
+
+    /// Keep the messages spilled from a queue in the store, rather than in a spill file, if
+    /// the store can.
+    fn create_spill(&self, _id: &str) -> Option<Arc<dyn SpillStorage>> {
+        None
+    }
+}
+
+/// Reads the entries that other processes sharing a store journal, as they journal them.
+pub trait JournalFollower: Send {
+    /// Wait a short while for more entries, returning any journaled since the last call.
+    /// The entries from the last call must have been applied by the time this is called.
+    fn next_entries(&mut self) -> io::Result<Vec<JournalEntry>>;
+}
+
+/// Open the store given on the command line, such as sqlite:PATH or redis://HOST.
+pub fn open_store(location: &str) -> Result<Arc<dyn Store>, String> {
+    #[cfg(feature = "sqlite")]
+    {
+        if let Some(path) = location.strip_prefix("sqlite:") {
+            let store = crate::sqlite::SqliteStore::open(path.as_ref())
+                .map_err(|e| format!("Unable to open SQLite database {}: {}", path, e))?;
+            return Ok(Arc::new(store));
+        }
+    }
+    #[cfg(feature = "redis")]
+    {
+        if location.starts_with("redis://") {
+            let store = crate::redis_store::RedisStore::open(location)
+                .map_err(|e| format!("Unable to connect to Redis: {}", e))?;
+            return Ok(Arc::new(store));
+        }
+    }
+    Err(format!(
+        "Unsupported store '{}'. Expected sqlite:PATH or redis://HOST, with the sqlite or redis \
+         feature enabled",
+        location
+    ))
+}
+
+/// Call the store from a request, on a thread that may block, so the runtime doesn't wait.
+pub async fn run_blocking<T, F>(f: F) -> io::Result<T>
+where
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .unwrap_or_else(|e| Err(io::Error::new(ErrorKind::Other, e.to_string())))
+}
+
+/// Write the queues, topics and messages, including in-flight messages, to the store. Each
+/// queue and topic is only locked while it's exported. Spilled messages are read, and the
+/// snapshot written, on the file writer thread, after the journal entries queued before it, so
+/// requests don't wait for the store.
+pub async fn save_snapshot(store: Arc<dyn Store>, state: &State) -> io::Result<()> {
+    let (tx, rx) = oneshot::channel();
+    // Changes made while exporting are journaled after this position, so they're replayed.
+    let position = store.get_journal_position();
+    let (mut snapshot, spilled) = state.export();
+    state.file_writer.write(move || {
+        add_spilled_messages(&mut snapshot, spilled);
+        let result = store.save_snapshot(&snapshot, position.as_deref());
+        if result.is_ok() {
+            info!("Saved snapshot to {}", store.get_location());
+        }
+        let _ = tx.send(result);
+    });
+    rx.await.unwrap_or_else(|_| {
+        Err(io::Error::new(
+            ErrorKind::Other,
+            "The file writer stopped before saving the snapshot",
+        ))
+    })
+}
+
+/// Upgrade a snapshot written by an older version to the current format, then parse it.
+pub fn migrate_snapshot(mut value: serde_json::Value) -> serde_json::Result<Snapshot> {
+    // Snapshots from before versioning was added are version 1.
+    let version = value.get("version").and_then(|x| x.as_u64()).unwrap_or(1);
+    if version > SNAPSHOT_VERSION {
+        return Err(serde::de::Error::custom(format!(
+            "snapshot version {} is newer than the supported version {}",
+            version, SNAPSHOT_VERSION
+        )));
+    }
+
+    if let Some(fields) = value.as_object_mut() {
+        // Version 2 added in-flight messages.
+        if version < 2 {
+            fields.insert("received_messages".to_string(), json!({}));
+        }
+        fields.insert("version".to_string(), json!(SNAPSHOT_VERSION));
+    }
+    serde_json::from_value(value)
+}
+
+/// Keeps the snapshot and journal as files in a data directory.
+pub struct FileStore {
+    dir: PathBuf,
+    compression: Compression,
+}
+
+impl FileStore {
+    pub fn new(dir: PathBuf, compression: Compression) -> Self {
+        Self { dir, compression }
+    }
+
+    /// The journal is emptied whenever a snapshot is saved, since the snapshot includes it.
+    fn clear_journal(&self) -> io::Result<()> {
+        for compression in Compression::ALL.iter() {
+            remove_existing(&compression.get_path(&self.dir, JOURNAL_FILE))?;
+        }
+        Ok(())
+    }
+}
+
+impl Store for FileStore {
+    fn get_location(&self) -> String {
+        self.dir.display().to_string()
+    }
+
+    fn load_snapshot(&self) -> io::Result<Option<Snapshot>> {
+        let mut found = None;
+        for compression in Compression::ALL.iter() {
+            let path = compression.get_path(&self.dir, SNAPSHOT_FILE);
+            if let Some(file) = open_existing(&path)? {
+                found = Some((path, compression.decode(file)?));
+                break;
+            }
+        }
+        let (path, reader) = match found {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let value = serde_json::from_reader(reader)?;
+        let snapshot = migrate_snapshot(value)?;
+        info!("Loaded snapshot from {}", path.display());
+        Ok(Some(snapshot))
+    }
+
+    /// The snapshot is written to a temporary file first and renamed over the previous one, so
+    /// a crash while saving never leaves a partial snapshot behind.
+    fn save_snapshot(&self, snapshot: &serde_json::Value, _: Option<&str>) -> io::Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let path = self.compression.get_path(&self.dir, SNAPSHOT_FILE);
+        let temp_path = self.dir.join(SNAPSHOT_TEMP_FILE);
+        let writer = self
+            .compression
+            .encode(BufWriter::new(File::create(&temp_path)?), |w| {
+                Ok(serde_json::to_writer(w, snapshot)?)
+            })?;
+        writer.into_inner()?.sync_all()?;
+        std::fs::rename(&temp_path, &path)?;
+        // Remove any snapshot saved with a different compression setting, so it isn't loaded
+        // instead.
+        for compression in Compression::ALL.iter() {
+            if *compression != self.compression {
+                remove_existing(&compression.get_path(&self.dir, SNAPSHOT_FILE))?;
+            }
+        }
+        self.clear_journal()
+    }
+
+    /// Each entry is compressed separately, so the journal can be appended to.
+    fn append_journal_entry(&self, entry: &JournalEntry) -> io::Result<()> {
+        let line = serde_json::to_string(entry)?;
+        let f = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.compression.get_path(&self.dir, JOURNAL_FILE))?;
+        self.compression.encode(f, |w| writeln!(w, "{}", line))?;
+        Ok(())
+    }
+
+    fn read_journal(&self) -> io::Result<Vec<JournalEntry>> {
+        let mut entries = Vec::new();
+        for compression in Compression::ALL.iter() {
+            let file = match open_existing(&compression.get_path(&self.dir, JOURNAL_FILE))? {
+                Some(x) => x,
+                None => continue,
+            };
+            for line in compression.decode(file)?.lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                entries.push(serde_json::from_str(&line)?);
+            }
+        }
+        Ok(entries)
+    }
+}
+
+/// A change recorded in the journal, to be replayed on startup or applied by other processes
+/// sharing the store.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum JournalEntry {
+    // A request that created, changed or deleted a resource, such as CreateQueue.
+    Action {
+        action: String,
+        account_id: String,
+        region: String,
+        params: HashMap<String, String>,
+        // Seeds the ids the request generated. Older journals don't have one.
+        #[serde(default)]
+        id_seed: Option<u64>,
+    },
+    MessageSent {
+        queue: QueuePath,
+        message: Message,
+    },
+    MessageDeleted {
+        queue: QueuePath,
+        message_id: String,
+    },
+    // A message was received, or its visibility timeout changed.
+    MessageReceived {
+        receipt_handle: ReceiveHandle,
+        message: ReceivedMessage,
+    },
+}
@@ -0,0 +1,83 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, ErrorKind};
+use std::path::Path;
+use std::time::Duration;
+
+/// Timed requests to play back after startup, read from a YAML (or JSON) file.
+/// Each event is a request with its `Action` and parameters, made `at` seconds after the
+/// scenario starts. Events with a `count` are repeated `every` so many seconds.
+///
+/// ```yaml
+/// events:
+///   - at: 2
+///     action: Publish
+///     params:
+///       TopicArn: arn:aws:sns:us-east-1:000000000000:order-events
+///       Message: hello
+///   - at: 5
+///     every: 0.5
+///     count: 10
+///     action: SendMessage
+///     params:
+///       QueueUrl: orders
+///       MessageBody: tick
+///   - at: 10
+///     action: PurgeQueue
+///     params:
+///       QueueUrl: orders
+/// ```
+#[derive(Debug, Default, Deserialize)]
+pub struct Scenario {
+    #[serde(default)]
+    pub events: Vec<ScenarioEvent>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ScenarioEvent {
+    pub at: f64,
+    #[serde(default)]
+    pub every: f64,
+    #[serde(default = "default_count")]
+    pub count: u32,
+    pub action: String,
+    #[serde(default)]
+    pub params: HashMap<String, String>,
+}
+
+fn default_count() -> u32 {
+    1
+}
+
+pub fn load_scenario(path: &Path) -> std::io::Result<Scenario> {
+    let file = File::open(path)?;
+    let scenario: Scenario = serde_yaml::from_reader(BufReader::new(file))
+        .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+    for event in &scenario.events {
+        if !event.at.is_finite() || event.at < 0.0 || !event.every.is_finite() || event.every < 0.0
+        {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid timing for {} event", event.action),
+            ));
+        }
+    }
+    Ok(scenario)
+}
+
+impl Scenario {
+    /// Every request the scenario makes, with its offset from the start, in order of time.
+    /// Requests due at the same time keep the order of their events in the file.
+    pub fn get_schedule(&self) -> Vec<(Duration, &ScenarioEvent)> {
+        let mut schedule = Vec::new();
+        for event in &self.events {
+            for i in 0..event.count {
+                let offset = event.at + event.every * f64::from(i);
+                schedule.push((Duration::from_secs_f64(offset), event));
+            }
+        }
+        schedule.sort_by_key(|(offset, _)| *offset);
+        schedule
+    }
+}
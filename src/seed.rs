@@ -0,0 +1,180 @@
+use crate::misc::{get_implicit_queue_attributes, validate_queue_name, validate_topic_name, write};
+use crate::state::{SNSSubscription, SNSTopic, SQSQueue, State};
+use log::{info, warn};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind};
+use std::path::{Path, PathBuf};
+
+/// Queues, topics and subscriptions to create at startup, read from a YAML (or JSON) file.
+///
+/// ```yaml
+/// queues:
+///   - name: orders
+///     attributes:
+///       VisibilityTimeout: "60"
+/// topics:
+///   - name: order-events
+///     subscriptions:
+///       - endpoint: orders
+///         attributes:
+///           RawMessageDelivery: "true"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+pub struct Seed {
+    #[serde(default)]
+    pub queues: Vec<SeedQueue>,
+    #[serde(default)]
+    pub topics: Vec<SeedTopic>,
+}
+
+/// The region and account default to those given on the command line.
+#[derive(Debug, Deserialize)]
+pub struct SeedQueue {
+    pub name: String,
+    pub region: Option<String>,
+    pub account_id: Option<String>,
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SeedTopic {
+    pub name: String,
+    pub region: Option<String>,
+    pub account_id: Option<String>,
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
+    #[serde(default)]
+    pub subscriptions: Vec<SeedSubscription>,
+}
+
+/// SQS endpoints may be a queue name in the topic's region and account, or a queue URL or ARN.
+#[derive(Debug, Deserialize)]
+pub struct SeedSubscription {
+    #[serde(default = "default_protocol")]
+    pub protocol: String,
+    pub endpoint: String,
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
+}
+
+fn default_protocol() -> String {
+    "sqs".to_string()
+}
+
+pub fn load_seed(path: &Path) -> std::io::Result<Seed> {
+    let file = File::open(path)?;
+    serde_yaml::from_reader(BufReader::new(file))
+        .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))
+}
+
+/// Create the seeded resources. Queues and topics that already exist, for example from a
+/// snapshot, are left as they are.
+pub fn apply_seed(s: &State, seed: Seed) {
+    for queue in seed.queues {
+        if let Err(e) = validate_queue_name(&queue.name, &queue.attributes) {
+            warn!("Skipping seeded queue {}: {}", queue.name, e);
+            continue;
+        }
+        let ctx = s.get_request_context(None, queue.account_id.as_deref(), queue.region.as_deref());
+        let mut q = SQSQueue::new(&queue.name, queue.attributes);
+        q.set_attribute_default("VisibilityTimeout", "30");
+        if s.add_queue(&ctx, q) {
+            info!("Created queue {}", s.get_queue_url(&ctx, &queue.name));
+        }
+    }
+
+    for topic in seed.topics {
+        if let Err(e) = validate_topic_name(&topic.name, &topic.attributes) {
+            warn!("Skipping seeded topic {}: {}", topic.name, e);
+            continue;
+        }
+        let ctx = s.get_request_context(None, topic.account_id.as_deref(), topic.region.as_deref());
+        let topic_arn = s.get_topic_arn(&ctx, &topic.name);
+        if s.add_topic(SNSTopic::new(&topic.name, &topic_arn, topic.attributes)) {
+            info!("Created topic {}", topic_arn.0);
+        }
+
+        for subscription in topic.subscriptions {
+            let mut endpoint = subscription.endpoint;
+            if subscription.protocol == "sqs" {
+                let path = s.get_queue_path(&ctx, &endpoint);
+                if !s.has_queue(&path) {
+                    let attributes = get_implicit_queue_attributes(path.get_name());
+                    if let Err(e) = validate_queue_name(path.get_name(), &attributes) {
+                        warn!("Skipping subscription to {}: {}", endpoint, e);
+                        continue;
+                    }
+                    let queue_ctx = s.get_request_context(
+                        None,
+                        Some(path.get_account_id()),
+                        Some(path.get_region()),
+                    );
+                    let mut q = SQSQueue::new(path.get_name(), attributes);
+                    q.set_attribute_default("VisibilityTimeout", "30");
+                    s.add_queue(&queue_ctx, q);
+                    info!("Created queue {} for subscription", path.as_str());
+                }
+                endpoint = path.get_arn();
+            }
+
+            let mut sub = SNSSubscription::new(
+                &topic_arn,
+                &subscription.protocol,
+                &endpoint,
+                &ctx.account_id,
+            );
+            sub.attributes = subscription.attributes;
+            if let Some(t) = s.get_topic(&topic_arn) {
+                write(&t).add_subscription(sub);
+            }
+        }
+    }
+}
+
+/// Files in an init directory are applied in order of their names. YAML and JSON files are
+/// seed files, and `.requests` files hold one URL-encoded request body per line.
+pub enum InitFile {
+    Seed(PathBuf),
+    Requests(PathBuf),
+}
+
+/// List the files to apply from an init directory. Files with other extensions are skipped.
+pub fn list_init_files(dir: &Path) -> std::io::Result<Vec<InitFile>> {
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let mut files = Vec::new();
+    for path in paths {
+        match path.extension().and_then(|x| x.to_str()) {
+            Some("yaml") | Some("yml") | Some("json") => files.push(InitFile::Seed(path)),
+            Some("requests") => files.push(InitFile::Requests(path)),
+            _ => warn!("Skipping {}", path.display()),
+        }
+    }
+    Ok(files)
+}
+
+/// Read the requests from a `.requests` file, skipping blank lines and `#` comments.
+pub fn read_init_requests(path: &Path) -> std::io::Result<Vec<HashMap<String, String>>> {
+    let mut requests = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let params = serde_urlencoded::from_str(line)
+            .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+        requests.push(params);
+    }
+    Ok(requests)
+}
@@ -0,0 +1,261 @@
+use crate::errors::{MyError, MyResult};
+use hmac::{Hmac, Mac, NewMac};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+// Sent as the payload hash by clients that don't sign the body.
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+// The payload hash of an aws-chunked body starts with this. Each chunk is signed separately,
+// which isn't verified.
+const STREAMING_PAYLOAD_PREFIX: &str = "STREAMING-";
+
+/// The parts of an incoming request that are covered by the signature.
+pub struct SignedRequest<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+    pub query: &'a str,
+    /// Header names must be lowercase.
+    pub headers: &'a HashMap<String, String>,
+    pub body: &'a [u8],
+}
+
+/// The parsed `Authorization` header.
+#[derive(Debug)]
+pub struct Authorization {
+    pub access_key: String,
+    pub date: String,
+    pub region: String,
+    pub service: String,
+    pub signed_headers: Vec<String>,
+    pub signature: String,
+}
+
+impl Authorization {
+    /// Parse a header of the form
+    /// `AWS4-HMAC-SHA256 Credential=<key>/<date>/<region>/<service>/aws4_request,
+    /// SignedHeaders=<headers>, Signature=<signature>`.
+    pub fn parse(header: &str) -> MyResult<Self> {
+        let rest = match header.trim().strip_prefix(ALGORITHM) {
+            Some(x) => x,
+            None => {
+                return Err(MyError::IncompleteSignature(format!(
+                    "Unsupported authorization algorithm. Expected {}",
+                    ALGORITHM
+                )))
+            }
+        };
+
+        let mut fields = HashMap::new();
+        for part in rest.split(',') {
+            let mut kv = part.trim().splitn(2, '=');
+            if let (Some(k), Some(v)) = (kv.next(), kv.next()) {
+                fields.insert(k, v);
+            }
+        }
+        let get_field = |name: &str| {
+            fields.get(name).copied().ok_or_else(|| {
+                MyError::IncompleteSignature(format!(
+                    "Authorization header requires '{}' parameter.",
+                    name
+                ))
+            })
+        };
+
+        let credential: Vec<&str> = get_field("Credential")?.split('/').collect();
+        if credential.len() != 5 || credential[4] != "aws4_request" {
+            return Err(MyError::IncompleteSignature(
+                "Credential should be scoped to a valid region, service and aws4_request."
+                    .to_string(),
+            ));
+        }
+
+        Ok(Authorization {
+            access_key: credential[0].to_string(),
+            date: credential[1].to_string(),
+            region: credential[2].to_string(),
+            service: credential[3].to_string(),
+            signed_headers: get_field("SignedHeaders")?
+                .split(';')
+                .map(String::from)
+                .collect(),
+            signature: get_field("Signature")?.to_string(),
+        })
+    }
+
+    fn get_scope(&self) -> String {
+        format!(
+            "{}/{}/{}/aws4_request",
+            self.date, self.region, self.service
+        )
+    }
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    // HMAC accepts keys of any length, so this cannot fail.
+    let mut mac = HmacSha256::new_varkey(key).expect("HMAC key");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Query parameters are sorted by name, then by value.
+fn get_canonical_query(query: &str) -> String {
+    let mut params: Vec<(&str, &str)> = query
+        .split('&')
+        .filter(|x| !x.is_empty())
+        .map(|x| {
+            let mut kv = x.splitn(2, '=');
+            (kv.next().unwrap_or_default(), kv.next().unwrap_or_default())
+        })
+        .collect();
+    params.sort_unstable();
+    params
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+fn get_canonical_request(req: &SignedRequest, auth: &Authorization) -> MyResult<String> {
+    let mut canonical_headers = String::new();
+    for name in &auth.signed_headers {
+        let value = req.headers.get(name).ok_or_else(|| {
+            MyError::IncompleteSignature(format!("Signed header '{}' is missing.", name))
+        })?;
+        let value = value.split_whitespace().collect::<Vec<_>>().join(" ");
+        canonical_headers.push_str(&format!("{}:{}\n", name, value));
+    }
+
+    // A payload hash sent by the client is what it signed, so it must match the body.
+    let body_hash = sha256_hex(req.body);
+    let payload_hash = match req.headers.get("x-amz-content-sha256") {
+        Some(x) if x == UNSIGNED_PAYLOAD || x.starts_with(STREAMING_PAYLOAD_PREFIX) => x.clone(),
+        Some(x) if *x != body_hash => return Err(MyError::ContentSha256Mismatch),
+        _ => body_hash,
+    };
+
+    Ok(format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        req.method,
+        if req.path.is_empty() { "/" } else { req.path },
+        get_canonical_query(req.query),
+        canonical_headers,
+        auth.signed_headers.join(";"),
+        payload_hash
+    ))
+}
+
+/// Verify the SigV4 signature of a request against the given access key -> secret key map.
+/// Returns the parsed authorization on success.
+pub fn verify_signature(
+    req: &SignedRequest,
+    credentials: &HashMap<String, String>,
+) -> MyResult<Authorization> {
+    let header = req
+        .headers
+        .get("authorization")
+        .ok_or(MyError::MissingAuthenticationToken)?;
+    let auth = Authorization::parse(header)?;
+    let secret_key = credentials
+        .get(&auth.access_key)
+        .ok_or(MyError::InvalidClientTokenId)?;
+    let amz_date = req.headers.get("x-amz-date").ok_or_else(|| {
+        MyError::IncompleteSignature("Authorization requires the X-Amz-Date header.".to_string())
+    })?;
+
+    let canonical_request = get_canonical_request(req, &auth)?;
+    let string_to_sign = format!(
+        "{}\n{}\n{}\n{}",
+        ALGORITHM,
+        amz_date,
+        auth.get_scope(),
+        sha256_hex(canonical_request.as_bytes())
+    );
+
+    let key = hmac_sha256(format!("AWS4{}", secret_key).as_bytes(), &auth.date);
+    let key = hmac_sha256(&key, &auth.region);
+    let key = hmac_sha256(&key, &auth.service);
+    let key = hmac_sha256(&key, "aws4_request");
+    let signature = hex::encode(hmac_sha256(&key, &string_to_sign));
+
+    if signature != auth.signature {
+        return Err(MyError::SignatureDoesNotMatch);
+    }
+    Ok(auth)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // From the get-vanilla case of the AWS SigV4 test suite.
+    const ACCESS_KEY: &str = "AKIDEXAMPLE";
+    const SECRET_KEY: &str = "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY";
+    const AUTHORIZATION: &str = "AWS4-HMAC-SHA256 \
+        Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+        SignedHeaders=host;x-amz-date, \
+        Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31";
+
+    fn get_headers(authorization: &str) -> HashMap<String, String> {
+        let mut headers = HashMap::new();
+        headers.insert("host".to_string(), "example.amazonaws.com".to_string());
+        headers.insert("x-amz-date".to_string(), "20150830T123600Z".to_string());
+        headers.insert("authorization".to_string(), authorization.to_string());
+        headers
+    }
+
+    fn verify(headers: &HashMap<String, String>, body: &[u8]) -> MyResult<Authorization> {
+        let req = SignedRequest {
+            method: "GET",
+            path: "/",
+            query: "",
+            headers,
+            body,
+        };
+        let mut credentials = HashMap::new();
+        credentials.insert(ACCESS_KEY.to_string(), SECRET_KEY.to_string());
+        verify_signature(&req, &credentials)
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        let auth = verify(&get_headers(AUTHORIZATION), b"").unwrap();
+        assert_eq!(auth.access_key, ACCESS_KEY);
+        assert_eq!(auth.region, "us-east-1");
+
+        let tampered = AUTHORIZATION.replace("5fa00fa3", "5fa00fa4");
+        assert!(matches!(
+            verify(&get_headers(&tampered), b""),
+            Err(MyError::SignatureDoesNotMatch)
+        ));
+        assert!(matches!(
+            verify(&get_headers(AUTHORIZATION), b"Action=ListQueues"),
+            Err(MyError::SignatureDoesNotMatch)
+        ));
+    }
+
+    #[test]
+    fn test_payload_hash_must_match_body() {
+        let mut headers = get_headers(AUTHORIZATION);
+        headers.insert("x-amz-content-sha256".to_string(), sha256_hex(b""));
+        assert!(verify(&headers, b"").is_ok());
+        assert!(matches!(
+            verify(&headers, b"Action=ListQueues"),
+            Err(MyError::ContentSha256Mismatch)
+        ));
+
+        // Streaming payloads are signed chunk by chunk, so aren't compared to the body.
+        let streaming = "STREAMING-AWS4-HMAC-SHA256-PAYLOAD".to_string();
+        headers.insert("x-amz-content-sha256".to_string(), streaming);
+        assert!(!matches!(
+            verify(&headers, b"Action=ListQueues"),
+            Err(MyError::ContentSha256Mismatch)
+        ));
+    }
+}
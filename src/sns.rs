@@ -1,128 +1,249 @@
+use crate::api::{
+    CreateTopicRequest, CreateTopicResponse, FromParams, PublishRequest, PublishResponse,
+    SubscribeRequest, SubscribeResponse,
+};
 use crate::errors::{MyError, MyResult};
-use crate::misc::{escape_xml, get_attributes, get_message_attributes, get_new_id};
-use crate::state::{Message, SNSSubscription, SNSTopic, State, TopicArn};
-use log::debug;
-use std::collections::HashMap;
+use crate::misc::{
+    get_implicit_queue_attributes, get_new_id, get_sns_attributes, lock, read, validate_attributes,
+    validate_queue_name, validate_topic_name, write, AttributeRule,
+};
+use crate::persistence::JournalEntry;
+use crate::state::{
+    get_approximate_message_size, DeliveryAttempt, DeliveryOutcome, FirehoseRecord, Message,
+    MessageAttributeValue, PlatformApplication, PlatformEndpoint, PushMessage, RequestContext,
+    SNSSubscription, SNSTopic, SQSQueue, State, TopicArn,
+};
+use crate::xml::{get_response_xml, ToXml, XmlWriter, SNS_NAMESPACE};
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::{debug, info, warn};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Instant;
+
+/// The attributes that can be set on a topic, and the values they accept. FifoTopic can only
+/// be set when the topic is created.
+const TOPIC_ATTRIBUTES: &[(&str, AttributeRule)] = &[
+    ("ArchivePolicy", AttributeRule::Json),
+    ("ContentBasedDeduplication", AttributeRule::Boolean),
+    ("DeliveryPolicy", AttributeRule::Json),
+    ("DisplayName", AttributeRule::Any),
+    (
+        "FifoThroughputScope",
+        AttributeRule::OneOf(&["Topic", "MessageGroup"]),
+    ),
+    ("KmsMasterKeyId", AttributeRule::Any),
+    ("Policy", AttributeRule::Json),
+    ("SignatureVersion", AttributeRule::OneOf(&["1", "2"])),
+    (
+        "TracingConfig",
+        AttributeRule::OneOf(&["PassThrough", "Active"]),
+    ),
+    ("ApplicationSuccessFeedbackRoleArn", AttributeRule::Any),
+    (
+        "ApplicationSuccessFeedbackSampleRate",
+        AttributeRule::Integer(0, 100),
+    ),
+    ("ApplicationFailureFeedbackRoleArn", AttributeRule::Any),
+    ("FirehoseSuccessFeedbackRoleArn", AttributeRule::Any),
+    (
+        "FirehoseSuccessFeedbackSampleRate",
+        AttributeRule::Integer(0, 100),
+    ),
+    ("FirehoseFailureFeedbackRoleArn", AttributeRule::Any),
+    ("HTTPSuccessFeedbackRoleArn", AttributeRule::Any),
+    (
+        "HTTPSuccessFeedbackSampleRate",
+        AttributeRule::Integer(0, 100),
+    ),
+    ("HTTPFailureFeedbackRoleArn", AttributeRule::Any),
+    ("LambdaSuccessFeedbackRoleArn", AttributeRule::Any),
+    (
+        "LambdaSuccessFeedbackSampleRate",
+        AttributeRule::Integer(0, 100),
+    ),
+    ("LambdaFailureFeedbackRoleArn", AttributeRule::Any),
+    ("SQSSuccessFeedbackRoleArn", AttributeRule::Any),
+    (
+        "SQSSuccessFeedbackSampleRate",
+        AttributeRule::Integer(0, 100),
+    ),
+    ("SQSFailureFeedbackRoleArn", AttributeRule::Any),
+    ("FifoTopic", AttributeRule::Boolean),
+];
+
+// Number of times to retry failed deliveries to remote queues.
+const REMOTE_DELIVERY_RETRIES: u32 = 2;
+
+struct ListTopicsResult<'a> {
+    topic_arns: Vec<&'a String>,
+}
+
+impl<'a> ToXml for ListTopicsResult<'a> {
+    fn write_xml(&self, w: &mut XmlWriter) {
+        w.element("Topics", |w| {
+            for arn in &self.topic_arns {
+                w.element("member", |w| w.text("TopicArn", arn));
+            }
+        });
+    }
+}
+
+/// The result of GetTopicAttributes and GetSubscriptionAttributes.
+struct AttributesResult<'a> {
+    attributes: BTreeMap<&'a String, &'a String>,
+}
+
+impl<'a> ToXml for AttributesResult<'a> {
+    fn write_xml(&self, w: &mut XmlWriter) {
+        w.element("Attributes", |w| w.entries(&self.attributes));
+    }
+}
+
+/// The result of ListSubscriptions and ListSubscriptionsByTopic.
+struct ListSubscriptionsResult<'a> {
+    subscriptions: Vec<&'a SNSSubscription>,
+}
+
+impl<'a> ToXml for ListSubscriptionsResult<'a> {
+    fn write_xml(&self, w: &mut XmlWriter) {
+        w.element("Subscriptions", |w| w.list("member", &self.subscriptions));
+    }
+}
+
+struct CreatePlatformApplicationResult {
+    platform_application_arn: String,
+}
+
+impl ToXml for CreatePlatformApplicationResult {
+    fn write_xml(&self, w: &mut XmlWriter) {
+        w.text("PlatformApplicationArn", &self.platform_application_arn);
+    }
+}
+
+struct CreatePlatformEndpointResult {
+    endpoint_arn: String,
+}
+
+impl ToXml for CreatePlatformEndpointResult {
+    fn write_xml(&self, w: &mut XmlWriter) {
+        w.text("EndpointArn", &self.endpoint_arn);
+    }
+}
+
+struct ListEndpointsResult<'a> {
+    endpoints: &'a [PlatformEndpoint],
+}
+
+impl<'a> ToXml for ListEndpointsResult<'a> {
+    fn write_xml(&self, w: &mut XmlWriter) {
+        w.element("Endpoints", |w| w.list("member", self.endpoints));
+    }
+}
+
+struct CheckIfPhoneNumberIsOptedOutResult {
+    is_opted_out: bool,
+}
+
+impl ToXml for CheckIfPhoneNumberIsOptedOutResult {
+    fn write_xml(&self, w: &mut XmlWriter) {
+        w.text("isOptedOut", &self.is_opted_out.to_string());
+    }
+}
+
+struct ListPhoneNumbersOptedOutResult {
+    phone_numbers: Vec<String>,
+}
+
+impl ToXml for ListPhoneNumbersOptedOutResult {
+    fn write_xml(&self, w: &mut XmlWriter) {
+        w.element("phoneNumbers", |w| {
+            w.text_list("member", &self.phone_numbers)
+        });
+    }
+}
+
+struct GetDataProtectionPolicyResult<'a> {
+    policy: Option<&'a str>,
+}
+
+impl<'a> ToXml for GetDataProtectionPolicyResult<'a> {
+    fn write_xml(&self, w: &mut XmlWriter) {
+        w.optional_text("DataProtectionPolicy", self.policy);
+    }
+}
 
 pub async fn list_topics(
     _form: HashMap<String, String>,
-    state: Arc<Mutex<State>>,
+    ctx: RequestContext,
+    state: Arc<State>,
 ) -> MyResult<String> {
-    let s = state.lock().await;
-    let mut topics_xml = String::new();
-    for topic in s.topics.values() {
-        let topic_xml = format!(
-            "<Topic><TopicArn>{}</TopicArn></Topic>",
-            escape_xml(&topic.arn)
-        );
-        topics_xml.push_str(&topic_xml);
-    }
-
-    let output = format!(
-        "<ListTopicsResponse>\
-            <ListTopicsResult>\
-                <Topics>\
-                {}\
-                </Topics>\
-            </ListTopicsResult>\
-            <ResponseMetadata>\
-                <RequestId>{}</RequestId>\
-            </ResponseMetadata>\
-        </ListTopicsResponse>",
-        topics_xml,
-        get_new_id()
-    );
-    Ok(output)
+    let mut topic_arns: Vec<TopicArn> = state
+        .get_topics()
+        .into_iter()
+        .map(|(arn, _)| arn)
+        .filter(|arn| arn.get_region() == ctx.region && arn.get_account_id() == ctx.account_id)
+        .collect();
+    topic_arns.sort();
+
+    let result = ListTopicsResult {
+        topic_arns: topic_arns.iter().map(|arn| &arn.0).collect(),
+    };
+    Ok(get_response_xml("ListTopics", SNS_NAMESPACE, Some(&result)))
 }
 
 pub async fn create_topic(
     form: HashMap<String, String>,
-    state: Arc<Mutex<State>>,
-) -> MyResult<String> {
-    let topic_name = form
-        .get("Name")
-        .ok_or_else(|| MyError::MissingParameter("Name".to_string()))?;
-    let attributes = get_attributes(&form);
-    let mut s = state.lock().await;
-    let arn = s.get_topic_arn(topic_name);
-    let topic = SNSTopic::new(topic_name, &arn, attributes);
-
-    s.add_topic(topic);
-    let topic_arn = s.get_topic_arn(&topic_name);
-
-    let output = format!(
-        "<CreateTopicResponse>\
-            <CreateTopicResult>\
-                <TopicArn>{}</TopicArn>\
-            </CreateTopicResult>\
-            <ResponseMetadata>\
-                <RequestId>{}</RequestId>\
-            </ResponseMetadata>\
-        </CreateTopicResponse>",
-        escape_xml(&topic_arn.0),
-        get_new_id(),
-    );
-    Ok(output)
-}
-
-pub async fn delete_topic(
-    form: HashMap<String, String>,
-    state: Arc<Mutex<State>>,
+    ctx: RequestContext,
+    state: Arc<State>,
 ) -> MyResult<String> {
+    let request = CreateTopicRequest::from_params(&form)?;
+    validate_topic_name(&request.name, &request.attributes)?;
+    validate_attributes(&request.attributes, TOPIC_ATTRIBUTES)?;
+    let topic_arn = state.get_topic_arn(&ctx, &request.name);
+    let topic = SNSTopic::new(&request.name, &topic_arn, request.attributes);
+
+    state.add_topic(topic);
+
+    let result = CreateTopicResponse {
+        topic_arn: topic_arn.0,
+    };
+    Ok(get_response_xml(
+        "CreateTopic",
+        SNS_NAMESPACE,
+        Some(&result),
+    ))
+}
+
+pub async fn delete_topic(form: HashMap<String, String>, state: Arc<State>) -> MyResult<String> {
     let topic_arn = form
         .get("TopicArn")
         .ok_or_else(|| MyError::MissingParameter("TopicArn".to_string()))?;
-    let mut s = state.lock().await;
-
-    s.remove_topic(&TopicArn(topic_arn.clone()));
+    state.remove_topic(&TopicArn(topic_arn.clone()));
 
-    let output = format!(
-        "<DeleteTopicResponse>\
-            <ResponseMetadata>\
-                <RequestId>{}</RequestId>\
-            </ResponseMetadata>\
-        </DeleteTopicResponse>",
-        get_new_id(),
-    );
-    Ok(output)
+    Ok(get_response_xml("DeleteTopic", SNS_NAMESPACE, None))
 }
 
 pub async fn get_topic_attributes(
     form: HashMap<String, String>,
-    state: Arc<Mutex<State>>,
+    state: Arc<State>,
 ) -> MyResult<String> {
     let topic_arn = form
         .get("TopicArn")
         .ok_or_else(|| MyError::MissingParameter("TopicArn".to_string()))?;
-    let s = state.lock().await;
     let arn = TopicArn(topic_arn.clone());
-    if let Some(t) = s.topics.get(&arn) {
-        let mut attributes_str = String::new();
-        for (k, v) in t.attributes.iter() {
-            attributes_str.push_str(&format!(
-                "<Attribute>\
-                    <Name>{}</Name>\
-                    <Value>{}</Value>\
-                 </Attribute>",
-                escape_xml(k),
-                escape_xml(v)
-            ));
-        }
-        let output = format!(
-            "<GetTopicAttributesResponse>\
-                <GetTopicAttributesResult>\
-                {}\
-                </GetTopicAttributesResult>\
-                <ResponseMetadata>\
-                    <RequestId>{}</RequestId>\
-                </ResponseMetadata>\
-            </GetTopicAttributesResponse>",
-            attributes_str,
-            get_new_id(),
-        );
-        Ok(output)
+    if let Some(t) = state.get_topic(&arn) {
+        let attributes = read(&t).get_all_attributes(arn.get_account_id());
+        let result = AttributesResult {
+            attributes: attributes.iter().collect(),
+        };
+        Ok(get_response_xml(
+            "GetTopicAttributes",
+            SNS_NAMESPACE,
+            Some(&result),
+        ))
     } else {
         Err(MyError::TopicNotFound(topic_arn.clone()))
     }
@@ -130,215 +251,858 @@ pub async fn get_topic_attributes(
 
 pub async fn set_topic_attributes(
     form: HashMap<String, String>,
-    state: Arc<Mutex<State>>,
+    state: Arc<State>,
 ) -> MyResult<String> {
     let topic_arn = form
         .get("TopicArn")
         .ok_or_else(|| MyError::MissingParameter("TopicArn".to_string()))?;
-    let attributes = get_attributes(&form);
-    let mut s = state.lock().await;
+    // The API sets a single attribute, but the indexed form is still accepted.
+    let attributes = match form.get("AttributeName") {
+        Some(name) => {
+            let mut attributes = HashMap::new();
+            let value = form.get("AttributeValue").cloned().unwrap_or_default();
+            attributes.insert(name.clone(), value);
+            attributes
+        }
+        None => get_sns_attributes(&form),
+    };
+    validate_attributes(&attributes, TOPIC_ATTRIBUTES)?;
+    if attributes.contains_key("FifoTopic") {
+        return Err(MyError::InvalidAttributeName("FifoTopic".to_string()));
+    }
+
     let arn = TopicArn(topic_arn.clone());
-    if let Some(q) = s.topics.get_mut(&arn) {
-        q.attributes = attributes;
-        let output = format!(
-            "<SetTopicAttributesResponse>\
-                <ResponseMetadata>\
-                    <RequestId>{}</RequestId>\
-                </ResponseMetadata>\
-            </SetTopicAttributesResponse>",
-            get_new_id(),
-        );
-        Ok(output)
+    if let Some(t) = state.get_topic(&arn) {
+        write(&t).attributes.extend(attributes);
+        Ok(get_response_xml("SetTopicAttributes", SNS_NAMESPACE, None))
     } else {
         Err(MyError::TopicNotFound(topic_arn.clone()))
     }
 }
 
-pub async fn publish(form: HashMap<String, String>, state: Arc<Mutex<State>>) -> MyResult<String> {
-    let target_arn = match form.get("TargetArn") {
-        Some(x) => x,
-        None => form
-            .get("TopicArn")
-            .ok_or_else(|| MyError::MissingParameter("TopicArn".to_string()))?,
+/// The details of a published notification, used to build the JSON envelope that SNS wraps
+/// around messages for subscriptions without raw message delivery.
+struct Notification<'a> {
+    message_id: &'a str,
+    topic_arn: &'a str,
+    subject: Option<&'a String>,
+    timestamp: DateTime<Utc>,
+    attributes: &'a HashMap<String, MessageAttributeValue>,
+    sequence_number: Option<&'a String>,
+}
+
+impl<'a> Notification<'a> {
+    fn get_envelope(&self, message: &str, unsubscribe_url: &str) -> String {
+        let mut envelope = json!({
+            "Type": "Notification",
+            "MessageId": self.message_id,
+            "TopicArn": self.topic_arn,
+        });
+        if let Some(subject) = self.subject {
+            envelope["Subject"] = json!(subject);
+        }
+        if let Some(sequence_number) = self.sequence_number {
+            envelope["SequenceNumber"] = json!(sequence_number);
+        }
+        envelope["Message"] = json!(message);
+        envelope["Timestamp"] = json!(self.timestamp.to_rfc3339_opts(SecondsFormat::Millis, true));
+        envelope["SignatureVersion"] = json!("1");
+        envelope["Signature"] = json!("EXAMPLE");
+        envelope["SigningCertURL"] = json!("EXAMPLE");
+        envelope["UnsubscribeURL"] = json!(unsubscribe_url);
+        if !self.attributes.is_empty() {
+            let mut attributes = serde_json::Map::new();
+            // Sorted, so that the envelope is the same every time.
+            let sorted: BTreeMap<_, _> = self.attributes.iter().collect();
+            for (k, v) in sorted {
+                attributes.insert(
+                    k.clone(),
+                    json!({ "Type": v.data_type, "Value": v.get_value() }),
+                );
+            }
+            envelope["MessageAttributes"] = serde_json::Value::Object(attributes);
+        }
+        envelope.to_string()
+    }
+}
+
+/// With a JSON message structure, the message is an object containing a message per protocol
+/// (or platform), with a fallback to the `default` message.
+fn get_protocol_message(message: &str, protocol: &str, is_json_structure: bool) -> String {
+    if is_json_structure {
+        if let Ok(serde_json::Value::Object(m)) = serde_json::from_str(message) {
+            if let Some(v) = m.get(protocol).or_else(|| m.get("default")) {
+                return match v {
+                    serde_json::Value::String(x) => x.clone(),
+                    x => x.to_string(),
+                };
+            }
+        }
+    }
+    message.to_string()
+}
+
+fn get_publish_response(message_id: &str, sequence_number: Option<&str>) -> String {
+    let result = PublishResponse {
+        message_id: message_id.to_string(),
+        sequence_number: sequence_number.map(|x| x.to_string()),
     };
+    get_response_xml("Publish", SNS_NAMESPACE, Some(&result))
+}
 
-    let mut message_body = form
-        .get("Message")
-        .ok_or_else(|| MyError::MissingParameter("Message".to_string()))?
-        .clone();
-    let message_structure = form
-        .get("MessageStructure")
-        .cloned()
-        .unwrap_or_else(|| "json".to_string());
+pub async fn publish(
+    form: HashMap<String, String>,
+    ctx: RequestContext,
+    state: Arc<State>,
+) -> MyResult<String> {
+    let request = PublishRequest::from_params(&form)?;
+    let target_arn = request.target_arn.as_ref().unwrap_or(&request.topic_arn);
+    let raw_message = &request.message;
+    let is_json_structure = request.message_structure.as_deref() == Some("json");
+    let attributes = Arc::new(request.message_attributes);
 
-    if message_structure == "json" {
-        message_body = format!("{{\"Message\":{}}}", message_body);
+    if target_arn.contains(":endpoint/") {
+        return publish_to_endpoint(target_arn, raw_message, is_json_structure, state).await;
     }
 
-    let attributes = get_message_attributes(&form);
-    let mut s = state.lock().await;
     let arn = TopicArn(target_arn.clone());
-    let queue_urls = match s.topics.get_mut(&arn) {
-        Some(t) => t.get_queue_urls(),
+    let message_id = get_new_id();
+    let now = state.now();
+
+    // Each queue subscription keeps its own copy of the message. Check there is room before
+    // recording the deduplication id, so that a rejected message can be retried.
+    let queue_subscriptions = state
+        .get_topic(&arn)
+        .map(|t| {
+            read(&t)
+                .subscriptions
+                .iter()
+                .filter(|x| x.protocol == "sqs")
+                .count()
+        })
+        .unwrap_or(0);
+    let size = get_approximate_message_size(raw_message, &attributes) * queue_subscriptions;
+    if !state.reserve_memory(size) {
+        return Err(MyError::MemoryBudgetExceeded);
+    }
+
+    let mut recorded_deduplication_id = None;
+    // The topic is only locked while its subscriptions are read, and each queue while it's
+    // delivered to, so that slow fan-outs don't hold up other requests.
+    let (subscriptions, sequence_number, display_name) = match state.get_topic(&arn) {
+        Some(t) => {
+            let mut t = write(&t);
+            let mut sequence_number = None;
+            if t.is_fifo() {
+                if request.message_group_id.is_none() {
+                    return Err(MyError::MissingParameter("MessageGroupId".to_string()));
+                }
+                let deduplication_id = match &request.message_deduplication_id {
+                    Some(x) => x.clone(),
+                    None if t.is_content_based_deduplication() => {
+                        format!("{:x}", Sha256::digest(raw_message.as_bytes()))
+                    }
+                    None => {
+                        return Err(MyError::InvalidParameter(
+                            "MessageDeduplicationId".to_string(),
+                            "The topic should either have ContentBasedDeduplication enabled \
+                             or MessageDeduplicationId provided explicitly."
+                                .to_string(),
+                        ));
+                    }
+                };
+
+                // Duplicates are accepted but not delivered again.
+                if let Some(m) = t.find_duplicate(&deduplication_id, now) {
+                    debug!("Duplicate message not delivered: {}", deduplication_id);
+                    return Ok(get_publish_response(
+                        &m.message_id,
+                        Some(&m.sequence_number),
+                    ));
+                }
+                sequence_number =
+                    Some(t.add_published_message(&deduplication_id, &message_id, now));
+                recorded_deduplication_id = Some(deduplication_id);
+            }
+            (
+                t.subscriptions.clone(),
+                sequence_number,
+                t.get_display_name().cloned(),
+            )
+        }
         None => {
             return Err(MyError::TopicNotFound(target_arn.clone()));
         }
     };
+    if let Some(deduplication_id) = recorded_deduplication_id {
+        state.schedule_deduplication_expiry(&arn, &deduplication_id);
+    }
+
+    state.send_event("Publish", target_arn, &message_id, Some(raw_message));
+    state.trace_message(&message_id, "Published", target_arn, None);
+
+    let notification = Notification {
+        message_id: &message_id,
+        topic_arn: target_arn,
+        // Like SNS, fall back to the topic's display name if there is no subject.
+        subject: request.subject.as_ref().or(display_name.as_ref()),
+        timestamp: state.get_reported_time(now),
+        attributes: &attributes,
+        sequence_number: sequence_number.as_ref(),
+    };
+
+    let mut remote_deliveries = Vec::new();
+    // Raw deliveries for the same protocol share one copy of the message.
+    let mut protocol_messages: HashMap<String, Arc<str>> = HashMap::new();
+    for sub in subscriptions {
+        if lock(&state.chaos).should_drop() {
+            debug!("Dropping notification to {}", sub.endpoint);
+            let detail = Some(format!("Not delivered to {}", sub.endpoint));
+            state.trace_message(&message_id, "Dropped", target_arn, detail);
+            state.add_delivery_attempt(
+                &sub.arn,
+                DeliveryAttempt {
+                    message_id: message_id.clone(),
+                    protocol: sub.protocol.clone(),
+                    endpoint: sub.endpoint.clone(),
+                    outcome: DeliveryOutcome::Dropped,
+                    latency_ms: 0.0,
+                    retry_count: 0,
+                    timestamp: Utc::now(),
+                },
+            );
+            continue;
+        }
 
-    // Send message to all subscribed queues.
-    let message = Message::new(&message_body, attributes);
-    let message_id = message.id.clone();
+        let message = protocol_messages
+            .entry(sub.protocol.clone())
+            .or_insert_with(|| {
+                get_protocol_message(raw_message, &sub.protocol, is_json_structure).into()
+            })
+            .clone();
+        // Raw deliveries carry the message attributes natively, otherwise they are included
+        // in the envelope.
+        let (body, message_attributes): (Arc<str>, _) = if sub.is_raw_message_delivery() {
+            (message, attributes.clone())
+        } else {
+            let unsubscribe_url = state.get_unsubscribe_url(&sub.arn);
+            (
+                notification.get_envelope(&message, &unsubscribe_url).into(),
+                Arc::default(),
+            )
+        };
 
-    for queue_url in queue_urls {
-        let path = s.get_queue_path(&queue_url);
-        if let Some(q) = s.queues.get_mut(&path) {
-            debug!("Message forwarded to queue {}: {}", q.name, message.content);
-            q.send_message(message.clone());
+        // Remote queues are sent to in the background, since the remote may be us.
+        if sub.protocol == "sqs" && state.is_remote_queue_url(&ctx, &sub.endpoint) {
+            remote_deliveries.push(RemoteDelivery {
+                subscription: sub,
+                body,
+                message_attributes,
+            });
+            continue;
         }
+
+        let started = Instant::now();
+        let outcome = match sub.protocol.as_str() {
+            "sqs" => {
+                let path = state.get_queue_path(&arn.get_context(), &sub.endpoint);
+                match state.get_queue(&path) {
+                    Some(q) => {
+                        debug!("Message forwarded to queue {}: {}", path.get_name(), body);
+                        let mut message = Message::new(body.clone(), message_attributes, now);
+                        message.trace_header = ctx.trace_header.clone();
+                        let delivered_id = message.id.clone();
+                        let journal_entry = JournalEntry::MessageSent {
+                            queue: path.clone(),
+                            message: message.clone(),
+                        };
+                        write(&q).send_message(message);
+                        state.journal(journal_entry);
+                        state.send_event("Deliver", path.as_str(), &delivered_id, Some(&body));
+                        let detail = format!("Published to {} as {}", target_arn, message_id);
+                        state.trace_message(&delivered_id, "Sent", path.as_str(), Some(detail));
+                        DeliveryOutcome::Delivered
+                    }
+                    None => DeliveryOutcome::EndpointNotFound,
+                }
+            }
+            "firehose" => {
+                debug!("Message delivered to firehose {}: {}", sub.endpoint, body);
+                state.add_firehose_record(FirehoseRecord {
+                    delivery_stream_arn: sub.endpoint.clone(),
+                    data: body.to_string(),
+                    timestamp: notification.timestamp,
+                });
+                DeliveryOutcome::Delivered
+            }
+            x => {
+                debug!(
+                    "Delivery to {} endpoints is not supported: {}",
+                    x, sub.endpoint
+                );
+                DeliveryOutcome::UnsupportedProtocol
+            }
+        };
+
+        state.add_delivery_attempt(
+            &sub.arn,
+            DeliveryAttempt {
+                message_id: message_id.clone(),
+                protocol: sub.protocol.clone(),
+                endpoint: sub.endpoint.clone(),
+                outcome,
+                latency_ms: started.elapsed().as_secs_f64() * 1000.0,
+                retry_count: 0,
+                timestamp: Utc::now(),
+            },
+        );
     }
 
-    let output = format!(
-        "<PublishResponse>\
-            <PublishResult>\
-                <MessageId>{}</MessageId>\
-            </PublishResult>\
-            <ResponseMetadata>\
-                <RequestId>{}</RequestId>\
-            </ResponseMetadata>\
-        </PublishResponse>",
-        message_id,
-        get_new_id(),
-    );
-    Ok(output)
+    // Remote queues may be slow or unreachable, so don't hold up the response for them.
+    if !remote_deliveries.is_empty() {
+        tokio::spawn(deliver_remote_messages(
+            remote_deliveries,
+            message_id.clone(),
+            ctx.trace_header,
+            state,
+        ));
+    }
+
+    Ok(get_publish_response(
+        &message_id,
+        sequence_number.as_deref(),
+    ))
+}
+
+/// A notification for a queue hosted elsewhere, sent once the Publish response is on its way.
+struct RemoteDelivery {
+    subscription: SNSSubscription,
+    body: Arc<str>,
+    message_attributes: Arc<HashMap<String, MessageAttributeValue>>,
+}
+
+/// Forward a published message to each subscribed remote queue, retrying failures, and
+/// record how each delivery went.
+async fn deliver_remote_messages(
+    deliveries: Vec<RemoteDelivery>,
+    message_id: String,
+    trace_header: Option<String>,
+    state: Arc<State>,
+) {
+    let client = reqwest::Client::new();
+    for RemoteDelivery {
+        subscription: sub,
+        body,
+        message_attributes,
+    } in deliveries
+    {
+        let started = Instant::now();
+        let mut retry_count = 0;
+        let outcome = loop {
+            let result = send_remote_message(
+                &client,
+                &sub.endpoint,
+                &body,
+                &message_attributes,
+                trace_header.as_deref(),
+            )
+            .await;
+            match result {
+                Ok(()) => {
+                    debug!(
+                        "Message forwarded to remote queue {}: {}",
+                        sub.endpoint, body
+                    );
+                    break DeliveryOutcome::Delivered;
+                }
+                Err(e) if retry_count < REMOTE_DELIVERY_RETRIES => {
+                    debug!("Retrying delivery to {}: {}", sub.endpoint, e);
+                    retry_count += 1;
+                }
+                Err(e) => {
+                    warn!("Failed to deliver message to {}: {}", sub.endpoint, e);
+                    break DeliveryOutcome::Failed(e);
+                }
+            }
+        };
+
+        state.add_delivery_attempt(
+            &sub.arn,
+            DeliveryAttempt {
+                message_id: message_id.clone(),
+                protocol: sub.protocol.clone(),
+                endpoint: sub.endpoint.clone(),
+                outcome,
+                latency_ms: started.elapsed().as_secs_f64() * 1000.0,
+                retry_count,
+                timestamp: Utc::now(),
+            },
+        );
+    }
+}
+
+/// Forward a message to a queue hosted elsewhere, e.g. another smoqs instance.
+async fn send_remote_message(
+    client: &reqwest::Client,
+    queue_url: &str,
+    body: &str,
+    attributes: &HashMap<String, MessageAttributeValue>,
+    trace_header: Option<&str>,
+) -> Result<(), String> {
+    let mut params = vec![
+        ("Action".to_string(), "SendMessage".to_string()),
+        ("QueueUrl".to_string(), queue_url.to_string()),
+        ("MessageBody".to_string(), body.to_string()),
+    ];
+    for (i, (k, v)) in attributes.iter().enumerate() {
+        let prefix = format!("MessageAttribute.{}", i + 1);
+        params.push((format!("{}.Name", prefix), k.clone()));
+        params.push((format!("{}.Value.DataType", prefix), v.data_type.clone()));
+        if let Some(x) = &v.string_value {
+            params.push((format!("{}.Value.StringValue", prefix), x.clone()));
+        }
+        if let Some(x) = &v.binary_value {
+            params.push((format!("{}.Value.BinaryValue", prefix), x.clone()));
+        }
+    }
+
+    let mut request = client.post(queue_url);
+    if let Some(x) = trace_header {
+        params.push((
+            "MessageSystemAttribute.1.Name".to_string(),
+            "AWSTraceHeader".to_string(),
+        ));
+        params.push((
+            "MessageSystemAttribute.1.Value.DataType".to_string(),
+            "String".to_string(),
+        ));
+        params.push((
+            "MessageSystemAttribute.1.Value.StringValue".to_string(),
+            x.to_string(),
+        ));
+        request = request.header("X-Amzn-Trace-Id", x);
+    }
+    let response = request
+        .form(&params)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    match response.status().is_success() {
+        true => Ok(()),
+        false => Err(format!("HTTP status {}", response.status())),
+    }
+}
+
+async fn publish_to_endpoint(
+    endpoint_arn: &str,
+    raw_message: &str,
+    is_json_structure: bool,
+    state: Arc<State>,
+) -> MyResult<String> {
+    let (platform, token) = match state.find_platform_endpoint(endpoint_arn) {
+        Some(e) => (e.platform, e.token),
+        None => return Err(MyError::EndpointNotFound(endpoint_arn.to_string())),
+    };
+
+    let message = get_protocol_message(raw_message, &platform, is_json_structure);
+    let message_id = get_new_id();
+    debug!("Message pushed to endpoint {}: {}", endpoint_arn, message);
+    state.add_push_message(PushMessage {
+        message_id: message_id.clone(),
+        endpoint_arn: endpoint_arn.to_string(),
+        platform,
+        token,
+        message,
+        timestamp: Utc::now(),
+    });
+
+    Ok(get_publish_response(&message_id, None))
 }
 
 pub async fn subscribe(
     form: HashMap<String, String>,
-    state: Arc<Mutex<State>>,
+    ctx: RequestContext,
+    state: Arc<State>,
 ) -> MyResult<String> {
-    let topic_arn = form
-        .get("TopicArn")
-        .ok_or_else(|| MyError::MissingParameter("TopicArn".to_string()))?;
-    let endpoint = form
-        .get("Endpoint")
-        .ok_or_else(|| MyError::MissingParameter("TopicArn".to_string()))?;
     // TODO: support other protocols?
-    let _protocol = form
-        .get("Protocol")
-        .ok_or_else(|| MyError::MissingParameter("Protocol".to_string()))?;
+    let SubscribeRequest {
+        topic_arn,
+        protocol,
+        endpoint,
+        attributes,
+        return_subscription_arn,
+    } = SubscribeRequest::from_params(&form)?;
 
-    let mut s = state.lock().await;
-    let account_id = s.account_id.clone();
-    let arn = TopicArn(topic_arn.clone());
-    if let Some(t) = s.topics.get_mut(&arn) {
-        let subscription = SNSSubscription::new_sqs(&arn, endpoint, &account_id);
-        let subscription_arn = subscription.arn.clone();
-        t.add_subscription(subscription);
-
-        let output = format!(
-            "<SubscribeResponse>\
-            <SubscribeResult>\
-                <SubscriptionArn>{}</SubscriptionArn>\
-            </SubscribeResult>\
-            <ResponseMetadata>\
-                <RequestId>{}</RequestId>\
-            </ResponseMetadata>\
-        </SubscribeResponse>",
-            escape_xml(&subscription_arn),
-            get_new_id(),
-        );
-        Ok(output)
-    } else {
-        Err(MyError::TopicNotFound(topic_arn.clone()))
+    let arn = TopicArn(topic_arn);
+    let topic = match state.get_topic(&arn) {
+        Some(x) => x,
+        None => return Err(MyError::TopicNotFound(arn.0)),
+    };
+    if protocol == "sqs" {
+        // Catch typos in the endpoint now, rather than silently dropping messages on publish.
+        let path = state.get_queue_path(&ctx, &endpoint);
+        if !state.has_queue(&path) {
+            if state.auto_create_subscribed_queues {
+                info!("Creating queue {} for subscription", path.as_str());
+                let queue_ctx = RequestContext {
+                    account_id: path.get_account_id().to_string(),
+                    region: path.get_region().to_string(),
+                    endpoint_url: None,
+                    trace_header: None,
+                };
+                let queue_attributes = get_implicit_queue_attributes(path.get_name());
+                validate_queue_name(path.get_name(), &queue_attributes)?;
+                let mut q = SQSQueue::new(path.get_name(), queue_attributes);
+                q.set_attribute_default("VisibilityTimeout", "30");
+                state.add_queue(&queue_ctx, q);
+            } else {
+                return Err(MyError::QueueNotFound(endpoint));
+            }
+        }
+    }
+
+    let mut subscription = SNSSubscription::new(&arn, &protocol, &endpoint, &ctx.account_id);
+    subscription.attributes = attributes;
+    // Endpoints that need confirming don't get an ARN until they're confirmed, unless
+    // the caller explicitly asks for it.
+    let pending_confirmation = subscription.requires_confirmation() && !return_subscription_arn;
+    let mut subscription_arn = write(&topic).add_subscription(subscription);
+    if pending_confirmation {
+        subscription_arn = "pending confirmation".to_string();
     }
+
+    let result = SubscribeResponse { subscription_arn };
+    Ok(get_response_xml("Subscribe", SNS_NAMESPACE, Some(&result)))
 }
 
-pub async fn unsubscribe(
-    form: HashMap<String, String>,
-    state: Arc<Mutex<State>>,
-) -> MyResult<String> {
+pub async fn unsubscribe(form: HashMap<String, String>, state: Arc<State>) -> MyResult<String> {
     let subscription_arn = form
         .get("SubscriptionArn")
         .ok_or_else(|| MyError::MissingParameter("SubscriptionArn".to_string()))?;
 
-    let mut s = state.lock().await;
-    for topic in s.topics.values_mut() {
-        topic.remove_subscription(subscription_arn);
+    for (_, topic) in state.get_topics() {
+        write(&topic).remove_subscription(subscription_arn);
     }
+    lock(&state.delivery_attempts).remove(subscription_arn);
 
-    let output = format!(
-        "<UnsubscribeResponse>\
-            <ResponseMetadata>\
-                <RequestId>{}</RequestId>\
-            </ResponseMetadata>\
-        </UnsubscribeResponse>",
-        get_new_id(),
-    );
-    Ok(output)
+    Ok(get_response_xml("Unsubscribe", SNS_NAMESPACE, None))
 }
 
 pub async fn list_subscriptions(
     _form: HashMap<String, String>,
-    state: Arc<Mutex<State>>,
+    ctx: RequestContext,
+    state: Arc<State>,
 ) -> MyResult<String> {
-    let s = state.lock().await;
-    let mut subscription_xml = String::new();
-    for topic in s.topics.values() {
-        for sub in &topic.subscriptions {
-            subscription_xml.push_str(&sub.get_subscription_xml());
+    // Copied out, since only one topic is locked at a time.
+    let mut subscriptions = Vec::new();
+    let sorted: BTreeMap<_, _> = state.get_topics().into_iter().collect();
+    for (arn, topic) in sorted {
+        if arn.get_region() != ctx.region {
+            continue;
         }
+        subscriptions.extend(
+            read(&topic)
+                .subscriptions
+                .iter()
+                .filter(|x| x.owner == ctx.account_id)
+                .cloned(),
+        );
     }
 
-    let output = format!(
-        "<ListSubscriptionsResponse>\
-            <ListSubscriptionsResult>\
-                <Subscriptions>\
-                    {}\
-                </Subscriptions>\
-            </ListSubscriptionsResult>\
-            <ResponseMetadata>\
-                <RequestId>{}</RequestId>\
-            </ResponseMetadata>\
-        </ListSubscriptionsResponse>",
-        subscription_xml,
-        get_new_id(),
-    );
-    Ok(output)
+    let result = ListSubscriptionsResult {
+        subscriptions: subscriptions.iter().collect(),
+    };
+    Ok(get_response_xml(
+        "ListSubscriptions",
+        SNS_NAMESPACE,
+        Some(&result),
+    ))
 }
 
 pub async fn list_subscriptions_by_topic(
     form: HashMap<String, String>,
-    state: Arc<Mutex<State>>,
+    state: Arc<State>,
 ) -> MyResult<String> {
     let topic_arn = form
         .get("TopicArn")
         .ok_or_else(|| MyError::MissingParameter("TopicArn".to_string()))?;
 
-    let s = state.lock().await;
-
     let arn = TopicArn(topic_arn.clone());
-    if let Some(t) = s.topics.get(&arn) {
-        let mut subscription_xml = String::new();
-        for sub in &t.subscriptions {
-            subscription_xml.push_str(&sub.get_subscription_xml());
-        }
-
-        let output = format!(
-            "<ListSubscriptionsByTopicResponse>\
-                <ListSubscriptionsByTopicResult>\
-                    <Subscriptions>\
-                        {}\
-                    </Subscriptions>\
-                </ListSubscriptionsByTopicResult>\
-                <ResponseMetadata>\
-                    <RequestId>{}</RequestId>\
-                </ResponseMetadata>\
-            </ListSubscriptionsByTopicResponse>",
-            subscription_xml,
-            get_new_id(),
-        );
-        Ok(output)
+    if let Some(t) = state.get_topic(&arn) {
+        let t = read(&t);
+        let result = ListSubscriptionsResult {
+            subscriptions: t.subscriptions.iter().collect(),
+        };
+        Ok(get_response_xml(
+            "ListSubscriptionsByTopic",
+            SNS_NAMESPACE,
+            Some(&result),
+        ))
     } else {
         Err(MyError::TopicNotFound(topic_arn.clone()))
     }
 }
+
+pub async fn create_platform_application(
+    form: HashMap<String, String>,
+    ctx: RequestContext,
+    state: Arc<State>,
+) -> MyResult<String> {
+    let name = form
+        .get("Name")
+        .ok_or_else(|| MyError::MissingParameter("Name".to_string()))?;
+    let platform = form
+        .get("Platform")
+        .ok_or_else(|| MyError::MissingParameter("Platform".to_string()))?;
+    let attributes = get_sns_attributes(&form);
+
+    let arn = state.get_platform_application_arn(&ctx, platform, name);
+    let mut platform_applications = lock(&state.platform_applications);
+    if !platform_applications.contains_key(&arn) {
+        let app = PlatformApplication::new(name, &arn, platform, attributes);
+        platform_applications.insert(arn.clone(), app);
+    }
+
+    let result = CreatePlatformApplicationResult {
+        platform_application_arn: arn,
+    };
+    Ok(get_response_xml(
+        "CreatePlatformApplication",
+        SNS_NAMESPACE,
+        Some(&result),
+    ))
+}
+
+pub async fn create_platform_endpoint(
+    form: HashMap<String, String>,
+    state: Arc<State>,
+) -> MyResult<String> {
+    let app_arn = form
+        .get("PlatformApplicationArn")
+        .ok_or_else(|| MyError::MissingParameter("PlatformApplicationArn".to_string()))?;
+    let token = form
+        .get("Token")
+        .ok_or_else(|| MyError::MissingParameter("Token".to_string()))?;
+    let custom_user_data = form.get("CustomUserData").map(|x| x.as_str());
+    let attributes = get_sns_attributes(&form);
+
+    let endpoint_arn = match lock(&state.platform_applications).get_mut(app_arn) {
+        Some(app) => app.add_endpoint(token, custom_user_data, attributes),
+        None => return Err(MyError::PlatformApplicationNotFound(app_arn.clone())),
+    };
+
+    let result = CreatePlatformEndpointResult { endpoint_arn };
+    Ok(get_response_xml(
+        "CreatePlatformEndpoint",
+        SNS_NAMESPACE,
+        Some(&result),
+    ))
+}
+
+pub async fn list_endpoints_by_platform_application(
+    form: HashMap<String, String>,
+    state: Arc<State>,
+) -> MyResult<String> {
+    let app_arn = form
+        .get("PlatformApplicationArn")
+        .ok_or_else(|| MyError::MissingParameter("PlatformApplicationArn".to_string()))?;
+
+    let platform_applications = lock(&state.platform_applications);
+    if let Some(app) = platform_applications.get(app_arn) {
+        let result = ListEndpointsResult {
+            endpoints: &app.endpoints,
+        };
+        Ok(get_response_xml(
+            "ListEndpointsByPlatformApplication",
+            SNS_NAMESPACE,
+            Some(&result),
+        ))
+    } else {
+        Err(MyError::PlatformApplicationNotFound(app_arn.clone()))
+    }
+}
+
+pub async fn opt_in_phone_number(
+    form: HashMap<String, String>,
+    state: Arc<State>,
+) -> MyResult<String> {
+    let phone_number = form
+        .get("phoneNumber")
+        .ok_or_else(|| MyError::MissingParameter("phoneNumber".to_string()))?;
+
+    lock(&state.opted_out_phone_numbers).remove(phone_number);
+
+    Ok(get_response_xml(
+        "OptInPhoneNumber",
+        SNS_NAMESPACE,
+        Some(&()),
+    ))
+}
+
+pub async fn check_if_phone_number_is_opted_out(
+    form: HashMap<String, String>,
+    state: Arc<State>,
+) -> MyResult<String> {
+    let phone_number = form
+        .get("phoneNumber")
+        .ok_or_else(|| MyError::MissingParameter("phoneNumber".to_string()))?;
+
+    let opted_out = lock(&state.opted_out_phone_numbers).contains(phone_number);
+
+    let result = CheckIfPhoneNumberIsOptedOutResult {
+        is_opted_out: opted_out,
+    };
+    Ok(get_response_xml(
+        "CheckIfPhoneNumberIsOptedOut",
+        SNS_NAMESPACE,
+        Some(&result),
+    ))
+}
+
+pub async fn list_phone_numbers_opted_out(
+    _form: HashMap<String, String>,
+    state: Arc<State>,
+) -> MyResult<String> {
+    let phone_numbers: Vec<String> = lock(&state.opted_out_phone_numbers)
+        .iter()
+        .cloned()
+        .collect();
+
+    let result = ListPhoneNumbersOptedOutResult { phone_numbers };
+    Ok(get_response_xml(
+        "ListPhoneNumbersOptedOut",
+        SNS_NAMESPACE,
+        Some(&result),
+    ))
+}
+
+pub async fn put_data_protection_policy(
+    form: HashMap<String, String>,
+    state: Arc<State>,
+) -> MyResult<String> {
+    let resource_arn = form
+        .get("ResourceArn")
+        .ok_or_else(|| MyError::MissingParameter("ResourceArn".to_string()))?;
+    let policy = form
+        .get("DataProtectionPolicy")
+        .ok_or_else(|| MyError::MissingParameter("DataProtectionPolicy".to_string()))?;
+    if let Err(e) = serde_json::from_str::<serde_json::Value>(policy) {
+        return Err(MyError::InvalidParameterValue(
+            "DataProtectionPolicy".to_string(),
+            policy.clone(),
+            format!("Must be valid JSON ({}).", e),
+        ));
+    }
+
+    let arn = TopicArn(resource_arn.clone());
+    if let Some(t) = state.get_topic(&arn) {
+        write(&t).data_protection_policy = Some(policy.clone());
+        Ok(get_response_xml(
+            "PutDataProtectionPolicy",
+            SNS_NAMESPACE,
+            None,
+        ))
+    } else {
+        Err(MyError::TopicNotFound(resource_arn.clone()))
+    }
+}
+
+pub async fn get_data_protection_policy(
+    form: HashMap<String, String>,
+    state: Arc<State>,
+) -> MyResult<String> {
+    let resource_arn = form
+        .get("ResourceArn")
+        .ok_or_else(|| MyError::MissingParameter("ResourceArn".to_string()))?;
+
+    let arn = TopicArn(resource_arn.clone());
+    if let Some(t) = state.get_topic(&arn) {
+        let t = read(&t);
+        let result = GetDataProtectionPolicyResult {
+            policy: t.data_protection_policy.as_deref(),
+        };
+        Ok(get_response_xml(
+            "GetDataProtectionPolicy",
+            SNS_NAMESPACE,
+            Some(&result),
+        ))
+    } else {
+        Err(MyError::TopicNotFound(resource_arn.clone()))
+    }
+}
+
+pub async fn set_subscription_attributes(
+    form: HashMap<String, String>,
+    state: Arc<State>,
+) -> MyResult<String> {
+    let subscription_arn = form
+        .get("SubscriptionArn")
+        .ok_or_else(|| MyError::MissingParameter("SubscriptionArn".to_string()))?;
+    let attribute_name = form
+        .get("AttributeName")
+        .ok_or_else(|| MyError::MissingParameter("AttributeName".to_string()))?;
+    let attribute_value = form.get("AttributeValue").cloned().unwrap_or_default();
+
+    // Topics are locked one at a time while looking for the subscription.
+    let found = state.get_topics().into_iter().any(|(_, t)| {
+        match write(&t).find_subscription_mut(subscription_arn) {
+            Some(sub) => {
+                sub.attributes
+                    .insert(attribute_name.clone(), attribute_value.clone());
+                true
+            }
+            None => false,
+        }
+    });
+    if found {
+        Ok(get_response_xml(
+            "SetSubscriptionAttributes",
+            SNS_NAMESPACE,
+            None,
+        ))
+    } else {
+        Err(MyError::SubscriptionNotFound(subscription_arn.clone()))
+    }
+}
+
+pub async fn get_subscription_attributes(
+    form: HashMap<String, String>,
+    state: Arc<State>,
+) -> MyResult<String> {
+    let subscription_arn = form
+        .get("SubscriptionArn")
+        .ok_or_else(|| MyError::MissingParameter("SubscriptionArn".to_string()))?;
+
+    let subscription = state
+        .get_topics()
+        .into_iter()
+        .find_map(|(_, t)| read(&t).find_subscription(subscription_arn).cloned());
+    match subscription {
+        Some(sub) => {
+            let mut attributes = sub.attributes.clone();
+            attributes.insert("SubscriptionArn".to_string(), sub.arn.clone());
+            attributes.insert("TopicArn".to_string(), sub.topic_arn.clone());
+            attributes.insert("Owner".to_string(), sub.owner.clone());
+            attributes.insert("Protocol".to_string(), sub.protocol.clone());
+            attributes.insert("Endpoint".to_string(), sub.endpoint.clone());
+            attributes.insert(
+                "ConfirmationWasAuthenticated".to_string(),
+                "true".to_string(),
+            );
+            attributes.insert("PendingConfirmation".to_string(), "false".to_string());
+            if let Entry::Vacant(v) = attributes.entry("RawMessageDelivery".to_string()) {
+                v.insert("false".to_string());
+            }
+
+            let result = AttributesResult {
+                attributes: attributes.iter().collect(),
+            };
+            Ok(get_response_xml(
+                "GetSubscriptionAttributes",
+                SNS_NAMESPACE,
+                Some(&result),
+            ))
+        }
+        None => Err(MyError::SubscriptionNotFound(subscription_arn.clone())),
+    }
+}
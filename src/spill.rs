@@ -0,0 +1,325 @@
+use crate::state::{Message, QueuePath};
+use log::warn;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+/// Where the messages spilled from a queue are kept, in order.
+pub trait SpillStorage: Send + Sync {
+    /// Add messages after those already kept.
+    fn append(&self, messages: &[Message]) -> std::io::Result<()>;
+
+    /// Read up to `count` messages starting at `position`, returning them with the position of
+    /// the next message. Messages that have been read stay until the storage is dropped, so
+    /// that exports can read them from an earlier position.
+    fn read(&self, position: u64, count: usize) -> std::io::Result<(Vec<Message>, u64)>;
+}
+
+/// A file holding messages from the back of a queue, one per line, where positions are byte
+/// offsets. It is created by the first write and removed once nothing refers to it.
+pub struct SpillFile {
+    path: PathBuf,
+}
+
+impl SpillFile {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+}
+
+impl SpillStorage for SpillFile {
+    fn append(&self, messages: &[Message]) -> std::io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut f = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let mut buf = Vec::new();
+        for message in messages {
+            serde_json::to_writer(&mut buf, message)?;
+            buf.push(b'\n');
+        }
+        f.write_all(&buf)
+    }
+
+    fn read(&self, offset: u64, count: usize) -> std::io::Result<(Vec<Message>, u64)> {
+        let mut f = File::open(&self.path)?;
+        f.seek(SeekFrom::Start(offset))?;
+        let mut reader = BufReader::new(f);
+        let mut messages = Vec::new();
+        let mut offset = offset;
+        let mut line = String::new();
+        while messages.len() < count {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+            if read == 0 {
+                break;
+            }
+            messages.push(serde_json::from_str(&line)?);
+            offset += read as u64;
+        }
+        Ok((messages, offset))
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Reading and writing spilled messages, done without holding the state lock.
+pub enum SpillJob {
+    Write {
+        storage: Arc<dyn SpillStorage>,
+        messages: Arc<Vec<Message>>,
+    },
+    Read {
+        storage: Arc<dyn SpillStorage>,
+        position: u64,
+        count: usize,
+    },
+}
+
+/// A spill job for a queue, with what it did.
+pub type SpillJobResult = (QueuePath, SpillJob, std::io::Result<SpillOutcome>);
+
+/// What a spill job did, for `Spill::finish()`.
+pub enum SpillOutcome {
+    Written,
+    Read(Vec<Message>, u64),
+}
+
+impl SpillJob {
+    pub fn get_storage(&self) -> &Arc<dyn SpillStorage> {
+        match self {
+            SpillJob::Write { storage, .. } | SpillJob::Read { storage, .. } => storage,
+        }
+    }
+
+    pub fn run(&self) -> std::io::Result<SpillOutcome> {
+        match self {
+            SpillJob::Write { storage, messages } => {
+                storage.append(messages)?;
+                Ok(SpillOutcome::Written)
+            }
+            SpillJob::Read {
+                storage,
+                position,
+                count,
+            } => {
+                let (messages, position) = storage.read(*position, *count)?;
+                Ok(SpillOutcome::Read(messages, position))
+            }
+        }
+    }
+}
+
+/// Messages at the back of a queue, kept out of memory to bound the in-memory backlog.
+/// While a queue is spilling, new messages are added here so that order is preserved. They are
+/// written to storage, and read back in batches as the in-memory backlog drains, by jobs that
+/// run outside the state lock, one at a time per queue.
+pub struct Spill {
+    storage: Arc<dyn SpillStorage>,
+    // The position of the next message to read back.
+    read_position: u64,
+    // The number of messages in storage that haven't been read back yet.
+    len: usize,
+    // Messages being written to storage, after those already there.
+    writing: Arc<Vec<Message>>,
+    // Messages to write once those have been written.
+    unwritten: Vec<Message>,
+    // Whether the next batch is being read back.
+    reading: bool,
+    batch_size: usize,
+    // Wakes the task that runs spill jobs, when a queue needs messages read back.
+    wake: Arc<Notify>,
+}
+
+impl Spill {
+    pub fn new(
+        storage: Arc<dyn SpillStorage>,
+        messages: Vec<Message>,
+        batch_size: usize,
+        wake: Arc<Notify>,
+    ) -> Self {
+        Self {
+            storage,
+            read_position: 0,
+            len: 0,
+            writing: Arc::default(),
+            unwritten: messages,
+            reading: false,
+            batch_size: batch_size.max(1),
+            wake,
+        }
+    }
+
+    pub fn get_batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    /// The number of spilled messages, whether or not they have been written yet.
+    pub fn len(&self) -> usize {
+        self.len + self.writing.len() + self.unwritten.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn push(&mut self, message: Message) {
+        self.unwritten.push(message);
+    }
+
+    /// Whether storage has nothing left to read back and nothing is being written to it, so
+    /// the messages not yet written can go straight back to the queue.
+    pub fn is_drained(&self) -> bool {
+        self.len == 0 && self.writing.is_empty() && !self.reading
+    }
+
+    /// Take the messages not yet written, once the spill is drained.
+    pub fn take_unwritten(&mut self) -> Vec<Message> {
+        std::mem::take(&mut self.unwritten)
+    }
+
+    /// Ask for the next batch to be read back without waiting for the next spill check.
+    pub fn request_refill(&self) {
+        if self.len > 0 && !self.reading {
+            self.wake.notify();
+        }
+    }
+
+    /// Get the next job for this spill, if there is one and none is running. The next batch is
+    /// read back once the in-memory backlog is below the batch size.
+    pub fn get_job(&mut self, backlog: usize) -> Option<SpillJob> {
+        if self.reading || !self.writing.is_empty() {
+            return None;
+        }
+        if self.len > 0 && backlog < self.batch_size {
+            self.reading = true;
+            return Some(SpillJob::Read {
+                storage: self.storage.clone(),
+                position: self.read_position,
+                count: self.batch_size,
+            });
+        }
+        if !self.unwritten.is_empty() {
+            self.writing = Arc::new(self.take_unwritten());
+            return Some(SpillJob::Write {
+                storage: self.storage.clone(),
+                messages: self.writing.clone(),
+            });
+        }
+        None
+    }
+
+    /// Whether a job was for this spill, rather than one since replaced.
+    pub fn owns(&self, job: &SpillJob) -> bool {
+        Arc::ptr_eq(&self.storage, job.get_storage())
+    }
+
+    /// Record the result of a job, returning any messages read back. Messages that couldn't
+    /// be written are kept to try again. If storage can't be read, the messages in it are
+    /// lost, so the spill stops relying on it.
+    pub fn finish(&mut self, result: std::io::Result<SpillOutcome>) -> Vec<Message> {
+        match result {
+            Ok(SpillOutcome::Written) => {
+                self.len += self.writing.len();
+                self.writing = Arc::default();
+                Vec::new()
+            }
+            Ok(SpillOutcome::Read(messages, position)) => {
+                self.reading = false;
+                self.read_position = position;
+                match messages.is_empty() {
+                    true => self.abandon_storage("some are missing"),
+                    false => self.len = self.len.saturating_sub(messages.len()),
+                }
+                messages
+            }
+            Err(e) if self.reading => {
+                self.reading = false;
+                self.abandon_storage(&e.to_string());
+                Vec::new()
+            }
+            Err(e) => {
+                warn!("Unable to spill messages: {}", e);
+                let mut messages = self.writing.to_vec();
+                messages.append(&mut self.unwritten);
+                self.unwritten = messages;
+                self.writing = Arc::default();
+                Vec::new()
+            }
+        }
+    }
+
+    fn abandon_storage(&mut self, reason: &str) {
+        warn!(
+            "Unable to read spilled messages back ({}). {} messages were lost.",
+            reason, self.len
+        );
+        self.len = 0;
+    }
+
+    /// Take note of the spilled messages, to be read once the state lock is released.
+    pub fn get_spilled_messages(&self) -> SpilledMessages {
+        let mut pending = self.writing.to_vec();
+        pending.extend(self.unwritten.iter().cloned());
+        SpilledMessages {
+            storage: self.storage.clone(),
+            position: self.read_position,
+            len: self.len,
+            pending,
+        }
+    }
+}
+
+/// The messages in a spill when the state was exported, for adding to the export.
+pub struct SpilledMessages {
+    storage: Arc<dyn SpillStorage>,
+    position: u64,
+    len: usize,
+    // Messages that weren't in storage yet.
+    pending: Vec<Message>,
+}
+
+impl SpilledMessages {
+    /// Read the messages, oldest first. This reads storage, so is done without the state lock.
+    pub fn read(self) -> std::io::Result<Vec<Message>> {
+        let mut messages = match self.len {
+            0 => Vec::new(),
+            len => self.storage.read(self.position, len)?.0,
+        };
+        messages.extend(self.pending);
+        Ok(messages)
+    }
+}
+
+/// Add the messages spilled from each queue to an export of the state, after the others in
+/// their queue.
+pub fn add_spilled_messages(
+    export: &mut serde_json::Value,
+    spilled: Vec<(QueuePath, SpilledMessages)>,
+) {
+    for (path, spill) in spilled {
+        let messages = match spill.read() {
+            Ok(x) => x,
+            Err(e) => {
+                warn!(
+                    "Unable to export spilled messages from {}: {}",
+                    path.as_str(),
+                    e
+                );
+                continue;
+            }
+        };
+        if let Some(exported) = export["queues"][path.as_str()]["messages"].as_array_mut() {
+            exported.extend(messages.iter().map(|m| serde_json::json!(m)));
+        }
+    }
+}
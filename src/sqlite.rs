@@ -0,0 +1,233 @@
+use crate::persistence::{migrate_snapshot, JournalEntry, Store};
+use crate::spill::SpillStorage;
+use crate::state::{Message, Snapshot};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+const SCHEMA: &str = "
+    PRAGMA journal_mode = WAL;
+    CREATE TABLE IF NOT EXISTS snapshot (
+        id INTEGER PRIMARY KEY CHECK (id = 1),
+        data TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS journal (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        entry TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS spilled_messages (
+        spill TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        message TEXT NOT NULL,
+        PRIMARY KEY (spill, position)
+    );
+";
+
+fn to_io_error(e: rusqlite::Error) -> io::Error {
+    io::Error::new(ErrorKind::Other, e.to_string())
+}
+
+type SharedConnection = Arc<Mutex<Connection>>;
+
+fn lock(connection: &SharedConnection) -> MutexGuard<'_, Connection> {
+    // A panic while holding the lock leaves nothing half done, since changes are made in
+    // transactions.
+    connection.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Keeps the snapshot, the journal and spilled messages in a SQLite database, rather than in
+/// files. Snapshots and journal entries are stored in the same JSON format as the files.
+pub struct SqliteStore {
+    path: PathBuf,
+    connection: SharedConnection,
+}
+
+impl SqliteStore {
+    /// Open the database, creating it if need be.
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(SCHEMA)?;
+        // Spilled messages are included in snapshots, so any left by a crash are out of date.
+        connection.execute("DELETE FROM spilled_messages", params![])?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+}
+
+impl Store for SqliteStore {
+    fn get_location(&self) -> String {
+        format!("sqlite:{}", self.path.display())
+    }
+
+    fn load_snapshot(&self) -> io::Result<Option<Snapshot>> {
+        let data: Option<String> = lock(&self.connection)
+            .query_row("SELECT data FROM snapshot WHERE id = 1", params![], |row| {
+                row.get(0)
+            })
+            .optional()
+            .map_err(to_io_error)?;
+        match data {
+            Some(x) => Ok(Some(migrate_snapshot(serde_json::from_str(&x)?)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn save_snapshot(&self, snapshot: &serde_json::Value, _: Option<&str>) -> io::Result<()> {
+        let data = serde_json::to_string(snapshot)?;
+        let mut connection = lock(&self.connection);
+        let tx = connection.transaction().map_err(to_io_error)?;
+        tx.execute(
+            "INSERT OR REPLACE INTO snapshot (id, data) VALUES (1, ?1)",
+            params![data],
+        )
+        .map_err(to_io_error)?;
+        tx.execute("DELETE FROM journal", params![])
+            .map_err(to_io_error)?;
+        tx.commit().map_err(to_io_error)
+    }
+
+    fn append_journal_entry(&self, entry: &JournalEntry) -> io::Result<()> {
+        let entry = serde_json::to_string(entry)?;
+        lock(&self.connection)
+            .execute("INSERT INTO journal (entry) VALUES (?1)", params![entry])
+            .map_err(to_io_error)?;
+        Ok(())
+    }
+
+    fn read_journal(&self) -> io::Result<Vec<JournalEntry>> {
+        let connection = lock(&self.connection);
+        let mut statement = connection
+            .prepare("SELECT entry FROM journal ORDER BY id")
+            .map_err(to_io_error)?;
+        let rows: Vec<String> = statement
+            .query_map(params![], |row| row.get(0))
+            .and_then(|rows| rows.collect())
+            .map_err(to_io_error)?;
+        let mut entries = Vec::with_capacity(rows.len());
+        for row in rows {
+            entries.push(serde_json::from_str(&row)?);
+        }
+        Ok(entries)
+    }
+
+    fn create_spill(&self, id: &str) -> Option<Arc<dyn SpillStorage>> {
+        Some(Arc::new(SqliteSpill {
+            connection: self.connection.clone(),
+            spill: id.to_string(),
+        }))
+    }
+}
+
+/// Messages spilled from a queue, as rows in the database numbered by position. Rows are kept
+/// once read back, for exports taken before then, and deleted once nothing refers to the spill.
+struct SqliteSpill {
+    connection: SharedConnection,
+    spill: String,
+}
+
+impl SpillStorage for SqliteSpill {
+    fn append(&self, messages: &[Message]) -> io::Result<()> {
+        let mut connection = lock(&self.connection);
+        let tx = connection.transaction().map_err(to_io_error)?;
+        let first: i64 = tx
+            .query_row(
+                "SELECT COALESCE(MAX(position) + 1, 0) FROM spilled_messages WHERE spill = ?1",
+                params![self.spill],
+                |row| row.get(0),
+            )
+            .map_err(to_io_error)?;
+        for (message, position) in messages.iter().zip(first..) {
+            let message = serde_json::to_string(message)?;
+            tx.execute(
+                "INSERT INTO spilled_messages (spill, position, message) VALUES (?1, ?2, ?3)",
+                params![self.spill, position, message],
+            )
+            .map_err(to_io_error)?;
+        }
+        tx.commit().map_err(to_io_error)
+    }
+
+    fn read(&self, position: u64, count: usize) -> io::Result<(Vec<Message>, u64)> {
+        let connection = lock(&self.connection);
+        let mut statement = connection
+            .prepare(
+                "SELECT position, message FROM spilled_messages
+                 WHERE spill = ?1 AND position >= ?2 ORDER BY position LIMIT ?3",
+            )
+            .map_err(to_io_error)?;
+        let rows: Vec<(i64, String)> = statement
+            .query_map(params![self.spill, position as i64, count as i64], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .and_then(|rows| rows.collect())
+            .map_err(to_io_error)?;
+        let mut messages = Vec::with_capacity(rows.len());
+        let mut next = position;
+        for (position, message) in rows {
+            messages.push(serde_json::from_str(&message)?);
+            next = position as u64 + 1;
+        }
+        Ok((messages, next))
+    }
+}
+
+impl Drop for SqliteSpill {
+    fn drop(&mut self) {
+        let _ = lock(&self.connection).execute(
+            "DELETE FROM spilled_messages WHERE spill = ?1",
+            params![self.spill],
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::misc::get_new_id;
+    use crate::state::State;
+    use chrono::Utc;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_sqlite_store() {
+        let path = std::env::temp_dir().join(format!("smoqs-test-{}.db", get_new_id()));
+        let store = SqliteStore::open(&path).unwrap();
+        assert!(store.load_snapshot().unwrap().is_none());
+
+        let entry = || JournalEntry::Action {
+            action: "CreateQueue".to_string(),
+            account_id: "000000000000".to_string(),
+            region: "us-east-1".to_string(),
+            params: HashMap::new(),
+            id_seed: None,
+        };
+        store.append_journal_entry(&entry()).unwrap();
+        store.append_journal_entry(&entry()).unwrap();
+        assert_eq!(store.read_journal().unwrap().len(), 2);
+
+        // Saving a snapshot clears the journal.
+        let state = State::new(3566, "us-east-1", "000000000000");
+        store.save_snapshot(&state.export().0, None).unwrap();
+        assert!(store.load_snapshot().unwrap().is_some());
+        assert!(store.read_journal().unwrap().is_empty());
+
+        let spill = store.create_spill("orders").unwrap();
+        let messages: Vec<Message> = (0..5)
+            .map(|i| Message::new(format!("message {}", i).into(), Arc::default(), Utc::now()))
+            .collect();
+        spill.append(&messages[..3]).unwrap();
+        spill.append(&messages[3..]).unwrap();
+        let (first, next) = spill.read(0, 2).unwrap();
+        let (rest, _) = spill.read(next, 10).unwrap();
+        let read: Vec<&str> = first.iter().chain(&rest).map(|m| &*m.content).collect();
+        let expected: Vec<&str> = messages.iter().map(|m| &*m.content).collect();
+        assert_eq!(read, expected);
+
+        drop(spill);
+        drop(store);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
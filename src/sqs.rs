@@ -1,132 +1,171 @@
+use crate::api::{
+    ChangeMessageVisibilityRequest, CreateQueueRequest, CreateQueueResponse, DeleteMessageRequest,
+    DeleteQueueRequest, FromParams, PurgeQueueRequest, ReceiveMessageRequest, SendMessageRequest,
+    SendMessageResponse,
+};
 use crate::errors::{MyError, MyResult};
 use crate::misc::{
-    escape_xml, get_attributes, get_message_attribute_names, get_message_attributes, get_new_id,
+    get_attributes, lock, read, validate_attributes, validate_queue_name, write, AttributeRule,
+};
+use crate::persistence::{run_blocking, JournalEntry};
+use crate::state::{
+    get_approximate_message_size, Message, ReceiveHandle, RequestContext, SQSQueue, State, Waiter,
+};
+use crate::xml::{
+    get_response_xml, get_streamed_response_xml, ResponseBody, ToXml, XmlWriter, SQS_NAMESPACE,
 };
-use crate::state::{Message, ReceiveHandle, SQSQueue, State};
-use crate::xml::FormatXML;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
-use tokio::sync::oneshot::Receiver;
-use tokio::sync::Mutex;
-use tokio::time::Duration;
+use tokio::time::{Duration, Instant};
+
+/// The attributes that can be set on a queue, and the values they accept.
+const QUEUE_ATTRIBUTES: &[(&str, AttributeRule)] = &[
+    ("DelaySeconds", AttributeRule::Integer(0, 900)),
+    (
+        "MaximumMessageSize",
+        AttributeRule::Integer(1024, 1_048_576),
+    ),
+    (
+        "MessageRetentionPeriod",
+        AttributeRule::Integer(60, 1_209_600),
+    ),
+    ("Policy", AttributeRule::Json),
+    (
+        "ReceiveMessageWaitTimeSeconds",
+        AttributeRule::Integer(0, 20),
+    ),
+    ("VisibilityTimeout", AttributeRule::Integer(0, 43_200)),
+    ("RedrivePolicy", AttributeRule::Json),
+    ("RedriveAllowPolicy", AttributeRule::Json),
+    ("KmsMasterKeyId", AttributeRule::Any),
+    (
+        "KmsDataKeyReusePeriodSeconds",
+        AttributeRule::Integer(60, 86_400),
+    ),
+    ("SqsManagedSseEnabled", AttributeRule::Boolean),
+    ("FifoQueue", AttributeRule::Boolean),
+    ("ContentBasedDeduplication", AttributeRule::Boolean),
+    (
+        "DeduplicationScope",
+        AttributeRule::OneOf(&["messageGroup", "queue"]),
+    ),
+    (
+        "FifoThroughputLimit",
+        AttributeRule::OneOf(&["perQueue", "perMessageGroupId"]),
+    ),
+];
+
+struct ListQueuesResult {
+    queue_urls: Vec<String>,
+}
+
+impl ToXml for ListQueuesResult {
+    fn write_xml(&self, w: &mut XmlWriter) {
+        w.text_list("QueueUrl", &self.queue_urls);
+    }
+}
+
+struct GetQueueAttributesResult<'a> {
+    attributes: BTreeMap<&'a String, &'a String>,
+}
+
+impl<'a> ToXml for GetQueueAttributesResult<'a> {
+    fn write_xml(&self, w: &mut XmlWriter) {
+        for (k, v) in &self.attributes {
+            w.element("Attribute", |w| {
+                w.text("Name", k);
+                w.text("Value", v);
+            });
+        }
+    }
+}
 
 pub async fn list_queues(
     _form: HashMap<String, String>,
-    state: Arc<Mutex<State>>,
+    ctx: RequestContext,
+    state: Arc<State>,
 ) -> MyResult<String> {
-    let queue_urls: Vec<String> = {
-        let s = state.lock().await;
-        s.queues
-            .values()
-            .map(|q| s.get_queue_url(&q.name))
-            .collect()
-    };
+    let mut queue_urls: Vec<String> = state
+        .get_queues()
+        .into_iter()
+        .filter(|(path, _)| {
+            path.get_region() == ctx.region && path.get_account_id() == ctx.account_id
+        })
+        .map(|(path, _)| state.get_queue_url(&ctx, path.get_name()))
+        .collect();
+    queue_urls.sort();
 
-    let output = format!(
-        "<ListQueuesResponse>\
-            <ListQueuesResult>\
-                {}\
-            </ListQueuesResult>\
-            <ResponseMetadata>\
-                <RequestId>{}</RequestId>\
-            </ResponseMetadata>\
-        </ListQueuesResponse>",
-        queue_urls.to_xml_string("QueueUrl"),
-        get_new_id()
-    );
-    Ok(output)
+    let result = ListQueuesResult { queue_urls };
+    Ok(get_response_xml("ListQueues", SQS_NAMESPACE, Some(&result)))
 }
 
 pub async fn create_queue(
     form: HashMap<String, String>,
-    state: Arc<Mutex<State>>,
+    ctx: RequestContext,
+    state: Arc<State>,
 ) -> MyResult<String> {
-    let queue_name = form
-        .get("QueueName")
-        .ok_or_else(|| MyError::MissingParameter("QueueName".to_string()))?;
-    let attributes = get_attributes(&form);
-    let mut q = SQSQueue::new(queue_name, attributes);
+    let request = CreateQueueRequest::from_params(&form)?;
+    let queue_name = &request.queue_name;
+    validate_queue_name(queue_name, &request.attributes)?;
+    validate_attributes(&request.attributes, QUEUE_ATTRIBUTES)?;
+    let mut q = SQSQueue::new(queue_name, request.attributes);
     q.set_attribute_default("VisibilityTimeout", "30");
 
-    let queue_url = {
-        let mut s = state.lock().await;
-        s.add_queue(q);
-        s.get_queue_url(&queue_name)
-    };
+    state.add_queue(&ctx, q);
+    let queue_url = state.get_queue_url(&ctx, queue_name);
 
-    let output = format!(
-        "<CreateQueueResponse>\
-            <CreateQueueResult>\
-                <QueueUrl>{}</QueueUrl>\
-            </CreateQueueResult>\
-            <ResponseMetadata>\
-                <RequestId>{}</RequestId>\
-            </ResponseMetadata>\
-        </CreateQueueResponse>",
-        escape_xml(&queue_url),
-        get_new_id(),
-    );
-    Ok(output)
+    let result = CreateQueueResponse { queue_url };
+    Ok(get_response_xml(
+        "CreateQueue",
+        SQS_NAMESPACE,
+        Some(&result),
+    ))
 }
 
 pub async fn delete_queue(
     form: HashMap<String, String>,
-    state: Arc<Mutex<State>>,
+    ctx: RequestContext,
+    state: Arc<State>,
 ) -> MyResult<String> {
-    let queue_url = form
-        .get("QueueUrl")
-        .ok_or_else(|| MyError::MissingParameter("QueueUrl".to_string()))?;
-    {
-        let mut s = state.lock().await;
-        s.remove_queue(queue_url);
+    let request = DeleteQueueRequest::from_params(&form)?;
+    state.remove_queue(&ctx, &request.queue_url);
+
+    Ok(get_response_xml("DeleteQueue", SQS_NAMESPACE, None))
+}
+
+pub async fn purge_queue(
+    form: HashMap<String, String>,
+    ctx: RequestContext,
+    state: Arc<State>,
+) -> MyResult<String> {
+    let request = PurgeQueueRequest::from_params(&form)?;
+    if !state.purge_queue(&ctx, &request.queue_url) {
+        return Err(MyError::QueueNotFound(request.queue_url));
     }
 
-    let output = format!(
-        "<DeleteQueueResponse>\
-            <ResponseMetadata>\
-                <RequestId>{}</RequestId>\
-            </ResponseMetadata>\
-        </DeleteQueueResponse>",
-        get_new_id(),
-    );
-    Ok(output)
+    Ok(get_response_xml("PurgeQueue", SQS_NAMESPACE, None))
 }
 
 pub async fn get_queue_attributes(
     form: HashMap<String, String>,
-    state: Arc<Mutex<State>>,
+    ctx: RequestContext,
+    state: Arc<State>,
 ) -> MyResult<String> {
     let queue_url = form
         .get("QueueUrl")
         .ok_or_else(|| MyError::MissingParameter("QueueUrl".to_string()))?;
-    let s = state.lock().await;
-    let path = s.get_queue_path(queue_url);
-    if let Some(q) = s.queues.get(&path) {
-        let mut attributes_str = String::new();
-        for (k, v) in q.attributes.iter() {
-            attributes_str.push_str(&format!(
-                "<Attribute>\
-                    <Name>{}</Name>\
-                    <Value>{}</Value>\
-                 </Attribute>",
-                escape_xml(k),
-                escape_xml(v)
-            ));
-        }
-        let output = format!(
-            "<GetQueueAttributesResponse>\
-                <GetQueueAttributesResult>\
-                {}\
-                </GetQueueAttributesResult>\
-                <ResponseMetadata>\
-                    <RequestId>{}</RequestId>\
-                </ResponseMetadata>\
-            </GetQueueAttributesResponse>",
-            attributes_str,
-            get_new_id(),
-        );
-        Ok(output)
+    let path = state.get_queue_path(&ctx, queue_url);
+    if let Some(q) = state.get_queue(&path) {
+        let q = read(&q);
+        let result = GetQueueAttributesResult {
+            attributes: q.attributes.iter().collect(),
+        };
+        Ok(get_response_xml(
+            "GetQueueAttributes",
+            SQS_NAMESPACE,
+            Some(&result),
+        ))
     } else {
         Err(MyError::QueueNotFound(queue_url.clone()))
     }
@@ -134,25 +173,24 @@ pub async fn get_queue_attributes(
 
 pub async fn set_queue_attributes(
     form: HashMap<String, String>,
-    state: Arc<Mutex<State>>,
+    ctx: RequestContext,
+    state: Arc<State>,
 ) -> MyResult<String> {
     let queue_url = form
         .get("QueueUrl")
         .ok_or_else(|| MyError::MissingParameter("QueueUrl".to_string()))?;
     let attributes = get_attributes(&form);
-    let mut s = state.lock().await;
-    let path = s.get_queue_path(queue_url);
-    if let Some(q) = s.queues.get_mut(&path) {
-        q.attributes = attributes;
-        let output = format!(
-            "<SetQueueAttributesResponse>\
-                <ResponseMetadata>\
-                    <RequestId>{}</RequestId>\
-                </ResponseMetadata>\
-            </SetQueueAttributesResponse>",
-            get_new_id(),
-        );
-        Ok(output)
+    validate_attributes(&attributes, QUEUE_ATTRIBUTES)?;
+    // A queue can't be changed to or from FIFO once it has been created.
+    if attributes.contains_key("FifoQueue") {
+        return Err(MyError::InvalidAttributeName("FifoQueue".to_string()));
+    }
+    let path = state.get_queue_path(&ctx, queue_url);
+    if let Some(q) = state.get_queue(&path) {
+        write(&q).attributes = attributes;
+        // The retention period may have changed, so check again from now.
+        state.schedule_retention_check(&path, state.now());
+        Ok(get_response_xml("SetQueueAttributes", SQS_NAMESPACE, None))
     } else {
         Err(MyError::QueueNotFound(queue_url.clone()))
     }
@@ -160,73 +198,104 @@ pub async fn set_queue_attributes(
 
 pub async fn send_message(
     form: HashMap<String, String>,
-    state: Arc<Mutex<State>>,
+    ctx: RequestContext,
+    state: Arc<State>,
 ) -> MyResult<String> {
-    let queue_url = form
-        .get("QueueUrl")
-        .ok_or_else(|| MyError::MissingParameter("QueueUrl".to_string()))?;
-    let message_body = form
-        .get("MessageBody")
-        .ok_or_else(|| MyError::MissingParameter("MessageBody".to_string()))?;
-    // TODO: Support delayed queue.
-    let _delay_seconds: u16 = form
-        .get("DelaySeconds")
-        .map(|sec| sec.parse().ok())
-        .flatten()
-        .unwrap_or(0);
-    let attributes = get_message_attributes(&form);
-    let mut s = state.lock().await;
-    let path = s.get_queue_path(queue_url);
-    if let Some(q) = s.queues.get_mut(&path) {
-        let message = Message::new(message_body, attributes);
+    let SendMessageRequest {
+        queue_url,
+        message_body,
+        message_attributes,
+        delay_seconds,
+        trace_header,
+    } = SendMessageRequest::from_params(&form)?;
+    let path = state.get_queue_path(&ctx, &queue_url);
+    let now = state.now();
+    if !state.reserve_memory(get_approximate_message_size(
+        &message_body,
+        &message_attributes,
+    )) {
+        return Err(MyError::MemoryBudgetExceeded);
+    }
+    if let Some(q) = state.get_queue(&path) {
+        let mut message = Message::new(
+            message_body.as_str().into(),
+            Arc::new(message_attributes),
+            now,
+        );
+        message.trace_header = trace_header.or(ctx.trace_header);
         let message_id = message.id.clone();
-        let md5_message = message.get_content_md5();
-        let md5_attributes = message.get_attribute_md5();
-        q.send_message(message);
-
-        let output = format!(
-            "<SendMessageResponse>\
-                <SendMessageResult>\
-                    <MD5OfMessageBody>{}</MD5OfMessageBody>\
-                    <MD5OfMessageAttributes>{}</MD5OfMessageAttributes>\
-                    <MessageId>{}</MessageId>\
-                </SendMessageResult>\
-                <ResponseMetadata>\
-                    <RequestId>{}</RequestId>\
-                </ResponseMetadata>\
-            </SendMessageResponse>",
-            md5_message,
-            md5_attributes,
-            message_id,
-            get_new_id(),
+        let md5_message = message.get_content_md5().to_string();
+        let md5_attributes = message.get_attribute_md5().to_string();
+        let journal_entry = JournalEntry::MessageSent {
+            queue: path.clone(),
+            message: message.clone(),
+        };
+        // The message's own delay overrides the queue's default.
+        let delay_seconds = delay_seconds.unwrap_or_else(|| read(&q).get_delay_seconds());
+        match delay_seconds {
+            0 => write(&q).send_message(message),
+            delay_seconds => state.delay_message(&path, message, delay_seconds),
+        }
+        state.journal(journal_entry);
+        state.send_event(
+            "SendMessage",
+            path.as_str(),
+            &message_id,
+            Some(&message_body),
         );
-        Ok(output)
+        state.trace_message(&message_id, "Sent", path.as_str(), None);
+
+        let result = SendMessageResponse {
+            message_id,
+            md5_of_message_body: md5_message,
+            md5_of_message_attributes: Some(md5_attributes),
+        };
+        Ok(get_response_xml(
+            "SendMessage",
+            SQS_NAMESPACE,
+            Some(&result),
+        ))
     } else {
-        Err(MyError::QueueNotFound(queue_url.clone()))
+        Err(MyError::QueueNotFound(queue_url))
     }
 }
 
 enum MessageOrWaiter {
     Message(Vec<Message>),
-    Waiter(Receiver<bool>),
+    Waiter(Waiter),
 }
 
-async fn get_message_or_waiter(
+/// Take messages from the queue, or get a place in line to wait for them. `waiter` is the
+/// receive's place in line from waiting before, if it has already waited.
+fn get_message_or_waiter(
+    ctx: &RequestContext,
     queue_url: &str,
     max_count: u8,
-    state: Arc<Mutex<State>>,
+    waiter: Option<Waiter>,
+    state: &State,
 ) -> MyResult<MessageOrWaiter> {
-    let mut s = state.lock().await;
-    let path = s.get_queue_path(queue_url);
-    match s.queues.get_mut(&path) {
+    let path = state.get_queue_path(ctx, queue_url);
+    let shutting_down = state.is_shutting_down();
+    let shuffle_delivery = lock(&state.chaos).shuffle_delivery;
+    match state.get_queue(&path) {
         Some(q) => {
-            match q.has_message() {
+            let mut q = write(&q);
+            let count = q
+                .get_receivable_count(waiter.as_ref())
+                .min(max_count as usize) as u8;
+            match count > 0 && !q.paused {
+                true if shuffle_delivery && !q.is_fifo() => {
+                    let mut chaos = lock(&state.chaos);
+                    let messages = q.receive_messages_unordered(count, |x| chaos.pick_index(x));
+                    Ok(MessageOrWaiter::Message(messages))
+                }
                 true => {
                     // Pop messages.
-                    let messages = q.receive_messages(max_count);
+                    let messages = q.receive_messages(count);
                     Ok(MessageOrWaiter::Message(messages))
                 }
-                false => Ok(MessageOrWaiter::Waiter(q.get_waiter())),
+                false if shutting_down => Ok(MessageOrWaiter::Message(Vec::new())),
+                false => Ok(MessageOrWaiter::Waiter(q.get_waiter(waiter.is_some()))),
             }
         }
         None => Err(MyError::QueueNotFound(queue_url.to_string())),
@@ -235,149 +304,289 @@ async fn get_message_or_waiter(
 
 pub async fn receive_message(
     form: HashMap<String, String>,
-    state: Arc<Mutex<State>>,
-) -> MyResult<String> {
-    let queue_url = form
-        .get("QueueUrl")
-        .ok_or_else(|| MyError::MissingParameter("QueueUrl".to_string()))?;
-    let mut max_count: u8 = form
-        .get("MaxNumberOfMessages")
-        .map(|n| n.parse().ok())
-        .flatten()
-        .unwrap_or(1);
-    if max_count > 10 || max_count < 1 {
+    ctx: RequestContext,
+    state: Arc<State>,
+) -> MyResult<ResponseBody> {
+    let request = ReceiveMessageRequest::from_params(&form)?;
+    let queue_url = &request.queue_url;
+    let mut max_count = request.max_number_of_messages.unwrap_or(1);
+    if !(1..=10).contains(&max_count) {
         max_count = 1;
     }
-    let wait_time_seconds: u64 = form
-        .get("WaitTimeSeconds")
-        .map(|n| n.parse().ok())
-        .flatten()
-        .unwrap_or(0);
-    let visibility_timeout_recv: Option<u32> = form
-        .get("VisibilityTimeout")
-        .map(|n| n.parse().ok())
-        .flatten();
-    let attribute_names = get_message_attribute_names(&form);
-
-    let mut messages: Vec<Message> =
-        match get_message_or_waiter(&queue_url, max_count, state.clone()).await? {
-            MessageOrWaiter::Message(x) => {
-                // Message already waiting.
-                x
-            }
-            MessageOrWaiter::Waiter(w) => {
-                if wait_time_seconds > 0 {
-                    // No messages, but we want to wait.
-                    if tokio::time::timeout(Duration::new(wait_time_seconds, 0), w)
-                        .await
-                        .is_ok()
-                    {
-                        // We got a message.
-                        match get_message_or_waiter(&queue_url, max_count, state.clone()).await? {
-                            MessageOrWaiter::Message(x) => x,
-                            MessageOrWaiter::Waiter(_) => Vec::new(),
-                        }
-                    } else {
-                        Vec::new()
-                    }
-                } else {
-                    Vec::new()
-                }
-            }
+    let max_wait_time_seconds = state.max_wait_time_seconds;
+    let wait_time_seconds = request
+        .wait_time_seconds
+        .unwrap_or(0)
+        .min(max_wait_time_seconds);
+
+    // Long polls are woken in the order they started waiting, and the message that wakes one
+    // is set aside for it, so later receives can't take it first. One that is woken but finds
+    // its message gone waits again, at the front, until its wait time is up.
+    let deadline = Instant::now() + Duration::new(wait_time_seconds, 0);
+    let mut waiter = None;
+    let mut messages: Vec<Message> = loop {
+        let mut w = match get_message_or_waiter(&ctx, queue_url, max_count, waiter, &state)? {
+            MessageOrWaiter::Message(x) => break x,
+            MessageOrWaiter::Waiter(w) => w,
         };
+        let now = Instant::now();
+        if now >= deadline {
+            break Vec::new();
+        }
+        if tokio::time::timeout(deadline - now, &mut w.bell)
+            .await
+            .is_err()
+        {
+            // The wait is over, but take any message that arrived just as it ended.
+            match get_message_or_waiter(&ctx, queue_url, max_count, Some(w), &state)? {
+                MessageOrWaiter::Message(x) => break x,
+                MessageOrWaiter::Waiter(_) => break Vec::new(),
+            }
+        }
+        waiter = Some(w);
+    };
 
     if !messages.is_empty() {
-        let mut s = state.lock().await;
-        let path = s.get_queue_path(queue_url);
-        if let Some(q) = s.queues.get(&path) {
-            let visibility_timeout_queue: u32 = q
-                .get_attribute("VisibilityTimeout", "600")
-                .parse()
-                .unwrap_or(600);
+        let (path, visibility_timeout, store) = {
+            let path = state.get_queue_path(&ctx, queue_url);
+            let visibility_timeout = state.get_queue(&path).map(|q| {
+                let visibility_timeout_queue: u32 = read(&q)
+                    .get_attribute("VisibilityTimeout", "600")
+                    .parse()
+                    .unwrap_or(600);
 
-            // Prefer visibility timeout of the request, and fallback to that of the queue.
-            let visibility_timeout = visibility_timeout_recv.unwrap_or(visibility_timeout_queue);
+                // Prefer visibility timeout of the request, and fallback to that of the queue.
+                request
+                    .visibility_timeout
+                    .unwrap_or(visibility_timeout_queue)
+            });
+            let store = state.store.clone().filter(|x| x.is_shared());
+            (path, visibility_timeout, store)
+        };
 
-            // All received messages are cached, so they can be requeued if not
-            // deleted within the required timeout.
+        if let Some(visibility_timeout) = visibility_timeout {
             for message in messages.iter_mut() {
                 message.receive_count += 1;
-                message.receipt_handle =
-                    s.add_received_message(message.clone(), path.clone(), visibility_timeout);
+                message.receipt_handle = ReceiveHandle::new();
+            }
+
+            // Claim the messages in the shared store, so that no other process receives them
+            // too. Those another process has already received are in flight there.
+            let claimed = match store {
+                Some(store) => {
+                    let claims: Vec<(String, ReceiveHandle)> = messages
+                        .iter()
+                        .map(|m| (m.id.clone(), m.receipt_handle.clone()))
+                        .collect();
+                    run_blocking(move || {
+                        claims
+                            .iter()
+                            .map(|(id, handle)| store.claim_message(id, handle, visibility_timeout))
+                            .collect()
+                    })
+                    .await
+                }
+                None => Ok(vec![true; messages.len()]),
+            };
+
+            // All received messages are cached, so they can be requeued if not
+            // deleted within the required timeout.
+            let claimed = match claimed {
+                Ok(x) => x,
+                Err(e) => {
+                    // Make the messages visible again straight away.
+                    for message in messages {
+                        state.add_received_message(
+                            message,
+                            path.clone(),
+                            0,
+                            ReceiveHandle::new(),
+                            false,
+                        );
+                    }
+                    return Err(MyError::StoreUnavailable(e.to_string()));
+                }
+            };
+            let mut received = Vec::with_capacity(messages.len());
+            for (message, claimed) in messages.into_iter().zip(claimed) {
+                // Simulate message loss by discarding some messages instead of delivering them.
+                if claimed && lock(&state.chaos).should_drop() {
+                    let detail = Some("Lost on receive".to_string());
+                    state.trace_message(&message.id, "Dropped", path.as_str(), detail);
+                    state.journal(JournalEntry::MessageDeleted {
+                        queue: path.clone(),
+                        message_id: message.id.clone(),
+                    });
+                    continue;
+                }
+                let handle = message.receipt_handle.clone();
+                state.add_received_message(
+                    message.clone(),
+                    path.clone(),
+                    visibility_timeout,
+                    handle,
+                    claimed,
+                );
+                if claimed {
+                    state.send_event("ReceiveMessage", path.as_str(), &message.id, None);
+                    let detail = format!("Receive count {}", message.receive_count);
+                    state.trace_message(&message.id, "Received", path.as_str(), Some(detail));
+
+                    if lock(&state.chaos).should_duplicate() {
+                        if let Some(q) = state.get_queue(&path) {
+                            write(&q).send_message(message.clone());
+                        }
+                        let detail = Some("Left in the queue to be delivered again".to_string());
+                        state.trace_message(&message.id, "Duplicated", path.as_str(), detail);
+                    }
+                    received.push(message);
+                }
             }
+            messages = received;
         }
     }
 
-    let messages_xml: Vec<String> = messages
-        .iter()
-        .map(|m| m.get_message_xml(&attribute_names))
-        .collect();
-
-    let output = format!(
-        "<ReceiveMessageResponse>\
-          <ReceiveMessageResult>\
-            {}\
-          </ReceiveMessageResult>\
-          <ResponseMetadata>\
-            <RequestId>{}</RequestId>\
-          </ResponseMetadata>\
-        </ReceiveMessageResponse>",
-        messages_xml.join(""),
-        get_new_id(),
-    );
-    Ok(output)
+    // Each message is written as the response is streamed, so a batch of large messages is
+    // never held in memory as a whole. Their bodies are shared with the in-flight copies.
+    let ReceiveMessageRequest {
+        attribute_names,
+        message_attribute_names,
+        ..
+    } = request;
+    let result = messages.into_iter().map(move |message| {
+        let mut w = XmlWriter::default();
+        w.element("Message", |w| {
+            message.write_message_xml(w, &attribute_names, &message_attribute_names)
+        });
+        w.into_string()
+    });
+    Ok(get_streamed_response_xml(
+        "ReceiveMessage",
+        SQS_NAMESPACE,
+        result,
+    ))
 }
 
 pub async fn delete_message(
     form: HashMap<String, String>,
-    state: Arc<Mutex<State>>,
+    ctx: RequestContext,
+    state: Arc<State>,
 ) -> MyResult<String> {
-    let receipt_handle = form
-        .get("ReceiptHandle")
-        .ok_or_else(|| MyError::MissingParameter("ReceiptHandle".to_string()))?;
-    let mut s = state.lock().await;
-    s.delete_received_message(&ReceiveHandle(receipt_handle.clone()));
-
-    let output = format!(
-        "<DeleteMessageResponse>\
-          <ResponseMetadata>\
-            <RequestId>{}</RequestId>\
-          </ResponseMetadata>\
-        </DeleteMessageResponse>",
-        get_new_id(),
-    );
-    Ok(output)
+    let request = DeleteMessageRequest::from_params(&form)?;
+    let path = state.get_queue_path(&ctx, &request.queue_url);
+    let handle = ReceiveHandle(request.receipt_handle);
+    let message_id = state.get_received_message_id(&path, &handle);
+    let store = state.store.clone().filter(|x| x.is_shared());
+
+    // Mark the message as deleted in the shared store, unless another process has received
+    // it since, in which case it's in flight there.
+    let deleted = match (message_id, store) {
+        (Some(id), Some(store)) => {
+            let handle = handle.clone();
+            run_blocking(move || store.delete_message(&id, &handle))
+                .await
+                .map_err(|e| MyError::StoreUnavailable(e.to_string()))?
+        }
+        _ => true,
+    };
+
+    if deleted {
+        if let Some(m) = state.delete_received_message(&path, &handle) {
+            let id = m.message.id;
+            state.send_event("DeleteMessage", path.as_str(), &id, None);
+            state.trace_message(&id, "Deleted", path.as_str(), None);
+            state.journal(JournalEntry::MessageDeleted {
+                queue: path,
+                message_id: id,
+            });
+        }
+    }
+
+    Ok(get_response_xml("DeleteMessage", SQS_NAMESPACE, None))
 }
 
 pub async fn change_message_visibility(
     form: HashMap<String, String>,
-    state: Arc<Mutex<State>>,
+    ctx: RequestContext,
+    state: Arc<State>,
 ) -> MyResult<String> {
-    let receipt_handle = form
-        .get("ReceiptHandle")
-        .ok_or_else(|| MyError::MissingParameter("ReceiptHandle".to_string()))?;
-    let visibility_timeout_recv: Option<u32> = form
-        .get("VisibilityTimeout")
-        .map(|n| n.parse().ok())
-        .flatten();
-
-    if let Some(visibility_timeout) = visibility_timeout_recv {
-        let mut s = state.lock().await;
-        if let Some(msg) = s
-            .received_messages
-            .get_mut(&ReceiveHandle(receipt_handle.clone()))
-        {
-            msg.set_visibility_timeout(visibility_timeout);
+    let request = ChangeMessageVisibilityRequest::from_params(&form)?;
+    let path = state.get_queue_path(&ctx, &request.queue_url);
+    let visibility_timeout = request.visibility_timeout;
+    let handle = ReceiveHandle(request.receipt_handle);
+    let message_id = state.get_received_message_id(&path, &handle);
+    let store = state.store.clone().filter(|x| x.is_shared());
+
+    // Claim the message again in the shared store, for the new timeout.
+    let claimed = match (message_id, store) {
+        (Some(id), Some(store)) => {
+            let handle = handle.clone();
+            run_blocking(move || store.claim_message(&id, &handle, visibility_timeout))
+                .await
+                .map_err(|e| MyError::StoreUnavailable(e.to_string()))?
         }
+        _ => true,
+    };
+
+    if claimed {
+        state.set_visibility_timeout(&path, &handle, visibility_timeout);
+    }
+
+    Ok(get_response_xml(
+        "ChangeMessageVisibility",
+        SQS_NAMESPACE,
+        None,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_form(params: &[(&str, &str)]) -> HashMap<String, String> {
+        params
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    async fn receive(state: &Arc<State>, max: &str) -> MyResult<usize> {
+        let ctx = state.get_request_context(None, None, None);
+        let form = get_form(&[
+            ("QueueUrl", "http://localhost:3566/000000000000/orders"),
+            ("MaxNumberOfMessages", max),
+        ]);
+        let body = receive_message(form, ctx, state.clone()).await?;
+        Ok(body.into_string().matches("<Message>").count())
     }
 
-    let output = format!(
-        "<ChangeMessageVisibilityResponse>\
-          <ResponseMetadata>\
-            <RequestId>{}</RequestId>\
-          </ResponseMetadata>\
-        </ChangeMessageVisibilityResponse>",
-        get_new_id(),
-    );
-    Ok(output)
+    #[tokio::test]
+    async fn test_max_number_of_messages_out_of_range() {
+        let state = Arc::new(State::new(3566, "us-east-1", "000000000000"));
+        let ctx = state.get_request_context(None, None, None);
+        create_queue(get_form(&[("QueueName", "orders")]), ctx, state.clone())
+            .await
+            .unwrap();
+        for i in 0..30 {
+            let ctx = state.get_request_context(None, None, None);
+            let form = get_form(&[
+                ("QueueUrl", "http://localhost:3566/000000000000/orders"),
+                ("MessageBody", &i.to_string()),
+            ]);
+            send_message(form, ctx, state.clone()).await.unwrap();
+        }
+
+        // Like SQS used to, out of range values are treated as 1.
+        assert_eq!(receive(&state, "0").await.unwrap(), 1);
+        assert_eq!(receive(&state, "11").await.unwrap(), 1);
+        assert_eq!(receive(&state, "10").await.unwrap(), 10);
+
+        match receive(&state, "ten").await {
+            Err(MyError::InvalidParameterValue(name, value, _)) => {
+                assert_eq!(name, "MaxNumberOfMessages");
+                assert_eq!(value, "ten");
+            }
+            x => panic!("Unexpected result: {:?}", x),
+        }
+    }
 }
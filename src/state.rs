@@ -1,183 +1,1782 @@
-use crate::misc::{escape_xml, get_new_id};
+use crate::capture::{append_request, CapturedRequest};
+use crate::chaos::Chaos;
+use crate::misc::{
+    get_new_id, get_new_seed, get_region_from_host, lock, read, write, FileWriter, IdGenerator,
+};
+use crate::persistence::{JournalEntry, Store, SNAPSHOT_VERSION};
+use crate::spill::{Spill, SpillFile, SpillJob, SpillJobResult, SpillStorage, SpilledMessages};
+use crate::timers::{Timer, Timers};
+use crate::xml::{ToXml, XmlWriter};
 use chrono::{DateTime, Utc};
-use log::warn;
+use dashmap::DashMap;
+use log::{debug, info, warn};
 use md5::{Digest, Md5};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use std::collections::hash_map::Entry;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, Weak};
+use tokio::sync::{broadcast, mpsc, oneshot, Notify};
 
+// Only keep the most recent push notifications, firehose records and delivery attempts.
+const MAX_PUSH_MESSAGES: usize = 1000;
+const MAX_FIREHOSE_RECORDS: usize = 1000;
+const MAX_DELIVERY_ATTEMPTS: usize = 100;
+const MAX_AUDIT_RECORDS: usize = 1000;
+const MAX_TRACED_MESSAGES: usize = 10000;
+
+// Actions that create, change or delete resources are recorded in the audit log.
+pub const AUDITED_ACTIONS: &[&str] = &[
+    "CreateQueue",
+    "DeleteQueue",
+    "SetQueueAttributes",
+    "PurgeQueue",
+    "CreateTopic",
+    "DeleteTopic",
+    "SetTopicAttributes",
+    "Subscribe",
+    "Unsubscribe",
+    "SetSubscriptionAttributes",
+    "CreatePlatformApplication",
+    "CreatePlatformEndpoint",
+    "OptInPhoneNumber",
+    "PutDataProtectionPolicy",
+];
+
+// Allowed for a message's id, timestamps and bookkeeping, on top of its body and attributes,
+// when estimating memory use.
+const MESSAGE_OVERHEAD_BYTES: usize = 256;
+
+// Slow event stream subscribers miss events once this many are pending.
+const MAX_PENDING_EVENTS: usize = 1000;
+
+// Messages published to FIFO topics with the same deduplication id within this window
+// are only delivered once.
+const DEDUPLICATION_MINUTES: i64 = 5;
+
+/// Details of the caller that a request is scoped to.
+#[derive(Clone)]
+pub struct RequestContext {
+    pub account_id: String,
+    pub region: String,
+    // The base URL the client reached us on, if it differs from our own (e.g. via a proxy).
+    pub endpoint_url: Option<String>,
+    // The X-Ray trace header, propagated to messages sent by this request.
+    pub trace_header: Option<String>,
+}
+
+/// Everything the server keeps, shared between requests as an `Arc<State>`.
+///
+/// Each queue and topic has a read-write lock of its own, so a request only waits for others
+/// changing the same one. Requests that only read, such as ListQueues, GetQueueAttributes and
+/// ListTopics, share it, so monitoring can poll alongside send and receive traffic. Creating,
+/// deleting, importing and exporting queues and topics also takes the resources lock.
+/// To avoid deadlocks, a queue or topic is never locked while iterating over the maps, the
+/// maps aren't used while one is locked, and only one is locked at a time. The other locks
+/// guard a single field each, and are never held while locking a queue or topic.
 pub struct State {
+    // The account and region used when a request doesn't identify one.
     pub account_id: String,
     region: String,
+    port: u16,
     endpoint_url: String,
-    pub queues: HashMap<QueuePath, SQSQueue>,
-    pub topics: HashMap<TopicArn, SNSTopic>,
-    pub received_messages: HashMap<ReceiveHandle, ReceivedMessage>,
+    queues: DashMap<QueuePath, Arc<RwLock<SQSQueue>>>,
+    topics: DashMap<TopicArn, Arc<RwLock<SNSTopic>>>,
+    // Held while queues and topics are created, deleted, imported or exported.
+    resources: Mutex<()>,
+    pub platform_applications: Mutex<HashMap<String, PlatformApplication>>,
+    pub push_messages: Mutex<VecDeque<PushMessage>>,
+    pub opted_out_phone_numbers: Mutex<BTreeSet<String>>,
+    pub firehose_records: Mutex<VecDeque<FirehoseRecord>>,
+    pub delivery_attempts: Mutex<HashMap<String, VecDeque<DeliveryAttempt>>>,
+    pub audit_log: Mutex<VecDeque<AuditRecord>>,
+    message_traces: Mutex<MessageTraces>,
+    // Also append firehose records to this file, as newline-delimited JSON.
+    pub firehose_file: Option<PathBuf>,
+    // Captured requests and firehose records are written in the background, so requests
+    // don't wait on the disk.
+    pub file_writer: FileWriter,
+    // Create missing queues when subscribing SQS endpoints, rather than rejecting them.
+    pub auto_create_subscribed_queues: bool,
+    // When set, reject requests not signed by one of these access key -> secret key pairs.
+    pub signature_credentials: Option<HashMap<String, String>>,
+    // Requests signed with these access keys are scoped to the mapped account.
+    pub access_key_accounts: HashMap<String, String>,
+    // Set on shutdown, so long polls return immediately.
+    shutting_down: AtomicBool,
+    // Long polls wait at most this long, regardless of WaitTimeSeconds.
+    pub max_wait_time_seconds: u64,
+    // Build queue URLs from X-Forwarded-Host and X-Forwarded-Proto, when present.
+    pub use_forwarded_headers: bool,
+    // Requests to <service>.<region>.<domain> are scoped to that region.
+    pub virtual_host_domain: String,
+    // Record every request to this file, for replaying later.
+    pub capture_file: Option<PathBuf>,
+    // Snapshots are saved to and restored from this store, such as a data directory.
+    pub store: Option<Arc<dyn Store>>,
+    // Also append every change to a journal in the store, once enabled after replaying it.
+    journal_enabled: AtomicBool,
+    // Failures to inject into requests, for testing clients.
+    pub chaos: Mutex<Chaos>,
+    // Queues with more messages than this keep the rest in the store, if it can hold them,
+    // or in a spill file in spill_dir.
+    pub spill_threshold: Option<usize>,
+    pub spill_dir: PathBuf,
+    // Wakes the task that reads and writes spill files, when a queue runs low on messages.
+    pub spill_wake: Arc<Notify>,
+    // Reject new messages once those held in memory take roughly this many bytes.
+    pub memory_budget: Option<usize>,
+    memory_use: Mutex<MemoryUse>,
+    // In virtual clock mode, time stands still unless advanced through the admin API.
+    virtual_now: Mutex<Option<DateTime<Utc>>>,
+    // Everything that happens because time has passed, in the order it is due.
+    pub timers: Mutex<Timers>,
+    // Generates ids while handling requests, so that they can be made deterministic.
+    pub ids: Arc<IdGenerator>,
+    // Added to timestamps in responses and notifications, to simulate a server whose clock
+    // is out of step with the client's.
+    pub clock_skew: chrono::Duration,
+    events: broadcast::Sender<MessageEvent>,
+    // Channels registered in-process, which receive every event without dropping any.
+    event_hooks: Mutex<Vec<mpsc::UnboundedSender<MessageEvent>>>,
+    started: DateTime<Utc>,
 }
 
 impl State {
     pub fn new(port: u16, region: &str, account_id: &str) -> Self {
+        let (events, _) = broadcast::channel(MAX_PENDING_EVENTS);
         Self {
             account_id: account_id.to_string(),
             region: region.to_string(),
+            port,
             endpoint_url: format!("http://localhost:{}", port),
-            queues: HashMap::new(),
-            topics: HashMap::new(),
-            received_messages: HashMap::new(),
+            queues: DashMap::new(),
+            topics: DashMap::new(),
+            resources: Mutex::new(()),
+            platform_applications: Mutex::default(),
+            push_messages: Mutex::default(),
+            opted_out_phone_numbers: Mutex::default(),
+            firehose_records: Mutex::default(),
+            delivery_attempts: Mutex::default(),
+            audit_log: Mutex::default(),
+            message_traces: Mutex::default(),
+            firehose_file: None,
+            file_writer: FileWriter::default(),
+            auto_create_subscribed_queues: false,
+            signature_credentials: None,
+            access_key_accounts: HashMap::new(),
+            shutting_down: AtomicBool::new(false),
+            max_wait_time_seconds: 20,
+            use_forwarded_headers: false,
+            virtual_host_domain: "smoqs.local".to_string(),
+            capture_file: None,
+            store: None,
+            journal_enabled: AtomicBool::new(false),
+            chaos: Mutex::new(Chaos::new()),
+            spill_threshold: None,
+            spill_dir: std::env::temp_dir().join("smoqs-spill"),
+            spill_wake: Arc::default(),
+            memory_budget: None,
+            memory_use: Mutex::default(),
+            virtual_now: Mutex::new(None),
+            timers: Mutex::new(Timers::new()),
+            ids: Arc::new(IdGenerator::default()),
+            clock_skew: chrono::Duration::zero(),
+            events,
+            event_hooks: Mutex::default(),
+            started: Utc::now(),
         }
     }
 
-    pub fn add_queue(&mut self, queue: SQSQueue) -> bool {
-        let url = self.get_queue_url(&queue.name);
-        let path = self.get_queue_path(&url);
-        match self.queues.entry(path) {
-            Entry::Vacant(v) => {
-                v.insert(queue);
+    pub fn get_stats(&self) -> Stats {
+        let queues = self.get_queues();
+        let topics = self.get_topics();
+        Stats {
+            uptime_seconds: (Utc::now() - self.started).num_seconds(),
+            queues: queues.len(),
+            topics: topics.len(),
+            subscriptions: topics
+                .iter()
+                .map(|(_, t)| read(t).subscriptions.len())
+                .sum(),
+            messages: queues
+                .iter()
+                .map(|(_, q)| read(q).get_message_count())
+                .sum(),
+            in_flight_messages: queues.iter().map(|(_, q)| read(q).in_flight.len()).sum(),
+            pending_long_polls: queues.iter().map(|(_, q)| read(q).get_waiter_count()).sum(),
+            memory_used_bytes: lock(&self.memory_use).used,
+            shutting_down: self.is_shutting_down(),
+        }
+    }
+
+    pub fn get_queue(&self, path: &QueuePath) -> Option<Arc<RwLock<SQSQueue>>> {
+        self.queues.get(path).map(|x| x.value().clone())
+    }
+
+    pub fn has_queue(&self, path: &QueuePath) -> bool {
+        self.queues.contains_key(path)
+    }
+
+    /// Get every queue, to be locked one at a time once the map is no longer held.
+    pub fn get_queues(&self) -> Vec<(QueuePath, Arc<RwLock<SQSQueue>>)> {
+        self.queues
+            .iter()
+            .map(|x| (x.key().clone(), x.value().clone()))
+            .collect()
+    }
+
+    pub fn get_topic(&self, topic_arn: &TopicArn) -> Option<Arc<RwLock<SNSTopic>>> {
+        self.topics.get(topic_arn).map(|x| x.value().clone())
+    }
+
+    /// Get every topic, to be locked one at a time once the map is no longer held.
+    pub fn get_topics(&self) -> Vec<(TopicArn, Arc<RwLock<SNSTopic>>)> {
+        self.topics
+            .iter()
+            .map(|x| (x.key().clone(), x.value().clone()))
+            .collect()
+    }
+
+    fn lock_resources(&self) -> MutexGuard<'_, ()> {
+        lock(&self.resources)
+    }
+
+    /// List all queues, with their current message counts, sorted by URL.
+    pub fn get_queue_summaries(&self) -> Vec<QueueSummary> {
+        let mut summaries: Vec<QueueSummary> = self
+            .get_queues()
+            .into_iter()
+            .map(|(path, q)| {
+                let q = read(&q);
+                let ctx = self.get_request_context(
+                    None,
+                    Some(path.get_account_id()),
+                    Some(path.get_region()),
+                );
+                QueueSummary {
+                    name: q.name.clone(),
+                    url: self.get_queue_url(&ctx, &q.name),
+                    region: ctx.region,
+                    account_id: ctx.account_id,
+                    attributes: q.attributes.clone(),
+                    messages_visible: q.get_message_count(),
+                    messages_in_flight: q.in_flight.len(),
+                    messages_delayed: q.delayed.len(),
+                    paused: q.paused,
+                }
+            })
+            .collect();
+        summaries.sort_by(|a, b| a.url.cmp(&b.url));
+        summaries
+    }
+
+    /// List all topics and their subscriptions, sorted by ARN.
+    pub fn get_topic_summaries(&self) -> Vec<TopicSummary> {
+        let mut summaries: Vec<TopicSummary> = self
+            .get_topics()
+            .into_iter()
+            .map(|(arn, t)| {
+                let t = read(&t);
+                TopicSummary {
+                    name: t.name.clone(),
+                    arn: t.arn.clone(),
+                    attributes: t.get_all_attributes(arn.get_account_id()),
+                    subscriptions: t.subscriptions.clone(),
+                }
+            })
+            .collect();
+        summaries.sort_by(|a, b| a.arn.cmp(&b.arn));
+        summaries
+    }
+
+    /// List the messages that have been received but not yet deleted, soonest to expire first.
+    pub fn get_in_flight_messages(&self) -> Vec<InFlightMessage> {
+        let now = self.now();
+        let mut messages = Vec::new();
+        for (path, q) in self.get_queues() {
+            let q = read(&q);
+            messages.extend(q.in_flight.iter().map(|(handle, m)| InFlightMessage {
+                receipt_handle: handle.0.clone(),
+                queue: path.as_str().to_string(),
+                message_id: m.message.id.clone(),
+                receive_count: m.message.receive_count,
+                visibility_remaining_seconds: m.get_visibility_remaining_seconds(now),
+            }));
+        }
+        messages.sort_by_key(|m| m.visibility_remaining_seconds);
+        messages
+    }
+
+    /// Get a view of every queue, with its messages, and every topic, with its subscriptions,
+    /// for tests running smoqs in-process to compare or assert on. Queues are keyed by URL and
+    /// topics by ARN, in order, so views serialize the same way each time.
+    pub fn snapshot(&self) -> StateView {
+        let mut queues = BTreeMap::new();
+        for (path, q) in self.get_queues() {
+            let q = read(&q);
+            let ctx = self.get_request_context(
+                None,
+                Some(path.get_account_id()),
+                Some(path.get_region()),
+            );
+            let mut in_flight: Vec<InFlightView> = q
+                .in_flight
+                .iter()
+                .map(|(handle, m)| InFlightView {
+                    receipt_handle: handle.0.clone(),
+                    visible_at: m.expires,
+                    message: MessageView::new(&m.message),
+                })
+                .collect();
+            in_flight.sort_by_key(|x| x.visible_at);
+            let view = QueueView {
+                attributes: q.attributes.clone().into_iter().collect(),
+                messages: q.messages.iter().map(MessageView::new).collect(),
+                messages_spilled: q.spill.as_ref().map(|x| x.len()).unwrap_or(0),
+                in_flight,
+                paused: q.paused,
+            };
+            queues.insert(self.get_queue_url(&ctx, &q.name), view);
+        }
+
+        let topics = self
+            .get_topics()
+            .into_iter()
+            .map(|(arn, t)| {
+                let t = read(&t);
+                let view = TopicView {
+                    attributes: t.attributes.clone().into_iter().collect(),
+                    subscriptions: t
+                        .subscriptions
+                        .iter()
+                        .map(|x| SubscriptionView {
+                            arn: x.arn.clone(),
+                            protocol: x.protocol.clone(),
+                            endpoint: x.endpoint.clone(),
+                            attributes: x.attributes.clone().into_iter().collect(),
+                        })
+                        .collect(),
+                };
+                (arn.0, view)
+            })
+            .collect();
+        StateView { queues, topics }
+    }
+
+    /// Serialize the queues, including queued messages, the topics, including subscriptions,
+    /// and in-flight messages with their receipt handles.
+    /// Spilled messages aren't included, since reading them would hold the queue's lock.
+    /// Instead, the messages spilled from each queue are noted while it's locked, to be added
+    /// to the export with `add_spilled_messages()` once it's released.
+    pub fn export(&self) -> (serde_json::Value, Vec<(QueuePath, SpilledMessages)>) {
+        let _resources = self.lock_resources();
+        let mut queues = serde_json::Map::new();
+        let mut received_messages = serde_json::Map::new();
+        let mut spilled = Vec::new();
+        for (path, q) in self.get_queues() {
+            let q = read(&q);
+            if let Some(spill) = &q.spill {
+                spilled.push((path.clone(), spill.get_spilled_messages()));
+            }
+            queues.insert(path.as_str().to_string(), json!(&*q));
+            for (handle, m) in &q.in_flight {
+                received_messages.insert(handle.0.clone(), json!(m));
+            }
+        }
+        let topics: serde_json::Map<String, serde_json::Value> = self
+            .get_topics()
+            .into_iter()
+            .map(|(arn, t)| (arn.0, json!(&*read(&t))))
+            .collect();
+        let export = json!({
+            "version": SNAPSHOT_VERSION,
+            "queues": queues,
+            "topics": topics,
+            "received_messages": received_messages,
+        });
+        (export, spilled)
+    }
+
+    /// Replace the queues, topics and in-flight messages with those from an export.
+    /// In-flight messages are dropped if their queue isn't in the export.
+    /// Timers are scheduled for the imported messages, so they expire as they would have.
+    pub fn import(&self, snapshot: Snapshot) {
+        let _resources = self.lock_resources();
+        self.queues.clear();
+        for (path, q) in snapshot.queues {
+            self.queues.insert(path, Arc::new(RwLock::new(q)));
+        }
+        self.topics.clear();
+        for (arn, t) in snapshot.topics {
+            self.topics.insert(arn, Arc::new(RwLock::new(t)));
+        }
+        for (handle, mut m) in snapshot.received_messages {
+            if let Some(q) = self.get_queue(&m.queue_path) {
+                m.message.receipt_handle = handle.clone();
+                write(&q).in_flight.insert(handle, m);
+            }
+        }
+        self.schedule_timers();
+        self.update_memory_used();
+    }
+
+    /// Schedule the timers for every queue, delayed and in-flight message and deduplication
+    /// id, after replacing them wholesale. Timers that were already scheduled are skipped
+    /// when they run, if they no longer apply.
+    fn schedule_timers(&self) {
+        let now = self.now();
+        for (path, q) in self.get_queues() {
+            self.schedule_retention_check(&path, now);
+            let (delayed, in_flight): (Vec<_>, Vec<_>) = {
+                let q = read(&q);
+                let delayed = q.delayed.iter().map(|(id, m)| (m.visible_at, id.clone()));
+                let in_flight = q.in_flight.iter().map(|(h, m)| (m.expires, h.clone()));
+                (delayed.collect(), in_flight.collect())
+            };
+            let mut timers = lock(&self.timers);
+            for (visible_at, id) in delayed {
+                timers.schedule(visible_at, Timer::DelayElapsed(path.clone(), id));
+            }
+            for (expires, handle) in in_flight {
+                timers.schedule(expires, Timer::VisibilityExpired(path.clone(), handle));
+            }
+        }
+        for (arn, t) in self.get_topics() {
+            let expiries: Vec<(DateTime<Utc>, String)> = read(&t)
+                .published_messages
+                .iter()
+                .map(|(id, m)| (m.get_deduplication_expiry(), id.clone()))
+                .collect();
+            let mut timers = lock(&self.timers);
+            for (expiry, id) in expiries {
+                timers.schedule(expiry, Timer::DeduplicationExpired(arn.clone(), id));
+            }
+        }
+    }
+
+    /// Run every timer that is due. Each timer checks whether it still applies first, since
+    /// timers aren't cancelled when messages are deleted or their timeouts change.
+    pub fn run_due_timers(&self) {
+        let now = self.now();
+        let due = lock(&self.timers).take_due(now);
+        for timer in due {
+            match timer {
+                Timer::DelayElapsed(path, id) => self.release_delayed_message(&path, &id),
+                Timer::VisibilityExpired(path, handle) => {
+                    let expired = self
+                        .get_queue(&path)
+                        .and_then(|q| read(&q).in_flight.get(&handle).map(|m| m.has_expired(now)))
+                        .unwrap_or(false);
+                    if expired {
+                        self.requeue_received_messages(&path, &[handle]);
+                    }
+                }
+                Timer::RetentionCheck(path, due) => self.expire_retained_messages(&path, due),
+                Timer::DeduplicationExpired(arn, id) => {
+                    if let Some(t) = self.get_topic(&arn) {
+                        write(&t).expire_published_message(&id, now);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Get how long until the next timer is due, or None if there are no timers or the
+    /// virtual clock is in use, since then timers only come due when the clock is advanced.
+    pub fn get_time_until_next_timer(&self) -> Option<std::time::Duration> {
+        if lock(&self.virtual_now).is_some() {
+            return None;
+        }
+        let next = lock(&self.timers).get_next_due()?;
+        Some((next - self.now()).to_std().unwrap_or_default())
+    }
+
+    /// The time used for visibility timeouts, deduplication windows and message ages.
+    pub fn now(&self) -> DateTime<Utc> {
+        lock(&self.virtual_now).unwrap_or_else(Utc::now)
+    }
+
+    /// The time to report to clients, which is skewed from the actual time if --clock-skew
+    /// is given.
+    pub fn get_reported_time(&self, time: DateTime<Utc>) -> DateTime<Utc> {
+        time + self.clock_skew
+    }
+
+    /// Freeze the clock at the current time.
+    pub fn use_virtual_clock(&self) {
+        self.use_virtual_clock_at(Utc::now());
+    }
+
+    /// Freeze the clock at the given time.
+    pub fn use_virtual_clock_at(&self, now: DateTime<Utc>) {
+        *lock(&self.virtual_now) = Some(now);
+    }
+
+    /// Move the virtual clock forward and run any timers that are due as a result, such as
+    /// visibility timeouts expiring. Returns the new time, or None if the virtual clock isn't
+    /// in use.
+    pub fn advance_clock(&self, duration: chrono::Duration) -> Option<DateTime<Utc>> {
+        let now = {
+            let mut virtual_now = lock(&self.virtual_now);
+            let now = (*virtual_now)? + duration;
+            *virtual_now = Some(now);
+            now
+        };
+        self.run_due_timers();
+        Some(now)
+    }
+
+    /// Wake all pending long polls and stop any new ones from waiting.
+    pub fn shutdown(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        for (_, q) in self.get_queues() {
+            write(&q).wake_all_receivers();
+        }
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    /// Queue URLs and other links use https, for when serving over TLS.
+    pub fn use_https(&mut self) {
+        self.endpoint_url = format!("https://localhost:{}", self.port);
+    }
+
+    pub fn get_endpoint_url(&self) -> &str {
+        &self.endpoint_url
+    }
+
+    /// Use the port the server actually bound to, when it was asked for an ephemeral one.
+    pub fn set_port(&mut self, port: u16) {
+        let scheme = self.endpoint_url.split("://").next().unwrap_or("http");
+        self.endpoint_url = format!("{}://localhost:{}", scheme, port);
+        self.port = port;
+    }
+
+    pub fn add_queue(&self, ctx: &RequestContext, queue: SQSQueue) -> bool {
+        let path = QueuePath::new(&ctx.region, &ctx.account_id, &queue.name);
+        let _resources = self.lock_resources();
+        match self.queues.entry(path.clone()) {
+            dashmap::mapref::entry::Entry::Vacant(v) => {
+                v.insert(Arc::new(RwLock::new(queue)));
+                let now = self.now();
+                self.schedule_retention_check(&path, now);
                 true
             }
-            Entry::Occupied(_) => false,
+            dashmap::mapref::entry::Entry::Occupied(_) => false,
+        }
+    }
+
+    /// Check a queue for messages past its retention period at the given time, instead of
+    /// when the check was previously scheduled for. Call this when the retention period
+    /// changes.
+    pub fn schedule_retention_check(&self, path: &QueuePath, due: DateTime<Utc>) {
+        if let Some(q) = self.get_queue(path) {
+            write(&q).next_retention_check = Some(due);
+            lock(&self.timers).schedule(due, Timer::RetentionCheck(path.clone(), due));
+        }
+    }
+
+    /// Delete the queued messages that are past the queue's retention period, then schedule
+    /// the next check for when the oldest remaining message will be.
+    fn expire_retained_messages(&self, path: &QueuePath, due: DateTime<Utc>) {
+        let now = self.now();
+        let q = match self.get_queue(path) {
+            Some(x) => x,
+            None => return,
+        };
+        let mut q = write(&q);
+        // The check has been rescheduled since, so this one no longer applies.
+        if q.next_retention_check != Some(due) {
+            return;
+        }
+        let retention_period = q.get_retention_period();
+        let cutoff = now - retention_period;
+        let mut expired = Vec::new();
+        q.messages.retain(|m| {
+            if m.sent > cutoff {
+                return true;
+            }
+            expired.push(m.id.clone());
+            false
+        });
+        let oldest = q.messages.iter().map(|m| m.sent).min().unwrap_or(now);
+        // Checks are at least a second apart, so that a backlog of messages sent over time
+        // is deleted in batches rather than one by one.
+        let next = (oldest + retention_period).max(now + chrono::Duration::seconds(1));
+        q.next_retention_check = Some(next);
+        drop(q);
+        lock(&self.timers).schedule(next, Timer::RetentionCheck(path.clone(), next));
+
+        for id in expired {
+            let detail = Some("Past the retention period".to_string());
+            self.trace_message(&id, "Expired", path.as_str(), detail);
+            self.journal(JournalEntry::MessageDeleted {
+                queue: path.clone(),
+                message_id: id,
+            });
+        }
+    }
+
+    /// Hold a message back from its queue until the delay has passed.
+    pub fn delay_message(&self, path: &QueuePath, message: Message, delay_seconds: u32) {
+        let visible_at = self.now() + chrono::Duration::seconds(delay_seconds as i64);
+        if let Some(q) = self.get_queue(path) {
+            let timer = Timer::DelayElapsed(path.clone(), message.id.clone());
+            write(&q).delayed.insert(
+                message.id.clone(),
+                DelayedMessage {
+                    visible_at,
+                    message,
+                },
+            );
+            lock(&self.timers).schedule(visible_at, timer);
+        }
+    }
+
+    fn release_delayed_message(&self, path: &QueuePath, message_id: &str) {
+        if let Some(q) = self.get_queue(path) {
+            let mut q = write(&q);
+            if let Some(delayed) = q.delayed.remove(message_id) {
+                q.send_message(delayed.message);
+            }
         }
     }
 
-    pub fn remove_queue(&mut self, queue_url: &str) -> bool {
-        let path = self.get_queue_path(queue_url);
+    /// Schedule a FIFO topic's deduplication id to be forgotten once its window has passed.
+    pub fn schedule_deduplication_expiry(&self, arn: &TopicArn, deduplication_id: &str) {
+        let expiry = self.get_topic(arn).and_then(|t| {
+            read(&t)
+                .published_messages
+                .get(deduplication_id)
+                .map(|m| m.get_deduplication_expiry())
+        });
+        if let Some(expiry) = expiry {
+            let timer = Timer::DeduplicationExpired(arn.clone(), deduplication_id.to_string());
+            lock(&self.timers).schedule(expiry, timer);
+        }
+    }
+
+    pub fn remove_queue(&self, ctx: &RequestContext, queue_url: &str) -> bool {
+        let path = self.get_queue_path(ctx, queue_url);
+        let _resources = self.lock_resources();
         self.queues.remove(&path).is_some()
     }
 
-    pub fn get_queue_path(&self, queue_url: &str) -> QueuePath {
+    /// Remove every message from a queue, including those in flight.
+    pub fn purge_queue(&self, ctx: &RequestContext, queue_url: &str) -> bool {
+        let path = self.get_queue_path(ctx, queue_url);
+        match self.get_queue(&path) {
+            Some(q) => {
+                let mut q = write(&q);
+                q.messages.clear();
+                q.delayed.clear();
+                q.in_flight.clear();
+                q.spill = None;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Queues are identified by region, account and name. The region and account come from
+    /// the queue ARN or URL where possible, falling back to those of the request.
+    /// Subscription endpoints are resolved the same way.
+    pub fn get_queue_path(&self, ctx: &RequestContext, queue_url: &str) -> QueuePath {
         if queue_url.starts_with("arn") {
+            let parts: Vec<&str> = queue_url.splitn(6, ':').collect();
+            if parts.len() == 6 {
+                let path = QueuePath::new(parts[3], parts[4], parts[5]);
+                return self.resolve_queue_arn(ctx, queue_url, path);
+            }
             let p = queue_url.rsplit(':').next().unwrap_or(queue_url);
-            QueuePath(p.to_string())
+            QueuePath::new(&ctx.region, &ctx.account_id, p)
         } else {
-            let p = queue_url.rsplit('/').next().unwrap_or(queue_url);
-            QueuePath(p.to_string())
+            let mut segments = queue_url.rsplit('/');
+            let p = segments.next().unwrap_or(queue_url);
+            let account_id = match segments.next() {
+                Some(x) if !x.is_empty() && x.chars().all(|c| c.is_ascii_digit()) => x,
+                _ => &ctx.account_id,
+            };
+            let region = Url::parse(queue_url)
+                .ok()
+                .and_then(|x| {
+                    x.host_str()
+                        .and_then(get_region_from_host)
+                        .map(String::from)
+                })
+                .unwrap_or_else(|| ctx.region.clone());
+            QueuePath::new(&region, account_id, p)
+        }
+    }
+
+    /// Resolve a queue ARN that matches no queue exactly to a local queue with the same name,
+    /// since clients often build ARNs with placeholder regions and accounts. Queues in the
+    /// ARN's region are preferred, then those in the request's account and region. The ARN is
+    /// kept as it is if there's no such queue.
+    fn resolve_queue_arn(&self, ctx: &RequestContext, arn: &str, path: QueuePath) -> QueuePath {
+        if self.queues.contains_key(&path) {
+            return path;
+        }
+        let paths: Vec<QueuePath> = self.queues.iter().map(|x| x.key().clone()).collect();
+        let resolved = paths
+            .iter()
+            .filter(|x| x.get_name() == path.get_name())
+            .min_by_key(|x| {
+                (
+                    x.get_region() != path.get_region(),
+                    x.get_account_id() != ctx.account_id,
+                    x.get_region() != ctx.region,
+                    x.as_str(),
+                )
+            })
+            .cloned();
+        match resolved {
+            Some(x) => {
+                warn!(
+                    "Queue ARN {} does not match region {} and account {}. \
+                     Resolving to local queue {}",
+                    arn,
+                    x.get_region(),
+                    x.get_account_id(),
+                    x.as_str()
+                );
+                x
+            }
+            None => path,
+        }
+    }
+
+    /// Check whether a queue URL refers to a queue on another host or port, such as another
+    /// smoqs instance. Both the host and port must match one we're reached on, including the
+    /// one the request came through. Queue ARNs and AWS queue URLs are always resolved locally.
+    pub fn is_remote_queue_url(&self, ctx: &RequestContext, queue_url: &str) -> bool {
+        let url = match Url::parse(queue_url) {
+            Ok(x) if x.scheme() == "http" || x.scheme() == "https" => x,
+            _ => return false,
+        };
+        let host = url.host_str().unwrap_or_default();
+        if host.ends_with("amazonaws.com") {
+            return false;
         }
+        let port = url.port_or_known_default();
+        let is_request_url = ctx
+            .endpoint_url
+            .as_ref()
+            .and_then(|x| Url::parse(x).ok())
+            .map(|x| x.host_str() == Some(host) && x.port_or_known_default() == port)
+            .unwrap_or(false);
+        let is_local_host = matches!(host, "localhost" | "127.0.0.1" | "0.0.0.0" | "[::1]")
+            || self.parse_virtual_host(host).is_some();
+        !(is_request_url || (is_local_host && port == Some(self.port)))
     }
 
-    pub fn get_queue_url(&self, queue_name: &str) -> String {
-        format!("{}/{}/{}", self.endpoint_url, self.account_id, queue_name)
+    /// Parse a virtual host such as `sqs.us-east-1.smoqs.local` into its service and region.
+    pub fn parse_virtual_host<'a>(&self, host: &'a str) -> Option<(&'a str, &'a str)> {
+        let host = host.split(':').next().unwrap_or(host);
+        let prefix = host
+            .strip_suffix(self.virtual_host_domain.as_str())?
+            .strip_suffix('.')?;
+        let mut parts = prefix.splitn(2, '.');
+        match (parts.next(), parts.next()) {
+            (Some(service), Some(region)) if service == "sqs" || service == "sns" => {
+                Some((service, region))
+            }
+            _ => None,
+        }
     }
 
-    pub fn add_topic(&mut self, topic: SNSTopic) -> bool {
-        let arn = self.get_topic_arn(&topic.name);
+    /// Get the base URL for a request made to a virtual host, so that queue URLs keep using it.
+    pub fn get_virtual_host_url(&self, host: &str) -> Option<String> {
+        self.parse_virtual_host(host)?;
+        let scheme = self.endpoint_url.split("://").next().unwrap_or("http");
+        Some(format!("{}://{}", scheme, host))
+    }
+
+    pub fn get_queue_url(&self, ctx: &RequestContext, queue_name: &str) -> String {
+        let endpoint_url = ctx.endpoint_url.as_ref().unwrap_or(&self.endpoint_url);
+        format!("{}/{}/{}", endpoint_url, ctx.account_id, queue_name)
+    }
+
+    /// Get the request context for the given access key, explicit account id and region.
+    pub fn get_request_context(
+        &self,
+        access_key: Option<&str>,
+        account_id: Option<&str>,
+        region: Option<&str>,
+    ) -> RequestContext {
+        let account_id = account_id
+            .or_else(|| {
+                access_key.and_then(|k| self.access_key_accounts.get(k).map(|x| x.as_str()))
+            })
+            .unwrap_or(&self.account_id);
+        RequestContext {
+            account_id: account_id.to_string(),
+            region: region.unwrap_or(&self.region).to_string(),
+            endpoint_url: None,
+            trace_header: None,
+        }
+    }
+
+    pub fn add_topic(&self, topic: SNSTopic) -> bool {
+        let arn = TopicArn(topic.arn.clone());
+        let _resources = self.lock_resources();
         match self.topics.entry(arn) {
-            Entry::Vacant(v) => {
-                v.insert(topic);
+            dashmap::mapref::entry::Entry::Vacant(v) => {
+                v.insert(Arc::new(RwLock::new(topic)));
                 true
             }
-            Entry::Occupied(_) => false,
+            dashmap::mapref::entry::Entry::Occupied(_) => false,
         }
     }
 
-    pub fn remove_topic(&mut self, topic_arn: &TopicArn) -> bool {
+    pub fn remove_topic(&self, topic_arn: &TopicArn) -> bool {
+        let _resources = self.lock_resources();
         self.topics.remove(topic_arn).is_some()
     }
 
-    pub fn get_topic_arn(&self, topic_name: &str) -> TopicArn {
+    pub fn get_topic_arn(&self, ctx: &RequestContext, topic_name: &str) -> TopicArn {
         TopicArn(format!(
             "arn:aws:sns:{}:{}:{}",
-            self.region, self.account_id, topic_name
+            ctx.region, ctx.account_id, topic_name
         ))
     }
 
+    pub fn get_platform_application_arn(
+        &self,
+        ctx: &RequestContext,
+        platform: &str,
+        name: &str,
+    ) -> String {
+        format!(
+            "arn:aws:sns:{}:{}:app/{}/{}",
+            ctx.region, ctx.account_id, platform, name
+        )
+    }
+
+    pub fn find_platform_endpoint(&self, endpoint_arn: &str) -> Option<PlatformEndpoint> {
+        lock(&self.platform_applications)
+            .values()
+            .flat_map(|a| a.endpoints.iter())
+            .find(|e| e.arn == endpoint_arn)
+            .cloned()
+    }
+
+    pub fn add_push_message(&self, message: PushMessage) {
+        let mut push_messages = lock(&self.push_messages);
+        if push_messages.len() >= MAX_PUSH_MESSAGES {
+            push_messages.pop_front();
+        }
+        push_messages.push_back(message);
+    }
+
+    pub fn add_audit_record(&self, record: AuditRecord) {
+        let mut audit_log = lock(&self.audit_log);
+        if audit_log.len() >= MAX_AUDIT_RECORDS {
+            audit_log.pop_front();
+        }
+        audit_log.push_back(record);
+    }
+
+    pub fn add_firehose_record(&self, record: FirehoseRecord) {
+        if let Some(path) = &self.firehose_file {
+            let (path, data) = (path.clone(), record.data.clone());
+            self.file_writer.write(move || {
+                let result = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .and_then(|mut f| writeln!(f, "{}", data));
+                if let Err(e) = result {
+                    warn!(
+                        "Failed to write firehose record to {}: {:?}",
+                        path.display(),
+                        e
+                    );
+                }
+            });
+        }
+
+        let mut firehose_records = lock(&self.firehose_records);
+        if firehose_records.len() >= MAX_FIREHOSE_RECORDS {
+            firehose_records.pop_front();
+        }
+        firehose_records.push_back(record);
+    }
+
+    pub fn capture_request(&self, action: &str, params: &HashMap<String, String>) {
+        if let Some(path) = &self.capture_file {
+            let request = CapturedRequest {
+                timestamp: Utc::now(),
+                action: action.to_string(),
+                params: params.clone(),
+            };
+            let path = path.clone();
+            self.file_writer.write(move || {
+                if let Err(e) = append_request(&path, &request) {
+                    warn!("Failed to capture request to {}: {:?}", path.display(), e);
+                }
+            });
+        }
+    }
+
+    pub fn add_delivery_attempt(&self, subscription_arn: &str, attempt: DeliveryAttempt) {
+        self.trace_message(
+            &attempt.message_id,
+            "Delivery",
+            subscription_arn,
+            Some(format!(
+                "{:?} to {} endpoint {}",
+                attempt.outcome, attempt.protocol, attempt.endpoint
+            )),
+        );
+        let mut delivery_attempts = lock(&self.delivery_attempts);
+        let attempts = delivery_attempts
+            .entry(subscription_arn.to_string())
+            .or_default();
+        if attempts.len() >= MAX_DELIVERY_ATTEMPTS {
+            attempts.pop_front();
+        }
+        attempts.push_back(attempt);
+    }
+
+    pub fn get_unsubscribe_url(&self, subscription_arn: &str) -> String {
+        format!(
+            "{}/?Action=Unsubscribe&SubscriptionArn={}",
+            self.endpoint_url, subscription_arn
+        )
+    }
+
+    /// Add an event to the lifecycle trace of a message.
+    pub fn trace_message(
+        &self,
+        message_id: &str,
+        event: &str,
+        resource: &str,
+        detail: Option<String>,
+    ) {
+        let mut traces = lock(&self.message_traces);
+        let MessageTraces { events, ids } = &mut *traces;
+        let message_events = match events.entry(message_id.to_string()) {
+            Entry::Occupied(o) => o.into_mut(),
+            Entry::Vacant(v) => {
+                ids.push_back(message_id.to_string());
+                v.insert(Vec::new())
+            }
+        };
+        message_events.push(LifecycleEvent {
+            timestamp: Utc::now(),
+            event: event.to_string(),
+            resource: resource.to_string(),
+            detail,
+        });
+
+        if ids.len() > MAX_TRACED_MESSAGES {
+            if let Some(id) = ids.pop_front() {
+                events.remove(&id);
+            }
+        }
+    }
+
+    /// Get the lifecycle trace of a message, if it's one of those traced most recently.
+    pub fn get_message_trace(&self, message_id: &str) -> Option<Vec<LifecycleEvent>> {
+        lock(&self.message_traces).events.get(message_id).cloned()
+    }
+
+    /// Start journaling changes, once the journal has been replayed.
+    pub fn enable_journal(&self) {
+        self.journal_enabled.store(true, Ordering::SeqCst);
+    }
+
+    /// Append an entry to the journal in the background, if journaling is enabled.
+    pub fn journal(&self, entry: JournalEntry) {
+        let enabled = self.journal_enabled.load(Ordering::SeqCst);
+        if let (true, Some(store)) = (enabled, &self.store) {
+            let store = store.clone();
+            self.file_writer.write(move || {
+                if let Err(e) = store.append_journal_entry(&entry) {
+                    warn!(
+                        "Failed to write to journal in {}: {}",
+                        store.get_location(),
+                        e
+                    );
+                }
+            });
+        }
+    }
+
+    /// Apply a message entry from the journal. Actions are replayed as requests instead.
+    /// Entries may be applied more than once, such as those journaled after a snapshot was
+    /// taken but before its position in the journal, so applying one again changes nothing.
+    pub fn apply_journal_entry(&self, entry: JournalEntry) {
+        match entry {
+            JournalEntry::MessageSent { queue, message } => {
+                if let Some(q) = self.get_queue(&queue) {
+                    let mut q = write(&q);
+                    let in_flight = q.in_flight.values().any(|m| m.message.id == message.id);
+                    if !in_flight && !q.messages.iter().any(|m| m.id == message.id) {
+                        q.send_message(message);
+                    }
+                }
+            }
+            JournalEntry::MessageDeleted { queue, message_id } => {
+                if let Some(q) = self.get_queue(&queue) {
+                    let mut q = write(&q);
+                    q.messages.retain(|m| m.id != message_id);
+                    q.in_flight.retain(|_, m| m.message.id != message_id);
+                }
+            }
+            JournalEntry::MessageReceived {
+                receipt_handle,
+                mut message,
+            } => {
+                // The message may be queued, or in flight from an earlier receive or from
+                // before its visibility timeout was changed.
+                let path = message.queue_path.clone();
+                let q = match self.get_queue(&path) {
+                    Some(x) => x,
+                    None => return,
+                };
+                let id = message.message.id.clone();
+                let expires = message.expires;
+                message.message.receipt_handle = receipt_handle.clone();
+                {
+                    let mut q = write(&q);
+                    q.messages.retain(|m| m.id != id);
+                    q.in_flight.retain(|_, m| m.message.id != id);
+                    q.in_flight.insert(receipt_handle.clone(), message);
+                }
+                let timer = Timer::VisibilityExpired(path, receipt_handle);
+                lock(&self.timers).schedule(expires, timer);
+            }
+            JournalEntry::Action { .. } => {}
+        }
+    }
+
+    /// Notify event stream subscribers and event hooks, if there are any.
+    pub fn send_event(&self, action: &str, resource: &str, message_id: &str, body: Option<&str>) {
+        let event = MessageEvent {
+            timestamp: Utc::now(),
+            action: action.to_string(),
+            resource: resource.to_string(),
+            message_id: message_id.to_string(),
+            body: body.map(String::from),
+        };
+        // Sending only fails once the receiver is dropped.
+        lock(&self.event_hooks).retain(|hook| hook.send(event.clone()).is_ok());
+        // This only fails if nobody is listening.
+        let _ = self.events.send(event);
+    }
+
+    /// Send every message that is sent, published, delivered, received or deleted to this
+    /// channel, for tests that run smoqs in-process. Unlike the event stream, no events are
+    /// dropped if the receiver falls behind. The hook is removed once its receiver is dropped.
+    pub fn add_event_hook(&self, hook: mpsc::UnboundedSender<MessageEvent>) {
+        lock(&self.event_hooks).push(hook);
+    }
+
+    pub fn subscribe_events(&self) -> broadcast::Receiver<MessageEvent> {
+        self.events.subscribe()
+    }
+
+    /// Mark a message as in flight until its visibility timeout expires. Messages claimed by
+    /// another process sharing the store are in flight there, and aren't journaled here.
     pub fn add_received_message(
-        &mut self,
+        &self,
         message: Message,
         queue_path: QueuePath,
         timeout_seconds: u32,
-    ) -> ReceiveHandle {
-        let handle = ReceiveHandle::new();
-        let rec_msg = ReceivedMessage::new(message, queue_path, timeout_seconds);
-        self.received_messages.insert(handle.clone(), rec_msg);
-        handle
+        handle: ReceiveHandle,
+        claimed: bool,
+    ) {
+        let q = match self.get_queue(&queue_path) {
+            Some(x) => x,
+            None => return,
+        };
+        let rec_msg = ReceivedMessage::new(message, queue_path, timeout_seconds, self.now());
+        let timer = Timer::VisibilityExpired(rec_msg.queue_path.clone(), handle.clone());
+        lock(&self.timers).schedule(rec_msg.expires, timer);
+        // Journaled once it's in flight, so that snapshots taken in between still include it.
+        write(&q).in_flight.insert(handle.clone(), rec_msg.clone());
+        if claimed {
+            self.journal(JournalEntry::MessageReceived {
+                receipt_handle: handle,
+                message: rec_msg,
+            });
+        }
     }
 
-    pub fn delete_received_message(&mut self, handle: &ReceiveHandle) {
-        self.received_messages.remove(handle);
+    /// Change how long until a received message becomes visible again, counting from now,
+    /// and journal the change. Returns whether the message was found.
+    pub fn set_visibility_timeout(
+        &self,
+        path: &QueuePath,
+        handle: &ReceiveHandle,
+        timeout_seconds: u32,
+    ) -> bool {
+        let now = self.now();
+        let q = match self.get_queue(path) {
+            Some(x) => x,
+            None => return false,
+        };
+        let message = match write(&q).in_flight.get_mut(handle) {
+            Some(m) => {
+                m.set_visibility_timeout(timeout_seconds, now);
+                m.clone()
+            }
+            None => return false,
+        };
+        let timer = Timer::VisibilityExpired(path.clone(), handle.clone());
+        lock(&self.timers).schedule(message.expires, timer);
+        self.journal(JournalEntry::MessageReceived {
+            receipt_handle: handle.clone(),
+            message,
+        });
+        true
+    }
+
+    /// Get the id of a received message, if it's still in flight from the queue.
+    pub fn get_received_message_id(
+        &self,
+        path: &QueuePath,
+        handle: &ReceiveHandle,
+    ) -> Option<String> {
+        let q = self.get_queue(path)?;
+        let q = read(&q);
+        q.in_flight.get(handle).map(|m| m.message.id.clone())
+    }
+
+    /// Remove a received message from its queue, returning it if it was found.
+    pub fn delete_received_message(
+        &self,
+        path: &QueuePath,
+        handle: &ReceiveHandle,
+    ) -> Option<ReceivedMessage> {
+        let q = self.get_queue(path)?;
+        let mut q = write(&q);
+        q.in_flight.remove(handle)
+    }
+
+    /// Find the queued and in-flight messages that match the predicate. Messages spilled to
+    /// disk aren't included, since that would mean reading every spill file.
+    pub fn find_messages<F>(&self, predicate: F) -> Vec<FoundMessage>
+    where
+        F: Fn(&Message) -> bool,
+    {
+        let mut found = Vec::new();
+        let mut in_flight = Vec::new();
+        for (path, q) in self.get_queues() {
+            let q = read(&q);
+            found.extend(
+                q.messages
+                    .iter()
+                    .filter(|m| predicate(m))
+                    .map(|m| FoundMessage {
+                        queue: path.as_str().to_string(),
+                        in_flight: false,
+                        message: m.clone(),
+                    }),
+            );
+            in_flight.extend(
+                q.in_flight
+                    .values()
+                    .filter(|m| predicate(&m.message))
+                    .map(|m| FoundMessage {
+                        queue: path.as_str().to_string(),
+                        in_flight: true,
+                        message: m.message.clone(),
+                    }),
+            );
+        }
+        found.extend(in_flight);
+        found
+    }
+
+    /// Delete a queued or in-flight message by id. Returns whether the message was found.
+    /// Messages spilled to disk aren't found until they have been read back.
+    pub fn delete_message_by_id(&self, message_id: &str) -> bool {
+        let mut found = None;
+        for (path, q) in self.get_queues() {
+            let mut q = write(&q);
+            if let Some(i) = q.messages.iter().position(|m| m.id == message_id) {
+                q.messages.remove(i);
+                found = Some(path);
+                break;
+            }
+            let handle = q
+                .in_flight
+                .iter()
+                .find(|(_, m)| m.message.id == message_id)
+                .map(|(handle, _)| handle.clone());
+            if let Some(handle) = handle {
+                q.in_flight.remove(&handle);
+                found = Some(path);
+                break;
+            }
+        }
+
+        match found {
+            Some(path) => {
+                let detail = Some("Deleted through the admin API".to_string());
+                self.trace_message(message_id, "Deleted", path.as_str(), detail);
+                self.journal(JournalEntry::MessageDeleted {
+                    queue: path,
+                    message_id: message_id.to_string(),
+                });
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Move all queued messages from one queue to another, as if newly sent.
+    /// Returns the number of messages moved, or None if either queue doesn't exist.
+    /// Messages spilled to disk stay in the source queue, to be moved once read back.
+    pub fn move_messages(&self, from: &QueuePath, to: &QueuePath) -> Option<usize> {
+        let target = self.get_queue(to)?;
+        let source = self.get_queue(from)?;
+        let messages: Vec<Message> = write(&source).messages.drain(..).collect();
+        let count = messages.len();
+        for mut message in messages {
+            message.receive_count = 0;
+            self.trace_message(
+                &message.id,
+                "Moved",
+                to.as_str(),
+                Some(from.as_str().to_string()),
+            );
+            self.journal(JournalEntry::MessageDeleted {
+                queue: from.clone(),
+                message_id: message.id.clone(),
+            });
+            self.journal(JournalEntry::MessageSent {
+                queue: to.clone(),
+                message: message.clone(),
+            });
+            write(&target).send_message(message);
+        }
+        Some(count)
+    }
+
+    /// Recount the memory used by messages, including those delayed or in flight, but not
+    /// those in spill files.
+    pub fn update_memory_used(&self) {
+        let mut used = 0;
+        for (_, q) in self.get_queues() {
+            let q = read(&q);
+            let delayed = q.delayed.values().map(|d| &d.message);
+            let in_flight = q.in_flight.values().map(|m| &m.message);
+            used += q
+                .messages
+                .iter()
+                .chain(delayed)
+                .chain(in_flight)
+                .map(Message::get_approximate_size)
+                .sum::<usize>();
+        }
+
+        let mut memory_use = lock(&self.memory_use);
+        memory_use.used = used;
+        let budget = match self.memory_budget {
+            Some(x) if memory_use.over_budget => x,
+            _ => return,
+        };
+        if used < budget {
+            info!(
+                "Messages are back under the memory budget, using {} of {} bytes. \
+                 Accepting new messages again.",
+                used, budget
+            );
+            memory_use.over_budget = false;
+        } else {
+            warn!(
+                "Messages are still over the memory budget, using {} of {} bytes. \
+                 New messages are being rejected until consumers catch up.",
+                used, budget
+            );
+        }
+    }
+
+    /// Account for `size` more bytes of messages, unless that would take memory use over the
+    /// budget. Returns whether there was room, in which case the messages can be stored.
+    pub fn reserve_memory(&self, size: usize) -> bool {
+        let budget = match self.memory_budget {
+            Some(x) => x,
+            None => return true,
+        };
+        let mut memory_use = lock(&self.memory_use);
+        if memory_use.used + size > budget {
+            if !memory_use.over_budget {
+                warn!(
+                    "Messages are over the memory budget, using {} of {} bytes. \
+                     Rejecting new messages until consumers catch up.",
+                    memory_use.used, budget
+                );
+                memory_use.over_budget = true;
+            }
+            return false;
+        }
+        memory_use.used += size;
+        true
+    }
+
+    /// Move the messages beyond the spill threshold in each queue to a spill, and get the jobs
+    /// that write spilled messages to disk or read them back, to be run without the lock.
+    pub fn get_spill_jobs(&self) -> Vec<(QueuePath, SpillJob)> {
+        let threshold = match self.spill_threshold {
+            Some(x) => x,
+            None => return Vec::new(),
+        };
+        let mut jobs = Vec::new();
+        for (path, q) in self.get_queues() {
+            let mut q = write(&q);
+            // Once a queue is spilling, new messages go straight to the spill.
+            if q.spill.is_none() && q.messages.len() > threshold {
+                let id = get_new_id();
+                let storage: Arc<dyn SpillStorage> =
+                    match self.store.as_ref().and_then(|x| x.create_spill(&id)) {
+                        Some(x) => x,
+                        None => Arc::new(SpillFile::new(self.spill_dir.join(id + ".jsonl"))),
+                    };
+                let messages: Vec<Message> = q.messages.drain(threshold..).collect();
+                debug!(
+                    "Spilling {} messages from {}",
+                    messages.len(),
+                    path.as_str()
+                );
+                let wake = self.spill_wake.clone();
+                q.spill = Some(Spill::new(storage, messages, threshold, wake));
+            }
+            let backlog = q.messages.len();
+            if let Some(job) = q.spill.as_mut().and_then(|x| x.get_job(backlog)) {
+                jobs.push((path.clone(), job));
+            }
+        }
+        jobs
+    }
+
+    /// Record the results of spill jobs, returning the messages read back to their queues.
+    /// Results for queues that have since been deleted or replaced are ignored.
+    pub fn finish_spill_jobs(&self, results: Vec<SpillJobResult>) {
+        for (path, job, result) in results {
+            let q = match self.get_queue(&path) {
+                Some(x) => x,
+                None => continue,
+            };
+            let mut q = write(&q);
+            let spill = match &mut q.spill {
+                Some(x) if x.owns(&job) => x,
+                _ => continue,
+            };
+            let mut messages = spill.finish(result);
+            // Once storage is used up, the rest of the spill goes back in memory.
+            if spill.is_drained() {
+                messages.extend(spill.take_unwritten());
+                q.spill = None;
+            }
+            q.messages.extend(messages);
+            q.refill_from_spill();
+            if !q.paused && q.has_message() {
+                q.wake_receiver();
+            }
+        }
+    }
+
+    /// Send received messages back to their queue, unless they have been received 3 or more
+    /// times, in which case they are deleted. Returns the number of messages found.
+    pub fn requeue_received_messages(&self, path: &QueuePath, handles: &[ReceiveHandle]) -> usize {
+        let mut count = 0;
+        for handle in handles {
+            let msg = match self.delete_received_message(path, handle) {
+                Some(x) => x,
+                None => continue,
+            };
+            count += 1;
+
+            let id = msg.message.id.clone();
+            self.trace_message(&id, "VisibilityExpired", path.as_str(), None);
+            if msg.message.receive_count < 3 {
+                if let Some(q) = self.get_queue(path) {
+                    let mut q = write(&q);
+                    debug!(
+                        "Requeuing message to queue {} after Visibility Timeout: {}",
+                        q.name, msg.message.content
+                    );
+                    q.send_message(msg.message);
+                }
+            } else {
+                let detail = format!("Received {} times", msg.message.receive_count);
+                self.trace_message(&id, "Dropped", path.as_str(), Some(detail));
+                self.journal(JournalEntry::MessageDeleted {
+                    queue: path.clone(),
+                    message_id: id,
+                });
+            }
+        }
+        count
+    }
+}
+
+/// Roughly how many bytes the messages held in memory take. Messages leave the state in many
+/// ways, so this is recounted periodically, and added to as messages are sent.
+#[derive(Default)]
+struct MemoryUse {
+    used: usize,
+    // Set once a message has been rejected, until memory use is back under the budget.
+    over_budget: bool,
+}
+
+/// The lifecycle traces of the most recently traced messages.
+#[derive(Default)]
+struct MessageTraces {
+    events: HashMap<String, Vec<LifecycleEvent>>,
+    // Message ids in the order they were first traced, so the oldest can be dropped.
+    ids: VecDeque<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MessageAttributeValue {
+    pub data_type: String,
+    pub string_value: Option<String>,
+    // Base64-encoded, as sent by the client.
+    pub binary_value: Option<String>,
+}
+
+impl MessageAttributeValue {
+    pub fn string(value: &str) -> Self {
+        Self {
+            data_type: "String".to_string(),
+            string_value: Some(value.to_string()),
+            binary_value: None,
+        }
+    }
+
+    pub fn is_binary(&self) -> bool {
+        self.data_type.starts_with("Binary")
+    }
+
+    /// Get the value as it appears in an SNS notification envelope.
+    pub fn get_value(&self) -> String {
+        self.string_value
+            .clone()
+            .or_else(|| self.binary_value.clone())
+            .unwrap_or_default()
+    }
+}
+
+impl ToXml for MessageAttributeValue {
+    fn write_xml(&self, w: &mut XmlWriter) {
+        w.text("DataType", &self.data_type);
+        match self.is_binary() {
+            true => w.text(
+                "BinaryValue",
+                self.binary_value.as_deref().unwrap_or_default(),
+            ),
+            false => w.text(
+                "StringValue",
+                self.string_value.as_deref().unwrap_or_default(),
+            ),
+        }
     }
 }
 
-#[derive(Debug, Clone)]
+/// Check whether an attribute name was requested, allowing for `All`, `.*` and `prefix.*`.
+pub fn is_attribute_requested(name: &str, attribute_names: &[String]) -> bool {
+    attribute_names.iter().any(|n| {
+        if n == "All" || n == ".*" {
+            true
+        } else if let Some(prefix) = n.strip_suffix(".*") {
+            name.starts_with(prefix) && name[prefix.len()..].starts_with('.')
+        } else {
+            n == name
+        }
+    })
+}
+
+/// Calculate the MD5 of message attributes the same way SQS does, so that SDKs which verify
+/// MD5OfMessageAttributes accept our responses.
+fn get_attributes_md5(attributes: &[(&String, &MessageAttributeValue)]) -> String {
+    fn update_length_prefixed(hasher: &mut Md5, bytes: &[u8]) {
+        hasher.update((bytes.len() as u32).to_be_bytes());
+        hasher.update(bytes);
+    }
+
+    let mut hasher = Md5::new();
+    for (k, v) in attributes {
+        update_length_prefixed(&mut hasher, k.as_bytes());
+        update_length_prefixed(&mut hasher, v.data_type.as_bytes());
+        if v.is_binary() {
+            let bytes = v
+                .binary_value
+                .as_ref()
+                .map(|b| base64::decode(b).unwrap_or_default())
+                .unwrap_or_default();
+            hasher.update([2]);
+            update_length_prefixed(&mut hasher, &bytes);
+        } else {
+            hasher.update([1]);
+            update_length_prefixed(
+                &mut hasher,
+                v.string_value.as_deref().unwrap_or_default().as_bytes(),
+            );
+        }
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// The body and attributes are shared, so that copies of a message, such as those delivered
+/// to several subscriptions or kept while it is in flight, don't copy them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "StoredMessage")]
 pub struct Message {
     pub id: String,
-    pub content: String,
-    attributes: HashMap<String, String>,
+    pub content: Arc<str>,
+    attributes: Arc<HashMap<String, MessageAttributeValue>>,
     pub receive_count: u8,
+    #[serde(skip)]
     pub receipt_handle: ReceiveHandle,
+    pub trace_header: Option<String>,
+    pub sent: DateTime<Utc>,
+    // Computed once, when the message is sent or loaded, rather than each time it is received.
+    #[serde(skip)]
+    digests: Arc<MessageDigests>,
+}
+
+/// A message as it is saved in snapshots, the journal and spill files. The digests aren't
+/// saved, since they can be computed again.
+#[derive(Deserialize)]
+struct StoredMessage {
+    id: String,
+    content: Arc<str>,
+    attributes: Arc<HashMap<String, MessageAttributeValue>>,
+    receive_count: u8,
+    trace_header: Option<String>,
+    sent: DateTime<Utc>,
+}
+
+impl From<StoredMessage> for Message {
+    fn from(m: StoredMessage) -> Self {
+        Self {
+            id: m.id,
+            digests: Arc::new(MessageDigests::new(&m.content, &m.attributes)),
+            content: m.content,
+            attributes: m.attributes,
+            receive_count: m.receive_count,
+            receipt_handle: ReceiveHandle::new(),
+            trace_header: m.trace_header,
+            sent: m.sent,
+        }
+    }
+}
+
+/// The MD5 digests of a message's body and of all of its attributes.
+#[derive(Debug)]
+struct MessageDigests {
+    body: String,
+    attributes: String,
+}
+
+impl MessageDigests {
+    fn new(content: &str, attributes: &HashMap<String, MessageAttributeValue>) -> Self {
+        let mut hasher = Md5::new();
+        hasher.update(content.as_bytes());
+        let mut attributes: Vec<(&String, &MessageAttributeValue)> = attributes.iter().collect();
+        attributes.sort_by(|a, b| a.0.cmp(b.0));
+        Self {
+            body: format!("{:x}", hasher.finalize()),
+            attributes: get_attributes_md5(&attributes),
+        }
+    }
 }
 
 impl Message {
-    pub fn new(content: &str, attributes: HashMap<String, String>) -> Self {
+    pub fn new(
+        content: Arc<str>,
+        attributes: Arc<HashMap<String, MessageAttributeValue>>,
+        sent: DateTime<Utc>,
+    ) -> Self {
         Self {
-            id: uuid::Uuid::new_v4().to_string(),
-            content: content.to_string(),
+            id: get_new_id(),
+            digests: Arc::new(MessageDigests::new(&content, &attributes)),
+            content,
             attributes,
             receive_count: 0,
             receipt_handle: ReceiveHandle::new(),
+            trace_header: None,
+            sent,
+        }
+    }
+
+    /// Roughly how many bytes the message takes in memory.
+    pub fn get_approximate_size(&self) -> usize {
+        get_approximate_message_size(&self.content, &self.attributes)
+    }
+
+    pub fn get_content_md5(&self) -> &str {
+        &self.digests.body
+    }
+
+    /// Get the requested attributes, sorted by name.
+    fn get_selected_attributes(
+        &self,
+        attribute_names: &[String],
+    ) -> Vec<(&String, &MessageAttributeValue)> {
+        let mut attributes: Vec<(&String, &MessageAttributeValue)> = self
+            .attributes
+            .iter()
+            .filter(|(k, _)| is_attribute_requested(k, attribute_names))
+            .collect();
+        attributes.sort_by(|a, b| a.0.cmp(b.0));
+        attributes
+    }
+
+    pub fn get_attribute(&self, name: &str) -> Option<&MessageAttributeValue> {
+        self.attributes.get(name)
+    }
+
+    /// Get the digest of all of the message's attributes.
+    pub fn get_attribute_md5(&self) -> &str {
+        &self.digests.attributes
+    }
+
+    /// Write the message as it appears in a ReceiveMessage response, with the requested
+    /// system attributes and message attributes.
+    pub fn write_message_xml(
+        &self,
+        w: &mut XmlWriter,
+        system_attribute_names: &[String],
+        attribute_names: &[String],
+    ) {
+        w.text("MessageId", &self.id);
+        w.text("ReceiptHandle", &self.receipt_handle.0);
+        w.text("MD5OfBody", self.get_content_md5());
+        w.text("Body", &self.content);
+        if let Some(trace_header) = &self.trace_header {
+            if is_attribute_requested("AWSTraceHeader", system_attribute_names) {
+                w.element("Attribute", |w| {
+                    w.text("Name", "AWSTraceHeader");
+                    w.text("Value", trace_header);
+                });
+            }
+        }
+
+        let attributes = self.get_selected_attributes(attribute_names);
+        if !attributes.is_empty() {
+            // Only attributes that were asked for are included in the digest.
+            if attributes.len() == self.attributes.len() {
+                w.text("MD5OfMessageAttributes", self.get_attribute_md5());
+            } else {
+                w.text("MD5OfMessageAttributes", &get_attributes_md5(&attributes));
+            }
+            for (k, v) in attributes {
+                w.element("MessageAttribute", |w| {
+                    w.text("Name", k);
+                    w.value("Value", v);
+                });
+            }
         }
     }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct QueuePath(String);
+
+impl QueuePath {
+    pub fn new(region: &str, account_id: &str, queue_name: &str) -> Self {
+        Self(format!("{}/{}/{}", region, account_id, queue_name))
+    }
 
-    pub fn get_content_md5(&self) -> String {
-        let mut hasher = Md5::new();
-        hasher.update(self.content.as_bytes());
-        format!("{:x}", hasher.finalize())
+    pub fn as_str(&self) -> &str {
+        &self.0
     }
 
-    pub fn get_attribute_md5(&self) -> String {
-        let mut hasher = Md5::new();
-        for (k, v) in self.attributes.iter() {
-            hasher.update(k.as_bytes());
-            hasher.update(v.as_bytes());
-        }
-        format!("{:x}", hasher.finalize())
+    pub fn get_region(&self) -> &str {
+        self.0.split('/').next().unwrap_or_default()
     }
 
-    pub fn get_attribute_xml(&self, attribute_names: &[String]) -> String {
-        let mut attributes_str = String::new();
-        for k in attribute_names {
-            if let Some(v) = self.attributes.get(k) {
-                attributes_str.push_str(&format!(
-                    "<Attribute>\
-                        <Name>{}</Name>\
-                        <Value>{}</Value>\
-                     </Attribute>",
-                    escape_xml(k),
-                    escape_xml(v)
-                ));
-            }
-        }
-        attributes_str
+    pub fn get_account_id(&self) -> &str {
+        self.0.split('/').nth(1).unwrap_or_default()
+    }
+
+    pub fn get_name(&self) -> &str {
+        self.0.rsplit('/').next().unwrap_or_default()
     }
 
-    pub fn get_message_xml(&self, attribute_names: &[String]) -> String {
+    pub fn get_arn(&self) -> String {
         format!(
-            "<Message>\
-              <MessageId>{}</MessageId>\
-              <ReceiptHandle>\
-                {}\
-              </ReceiptHandle>\
-              <MD5OfBody>{}</MD5OfBody>\
-              <Body>{}</Body>\
-              {}\
-            </Message>",
-            self.id,
-            self.receipt_handle.0,
-            self.get_content_md5(),
-            escape_xml(&self.content),
-            self.get_attribute_xml(attribute_names),
+            "arn:aws:sqs:{}:{}:{}",
+            self.get_region(),
+            self.get_account_id(),
+            self.get_name()
         )
     }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
-pub struct QueuePath(String);
+/// Roughly how many bytes a message with this body and these attributes takes in memory,
+/// including a fixed allowance for its id, timestamps and bookkeeping.
+pub fn get_approximate_message_size(
+    body: &str,
+    attributes: &HashMap<String, MessageAttributeValue>,
+) -> usize {
+    let attributes_size: usize = attributes
+        .iter()
+        .map(|(name, value)| {
+            let string_size = value.string_value.as_ref().map(|x| x.len()).unwrap_or(0);
+            let binary_size = value.binary_value.as_ref().map(|x| x.len()).unwrap_or(0);
+            name.len() + value.data_type.len() + string_size + binary_size
+        })
+        .sum();
+    MESSAGE_OVERHEAD_BYTES + body.len() + attributes_size
+}
+
+/// A message sent with a delay, which is added to its queue once the delay has passed.
+#[derive(Serialize, Deserialize)]
+pub struct DelayedMessage {
+    pub visible_at: DateTime<Utc>,
+    pub message: Message,
+}
 
+#[derive(Serialize, Deserialize)]
 pub struct SQSQueue {
     pub name: String,
     pub attributes: HashMap<String, String>,
     pub messages: VecDeque<Message>,
-    // Ring the bell when sending messages, if one exists.
-    // This allows us to wait for messages efficiently without polling.
-    pub bell: Option<tokio::sync::oneshot::Sender<bool>>,
+    // The number of messages sent to the queue, not counting redeliveries.
+    pub messages_sent: u64,
+    // Paused queues accept messages but don't hand them out until resumed.
+    #[serde(default)]
+    pub paused: bool,
+    // Messages sent with a delay, by id, until they become visible.
+    #[serde(default)]
+    pub delayed: HashMap<String, DelayedMessage>,
+    // Messages that have been received but not deleted, by receipt handle. These are exported
+    // alongside the queues rather than in them, as they always have been.
+    #[serde(skip)]
+    pub in_flight: HashMap<ReceiveHandle, ReceivedMessage>,
+    // When the current retention check is scheduled for. Earlier checks are skipped.
+    #[serde(skip)]
+    pub next_retention_check: Option<DateTime<Utc>>,
+    // Messages beyond the spill threshold, in order after those in memory. Exports note these
+    // and add them after the rest, with `add_spilled_messages()`.
+    #[serde(skip)]
+    pub spill: Option<Spill>,
+    // Long polls waiting for a message, in the order they started waiting, with their tickets.
+    // Each message that becomes available rings the bell of the first, so that waiting doesn't
+    // need polling.
+    #[serde(skip)]
+    bells: VecDeque<(oneshot::Sender<bool>, Weak<()>)>,
+    // The tickets of long polls that have been woken, each of which has a message set aside
+    // until it takes it or gives up waiting.
+    #[serde(skip)]
+    reserved: Vec<Weak<()>>,
 }
 
 impl SQSQueue {
@@ -186,7 +1785,14 @@ impl SQSQueue {
             name: name.to_string(),
             attributes,
             messages: VecDeque::new(),
-            bell: None,
+            messages_sent: 0,
+            paused: false,
+            delayed: HashMap::new(),
+            in_flight: HashMap::new(),
+            next_retention_check: None,
+            spill: None,
+            bells: VecDeque::new(),
+            reserved: Vec::new(),
         }
     }
 
@@ -197,32 +1803,162 @@ impl SQSQueue {
             .unwrap_or(default.to_string())
     }
 
+    /// Get how long new messages are delayed by default, from the DelaySeconds attribute.
+    pub fn get_delay_seconds(&self) -> u32 {
+        self.attributes
+            .get("DelaySeconds")
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(0)
+    }
+
+    /// Get how long messages are kept, from the MessageRetentionPeriod attribute.
+    pub fn get_retention_period(&self) -> chrono::Duration {
+        let seconds = self
+            .attributes
+            .get("MessageRetentionPeriod")
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(345_600);
+        chrono::Duration::seconds(seconds)
+    }
+
     pub fn set_attribute_default(&mut self, key: &str, default: &str) {
         if let Entry::Vacant(v) = self.attributes.entry(key.to_string()) {
             v.insert(default.to_string());
         }
     }
 
+    /// Whether a message can be received now. Spilled messages can't be until they have been
+    /// read back.
     pub fn has_message(&self) -> bool {
         !self.messages.is_empty()
+            || matches!(&self.spill, Some(x) if x.is_drained() && !x.is_empty())
+    }
+
+    pub fn is_fifo(&self) -> bool {
+        self.attributes
+            .get("FifoQueue")
+            .map(|v| v.eq_ignore_ascii_case("true"))
+            .unwrap_or_else(|| self.name.ends_with(".fifo"))
+    }
+
+    /// The number of messages waiting, including any that have been spilled.
+    pub fn get_message_count(&self) -> usize {
+        self.messages.len() + self.spill.as_ref().map(|x| x.len()).unwrap_or(0)
     }
 
-    pub fn get_waiter(&mut self) -> tokio::sync::oneshot::Receiver<bool> {
-        let (tx, rx) = tokio::sync::oneshot::channel();
-        self.bell = Some(tx);
-        rx
+    /// Wait for a message, behind any long polls already waiting. A long poll that was woken
+    /// but found its message gone, such as after a purge, keeps its place at the front instead.
+    pub fn get_waiter(&mut self, keep_place: bool) -> Waiter {
+        self.refill_from_spill();
+        // Forget long polls that have timed out.
+        self.bells.retain(|(b, _)| !b.is_closed());
+        let (tx, rx) = oneshot::channel();
+        let ticket = Arc::new(());
+        let bell = (tx, Arc::downgrade(&ticket));
+        match keep_place {
+            true => self.bells.push_front(bell),
+            false => self.bells.push_back(bell),
+        }
+        Waiter { bell: rx, ticket }
+    }
+
+    pub fn get_waiter_count(&self) -> usize {
+        self.bells.iter().filter(|(b, _)| !b.is_closed()).count()
+    }
+
+    /// Get how many messages a receive can take, leaving those set aside for long polls woken
+    /// ahead of it. `waiter` is the receive's own place in line, if it has been waiting, and
+    /// any message set aside for it is taken up.
+    pub fn get_receivable_count(&mut self, waiter: Option<&Waiter>) -> usize {
+        let own_ticket = waiter.map(|w| Arc::downgrade(&w.ticket));
+        // This receive takes up its own message, and long polls that gave up no longer need theirs.
+        self.reserved.retain(|t| match &own_ticket {
+            Some(x) if x.ptr_eq(t) => false,
+            _ => t.upgrade().is_some(),
+        });
+        self.refill_from_spill();
+        self.messages.len().saturating_sub(self.reserved.len())
     }
 
     pub fn send_message(&mut self, message: Message) {
-        self.messages.push_back(message);
-        if let Some(sender) = self.bell.take() {
-            if let Err(e) = sender.send(true) {
-                warn!("Failed to notify receiver of message: {:?}", e);
+        if message.receive_count == 0 {
+            self.messages_sent += 1;
+        }
+        match &mut self.spill {
+            Some(spill) => spill.push(message),
+            None => self.messages.push_back(message),
+        }
+        if !self.paused {
+            self.wake_receiver();
+        }
+    }
+
+    /// Stop handing out messages, or start again, waking any pending long poll.
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        if !paused && self.has_message() {
+            self.wake_receiver();
+        }
+    }
+
+    /// Wake the long poll that has been waiting longest, setting a message aside for it. Those
+    /// that have timed out are skipped.
+    pub fn wake_receiver(&mut self) {
+        while let Some((sender, ticket)) = self.bells.pop_front() {
+            if sender.send(true).is_ok() {
+                self.reserved.push(ticket);
+                return;
+            }
+        }
+    }
+
+    /// Wake every long poll, without setting messages aside for them.
+    pub fn wake_all_receivers(&mut self) {
+        for (sender, _) in self.bells.drain(..) {
+            // Any that have timed out no longer need waking.
+            let _ = sender.send(false);
+        }
+    }
+
+    /// Take messages back from the spill once the in-memory backlog runs low. Those in storage
+    /// are read back in the background, so may not be available yet.
+    fn refill_from_spill(&mut self) {
+        let spill = match &mut self.spill {
+            Some(x) if self.messages.len() < x.get_batch_size() => x,
+            _ => return,
+        };
+        match spill.is_drained() {
+            true => {
+                self.messages.extend(spill.take_unwritten());
+                self.spill = None;
+            }
+            false => spill.request_refill(),
+        }
+    }
+
+    /// Receive messages from anywhere in the backlog rather than the front, since standard queues
+    /// make no ordering guarantee. `pick` chooses an index below the given length.
+    pub fn receive_messages_unordered(
+        &mut self,
+        count: u8,
+        mut pick: impl FnMut(usize) -> usize,
+    ) -> Vec<Message> {
+        self.refill_from_spill();
+        let mut messages_out = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            if self.messages.is_empty() {
+                break;
+            }
+            let i = pick(self.messages.len());
+            if let Some(x) = self.messages.remove(i) {
+                messages_out.push(x);
             }
         }
+        messages_out
     }
 
     pub fn receive_messages(&mut self, count: u8) -> Vec<Message> {
+        self.refill_from_spill();
         let mut messages_out = Vec::with_capacity(count as usize);
         for _ in 0..count {
             match self.messages.pop_front() {
@@ -234,6 +1970,14 @@ impl SQSQueue {
     }
 }
 
+/// A long poll's place in line for a queue's messages. Once the bell rings, a message is set
+/// aside for the long poll, which it gives up if the waiter is dropped before taking it.
+pub struct Waiter {
+    pub bell: oneshot::Receiver<bool>,
+    ticket: Arc<()>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct SNSSubscription {
     pub id: String,
     pub arn: String,
@@ -241,48 +1985,83 @@ pub struct SNSSubscription {
     pub protocol: String,
     pub endpoint: String,
     pub topic_arn: String,
+    pub attributes: HashMap<String, String>,
 }
 
 impl SNSSubscription {
-    pub fn new_sqs(topic_arn: &TopicArn, endpoint: &str, account_id: &str) -> Self {
+    pub fn new(topic_arn: &TopicArn, protocol: &str, endpoint: &str, account_id: &str) -> Self {
         let id = get_new_id();
         let arn = format!("{}:{}", topic_arn.0, id);
         Self {
             id,
             arn,
             owner: account_id.to_string(),
-            protocol: "sqs".to_string(),
+            protocol: protocol.to_string(),
             endpoint: endpoint.to_string(),
             topic_arn: topic_arn.0.clone(),
+            attributes: HashMap::new(),
         }
     }
 
-    pub fn get_subscription_xml(&self) -> String {
-        format!(
-            "<member>\
-                <TopicArn>{}</TopicArn>\
-                <Protocol>{}</Protocol>\
-                <SubscriptionArn>{}</SubscriptionArn>\
-                <Owner>{}</Owner>\
-                <Endpoint>{}</Endpoint>\
-            </member>",
-            escape_xml(&self.topic_arn),
-            escape_xml(&self.protocol),
-            escape_xml(&self.arn),
-            escape_xml(&self.owner),
-            escape_xml(&self.endpoint)
+    /// Subscriptions to these protocols must be confirmed by the endpoint in AWS.
+    pub fn requires_confirmation(&self) -> bool {
+        matches!(
+            self.protocol.as_str(),
+            "http" | "https" | "email" | "email-json"
         )
     }
+
+    pub fn is_raw_message_delivery(&self) -> bool {
+        self.attributes
+            .get("RawMessageDelivery")
+            .map(|v| v.eq_ignore_ascii_case("true"))
+            .unwrap_or(false)
+    }
+}
+
+impl ToXml for SNSSubscription {
+    fn write_xml(&self, w: &mut XmlWriter) {
+        w.text("TopicArn", &self.topic_arn);
+        w.text("Protocol", &self.protocol);
+        w.text("SubscriptionArn", &self.arn);
+        w.text("Owner", &self.owner);
+        w.text("Endpoint", &self.endpoint);
+    }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
 pub struct TopicArn(pub String);
 
+impl TopicArn {
+    pub fn get_region(&self) -> &str {
+        self.0.split(':').nth(3).unwrap_or_default()
+    }
+
+    /// The context of the account and region that owns the topic.
+    pub fn get_context(&self) -> RequestContext {
+        RequestContext {
+            account_id: self.get_account_id().to_string(),
+            region: self.get_region().to_string(),
+            endpoint_url: None,
+            trace_header: None,
+        }
+    }
+
+    pub fn get_account_id(&self) -> &str {
+        self.0.split(':').nth(4).unwrap_or_default()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct SNSTopic {
     pub name: String,
     pub arn: String,
     pub attributes: HashMap<String, String>,
     pub subscriptions: Vec<SNSSubscription>,
+    pub data_protection_policy: Option<String>,
+    // FIFO topics only.
+    sequence_number: u128,
+    published_messages: HashMap<String, PublishedMessage>,
 }
 
 impl SNSTopic {
@@ -292,32 +2071,209 @@ impl SNSTopic {
             arn: arn.0.clone(),
             attributes,
             subscriptions: Vec::new(),
+            data_protection_policy: None,
+            sequence_number: 0,
+            published_messages: HashMap::new(),
+        }
+    }
+
+    pub fn get_display_name(&self) -> Option<&String> {
+        self.attributes.get("DisplayName").filter(|x| !x.is_empty())
+    }
+
+    pub fn is_fifo(&self) -> bool {
+        self.attributes
+            .get("FifoTopic")
+            .map(|v| v.eq_ignore_ascii_case("true"))
+            .unwrap_or_else(|| self.name.ends_with(".fifo"))
+    }
+
+    pub fn is_content_based_deduplication(&self) -> bool {
+        self.attributes
+            .get("ContentBasedDeduplication")
+            .map(|v| v.eq_ignore_ascii_case("true"))
+            .unwrap_or(false)
+    }
+
+    /// Find a message published with the same deduplication id within the deduplication window.
+    pub fn find_duplicate(
+        &self,
+        deduplication_id: &str,
+        now: DateTime<Utc>,
+    ) -> Option<&PublishedMessage> {
+        self.published_messages
+            .get(deduplication_id)
+            .filter(|m| m.get_deduplication_expiry() > now)
+    }
+
+    /// Forget a deduplication id, if its window has passed. It may have been reused since
+    /// the timer was scheduled.
+    pub fn expire_published_message(&mut self, deduplication_id: &str, now: DateTime<Utc>) {
+        let expired = self
+            .published_messages
+            .get(deduplication_id)
+            .map(|m| m.get_deduplication_expiry() <= now)
+            .unwrap_or(false);
+        if expired {
+            self.published_messages.remove(deduplication_id);
+        }
+    }
+
+    /// Record a published message for deduplication, and return its sequence number.
+    pub fn add_published_message(
+        &mut self,
+        deduplication_id: &str,
+        message_id: &str,
+        now: DateTime<Utc>,
+    ) -> String {
+        self.sequence_number += 1;
+        let sequence_number = format!("{:020}", self.sequence_number);
+        self.published_messages.insert(
+            deduplication_id.to_string(),
+            PublishedMessage {
+                message_id: message_id.to_string(),
+                sequence_number: sequence_number.clone(),
+                published: now,
+            },
+        );
+        sequence_number
+    }
+
+    /// Get the full set of topic attributes, as returned by GetTopicAttributes.
+    /// Computed attributes are filled in first, then overridden by any user-set attributes.
+    pub fn get_all_attributes(&self, owner: &str) -> HashMap<String, String> {
+        let policy = json!({
+            "Version": "2008-10-17",
+            "Id": "__default_policy_ID",
+            "Statement": [{
+                "Sid": "__default_statement_ID",
+                "Effect": "Allow",
+                "Principal": { "AWS": "*" },
+                "Action": [
+                    "SNS:GetTopicAttributes",
+                    "SNS:SetTopicAttributes",
+                    "SNS:AddPermission",
+                    "SNS:RemovePermission",
+                    "SNS:DeleteTopic",
+                    "SNS:Subscribe",
+                    "SNS:ListSubscriptionsByTopic",
+                    "SNS:Publish"
+                ],
+                "Resource": self.arn,
+                "Condition": { "StringEquals": { "AWS:SourceOwner": owner } }
+            }]
+        });
+        let delivery_policy = self
+            .attributes
+            .get("DeliveryPolicy")
+            .cloned()
+            .unwrap_or_else(|| {
+                json!({
+                    "http": {
+                        "defaultHealthyRetryPolicy": {
+                            "minDelayTarget": 20,
+                            "maxDelayTarget": 20,
+                            "numRetries": 3,
+                            "numMaxDelayRetries": 0,
+                            "numNoDelayRetries": 0,
+                            "numMinDelayRetries": 0,
+                            "backoffFunction": "linear"
+                        },
+                        "disableSubscriptionOverrides": false
+                    }
+                })
+                .to_string()
+            });
+
+        let mut attributes = HashMap::new();
+        attributes.insert("TopicArn".to_string(), self.arn.clone());
+        attributes.insert("Owner".to_string(), owner.to_string());
+        attributes.insert("DisplayName".to_string(), String::new());
+        attributes.insert("Policy".to_string(), policy.to_string());
+        attributes.insert("EffectiveDeliveryPolicy".to_string(), delivery_policy);
+        attributes.insert(
+            "SubscriptionsConfirmed".to_string(),
+            self.subscriptions.len().to_string(),
+        );
+        attributes.insert("SubscriptionsPending".to_string(), "0".to_string());
+        attributes.insert("SubscriptionsDeleted".to_string(), "0".to_string());
+        for (k, v) in self.attributes.iter() {
+            attributes.insert(k.clone(), v.clone());
         }
+        attributes
     }
 
-    pub fn add_subscription(&mut self, subscription: SNSSubscription) {
+    /// Add the subscription and return its ARN.
+    /// If there is already a subscription for the endpoint, its ARN is returned instead.
+    pub fn add_subscription(&mut self, subscription: SNSSubscription) -> String {
         for sub in self.subscriptions.iter() {
             if sub.topic_arn == subscription.topic_arn && sub.endpoint == subscription.endpoint {
                 // Already exists - do nothing.
-                return;
+                return sub.arn.clone();
             }
         }
+        let arn = subscription.arn.clone();
         self.subscriptions.push(subscription);
+        arn
     }
 
     pub fn remove_subscription(&mut self, subscription_arn: &str) {
         self.subscriptions.retain(|s| s.arn != subscription_arn)
     }
 
-    pub fn get_queue_urls(&self) -> Vec<String> {
+    pub fn find_subscription(&self, subscription_arn: &str) -> Option<&SNSSubscription> {
         self.subscriptions
             .iter()
-            .map(|s| s.endpoint.clone())
-            .collect()
+            .find(|s| s.arn == subscription_arn)
     }
+
+    pub fn find_subscription_mut(
+        &mut self,
+        subscription_arn: &str,
+    ) -> Option<&mut SNSSubscription> {
+        self.subscriptions
+            .iter_mut()
+            .find(|s| s.arn == subscription_arn)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub enum DeliveryOutcome {
+    Delivered,
+    EndpointNotFound,
+    UnsupportedProtocol,
+    Failed(String),
+    // Deliberately not delivered, to simulate message loss.
+    Dropped,
+}
+
+/// An attempt to deliver a notification to a subscription.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeliveryAttempt {
+    pub message_id: String,
+    pub protocol: String,
+    pub endpoint: String,
+    pub outcome: DeliveryOutcome,
+    pub latency_ms: f64,
+    pub retry_count: u32,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// A message published to a FIFO topic, kept for the deduplication window.
+#[derive(Serialize, Deserialize)]
+pub struct PublishedMessage {
+    pub message_id: String,
+    pub sequence_number: String,
+    published: DateTime<Utc>,
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+impl PublishedMessage {
+    pub fn get_deduplication_expiry(&self) -> DateTime<Utc> {
+        self.published + chrono::Duration::minutes(DEDUPLICATION_MINUTES)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub struct ReceiveHandle(pub String);
 
 impl ReceiveHandle {
@@ -326,7 +2282,7 @@ impl ReceiveHandle {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ReceivedMessage {
     pub message: Message,
     pub queue_path: QueuePath,
@@ -334,19 +2290,578 @@ pub struct ReceivedMessage {
 }
 
 impl ReceivedMessage {
-    pub fn new(message: Message, queue_path: QueuePath, timeout_seconds: u32) -> Self {
+    pub fn new(
+        message: Message,
+        queue_path: QueuePath,
+        timeout_seconds: u32,
+        now: DateTime<Utc>,
+    ) -> Self {
         Self {
             message,
             queue_path,
-            expires: Utc::now() + chrono::Duration::seconds(timeout_seconds as i64),
+            expires: now + chrono::Duration::seconds(timeout_seconds as i64),
+        }
+    }
+
+    pub fn has_expired(&self, now: DateTime<Utc>) -> bool {
+        now >= self.expires
+    }
+
+    pub fn get_visibility_remaining_seconds(&self, now: DateTime<Utc>) -> i64 {
+        (self.expires - now).num_seconds().max(0)
+    }
+
+    pub fn set_visibility_timeout(&mut self, visibility_timeout: u32, now: DateTime<Utc>) {
+        self.expires = now + chrono::Duration::seconds(visibility_timeout as i64)
+    }
+}
+
+pub struct PlatformApplication {
+    pub name: String,
+    pub arn: String,
+    pub platform: String,
+    pub attributes: HashMap<String, String>,
+    pub endpoints: Vec<PlatformEndpoint>,
+}
+
+impl PlatformApplication {
+    pub fn new(name: &str, arn: &str, platform: &str, attributes: HashMap<String, String>) -> Self {
+        Self {
+            name: name.to_string(),
+            arn: arn.to_string(),
+            platform: platform.to_string(),
+            attributes,
+            endpoints: Vec::new(),
+        }
+    }
+
+    /// Add an endpoint for the specified device token and return its ARN.
+    /// Creating an endpoint is idempotent, so an existing endpoint for the same token is reused.
+    pub fn add_endpoint(
+        &mut self,
+        token: &str,
+        custom_user_data: Option<&str>,
+        attributes: HashMap<String, String>,
+    ) -> String {
+        if let Some(e) = self.endpoints.iter().find(|e| e.token == token) {
+            return e.arn.clone();
+        }
+
+        let arn = format!(
+            "{}/{}",
+            self.arn.replacen(":app/", ":endpoint/", 1),
+            get_new_id()
+        );
+        let mut endpoint_attributes = attributes;
+        endpoint_attributes.insert("Token".to_string(), token.to_string());
+        if let Some(data) = custom_user_data {
+            endpoint_attributes.insert("CustomUserData".to_string(), data.to_string());
+        }
+        if let Entry::Vacant(v) = endpoint_attributes.entry("Enabled".to_string()) {
+            v.insert("true".to_string());
+        }
+
+        self.endpoints.push(PlatformEndpoint {
+            arn: arn.clone(),
+            platform: self.platform.clone(),
+            token: token.to_string(),
+            attributes: endpoint_attributes,
+        });
+        arn
+    }
+}
+
+#[derive(Clone)]
+pub struct PlatformEndpoint {
+    pub arn: String,
+    pub platform: String,
+    pub token: String,
+    pub attributes: HashMap<String, String>,
+}
+
+impl ToXml for PlatformEndpoint {
+    fn write_xml(&self, w: &mut XmlWriter) {
+        w.text("EndpointArn", &self.arn);
+        w.element("Attributes", |w| {
+            w.entries(&self.attributes.iter().collect())
+        });
+    }
+}
+
+/// A notification published to a platform endpoint, captured for later inspection.
+#[derive(Debug, Clone, Serialize)]
+pub struct PushMessage {
+    pub message_id: String,
+    pub endpoint_arn: String,
+    pub platform: String,
+    pub token: String,
+    pub message: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// A summary of the current state, for readiness probes and debugging.
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    pub uptime_seconds: i64,
+    pub queues: usize,
+    pub topics: usize,
+    pub subscriptions: usize,
+    pub messages: usize,
+    pub in_flight_messages: usize,
+    pub pending_long_polls: usize,
+    // Approximate, as of the last recount.
+    pub memory_used_bytes: usize,
+    pub shutting_down: bool,
+}
+
+/// A queue and its current message counts, for the admin API.
+#[derive(Debug, Serialize)]
+pub struct QueueSummary {
+    pub name: String,
+    pub url: String,
+    pub region: String,
+    pub account_id: String,
+    pub attributes: HashMap<String, String>,
+    pub messages_visible: usize,
+    pub messages_in_flight: usize,
+    pub messages_delayed: usize,
+    pub paused: bool,
+}
+
+/// A received message awaiting deletion, for the admin API.
+#[derive(Debug, Serialize)]
+pub struct InFlightMessage {
+    pub receipt_handle: String,
+    // The queue path the message will be returned to.
+    pub queue: String,
+    pub message_id: String,
+    pub receive_count: u8,
+    pub visibility_remaining_seconds: i64,
+}
+
+/// A message found by `State::find_messages()`.
+#[derive(Serialize)]
+pub struct FoundMessage {
+    pub queue: String,
+    pub in_flight: bool,
+    pub message: Message,
+}
+
+/// A topic and its subscriptions, for the admin API.
+#[derive(Serialize)]
+pub struct TopicSummary {
+    pub name: String,
+    pub arn: String,
+    pub attributes: HashMap<String, String>,
+    pub subscriptions: Vec<SNSSubscription>,
+}
+
+/// Every queue and topic, from `State::snapshot()`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StateView {
+    pub queues: BTreeMap<String, QueueView>,
+    pub topics: BTreeMap<String, TopicView>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QueueView {
+    pub attributes: BTreeMap<String, String>,
+    // The messages waiting in memory, in the order they will be received.
+    pub messages: Vec<MessageView>,
+    // The number of messages spilled out of memory, which come after those in memory.
+    pub messages_spilled: usize,
+    // Received messages that have not been deleted, soonest to become visible first.
+    pub in_flight: Vec<InFlightView>,
+    pub paused: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MessageView {
+    pub message_id: String,
+    pub body: String,
+    pub message_attributes: BTreeMap<String, MessageAttributeValue>,
+    pub receive_count: u8,
+    pub sent: DateTime<Utc>,
+}
+
+impl MessageView {
+    fn new(message: &Message) -> Self {
+        Self {
+            message_id: message.id.clone(),
+            body: message.content.to_string(),
+            message_attributes: message
+                .attributes
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+            receive_count: message.receive_count,
+            sent: message.sent,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct InFlightView {
+    pub receipt_handle: String,
+    // When the message returns to the queue unless it is deleted.
+    pub visible_at: DateTime<Utc>,
+    pub message: MessageView,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TopicView {
+    pub attributes: BTreeMap<String, String>,
+    pub subscriptions: Vec<SubscriptionView>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SubscriptionView {
+    pub arn: String,
+    pub protocol: String,
+    pub endpoint: String,
+    pub attributes: BTreeMap<String, String>,
+}
+
+/// A message being sent, published, received or deleted, for the admin event stream.
+#[derive(Debug, Clone, Serialize)]
+pub struct MessageEvent {
+    pub timestamp: DateTime<Utc>,
+    pub action: String,
+    // The queue path or topic ARN.
+    pub resource: String,
+    pub message_id: String,
+    pub body: Option<String>,
+}
+
+impl MessageEvent {
+    /// Whether the event is a new message arriving on a queue.
+    pub fn is_arrival(&self) -> bool {
+        self.action == "SendMessage" || self.action == "Deliver"
+    }
+}
+
+/// Something that happened to a message, for its lifecycle trace.
+#[derive(Debug, Clone, Serialize)]
+pub struct LifecycleEvent {
+    pub timestamp: DateTime<Utc>,
+    pub event: String,
+    // The queue path, topic ARN or subscription ARN.
+    pub resource: String,
+    pub detail: Option<String>,
+}
+
+/// A mutating action, recorded in the audit log.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditRecord {
+    pub timestamp: DateTime<Utc>,
+    pub action: String,
+    pub source_ip: Option<String>,
+    pub account_id: String,
+    pub region: String,
+    pub params: HashMap<String, String>,
+    // The error code, if the action failed.
+    pub error: Option<String>,
+    // Seeds the ids the action generates, so that it generates the same ones when it's applied
+    // from the journal.
+    #[serde(skip)]
+    pub id_seed: u64,
+}
+
+impl AuditRecord {
+    pub fn new(
+        action: &str,
+        source_ip: Option<IpAddr>,
+        ctx: &RequestContext,
+        params: &HashMap<String, String>,
+    ) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            action: action.to_string(),
+            source_ip: source_ip.map(|x| x.to_string()),
+            account_id: ctx.account_id.clone(),
+            region: ctx.region.clone(),
+            params: params.clone(),
+            error: None,
+            id_seed: get_new_seed(),
+        }
+    }
+}
+
+/// Queues, topics and in-flight messages, as exported by `State::export()`.
+#[derive(Deserialize)]
+pub struct Snapshot {
+    pub queues: HashMap<QueuePath, SQSQueue>,
+    pub topics: HashMap<TopicArn, SNSTopic>,
+    pub received_messages: HashMap<ReceiveHandle, ReceivedMessage>,
+}
+
+/// A record delivered to a firehose subscription.
+#[derive(Debug, Clone, Serialize)]
+pub struct FirehoseRecord {
+    pub delivery_stream_arn: String,
+    pub data: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::misc::with_id_generator;
+    use crate::spill::add_spilled_messages;
+
+    fn get_context(region: &str, account_id: &str) -> RequestContext {
+        RequestContext {
+            account_id: account_id.to_string(),
+            region: region.to_string(),
+            endpoint_url: None,
+            trace_header: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_message_ids_per_server() {
+        let new_message_id =
+            || async { Message::new("body".into(), Arc::default(), Utc::now()).id };
+        let first = Arc::new(IdGenerator::deterministic(42));
+        let second = Arc::new(IdGenerator::deterministic(42));
+
+        // Servers with the same seed get the same ids, however their requests interleave.
+        let first_ids = [
+            with_id_generator(first.clone(), new_message_id()).await,
+            with_id_generator(first.clone(), new_message_id()).await,
+        ];
+        let second_id = with_id_generator(second.clone(), new_message_id()).await;
+        let third_id = with_id_generator(first, new_message_id()).await;
+        let second_ids = [
+            second_id,
+            with_id_generator(second.clone(), new_message_id()).await,
+            with_id_generator(second, new_message_id()).await,
+        ];
+        assert_eq!(first_ids[..], second_ids[..2]);
+        assert_eq!(third_id, second_ids[2]);
+        assert_ne!(first_ids[0], first_ids[1]);
+    }
+
+    #[test]
+    fn test_remote_queue_url() {
+        let s = State::new(9324, "us-east-1", "000000000000");
+        let mut ctx = get_context("us-east-1", "000000000000");
+        ctx.endpoint_url = Some("https://queues.example.com".to_string());
+        let local = [
+            "http://localhost:9324/000000000000/orders",
+            "http://127.0.0.1:9324/000000000000/orders",
+            "http://sqs.eu-west-1.smoqs.local:9324/000000000000/orders",
+            "https://queues.example.com/000000000000/orders",
+            "https://sqs.us-east-1.amazonaws.com/000000000000/orders",
+            "arn:aws:sqs:us-east-1:000000000000:orders",
+        ];
+        for url in local.iter() {
+            assert!(!s.is_remote_queue_url(&ctx, url), "{}", url);
         }
+        let remote = [
+            "http://localhost:3566/000000000000/orders",
+            "http://smoqs-other:9324/000000000000/orders",
+            "http://queues.example.com:9324/000000000000/orders",
+        ];
+        for url in remote.iter() {
+            assert!(s.is_remote_queue_url(&ctx, url), "{}", url);
+        }
+    }
+
+    #[test]
+    fn test_queue_arn_for_other_region_and_account() {
+        let s = State::new(3566, "us-east-1", "000000000000");
+        let ctx = get_context("us-east-1", "000000000000");
+        s.add_queue(&ctx, SQSQueue::new("orders", HashMap::new()));
+        let expected = QueuePath::new("us-east-1", "000000000000", "orders");
+
+        let arns = [
+            "arn:aws:sqs:us-east-1:000000000000:orders",
+            "arn:aws:sqs:us-east-1:123456789012:orders",
+            "arn:aws:sqs:eu-west-1:000000000000:orders",
+            "arn:aws:sqs:eu-west-1:123456789012:orders",
+        ];
+        for arn in arns.iter() {
+            assert_eq!(s.get_queue_path(&ctx, arn), expected, "{}", arn);
+        }
+
+        let missing = "arn:aws:sqs:eu-west-1:123456789012:missing";
+        let path = s.get_queue_path(&ctx, missing);
+        assert_eq!(path, QueuePath::new("eu-west-1", "123456789012", "missing"));
+    }
+
+    #[test]
+    fn test_queue_arn_prefers_exact_match() {
+        let s = State::new(3566, "us-east-1", "000000000000");
+        let ctx = get_context("us-east-1", "000000000000");
+        let other_ctx = get_context("eu-west-1", "123456789012");
+        s.add_queue(&ctx, SQSQueue::new("orders", HashMap::new()));
+        s.add_queue(&other_ctx, SQSQueue::new("orders", HashMap::new()));
+
+        let path = s.get_queue_path(&ctx, "arn:aws:sqs:eu-west-1:123456789012:orders");
+        assert_eq!(path, QueuePath::new("eu-west-1", "123456789012", "orders"));
+        let path = s.get_queue_path(&ctx, "arn:aws:sqs:eu-west-1:555555555555:orders");
+        assert_eq!(path, QueuePath::new("eu-west-1", "123456789012", "orders"));
+    }
+
+    #[test]
+    fn test_apply_journal_entries_again() {
+        let s = State::new(3566, "us-east-1", "000000000000");
+        let ctx = get_context("us-east-1", "000000000000");
+        s.add_queue(&ctx, SQSQueue::new("orders", HashMap::new()));
+        let path = QueuePath::new("us-east-1", "000000000000", "orders");
+        let queue = s.get_queue(&path).unwrap();
+        let message = Message::new("hello".into(), Arc::default(), s.now());
+        let id = message.id.clone();
+        let handle = ReceiveHandle::new();
+        let received = ReceivedMessage::new(message.clone(), path.clone(), 30, s.now());
+
+        let sent = || JournalEntry::MessageSent {
+            queue: path.clone(),
+            message: message.clone(),
+        };
+        let receive = || JournalEntry::MessageReceived {
+            receipt_handle: handle.clone(),
+            message: received.clone(),
+        };
+        s.apply_journal_entry(sent());
+        s.apply_journal_entry(sent());
+        assert_eq!(read(&queue).messages.len(), 1);
+
+        // A message that's in flight isn't queued again.
+        s.apply_journal_entry(receive());
+        s.apply_journal_entry(receive());
+        s.apply_journal_entry(sent());
+        assert!(read(&queue).messages.is_empty());
+        assert_eq!(read(&queue).in_flight.len(), 1);
+        assert_eq!(read(&queue).in_flight[&handle].message.id, id);
+
+        s.apply_journal_entry(JournalEntry::MessageDeleted {
+            queue: path.clone(),
+            message_id: id,
+        });
+        assert!(read(&queue).messages.is_empty());
+        assert!(read(&queue).in_flight.is_empty());
+    }
+
+    fn run_spill_jobs(s: &State) {
+        let results = s
+            .get_spill_jobs()
+            .into_iter()
+            .map(|(path, job)| {
+                let result = job.run();
+                (path, job, result)
+            })
+            .collect();
+        s.finish_spill_jobs(results);
+    }
+
+    #[test]
+    fn test_spilled_messages() {
+        let mut s = State::new(3566, "us-east-1", "000000000000");
+        s.spill_threshold = Some(2);
+        s.spill_dir = std::env::temp_dir().join(format!("smoqs-test-{}", get_new_id()));
+        let ctx = get_context("us-east-1", "000000000000");
+        s.add_queue(&ctx, SQSQueue::new("orders", HashMap::new()));
+        let path = QueuePath::new("us-east-1", "000000000000", "orders");
+        let queue = s.get_queue(&path).unwrap();
+        let bodies: Vec<String> = (0..7).map(|i| format!("message {}", i)).collect();
+        for body in &bodies[..5] {
+            let message = Message::new(body.as_str().into(), Arc::default(), Utc::now());
+            write(&queue).send_message(message);
+        }
+        run_spill_jobs(&s);
+        for body in &bodies[5..] {
+            let message = Message::new(body.as_str().into(), Arc::default(), Utc::now());
+            write(&queue).send_message(message);
+        }
+        {
+            let q = read(&queue);
+            assert_eq!((q.messages.len(), q.get_message_count()), (2, 7));
+        }
+
+        // Exports include spilled messages, whether or not they have been written yet.
+        let (mut export, spilled) = s.export();
+        add_spilled_messages(&mut export, spilled);
+        let exported: Vec<&str> = export["queues"][path.as_str()]["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["content"].as_str().unwrap())
+            .collect();
+        assert_eq!(exported, bodies);
+
+        let mut received = Vec::new();
+        while received.len() < bodies.len() {
+            let messages = write(&queue).receive_messages(10);
+            if messages.is_empty() {
+                run_spill_jobs(&s);
+            }
+            received.extend(messages.into_iter().map(|m| m.content.to_string()));
+        }
+        assert_eq!(received, bodies);
+        assert!(read(&queue).spill.is_none());
+        std::fs::remove_dir(&s.spill_dir).unwrap();
     }
 
-    pub fn has_expired(&self) -> bool {
-        Utc::now() > self.expires
+    #[test]
+    fn test_unreadable_spill_file() {
+        let mut s = State::new(3566, "us-east-1", "000000000000");
+        s.spill_threshold = Some(1);
+        s.spill_dir = std::env::temp_dir().join(format!("smoqs-test-{}", get_new_id()));
+        let ctx = get_context("us-east-1", "000000000000");
+        s.add_queue(&ctx, SQSQueue::new("orders", HashMap::new()));
+        let path = QueuePath::new("us-east-1", "000000000000", "orders");
+        let queue = s.get_queue(&path).unwrap();
+        for body in ["first", "second", "third"].iter() {
+            let message = Message::new((*body).into(), Arc::default(), Utc::now());
+            write(&queue).send_message(message);
+        }
+        run_spill_jobs(&s);
+        std::fs::remove_dir_all(&s.spill_dir).unwrap();
+        let message = Message::new("fourth".into(), Arc::default(), Utc::now());
+        write(&queue).send_message(message);
+
+        // The messages in the file are lost, but the spill stops using it.
+        let received = write(&queue).receive_messages(10);
+        assert_eq!(&*received[0].content, "first");
+        run_spill_jobs(&s);
+        let mut q = write(&queue);
+        assert!(q.spill.is_none());
+        let received = q.receive_messages(10);
+        assert_eq!(&*received[0].content, "fourth");
     }
 
-    pub fn set_visibility_timeout(&mut self, visibility_timeout: u32) {
-        self.expires = Utc::now() + chrono::Duration::seconds(visibility_timeout as i64)
+    #[test]
+    fn test_competing_long_polls() {
+        let mut q = SQSQueue::new("orders", HashMap::new());
+        let new_message = |body: &str| Message::new(body.into(), Arc::default(), Utc::now());
+        let mut first = q.get_waiter(false);
+        let mut second = q.get_waiter(false);
+        assert_eq!(q.get_waiter_count(), 2);
+
+        // The long poll that waited longest is woken, and its message is set aside for it.
+        q.send_message(new_message("first"));
+        assert_eq!(first.bell.try_recv(), Ok(true));
+        assert!(second.bell.try_recv().is_err());
+        assert_eq!(q.get_receivable_count(None), 0);
+        assert_eq!(q.get_receivable_count(Some(&second)), 0);
+        assert_eq!(q.get_receivable_count(Some(&first)), 1);
+        assert_eq!(&*q.receive_messages(1)[0].content, "first");
+
+        // One that is woken but finds its message gone waits again, ahead of later arrivals.
+        q.send_message(new_message("second"));
+        assert_eq!(second.bell.try_recv(), Ok(true));
+        let mut third = q.get_waiter(false);
+        q.messages.clear();
+        assert_eq!(q.get_receivable_count(Some(&second)), 0);
+        let mut second = q.get_waiter(true);
+        q.send_message(new_message("third"));
+        assert_eq!(second.bell.try_recv(), Ok(true));
+        assert!(third.bell.try_recv().is_err());
+
+        // One that gives up waiting leaves its message for others.
+        drop(second);
+        assert_eq!(q.get_receivable_count(None), 1);
     }
 }
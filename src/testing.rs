@@ -0,0 +1,152 @@
+//! Helpers for integration tests, which act on a server's state directly rather than over
+//! HTTP. They make the same requests a client would, so queues and topics behave exactly as
+//! they do for SDK clients.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), String> {
+//! use smoqs::testing;
+//! use std::time::Duration;
+//!
+//! let server = smoqs::Server::spawn().await?;
+//! let state = server.get_state();
+//! let queue_url = testing::create_queue(&state, "orders").await?;
+//! let topic_arn = testing::create_topic(&state, "order-events").await?;
+//! testing::subscribe_queue(&state, &topic_arn, &queue_url).await?;
+//!
+//! testing::publish_json(&state, &topic_arn, &serde_json::json!({"id": 1})).await?;
+//! let body = testing::wait_for_message(&state, &queue_url, Duration::from_secs(5)).await?;
+//! assert!(body.is_some());
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::api::execute;
+#[cfg(feature = "sqs")]
+use crate::api::{
+    CreateQueueRequest, DeleteMessageRequest, ReceiveMessageRequest, SendMessageRequest,
+};
+#[cfg(feature = "sns")]
+use crate::api::{CreateTopicRequest, PublishRequest, SubscribeRequest};
+use crate::state::State;
+#[cfg(feature = "sns")]
+use serde::Serialize;
+use std::sync::Arc;
+#[cfg(feature = "sqs")]
+use std::time::Duration;
+
+/// Create a queue with the default attributes, in the default region and account, and
+/// return its URL. If the queue already exists, its URL is returned.
+#[cfg(feature = "sqs")]
+pub async fn create_queue(state: &Arc<State>, name: &str) -> Result<String, String> {
+    let response = execute(state, &CreateQueueRequest::new(name)).await?;
+    Ok(response.queue_url)
+}
+
+/// Create a topic in the default region and account, and return its ARN.
+#[cfg(feature = "sns")]
+pub async fn create_topic(state: &Arc<State>, name: &str) -> Result<String, String> {
+    let response = execute(state, &CreateTopicRequest::new(name)).await?;
+    Ok(response.topic_arn)
+}
+
+/// Subscribe a queue to a topic, and return the subscription ARN. Messages are delivered in
+/// the SNS envelope unless `RawMessageDelivery` is set on the subscription.
+#[cfg(feature = "sns")]
+pub async fn subscribe_queue(
+    state: &Arc<State>,
+    topic_arn: &str,
+    queue_url: &str,
+) -> Result<String, String> {
+    let request = SubscribeRequest::new(topic_arn, "sqs", queue_url);
+    let response = execute(state, &request).await?;
+    Ok(response.subscription_arn)
+}
+
+/// Send a message to a queue, and return its id.
+#[cfg(feature = "sqs")]
+pub async fn send_message(
+    state: &Arc<State>,
+    queue_url: &str,
+    body: &str,
+) -> Result<String, String> {
+    let response = execute(state, &SendMessageRequest::new(queue_url, body)).await?;
+    Ok(response.message_id)
+}
+
+/// Publish a value to a topic as JSON, and return the message id.
+#[cfg(feature = "sns")]
+pub async fn publish_json<T: Serialize>(
+    state: &Arc<State>,
+    topic_arn: &str,
+    message: &T,
+) -> Result<String, String> {
+    let message = serde_json::to_string(message).map_err(|e| e.to_string())?;
+    let response = execute(state, &PublishRequest::new(topic_arn, &message)).await?;
+    Ok(response.message_id)
+}
+
+/// Receive messages, delete them, and return their bodies.
+#[cfg(feature = "sqs")]
+async fn receive_and_delete(
+    state: &Arc<State>,
+    request: &ReceiveMessageRequest,
+) -> Result<Vec<String>, String> {
+    let response = execute(state, request).await?;
+    let mut bodies = Vec::new();
+    for message in response.messages {
+        let request = DeleteMessageRequest {
+            queue_url: request.queue_url.clone(),
+            receipt_handle: message.receipt_handle,
+        };
+        execute(state, &request).await?;
+        bodies.push(message.body);
+    }
+    Ok(bodies)
+}
+
+/// Receive and delete every message that is currently visible in a queue, and return their
+/// bodies in the order they were received.
+#[cfg(feature = "sqs")]
+pub async fn drain_queue(state: &Arc<State>, queue_url: &str) -> Result<Vec<String>, String> {
+    let request = ReceiveMessageRequest {
+        max_number_of_messages: Some(10),
+        ..ReceiveMessageRequest::new(queue_url)
+    };
+    let mut bodies = Vec::new();
+    loop {
+        let batch = receive_and_delete(state, &request).await?;
+        if batch.is_empty() {
+            return Ok(bodies);
+        }
+        bodies.extend(batch);
+    }
+}
+
+/// Wait up to `timeout` for a message to arrive in a queue, then delete it and return its
+/// body. Returns `None` if no message arrived in time.
+#[cfg(feature = "sqs")]
+pub async fn wait_for_message(
+    state: &Arc<State>,
+    queue_url: &str,
+    timeout: Duration,
+) -> Result<Option<String>, String> {
+    let request = ReceiveMessageRequest {
+        wait_time_seconds: Some(20),
+        ..ReceiveMessageRequest::new(queue_url)
+    };
+    let receive = async {
+        loop {
+            if let Some(body) = receive_and_delete(state, &request).await?.pop() {
+                return Ok(Some(body));
+            }
+            // Long polls return straight away once the server is shutting down.
+            if state.is_shutting_down() {
+                return Ok(None);
+            }
+        }
+    };
+    match tokio::time::timeout(timeout, receive).await {
+        Ok(result) => result,
+        Err(_) => Ok(None),
+    }
+}
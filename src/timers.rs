@@ -0,0 +1,84 @@
+//! Every change that happens because time has passed, rather than because of a request, is
+//! scheduled as a timer: delayed messages becoming visible, visibility timeouts expiring,
+//! messages passing their queue's retention period and FIFO deduplication ids expiring.
+//! A single task runs the timers as they come due.
+
+use crate::misc::lock;
+use crate::state::{QueuePath, ReceiveHandle, State, TopicArn};
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::Notify;
+use tokio::time::delay_for;
+
+#[derive(Debug, Clone)]
+pub enum Timer {
+    /// A delayed message becomes visible in its queue.
+    DelayElapsed(QueuePath, String),
+    /// A received message becomes visible again, unless it has been deleted or its
+    /// visibility timeout has been changed since.
+    VisibilityExpired(QueuePath, ReceiveHandle),
+    /// Delete the messages in a queue that are past its retention period. The time is when
+    /// the check was scheduled for, so that checks which have been replaced are skipped.
+    RetentionCheck(QueuePath, DateTime<Utc>),
+    /// A deduplication id leaves a FIFO topic's deduplication window.
+    DeduplicationExpired(TopicArn, String),
+}
+
+/// Pending timers, in the order they are due. Timers are never cancelled. Instead, each one
+/// checks whether it still applies when it runs, which keeps scheduling cheap.
+pub struct Timers {
+    pending: BTreeMap<(DateTime<Utc>, u64), Timer>,
+    // Breaks ties between timers due at the same time, so they run in the order scheduled.
+    next_id: u64,
+    // Wakes the timer task when a timer is scheduled before the one it is waiting for.
+    changed: Arc<Notify>,
+}
+
+impl Timers {
+    pub fn new() -> Self {
+        Self {
+            pending: BTreeMap::new(),
+            next_id: 0,
+            changed: Arc::new(Notify::new()),
+        }
+    }
+
+    pub fn schedule(&mut self, due: DateTime<Utc>, timer: Timer) {
+        if self.get_next_due().map(|next| due < next).unwrap_or(true) {
+            self.changed.notify();
+        }
+        self.pending.insert((due, self.next_id), timer);
+        self.next_id += 1;
+    }
+
+    /// Remove and return the timers that are due at `now`, in order.
+    pub fn take_due(&mut self, now: DateTime<Utc>) -> Vec<Timer> {
+        let later = self.pending.split_off(&(now, u64::MAX));
+        let due = std::mem::replace(&mut self.pending, later);
+        due.values().cloned().collect()
+    }
+
+    pub fn get_next_due(&self) -> Option<DateTime<Utc>> {
+        self.pending.keys().next().map(|(due, _)| *due)
+    }
+}
+
+/// Run timers as they come due. With the virtual clock, timers only come due when the clock
+/// is advanced, which runs them straight away, so this just waits for new timers.
+pub async fn run_timers(state: Arc<State>) {
+    let changed = lock(&state.timers).changed.clone();
+    loop {
+        state.run_due_timers();
+        let wait = state.get_time_until_next_timer();
+        match wait {
+            Some(wait) => {
+                tokio::select! {
+                    _ = delay_for(wait) => {}
+                    _ = changed.notified() => {}
+                }
+            }
+            None => changed.notified().await,
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use crate::conn::Connection;
+use log::debug;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use tokio_rustls::rustls::{NoClientAuth, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+// Connections that have completed the handshake, waiting for the server to take them.
+const MAX_PENDING_CONNECTIONS: usize = 64;
+
+/// Load the certificate chain and private key (PKCS#8 or RSA) for serving HTTPS.
+pub fn load_tls_config(cert_path: &Path, key_path: &Path) -> Result<Arc<ServerConfig>, String> {
+    let open = |path: &Path| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| format!("Unable to read {}: {}", path.display(), e))
+    };
+    let cert_chain = certs(&mut open(cert_path)?)
+        .map_err(|_| format!("Invalid certificate in {}", cert_path.display()))?;
+    let mut keys = pkcs8_private_keys(&mut open(key_path)?).unwrap_or_default();
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut open(key_path)?).unwrap_or_default();
+    }
+    let key = keys
+        .into_iter()
+        .next()
+        .ok_or_else(|| format!("No private key found in {}", key_path.display()))?;
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(cert_chain, key)
+        .map_err(|e| format!("Invalid certificate or key: {}", e))?;
+    config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+    Ok(Arc::new(config))
+}
+
+/// Complete the TLS handshake on each accepted connection. Handshakes run concurrently, and
+/// those that fail are dropped rather than stopping the server. A handshake counts towards the
+/// connection's header read timeout.
+pub fn accept_tls(
+    mut connections: mpsc::Receiver<Connection<TcpStream>>,
+    config: Arc<ServerConfig>,
+) -> mpsc::Receiver<TlsStream<Connection<TcpStream>>> {
+    let acceptor = TlsAcceptor::from(config);
+    let (tx, rx) = mpsc::channel(MAX_PENDING_CONNECTIONS);
+    tokio::spawn(async move {
+        while let Some(connection) = connections.recv().await {
+            let remote_addr = connection.remote_addr();
+            let acceptor = acceptor.clone();
+            let mut tx = tx.clone();
+            tokio::spawn(async move {
+                match acceptor.accept(connection).await {
+                    Ok(x) => {
+                        let _ = tx.send(x).await;
+                    }
+                    Err(e) => debug!("TLS handshake with {} failed: {}", remote_addr, e),
+                }
+            });
+        }
+    });
+    rx
+}
@@ -0,0 +1,578 @@
+use crate::misc::get_new_id;
+use crate::xml::{get_elements, get_raw_elements};
+use md5::{Digest, Md5};
+use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+const SQS_VERSION: &str = "2012-11-05";
+const SNS_VERSION: &str = "2010-03-31";
+
+type CheckResult = Result<(), String>;
+
+struct Response {
+    status: u16,
+    body: String,
+}
+
+impl Response {
+    fn get(&self, tag: &str) -> Option<String> {
+        get_elements(&self.body, tag).into_iter().next()
+    }
+
+    fn require(&self, tag: &str) -> Result<String, String> {
+        self.get(tag)
+            .ok_or_else(|| format!("The response has no {}: {}", tag, self.body))
+    }
+
+    fn expect_success(self, action: &str) -> Result<Self, String> {
+        if self.status == 200 {
+            Ok(self)
+        } else {
+            Err(format!(
+                "{} returned HTTP status {}: {}",
+                action, self.status, self.body
+            ))
+        }
+    }
+
+    fn expect_error(&self, status: u16, code: &str) -> CheckResult {
+        let actual_code = self.get("Code").unwrap_or_default();
+        if self.status == status && actual_code == code {
+            Ok(())
+        } else {
+            Err(format!(
+                "Expected HTTP status {} with code {}, got {} with code {}",
+                status, code, self.status, actual_code
+            ))
+        }
+    }
+}
+
+/// A message attribute to send. Binary values are base64-encoded.
+struct TestAttribute {
+    name: &'static str,
+    data_type: &'static str,
+    value: &'static str,
+}
+
+const TEST_ATTRIBUTES: &[TestAttribute] = &[
+    TestAttribute {
+        name: "Colour",
+        data_type: "String",
+        value: "blue",
+    },
+    TestAttribute {
+        name: "Count",
+        data_type: "Number",
+        value: "3",
+    },
+    TestAttribute {
+        name: "Payload",
+        data_type: "Binary",
+        value: "AQID",
+    },
+];
+
+/// The MD5 of message attributes, computed independently of smoqs as documented by AWS.
+fn get_expected_attributes_md5(attributes: &[TestAttribute]) -> String {
+    fn update_length_prefixed(hasher: &mut Md5, bytes: &[u8]) {
+        hasher.update((bytes.len() as u32).to_be_bytes());
+        hasher.update(bytes);
+    }
+
+    let sorted: BTreeMap<_, _> = attributes.iter().map(|a| (a.name, a)).collect();
+    let mut hasher = Md5::new();
+    for (name, a) in sorted {
+        update_length_prefixed(&mut hasher, name.as_bytes());
+        update_length_prefixed(&mut hasher, a.data_type.as_bytes());
+        if a.data_type == "Binary" {
+            hasher.update([2]);
+            update_length_prefixed(&mut hasher, &base64::decode(a.value).unwrap_or_default());
+        } else {
+            hasher.update([1]);
+            update_length_prefixed(&mut hasher, a.value.as_bytes());
+        }
+    }
+    hex::encode(hasher.finalize())
+}
+
+fn get_md5(data: &str) -> String {
+    hex::encode(Md5::digest(data.as_bytes()))
+}
+
+fn expect_eq(what: &str, expected: &str, actual: &str) -> CheckResult {
+    if expected == actual {
+        Ok(())
+    } else {
+        Err(format!("Expected {} {}, got {}", what, expected, actual))
+    }
+}
+
+/// Makes requests the way the AWS SDKs do, and tracks the resources created so they can be
+/// deleted afterwards.
+struct Verifier {
+    client: reqwest::Client,
+    endpoint: String,
+    prefix: String,
+    queues: RefCell<Vec<String>>,
+    topics: RefCell<Vec<String>>,
+}
+
+impl Verifier {
+    fn new(endpoint: &str) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            endpoint: endpoint.to_string(),
+            prefix: format!("smoqs-verify-{}", &get_new_id()[..8]),
+            queues: RefCell::new(Vec::new()),
+            topics: RefCell::new(Vec::new()),
+        }
+    }
+
+    async fn call(
+        &self,
+        version: &str,
+        action: &str,
+        params: &[(&str, &str)],
+    ) -> Result<Response, String> {
+        let mut form = vec![("Action", action), ("Version", version)];
+        form.extend_from_slice(params);
+        let response = self
+            .client
+            .post(&self.endpoint)
+            .form(&form)
+            .send()
+            .await
+            .map_err(|e| format!("{} failed: {}", action, e))?;
+        let status = response.status().as_u16();
+        let body = response
+            .text()
+            .await
+            .map_err(|e| format!("{} failed: {}", action, e))?;
+        Ok(Response { status, body })
+    }
+
+    async fn sqs(&self, action: &str, params: &[(&str, &str)]) -> Result<Response, String> {
+        self.call(SQS_VERSION, action, params).await
+    }
+
+    async fn sns(&self, action: &str, params: &[(&str, &str)]) -> Result<Response, String> {
+        self.call(SNS_VERSION, action, params).await
+    }
+
+    async fn create_queue(&self, suffix: &str) -> Result<String, String> {
+        let name = format!("{}-{}", self.prefix, suffix);
+        let response = self
+            .sqs("CreateQueue", &[("QueueName", &name)])
+            .await?
+            .expect_success("CreateQueue")?;
+        let queue_url = response.require("QueueUrl")?;
+        self.queues.borrow_mut().push(queue_url.clone());
+        Ok(queue_url)
+    }
+
+    async fn create_topic(&self, suffix: &str) -> Result<String, String> {
+        let name = format!("{}-{}", self.prefix, suffix);
+        let response = self
+            .sns("CreateTopic", &[("Name", &name)])
+            .await?
+            .expect_success("CreateTopic")?;
+        let topic_arn = response.require("TopicArn")?;
+        self.topics.borrow_mut().push(topic_arn.clone());
+        Ok(topic_arn)
+    }
+
+    async fn send(&self, queue_url: &str, body: &str) -> Result<Response, String> {
+        self.sqs(
+            "SendMessage",
+            &[("QueueUrl", queue_url), ("MessageBody", body)],
+        )
+        .await?
+        .expect_success("SendMessage")
+    }
+
+    /// Receive one message, waiting briefly for it, and return its raw XML.
+    async fn receive_one(&self, queue_url: &str) -> Result<String, String> {
+        let response = self
+            .sqs(
+                "ReceiveMessage",
+                &[
+                    ("QueueUrl", queue_url),
+                    ("MaxNumberOfMessages", "1"),
+                    ("WaitTimeSeconds", "2"),
+                    ("AttributeName.1", "All"),
+                    ("MessageAttributeName.1", "All"),
+                ],
+            )
+            .await?
+            .expect_success("ReceiveMessage")?;
+        get_raw_elements(&response.body, "Message")
+            .first()
+            .map(|x| x.to_string())
+            .ok_or_else(|| "No message was received".to_string())
+    }
+
+    async fn clean_up(&self) {
+        let queues = std::mem::take(&mut *self.queues.borrow_mut());
+        for queue_url in queues {
+            let _ = self.sqs("DeleteQueue", &[("QueueUrl", &queue_url)]).await;
+        }
+        let topics = std::mem::take(&mut *self.topics.borrow_mut());
+        for topic_arn in topics {
+            let _ = self.sns("DeleteTopic", &[("TopicArn", &topic_arn)]).await;
+        }
+    }
+
+    async fn check_create_queue_is_idempotent(&self) -> CheckResult {
+        let queue_url = self.create_queue("idempotent").await?;
+        let again = self.create_queue("idempotent").await?;
+        expect_eq("QueueUrl", &queue_url, &again)
+    }
+
+    async fn check_list_queues(&self) -> CheckResult {
+        let queue_url = self.create_queue("listed").await?;
+        let response = self
+            .sqs("ListQueues", &[("QueueNamePrefix", &self.prefix)])
+            .await?
+            .expect_success("ListQueues")?;
+        if get_elements(&response.body, "QueueUrl").contains(&queue_url) {
+            Ok(())
+        } else {
+            Err(format!("{} is missing from {}", queue_url, response.body))
+        }
+    }
+
+    async fn check_get_queue_url(&self) -> CheckResult {
+        let queue_url = self.create_queue("by-name").await?;
+        let name = format!("{}-by-name", self.prefix);
+        let response = self
+            .sqs("GetQueueUrl", &[("QueueName", &name)])
+            .await?
+            .expect_success("GetQueueUrl")?;
+        expect_eq("QueueUrl", &queue_url, &response.require("QueueUrl")?)
+    }
+
+    async fn check_send_md5(&self) -> CheckResult {
+        let queue_url = self.create_queue("send-md5").await?;
+        let body = "Hello, <world> & \"friends\" \u{1F600}";
+        let response = self.send(&queue_url, body).await?;
+        response.require("MessageId")?;
+        expect_eq(
+            "MD5OfMessageBody",
+            &get_md5(body),
+            &response.require("MD5OfMessageBody")?,
+        )
+    }
+
+    async fn check_send_attributes_md5(&self) -> CheckResult {
+        let queue_url = self.create_queue("attributes-md5").await?;
+        let mut params = vec![
+            ("QueueUrl".to_string(), queue_url),
+            ("MessageBody".to_string(), "with attributes".to_string()),
+        ];
+        for (i, a) in TEST_ATTRIBUTES.iter().enumerate() {
+            let prefix = format!("MessageAttribute.{}", i + 1);
+            let value_key = match a.data_type {
+                "Binary" => "BinaryValue",
+                _ => "StringValue",
+            };
+            params.push((format!("{}.Name", prefix), a.name.to_string()));
+            params.push((
+                format!("{}.Value.DataType", prefix),
+                a.data_type.to_string(),
+            ));
+            params.push((
+                format!("{}.Value.{}", prefix, value_key),
+                a.value.to_string(),
+            ));
+        }
+        let params: Vec<(&str, &str)> = params
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        let response = self
+            .sqs("SendMessage", &params)
+            .await?
+            .expect_success("SendMessage")?;
+        expect_eq(
+            "MD5OfMessageAttributes",
+            &get_expected_attributes_md5(TEST_ATTRIBUTES),
+            &response.require("MD5OfMessageAttributes")?,
+        )
+    }
+
+    async fn check_receive(&self) -> CheckResult {
+        let queue_url = self.create_queue("receive").await?;
+        let body = "receive & <check>";
+        let sent = self.send(&queue_url, body).await?;
+        let message = self.receive_one(&queue_url).await?;
+        let get = |tag: &str| {
+            get_elements(&message, tag)
+                .into_iter()
+                .next()
+                .ok_or_else(|| format!("The message has no {}: {}", tag, message))
+        };
+        expect_eq("MessageId", &sent.require("MessageId")?, &get("MessageId")?)?;
+        expect_eq("Body", body, &get("Body")?)?;
+        expect_eq("MD5OfBody", &get_md5(body), &get("MD5OfBody")?)?;
+        get("ReceiptHandle")?;
+        let names = get_elements(&message, "Name");
+        for name in &["SentTimestamp", "ApproximateReceiveCount"] {
+            if !names.iter().any(|x| x == name) {
+                return Err(format!("The message has no {} attribute", name));
+            }
+        }
+        Ok(())
+    }
+
+    async fn check_delete_message(&self) -> CheckResult {
+        let queue_url = self.create_queue("delete").await?;
+        self.send(&queue_url, "delete me").await?;
+        let message = self.receive_one(&queue_url).await?;
+        let handle = get_elements(&message, "ReceiptHandle")
+            .pop()
+            .ok_or("The message has no ReceiptHandle")?;
+        self.sqs(
+            "DeleteMessage",
+            &[("QueueUrl", &queue_url), ("ReceiptHandle", &handle)],
+        )
+        .await?
+        .expect_success("DeleteMessage")?;
+        Ok(())
+    }
+
+    async fn check_send_message_batch(&self) -> CheckResult {
+        let queue_url = self.create_queue("batch").await?;
+        let response = self
+            .sqs(
+                "SendMessageBatch",
+                &[
+                    ("QueueUrl", &queue_url),
+                    ("SendMessageBatchRequestEntry.1.Id", "first"),
+                    ("SendMessageBatchRequestEntry.1.MessageBody", "one"),
+                    ("SendMessageBatchRequestEntry.2.Id", "second"),
+                    ("SendMessageBatchRequestEntry.2.MessageBody", "two"),
+                ],
+            )
+            .await?
+            .expect_success("SendMessageBatch")?;
+        let entries = get_raw_elements(&response.body, "SendMessageBatchResultEntry");
+        if entries.len() == 2 {
+            Ok(())
+        } else {
+            Err(format!("Expected 2 result entries: {}", response.body))
+        }
+    }
+
+    async fn check_purge_queue(&self) -> CheckResult {
+        let queue_url = self.create_queue("purge").await?;
+        self.send(&queue_url, "purge me").await?;
+        self.sqs("PurgeQueue", &[("QueueUrl", &queue_url)])
+            .await?
+            .expect_success("PurgeQueue")?;
+        let response = self
+            .sqs("ReceiveMessage", &[("QueueUrl", &queue_url)])
+            .await?
+            .expect_success("ReceiveMessage")?;
+        if get_raw_elements(&response.body, "Message").is_empty() {
+            Ok(())
+        } else {
+            Err("A message was received after purging the queue".to_string())
+        }
+    }
+
+    async fn check_unknown_action(&self) -> CheckResult {
+        self.sqs("NotARealAction", &[])
+            .await?
+            .expect_error(400, "InvalidAction")
+    }
+
+    async fn check_missing_parameter(&self) -> CheckResult {
+        let queue_url = self.create_queue("missing-parameter").await?;
+        self.sqs("SendMessage", &[("QueueUrl", &queue_url)])
+            .await?
+            .expect_error(400, "MissingParameter")
+    }
+
+    async fn check_nonexistent_queue(&self) -> CheckResult {
+        let name = format!("{}-does-not-exist", self.prefix);
+        self.sqs("GetQueueAttributes", &[("QueueUrl", &name)])
+            .await?
+            .expect_error(400, "AWS.SimpleQueueService.NonExistentQueue")
+    }
+
+    async fn check_publish_envelope(&self) -> CheckResult {
+        let topic_arn = self.create_topic("envelope").await?;
+        let queue_url = self.create_queue("envelope").await?;
+        self.sns(
+            "Subscribe",
+            &[
+                ("TopicArn", &topic_arn),
+                ("Protocol", "sqs"),
+                ("Endpoint", &queue_url),
+            ],
+        )
+        .await?
+        .expect_success("Subscribe")?
+        .require("SubscriptionArn")?;
+        let published = self
+            .sns(
+                "Publish",
+                &[
+                    ("TopicArn", &topic_arn),
+                    ("Message", "envelope check"),
+                    ("Subject", "Check"),
+                ],
+            )
+            .await?
+            .expect_success("Publish")?;
+        let message_id = published.require("MessageId")?;
+
+        let message = self.receive_one(&queue_url).await?;
+        let body = get_elements(&message, "Body")
+            .pop()
+            .ok_or("The message has no Body")?;
+        let envelope: Value = serde_json::from_str(&body)
+            .map_err(|e| format!("The body isn't an SNS envelope ({}): {}", e, body))?;
+        let field = |name: &str| envelope[name].as_str().unwrap_or_default().to_string();
+        expect_eq("Type", "Notification", &field("Type"))?;
+        expect_eq("MessageId", &message_id, &field("MessageId"))?;
+        expect_eq("TopicArn", &topic_arn, &field("TopicArn"))?;
+        expect_eq("Message", "envelope check", &field("Message"))?;
+        expect_eq("Subject", "Check", &field("Subject"))?;
+        for name in &[
+            "Timestamp",
+            "SignatureVersion",
+            "Signature",
+            "UnsubscribeURL",
+        ] {
+            if field(name).is_empty() {
+                return Err(format!("The envelope has no {}: {}", name, body));
+            }
+        }
+        Ok(())
+    }
+
+    async fn check_raw_message_delivery(&self) -> CheckResult {
+        let topic_arn = self.create_topic("raw").await?;
+        let queue_url = self.create_queue("raw").await?;
+        self.sns(
+            "Subscribe",
+            &[
+                ("TopicArn", &topic_arn),
+                ("Protocol", "sqs"),
+                ("Endpoint", &queue_url),
+                ("Attributes.entry.1.key", "RawMessageDelivery"),
+                ("Attributes.entry.1.value", "true"),
+            ],
+        )
+        .await?
+        .expect_success("Subscribe")?;
+        self.sns(
+            "Publish",
+            &[("TopicArn", &topic_arn), ("Message", "raw check")],
+        )
+        .await?
+        .expect_success("Publish")?;
+        let message = self.receive_one(&queue_url).await?;
+        let body = get_elements(&message, "Body")
+            .pop()
+            .ok_or("The message has no Body")?;
+        expect_eq("Body", "raw check", &body)
+    }
+
+    async fn check_publish_to_missing_topic(&self) -> CheckResult {
+        let topic_arn = format!(
+            "arn:aws:sns:us-east-1:000000000000:{}-does-not-exist",
+            self.prefix
+        );
+        self.sns(
+            "Publish",
+            &[("TopicArn", &topic_arn), ("Message", "nowhere")],
+        )
+        .await?
+        .expect_error(404, "NotFound")
+    }
+}
+
+/// Run the conformance checks against a running instance and print the result of each.
+/// Returns the number of checks that failed.
+pub async fn verify(endpoint: &str) -> usize {
+    let v = Verifier::new(endpoint);
+    println!("Verifying {}", endpoint);
+
+    let results = vec![
+        (
+            "CreateQueue with an existing name returns the same QueueUrl",
+            v.check_create_queue_is_idempotent().await,
+        ),
+        (
+            "ListQueues includes queues matching the prefix",
+            v.check_list_queues().await,
+        ),
+        (
+            "GetQueueUrl returns the QueueUrl for a name",
+            v.check_get_queue_url().await,
+        ),
+        (
+            "SendMessage returns the MD5 of the body",
+            v.check_send_md5().await,
+        ),
+        (
+            "SendMessage returns the MD5 of the message attributes",
+            v.check_send_attributes_md5().await,
+        ),
+        (
+            "ReceiveMessage returns the message as sent",
+            v.check_receive().await,
+        ),
+        (
+            "DeleteMessage accepts a receipt handle",
+            v.check_delete_message().await,
+        ),
+        (
+            "SendMessageBatch returns a result for each entry",
+            v.check_send_message_batch().await,
+        ),
+        ("PurgeQueue empties the queue", v.check_purge_queue().await),
+        (
+            "Unknown actions fail with InvalidAction",
+            v.check_unknown_action().await,
+        ),
+        (
+            "Missing parameters fail with MissingParameter",
+            v.check_missing_parameter().await,
+        ),
+        (
+            "Missing queues fail with AWS.SimpleQueueService.NonExistentQueue",
+            v.check_nonexistent_queue().await,
+        ),
+        (
+            "Publish delivers an SNS envelope to SQS subscribers",
+            v.check_publish_envelope().await,
+        ),
+        (
+            "Publish delivers the raw message with RawMessageDelivery",
+            v.check_raw_message_delivery().await,
+        ),
+        (
+            "Publish to a missing topic fails with NotFound",
+            v.check_publish_to_missing_topic().await,
+        ),
+    ];
+    v.clean_up().await;
+
+    let mut failed = 0;
+    for (name, result) in &results {
+        match result {
+            Ok(()) => println!("PASS {}", name),
+            Err(e) => {
+                failed += 1;
+                println!("FAIL {}\n     {}", name, e);
+            }
+        }
+    }
+    println!("{} passed, {} failed", results.len() - failed, failed);
+    failed
+}
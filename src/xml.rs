@@ -1,21 +1,225 @@
-use crate::misc::escape_xml;
+use crate::misc::{escape_xml, escape_xml_into, get_new_id};
+use std::collections::BTreeMap;
+#[cfg(feature = "sqs")]
+use std::iter::once;
 
-pub trait FormatXML {
-    fn to_xml_string(&self, key: &str) -> String;
+/// The namespace of SQS responses.
+pub const SQS_NAMESPACE: &str = "http://queue.amazonaws.com/doc/2012-11-05/";
+/// The namespace of SNS responses.
+pub const SNS_NAMESPACE: &str = "http://sns.amazonaws.com/doc/2010-03-31/";
+/// The namespace of CloudWatch responses.
+pub const CLOUDWATCH_NAMESPACE: &str = "http://monitoring.amazonaws.com/doc/2010-08-01/";
+
+/// Writes an XML document. All text is escaped, so values can never change the structure of
+/// the document. Tag names are always our own, and are written as they are.
+#[derive(Default)]
+pub struct XmlWriter {
+    xml: String,
+}
+
+impl XmlWriter {
+    /// Write an element, with its contents written by `f`.
+    pub fn element<F: FnOnce(&mut Self)>(&mut self, tag: &str, f: F) {
+        self.open(tag);
+        f(self);
+        self.close(tag);
+    }
+
+    /// Write an element containing text.
+    pub fn text(&mut self, tag: &str, text: &str) {
+        self.element(tag, |w| escape_xml_into(&mut w.xml, text));
+    }
+
+    /// Write an element containing text, if there is any.
+    pub fn optional_text(&mut self, tag: &str, text: Option<&str>) {
+        if let Some(x) = text {
+            self.text(tag, x);
+        }
+    }
+
+    /// Write an element containing text for each value, e.g. `<member>` elements.
+    pub fn text_list<T: AsRef<str>>(&mut self, tag: &str, values: &[T]) {
+        for value in values {
+            self.text(tag, value.as_ref());
+        }
+    }
+
+    /// Write an element containing a value.
+    pub fn value<T: ToXml + ?Sized>(&mut self, tag: &str, value: &T) {
+        self.element(tag, |w| value.write_xml(w));
+    }
+
+    /// Write an element for each value.
+    pub fn list<T: ToXml>(&mut self, tag: &str, values: &[T]) {
+        for value in values {
+            self.value(tag, value);
+        }
+    }
+
+    /// Write the root element of a document, which declares its namespace.
+    pub fn root_element<F: FnOnce(&mut Self)>(&mut self, tag: &str, namespace: &str, f: F) {
+        self.open_root(tag, namespace);
+        f(self);
+        self.close(tag);
+    }
+
+    /// Write SNS attributes, as `<entry>` elements with a `<key>` and `<value>`.
+    pub fn entries(&mut self, attributes: &BTreeMap<&String, &String>) {
+        for (k, v) in attributes {
+            self.element("entry", |w| {
+                w.text("key", k);
+                w.text("value", v);
+            });
+        }
+    }
+
+    /// Open the root element of a response, which declares its namespace.
+    fn open_root(&mut self, tag: &str, namespace: &str) {
+        self.xml.push('<');
+        self.xml.push_str(tag);
+        self.xml.push_str(" xmlns=\"");
+        self.xml.push_str(&escape_xml(namespace));
+        self.xml.push_str("\">");
+    }
+
+    fn open(&mut self, tag: &str) {
+        self.xml.push('<');
+        self.xml.push_str(tag);
+        self.xml.push('>');
+    }
+
+    fn close(&mut self, tag: &str) {
+        self.xml.push_str("</");
+        self.xml.push_str(tag);
+        self.xml.push('>');
+    }
+
+    pub fn into_string(self) -> String {
+        self.xml
+    }
 }
 
-impl FormatXML for String {
-    fn to_xml_string(&self, key: &str) -> String {
-        format!("<{0}>{1}</{0}>", key, escape_xml(&self))
+/// Something that can be written as the contents of an XML element, such as the result of
+/// an action.
+pub trait ToXml {
+    fn write_xml(&self, w: &mut XmlWriter);
+}
+
+/// An empty result.
+impl ToXml for () {
+    fn write_xml(&self, _w: &mut XmlWriter) {}
+}
+
+impl<T: ToXml + ?Sized> ToXml for &T {
+    fn write_xml(&self, w: &mut XmlWriter) {
+        (**self).write_xml(w);
+    }
+}
+
+/// Build the response to a successful action. The result, if the action has one, is written
+/// inside `<{action}Result>`.
+pub fn get_response_xml(action: &str, namespace: &str, result: Option<&dyn ToXml>) -> String {
+    let tag = format!("{}Response", action);
+    let mut w = XmlWriter::default();
+    w.open_root(&tag, namespace);
+    if let Some(result) = result {
+        w.value(&format!("{}Result", action), result);
     }
+    w.element("ResponseMetadata", |w| w.text("RequestId", &get_new_id()));
+    w.close(&tag);
+    w.into_string()
 }
 
-impl<T> FormatXML for Vec<T>
+/// Build the response to a successful action, like `get_response_xml()`, but streamed. The
+/// result is written a chunk at a time, as the client reads the response.
+#[cfg(feature = "sqs")]
+pub fn get_streamed_response_xml<I>(action: &str, namespace: &str, result: I) -> ResponseBody
 where
-    T: FormatXML,
+    I: Iterator<Item = String> + Send + 'static,
 {
-    fn to_xml_string(&self, key: &str) -> String {
-        let list: Vec<String> = self.iter().map(|v| v.to_xml_string(key)).collect();
-        list.join("")
+    let request_id = get_new_id();
+    let mut w = XmlWriter::default();
+    w.open_root(&format!("{}Response", action), namespace);
+    w.open(&format!("{}Result", action));
+    let head = w.into_string();
+
+    let mut w = XmlWriter::default();
+    w.close(&format!("{}Result", action));
+    w.element("ResponseMetadata", |w| w.text("RequestId", &request_id));
+    w.close(&format!("{}Response", action));
+    let chunks = once(head).chain(result).chain(once(w.into_string()));
+    ResponseBody::Streamed(StreamedResponse {
+        request_id,
+        chunks: Box::new(chunks),
+    })
+}
+
+/// The body of a successful response. Most are built in full, but those that can be large
+/// are streamed, so that they are never held in memory all at once.
+pub enum ResponseBody {
+    Complete(String),
+    Streamed(StreamedResponse),
+}
+
+impl ResponseBody {
+    /// Get the whole body, writing the rest of it if it is streamed.
+    pub fn into_string(self) -> String {
+        match self {
+            ResponseBody::Complete(x) => x,
+            ResponseBody::Streamed(x) => x.collect(),
+        }
+    }
+}
+
+impl From<String> for ResponseBody {
+    fn from(body: String) -> Self {
+        ResponseBody::Complete(body)
     }
 }
+
+/// A response body that is written a chunk at a time, as it is read.
+pub struct StreamedResponse {
+    // The request id is at the end of the body, so it is kept for the response headers.
+    pub request_id: String,
+    chunks: Box<dyn Iterator<Item = String> + Send>,
+}
+
+impl Iterator for StreamedResponse {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        self.chunks.next()
+    }
+}
+
+/// The contents of every `<tag>` element in a response, as they appear in it.
+pub fn get_raw_elements<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let mut values = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        let end = match rest.find(&close) {
+            Some(x) => x,
+            None => break,
+        };
+        values.push(&rest[..end]);
+        rest = &rest[end + close.len()..];
+    }
+    values
+}
+
+/// The text of every `<tag>` element in a response, unescaped.
+pub fn get_elements(xml: &str, tag: &str) -> Vec<String> {
+    get_raw_elements(xml, tag)
+        .into_iter()
+        .map(|x| {
+            x.replace("&lt;", "<")
+                .replace("&gt;", ">")
+                .replace("&quot;", "\"")
+                .replace("&apos;", "'")
+                .replace("&amp;", "&")
+        })
+        .collect()
+}